@@ -80,6 +80,12 @@ pub struct DiscoveryRequest {
     pub mac: [u8; 16],
 
     pub envelopes: HashMap<String, DiscoveryRequestEnvelope>,
+
+    #[serde(default)]
+    pub protocolVersion: u32,
+
+    #[serde(default)]
+    pub replyFlags: u32,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
@@ -108,6 +114,9 @@ pub struct DiscoveryResponse {
 
     #[serde(with = "base64::SerdeFixedLengthBase64")]
     pub mac: [u8; 16],
+
+    #[serde(with = "base64::SerdeFixedLengthBase64")]
+    pub batchPublic: [u8; 32],
 }
 
 impl From<Vec<u8>> for RequestId {
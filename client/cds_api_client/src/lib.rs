@@ -92,9 +92,10 @@ pub struct CdsApiCredentials {
 }
 
 pub struct CdsApiDiscoveryResponse {
-    pub uuids:        Vec<Uuid>,
-    pub query_phones: Vec<u64>,
-    pub request_id:   usize,
+    pub uuids:                Vec<Uuid>,
+    pub directory_generation: u64,
+    pub query_phones:         Vec<u64>,
+    pub request_id:           usize,
 }
 
 impl CdsApiClient {
@@ -165,17 +166,19 @@ impl CdsApiClient {
             None => (),
         }
 
-        let (server_key, discovery_request) =
+        let (pending_discovery, discovery_request) =
             client.discovery_request(&mut rand::thread_rng(), attestation_key, negotiation, &phone_list)?;
         let discovery_response = self
             .put_discovery_request(&credentials, &enclave_name, cookies, discovery_request)
             .await?;
         debug!("discovery_response: {:#?}", discovery_response);
 
-        cds_client::Client::decode_discovery_response(server_key, discovery_response)
+        client
+            .decode_discovery_response(pending_discovery, discovery_response)
             .map_err(CdsApiClientError::from)
-            .map(|uuids| CdsApiDiscoveryResponse {
-                uuids,
+            .map(|results| CdsApiDiscoveryResponse {
+                uuids: results.uuids,
+                directory_generation: results.directory_generation,
                 query_phones: phone_list.to_vec(),
                 request_id,
             })
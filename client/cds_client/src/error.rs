@@ -38,4 +38,10 @@ pub enum CdsClientError {
 
     #[error("Error converting &[u8] to Uuid")]
     U8UuidConverionError,
+
+    #[error("Reply doesn't echo the nonce of the query")]
+    ReplyNonceMismatch,
+
+    #[error("Reply is too short to hold a result for each phone queried and the directory generation")]
+    ReplyTooShort,
 }
@@ -7,7 +7,7 @@
 
 use std::collections::HashMap;
 
-use byteorder::{BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
 use cds_api::entities::*;
 use rand::Rng;
 use ring::aead::{Aad, BoundKey, Nonce, NonceSequence, UnboundKey};
@@ -18,6 +18,11 @@ use crate::error::CdsClientError;
 
 pub mod error;
 
+// queries hold big-endian phones
+const DISCOVERY_PROTOCOL_VERSION: u32 = 0;
+// replies are encrypted under a batch key and start with the query nonce
+const DISCOVERY_REPLY_FLAGS: u32 = 1 | 2;
+
 #[derive(Clone)]
 pub struct Client {
     client_privkey: x25519_dalek::StaticSecret,
@@ -31,6 +36,20 @@ pub struct RequestNegotiation {
     pub encrypted_pending_request_id: EncryptedMessage,
 }
 
+/// What a client keeps of a discovery request it has sent, to decode the response to it.
+pub struct PendingDiscovery {
+    server_key:  [u8; 32],
+    query_nonce: [u8; 32],
+    phone_count: usize,
+}
+
+/// The results of a discovery request, along with the generation of the directory they were looked up in.
+#[derive(Debug, PartialEq)]
+pub struct DiscoveryResults {
+    pub uuids:                Vec<Uuid>,
+    pub directory_generation: u64,
+}
+
 pub struct EncryptedRequest {
     pub pending_request_id: Vec<u8>,
     pub encrypted_message:  EncryptedMessage,
@@ -147,7 +166,7 @@ impl Client {
         attestation_key: &str,
         negotiation: RequestNegotiation,
         phone_list: &[u64],
-    ) -> Result<([u8; 32], DiscoveryRequest), CdsClientError>
+    ) -> Result<(PendingDiscovery, DiscoveryRequest), CdsClientError>
     {
         let (client_key, server_key) = key_agreement(
             &self.client_privkey,
@@ -209,26 +228,70 @@ impl Client {
             iv: query_data_message.iv,
             mac: query_data_message.mac,
             envelopes,
+            protocolVersion: DISCOVERY_PROTOCOL_VERSION,
+            replyFlags: DISCOVERY_REPLY_FLAGS,
         };
 
-        Ok((server_key, discovery_request))
+        let pending_discovery = PendingDiscovery {
+            server_key,
+            query_nonce,
+            phone_count: phone_list.len(),
+        };
+        Ok((pending_discovery, discovery_request))
     }
 
-    pub fn decode_discovery_response(server_key: [u8; 32], response: DiscoveryResponse) -> Result<Vec<Uuid>, CdsClientError> {
-        let uuid_array_len = response.data.len();
-        let mut uuid_array = response.data;
-
-        uuid_array.extend_from_slice(&response.mac);
-        let mut ring_server_key = FixedOpeningKey::new(&server_key, &response.iv)?;
-        ring_server_key.open_in_place(&[], &mut uuid_array)?;
-        uuid_array.truncate(uuid_array_len);
+    pub fn decode_discovery_response(
+        &self,
+        pending_discovery: PendingDiscovery,
+        response: DiscoveryResponse,
+    ) -> Result<DiscoveryResults, CdsClientError>
+    {
+        let reply_len = response.data.len();
+        let mut reply = response.data;
+
+        reply.extend_from_slice(&response.mac);
+        self.open_reply(&pending_discovery.server_key, &response.batchPublic, &response.iv, &mut reply)?;
+        reply.truncate(reply_len);
+
+        // the reply starts with the nonce of the query it answers, followed by a uuid for each phone queried and then the
+        // little-endian generation of the directory; the server may pad what follows those out to a bucket size, so read
+        // no further than them
+        let query_nonce_len = pending_discovery.query_nonce.len();
+        if reply.get(..query_nonce_len) != Some(&pending_discovery.query_nonce[..]) {
+            return Err(CdsClientError::ReplyNonceMismatch);
+        }
+        let uuid_array_len = (pending_discovery.phone_count)
+            .checked_mul(std::mem::size_of::<Uuid>())
+            .ok_or(CdsClientError::ReplyTooShort)?;
+        let uuid_array = (reply[query_nonce_len..].get(..uuid_array_len)).ok_or(CdsClientError::ReplyTooShort)?;
+        let directory_generation_start = query_nonce_len + uuid_array_len;
+        let directory_generation = (reply.get(directory_generation_start..))
+            .and_then(|reply_tail| reply_tail.get(..std::mem::size_of::<u64>()))
+            .map(LittleEndian::read_u64)
+            .ok_or(CdsClientError::ReplyTooShort)?;
 
         // process the array in 16-byte chunks
         let mut uuids = Vec::new();
         for uuid_bytes in uuid_array.chunks_exact(std::mem::size_of::<Uuid>()) {
             uuids.push(Uuid::from_slice(uuid_bytes).map_err(|_| CdsClientError::U8UuidConverionError)?);
         }
-        Ok(uuids)
+        Ok(DiscoveryResults {
+            uuids,
+            directory_generation,
+        })
+    }
+
+    fn open_reply(
+        &self,
+        server_key: &[u8; 32],
+        batch_pubkey: &[u8; 32],
+        iv: &[u8; 12],
+        cyphertext: &mut [u8],
+    ) -> Result<(), CdsClientError>
+    {
+        let reply_key = reply_key_agreement(&self.client_privkey, server_key, batch_pubkey)?;
+        let mut ring_reply_key = FixedOpeningKey::new(&reply_key, iv)?;
+        ring_reply_key.open_in_place(batch_pubkey, cyphertext)
     }
 }
 
@@ -237,10 +300,9 @@ pub struct PendingRequest {
 }
 
 impl PendingRequest {
-    pub fn decrypt_reply(self, mut reply: EncryptedMessage) -> Result<(), CdsClientError> {
+    pub fn decrypt_reply(self, client: &Client, batch_pubkey: &[u8; 32], mut reply: EncryptedMessage) -> Result<(), CdsClientError> {
         reply.data.extend_from_slice(&reply.mac);
-        let mut ring_server_key = FixedOpeningKey::new(&self.server_key, &reply.iv)?;
-        ring_server_key.open_in_place(&[], &mut reply.data)?;
+        client.open_reply(&self.server_key, batch_pubkey, &reply.iv, &mut reply.data)?;
 
         Ok(())
     }
@@ -284,9 +346,133 @@ fn key_agreement(
 
     let prk = hkdf_salt.extract(&hkdf_secret);
     let key_type = CdsHkdfKeyType {};
-    let okm = prk.expand(&[&[0u8; 0]], key_type).unwrap();
+    let okm = prk.expand(&[&[0u8; 0]], key_type).map_err(|_| CdsClientError::ExtractHkdfError)?;
     okm.fill(keys.as_mut()).map_err(|_| CdsClientError::ExtractHkdfError)?;
     client_key.copy_from_slice(&keys[0..32]);
     server_key.copy_from_slice(&keys[32..64]);
     Ok((client_key, server_key))
 }
+
+struct CdsReplyHkdfKeyType {}
+impl ring::hkdf::KeyType for CdsReplyHkdfKeyType {
+    fn len(&self) -> usize {
+        32
+    }
+}
+
+fn reply_key_agreement(
+    client_privkey: &x25519_dalek::StaticSecret,
+    server_key: &[u8; 32],
+    batch_pubkey: &[u8; 32],
+) -> Result<[u8; 32], CdsClientError>
+{
+    let batch_dh_key = client_privkey.diffie_hellman(&x25519_dalek::PublicKey::from(*batch_pubkey));
+    let hkdf_secret = {
+        let mut hkdf_secret: [u8; 64] = [0; 64];
+        hkdf_secret[0..32].copy_from_slice(batch_dh_key.as_bytes());
+        hkdf_secret[32..64].copy_from_slice(server_key);
+        hkdf_secret
+    };
+    let hkdf_salt = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, batch_pubkey);
+
+    let mut reply_key: [u8; 32] = [0; 32];
+
+    let prk = hkdf_salt.extract(&hkdf_secret);
+    let okm = prk.expand(&[&[0u8; 0]], CdsReplyHkdfKeyType {}).map_err(|_| CdsClientError::ExtractHkdfError)?;
+    okm.fill(reply_key.as_mut()).map_err(|_| CdsClientError::ExtractHkdfError)?;
+    Ok(reply_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt_reply(client: &Client, pending_discovery: &PendingDiscovery, reply: &[u8]) -> DiscoveryResponse {
+        let batch_privkey = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+        let batch_pubkey = *x25519_dalek::PublicKey::from(&batch_privkey).as_bytes();
+        let reply_key = reply_key_agreement(&client.client_privkey, &pending_discovery.server_key, &batch_pubkey).unwrap();
+        let encrypted_reply = Client::encrypt_data(&mut rand::thread_rng(), &reply_key, reply, &batch_pubkey).unwrap();
+        DiscoveryResponse {
+            requestId:   RequestId(Vec::new()),
+            data:        encrypted_reply.data,
+            iv:          encrypted_reply.iv,
+            mac:         encrypted_reply.mac,
+            batchPublic: batch_pubkey,
+        }
+    }
+
+    fn pending_discovery(phone_count: usize) -> PendingDiscovery {
+        let mut random = rand::thread_rng();
+        PendingDiscovery {
+            server_key: random.gen(),
+            query_nonce: random.gen(),
+            phone_count,
+        }
+    }
+
+    #[test]
+    fn test_decode_padded_reply() {
+        let client = Client::new(&mut rand::thread_rng());
+        let pending_discovery = pending_discovery(2);
+        let uuids = [Uuid::new_v4(), Uuid::nil()];
+
+        let mut reply = pending_discovery.query_nonce.to_vec();
+        reply.extend(uuids.iter().flat_map(|uuid| uuid.as_bytes().to_vec()));
+        reply.extend(&7u64.to_le_bytes());
+        reply.resize(256, 0);
+        let response = encrypt_reply(&client, &pending_discovery, &reply);
+
+        let decoded = client.decode_discovery_response(pending_discovery, response).unwrap();
+        assert_eq!(decoded.uuids, uuids);
+        assert_eq!(decoded.directory_generation, 7);
+    }
+
+    #[test]
+    fn test_decode_directory_generation() {
+        let client = Client::new(&mut rand::thread_rng());
+        let pending_discovery = pending_discovery(1);
+        let uuid = Uuid::new_v4();
+        let directory_generation: u64 = rand::thread_rng().gen();
+
+        let mut reply = pending_discovery.query_nonce.to_vec();
+        reply.extend(uuid.as_bytes());
+        reply.extend(&directory_generation.to_le_bytes());
+        let response = encrypt_reply(&client, &pending_discovery, &reply);
+
+        let decoded = client.decode_discovery_response(pending_discovery, response).unwrap();
+        assert_eq!(decoded, DiscoveryResults {
+            uuids: vec![uuid],
+            directory_generation,
+        });
+    }
+
+    #[test]
+    fn test_decode_reply_without_directory_generation() {
+        let client = Client::new(&mut rand::thread_rng());
+        let pending_discovery = pending_discovery(1);
+
+        let mut reply = pending_discovery.query_nonce.to_vec();
+        reply.extend(Uuid::new_v4().as_bytes());
+        let response = encrypt_reply(&client, &pending_discovery, &reply);
+
+        match client.decode_discovery_response(pending_discovery, response) {
+            Err(CdsClientError::ReplyTooShort) => (),
+            result => panic!("decoded reply without directory generation: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_decode_short_reply() {
+        let client = Client::new(&mut rand::thread_rng());
+        let pending_discovery = pending_discovery(2);
+
+        let mut reply = pending_discovery.query_nonce.to_vec();
+        reply.extend(Uuid::new_v4().as_bytes());
+        let response = encrypt_reply(&client, &pending_discovery, &reply);
+
+        match client.decode_discovery_response(pending_discovery, response) {
+            Err(CdsClientError::ReplyTooShort) => (),
+            result => panic!("decoded short reply: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+/*
+ * Copyright (C) 2020 Signal Messenger, LLC.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! End-to-end test of a signed enclave on a real SGX machine.
+//!
+//! Usage: `hw_tests <signed enclave path> <hex SPID>`
+//!
+//! Loads the enclave, gets it a quote, then runs batches of discovery requests built by the client library through it
+//! the way the service does, each passing the same ratelimit state, checking every reply decrypts to the uuids of the
+//! synthetic directory. Exits non-zero on the first failure.
+
+use std::convert::TryFrom;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use cds_api::entities::{DiscoveryResponse, RequestId};
+use cds_client::{Client, EncryptedMessage, RequestNegotiation};
+use cds_enclave_ffi::args::{aes_gcm_iv_from_slice, aes_gcm_mac_from_slice, ServerCallArgsBuilder, ServerStartArgsBuilder};
+use cds_enclave_ffi::sgxsd::*;
+use failure::{bail, format_err, Error};
+use rand::Rng;
+use sgx_sdk_ffi::SgxEnclaveId;
+
+const ATTESTATION_KEY: &str = "hw-tests";
+
+const DIRECTORY_PHONES: u64 = 1 << 16;
+const REQUEST_PHONES: u64 = 64;
+const REQUESTS_PER_BATCH: usize = 8;
+const BATCHES: usize = 2;
+
+const RATELIMIT_STATE_SIZE: usize = 4096;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Directory {
+    phones: Vec<Phone>,
+    uuids: Vec<SgxsdUuid>,
+}
+
+struct PendingReply {
+    client: Client,
+    discovery: cds_client::PendingDiscovery,
+    expected: Vec<[u8; 16]>,
+    replies: mpsc::Receiver<SgxsdResult<MessageReply>>,
+}
+
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("hw_tests failed: {}", error);
+        std::process::exit(1);
+    }
+    println!("hw_tests passed");
+}
+
+fn run() -> Result<(), Error> {
+    let mut args = std::env::args().skip(1);
+    let (enclave_path, spid) = match (args.next(), args.next()) {
+        (Some(enclave_path), Some(spid)) => (enclave_path, parse_spid(&spid)?),
+        _ => bail!("usage: hw_tests <signed enclave path> <hex SPID>"),
+    };
+
+    let enclave_id = sgxsd_create_enclave(&enclave_path, false)?;
+    let res = run_enclave(enclave_id, &spid);
+    sgxsd_destroy_enclave(enclave_id)?;
+    res
+}
+
+fn run_enclave(enclave_id: SgxEnclaveId, spid: &[u8; 16]) -> Result<(), Error> {
+    sgxsd_node_init(enclave_id, 8, 16)?;
+    let quote = sgxsd_get_next_quote(enclave_id, spid, &[])?;
+    if quote.data.is_empty() {
+        bail!("empty quote");
+    }
+    sgxsd_set_current_quote(enclave_id)?;
+
+    let mut rng = rand::thread_rng();
+    let directory = Directory::new(&mut rng);
+    let mut ratelimit_state = vec![0; RATELIMIT_STATE_SIZE];
+    let ratelimit_uuid = SgxsdUuid::from(rng.gen::<[u8; 16]>());
+    for batch in 0..BATCHES {
+        run_batch(enclave_id, &directory, ratelimit_uuid, &mut ratelimit_state, &mut rng)
+            .map_err(|error| format_err!("batch {}: {}", batch, error))?;
+    }
+    Ok(())
+}
+
+fn run_batch(
+    enclave_id: SgxEnclaveId,
+    directory: &Directory,
+    ratelimit_uuid: SgxsdUuid,
+    ratelimit_state: &mut [u8],
+    rng: &mut (impl rand::RngCore + rand::CryptoRng),
+) -> Result<(), Error> {
+    let server_handle = 0;
+    let max_query_phones = u32::try_from(REQUEST_PHONES * REQUESTS_PER_BATCH as u64)?;
+    let start_args = ServerStartArgsBuilder::new(max_query_phones).max_ratelimit_states(1).build()?;
+    sgxsd_server_start(enclave_id, start_args.raw(), server_handle)?;
+
+    let mut pending_replies = Vec::with_capacity(REQUESTS_PER_BATCH);
+    for _ in 0..REQUESTS_PER_BATCH {
+        pending_replies.push(call(enclave_id, directory, server_handle, ratelimit_uuid, ratelimit_state, rng)?);
+    }
+
+    let stop_args = ServerStopArgs {
+        in_phones: directory.phones.as_ptr(),
+        in_phone_count: directory.phones.len(),
+        in_uuids: directory.uuids.as_ptr(),
+        ..Default::default()
+    };
+    if sgxsd_server_stop(enclave_id, &stop_args, server_handle)? != 0 {
+        bail!("server stop didn't finish the batch");
+    }
+
+    for pending_reply in pending_replies {
+        pending_reply.check()?;
+    }
+    Ok(())
+}
+
+fn call(
+    enclave_id: SgxEnclaveId,
+    directory: &Directory,
+    server_handle: SgxsdServerStateHandle,
+    ratelimit_uuid: SgxsdUuid,
+    ratelimit_state: &mut [u8],
+    rng: &mut (impl rand::RngCore + rand::CryptoRng),
+) -> Result<PendingReply, Error> {
+    let client = Client::new(rng);
+
+    let negotiation_request = SgxsdRequestNegotiationRequest {
+        client_pubkey: SgxsdCurve25519PublicKey {
+            x: *client.client_pubkey(),
+        },
+    };
+    let negotiation_response = sgxsd_negotiate_request(enclave_id, &negotiation_request)?;
+    let negotiation = RequestNegotiation {
+        server_ephemeral_pubkey: negotiation_response.server_ephemeral_pubkey.x,
+        server_static_pubkey: negotiation_response.server_static_pubkey.x,
+        encrypted_pending_request_id: EncryptedMessage {
+            iv: negotiation_response.encrypted_pending_request_id.iv.data,
+            mac: negotiation_response.encrypted_pending_request_id.mac.data,
+            data: negotiation_response.encrypted_pending_request_id.data.to_vec(),
+        },
+    };
+
+    // half of the phones asked for are in the directory
+    let (phones, expected): (Vec<u64>, Vec<[u8; 16]>) = (0..REQUEST_PHONES)
+        .map(|index| {
+            if index % 2 == 0 {
+                let found = rng.gen_range(0, directory.phones.len());
+                (u64::from_be(directory.phones[found]), directory.uuids[found].into())
+            } else {
+                (test_phone(DIRECTORY_PHONES + rng.gen_range(0, DIRECTORY_PHONES)), [0; 16])
+            }
+        })
+        .unzip();
+
+    let (discovery, mut request) = client.discovery_request(rng, ATTESTATION_KEY, negotiation, &phones)?;
+    let envelope = request
+        .envelopes
+        .remove(ATTESTATION_KEY)
+        .ok_or_else(|| format_err!("no envelope for {}", ATTESTATION_KEY))?;
+    let msg_header = SgxsdMessageHeader {
+        iv: aes_gcm_iv_from_slice("envelope iv", &envelope.iv)?,
+        mac: aes_gcm_mac_from_slice("envelope mac", &envelope.mac)?,
+        pending_request_id: SgxsdPendingRequestId::try_from(&envelope.requestId.0[..]).map_err(|()| format_err!("invalid request id"))?,
+    };
+
+    let call_args = ServerCallArgsBuilder::new(request.addressCount)
+        .query(
+            aes_gcm_iv_from_slice("query iv", &request.iv)?,
+            aes_gcm_mac_from_slice("query mac", &request.mac)?,
+            &mut request.data,
+        )
+        .query_commitment(request.commitment)
+        .ratelimit_state(ratelimit_uuid, ratelimit_state)
+        .build()?;
+
+    let (reply_tx, replies) = mpsc::channel();
+    sgxsd_server_call(
+        enclave_id,
+        *call_args.raw(),
+        &msg_header,
+        &envelope.data,
+        move |reply| {
+            let _ignore = reply_tx.send(reply);
+        },
+        server_handle,
+    )?;
+
+    Ok(PendingReply {
+        client,
+        discovery,
+        expected,
+        replies,
+    })
+}
+
+fn test_phone(number: u64) -> u64 {
+    15_550_000_000 + number
+}
+
+fn parse_spid(hex: &str) -> Result<[u8; 16], Error> {
+    let mut spid = [0; 16];
+    if hex.len() != spid.len() * 2 {
+        bail!("SPID must be {} hex digits", spid.len() * 2);
+    }
+    for (spid_byte, hex_byte) in spid.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *spid_byte = u8::from_str_radix(std::str::from_utf8(hex_byte)?, 16)?;
+    }
+    Ok(spid)
+}
+
+//
+// Directory impls
+//
+
+impl Directory {
+    fn new(rng: &mut impl rand::RngCore) -> Self {
+        let phones = (0..DIRECTORY_PHONES).map(|number| test_phone(number).to_be()).collect();
+        let uuids = (0..DIRECTORY_PHONES)
+            .map(|_| {
+                let mut uuid = [0; 16];
+                rng.fill_bytes(&mut uuid);
+                SgxsdUuid::from(uuid)
+            })
+            .collect();
+        Self { phones, uuids }
+    }
+}
+
+//
+// PendingReply impls
+//
+
+impl PendingReply {
+    fn check(self) -> Result<(), Error> {
+        let reply = self.replies.recv_timeout(REPLY_TIMEOUT)??;
+        let response = DiscoveryResponse {
+            requestId: RequestId(Vec::new()),
+            data: reply.data,
+            iv: reply.iv.data,
+            mac: reply.mac.data,
+            batchPublic: reply.batch_pubkey,
+        };
+        let uuids = self.client.decode_discovery_response(self.discovery, response)?.uuids;
+        if uuids.len() != self.expected.len() {
+            bail!("expected {} uuids in reply, got {}", self.expected.len(), uuids.len());
+        }
+        for (index, (uuid, expected)) in uuids.iter().zip(&self.expected).enumerate() {
+            if uuid.as_bytes() != expected {
+                bail!("wrong uuid for phone {} of request", index);
+            }
+        }
+        Ok(())
+    }
+}
@@ -26,5 +26,6 @@ mod bindgen_wrapper {
     include!(concat!(env!("OUT_DIR"), "/bindgen_wrapper.rs"));
 }
 
+pub mod args;
 pub mod ocalls;
 pub mod sgxsd;
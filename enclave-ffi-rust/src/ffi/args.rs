@@ -0,0 +1,718 @@
+/*
+ * Copyright (C) 2020 Signal Messenger, LLC.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Builders for the arguments of `sgxsd_server_start` and `sgxsd_server_call`.
+//!
+//! The enclave rejects inconsistent arguments with a bare `SGX_ERROR_INVALID_PARAMETER` or
+//! `CDS_ERROR_INVALID_REQUEST_SIZE`, after the call has already been queued. The builders check the same
+//! invariants up front and say which one was broken, and tie the built arguments to the lifetime of the
+//! buffers they point into.
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::num::NonZeroU64;
+use std::ptr;
+
+use super::sgxsd::{
+    CDSEncryptedMsg, CdsReplyScratch, Phone, ProtocolVersion, ReplyFlags, ReplyLayout, ReplyOrder, RequestPriority, SgxsdAesGcmIv, SgxsdAesGcmMac, SgxsdServerCallArgs, SgxsdServerInitArgs, SgxsdUuid, CDS_PROTOCOL_VERSION_1, SGXSD_SHA256_HASH_SIZE,
+};
+
+/// Size of the random nonce the client prepends to the phones of a query, which is covered by its commitment.
+pub const QUERY_COMMITMENT_NONCE_SIZE: usize = 32;
+
+pub type QueryCommitment = [u8; SGXSD_SHA256_HASH_SIZE as usize];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArgsError {
+    ZeroQueryPhones,
+    FairAdmissionExceedsCapacity { fair_admission_phones: u32, max_query_phones: u32 },
+    QuerySizeMismatch { query_phone_count: u32, expected: usize, actual: usize },
+    FieldSize { name: &'static str, expected: usize, actual: usize },
+    TooLarge { name: &'static str, size: usize },
+    TooSmall { name: &'static str, min_size: usize, size: usize },
+    Missing { name: &'static str },
+}
+
+#[derive(Debug, Default)]
+pub struct ServerStartArgsBuilder<'a> {
+    max_query_phones: u32,
+    max_ratelimit_states: u32,
+    max_untrusted_read_bytes: u32,
+    fair_admission_phones: u32,
+    miss_rate_alert_ppm: u32,
+    require_sorted_query: bool,
+    reply_padding_size: u32,
+    dedup_query_phones: bool,
+    max_phones_per_request: u32,
+    interactive_reserve_phones: u32,
+    canonicalization_rules: &'a [u8],
+    reply_scratch: Option<&'a mut [u8]>,
+}
+
+pub struct ServerStartArgs<'a> {
+    raw: SgxsdServerInitArgs,
+    _buffers: PhantomData<&'a mut [u8]>,
+}
+
+#[derive(Debug, Default)]
+pub struct ServerCallArgsBuilder<'a> {
+    query_phone_count: u32,
+    query_iv: SgxsdAesGcmIv,
+    query_mac: SgxsdAesGcmMac,
+    query_data: Option<&'a mut [u8]>,
+    query_commitment: Option<QueryCommitment>,
+    ratelimit_state: Option<(SgxsdUuid, &'a mut [u8])>,
+    admission_ticks: u64,
+    reply_flags: ReplyFlags,
+    reply_layout: ReplyLayout,
+    reply_order: ReplyOrder,
+    priority: RequestPriority,
+    query_fragment: Option<(NonZeroU64, bool)>,
+    since_change_token: u64,
+    protocol_version: ProtocolVersion,
+    query_key_size: u32,
+    query_chunk_commitments: Option<(u32, &'a [QueryCommitment])>,
+    request_id: u64,
+}
+
+pub struct ServerCallArgs<'a> {
+    raw: SgxsdServerCallArgs,
+    _buffers: PhantomData<&'a mut [u8]>,
+}
+
+//
+// ArgsError impls
+//
+
+impl std::error::Error for ArgsError {}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgsError::ZeroQueryPhones => write!(fmt, "query must contain at least one phone"),
+            ArgsError::FairAdmissionExceedsCapacity {
+                fair_admission_phones,
+                max_query_phones,
+            } => write!(
+                fmt,
+                "fair_admission_phones {} exceeds max_query_phones {}",
+                fair_admission_phones, max_query_phones
+            ),
+            ArgsError::QuerySizeMismatch {
+                query_phone_count,
+                expected,
+                actual,
+            } => write!(
+                fmt,
+                "query of {} phones must be {} bytes, got {}",
+                query_phone_count, expected, actual
+            ),
+            ArgsError::FieldSize { name, expected, actual } => write!(fmt, "{} must be {} bytes, got {}", name, expected, actual),
+            ArgsError::TooLarge { name, size } => write!(fmt, "{} of {} bytes is too large", name, size),
+            ArgsError::TooSmall { name, min_size, size } => write!(fmt, "{} of {} bytes is smaller than {} bytes", name, size, min_size),
+            ArgsError::Missing { name } => write!(fmt, "{} is required", name),
+        }
+    }
+}
+
+//
+// ServerStartArgsBuilder impls
+//
+
+impl<'a> ServerStartArgsBuilder<'a> {
+    pub fn new(max_query_phones: u32) -> Self {
+        Self {
+            max_query_phones,
+            ..Default::default()
+        }
+    }
+
+    pub fn max_ratelimit_states(mut self, max_ratelimit_states: u32) -> Self {
+        self.max_ratelimit_states = max_ratelimit_states;
+        self
+    }
+
+    /// Limits the bytes of untrusted memory each call may have the enclave read, or 0 for no limit.
+    pub fn max_untrusted_read_bytes(mut self, max_untrusted_read_bytes: u32) -> Self {
+        self.max_untrusted_read_bytes = max_untrusted_read_bytes;
+        self
+    }
+
+    /// Remaining batch capacity below which each session is held to its fair share, or 0 to disable.
+    pub fn fair_admission_phones(mut self, fair_admission_phones: u32) -> Self {
+        self.fair_admission_phones = fair_admission_phones;
+        self
+    }
+
+    /// Lookup miss rate average, in parts per million, above which the server metrics raise an alert, or 0 to disable.
+    pub fn miss_rate_alert_ppm(mut self, miss_rate_alert_ppm: u32) -> Self {
+        self.miss_rate_alert_ppm = miss_rate_alert_ppm;
+        self
+    }
+
+    /// Refuse queries whose keys aren't sorted in strictly ascending order, with `CDS_ERROR_QUERY_NOT_SORTED`.
+    pub fn require_sorted_query(mut self, require_sorted_query: bool) -> Self {
+        self.require_sorted_query = require_sorted_query;
+        self
+    }
+
+    /// Pad each reply with zeroes to a multiple of `reply_padding_size` bytes, so that its length leaks less about the
+    /// size of its query; 0 (the default) for no padding.
+    pub fn reply_padding_size(mut self, reply_padding_size: u32) -> Self {
+        self.reply_padding_size = reply_padding_size;
+        self
+    }
+
+    /// Look up each distinct phone of a batch in the directory only once, however many of its requests ask about it.
+    pub fn dedup_query_phones(mut self, dedup_query_phones: bool) -> Self {
+        self.dedup_query_phones = dedup_query_phones;
+        self
+    }
+
+    /// The most phones a single query may hold, so that one client can't take up the whole of a batch; larger queries
+    /// are refused with `CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED`. 0 (the default) for no limit beyond the batch's.
+    pub fn max_phones_per_request(mut self, max_phones_per_request: u32) -> Self {
+        self.max_phones_per_request = max_phones_per_request;
+        self
+    }
+
+    /// The capacity of each batch kept for interactive requests: a `CDS_REQUEST_PRIORITY_BULK` request that would leave
+    /// less than this much is refused with `CDS_ERROR_REQUEST_DEFERRED`, to be handed in to a later batch. 0 (the
+    /// default) to admit bulk requests as long as they fit.
+    pub fn interactive_reserve_phones(mut self, interactive_reserve_phones: u32) -> Self {
+        self.interactive_reserve_phones = interactive_reserve_phones;
+        self
+    }
+
+    /// Serialized canonicalization rules, see `cds_enclave/src/service/canonicalize.rs`.
+    pub fn canonicalization_rules(mut self, canonicalization_rules: &'a [u8]) -> Self {
+        self.canonicalization_rules = canonicalization_rules;
+        self
+    }
+
+    /// Untrusted memory each stop call writes the replies it can fit into, to be sent with
+    /// [`sgxsd_dispatch_reply_scratch`](super::sgxsd::sgxsd_dispatch_reply_scratch) before the next one. It must stay
+    /// allocated until the server has stopped.
+    pub fn reply_scratch(mut self, reply_scratch: &'a mut [u8]) -> Self {
+        self.reply_scratch = Some(reply_scratch);
+        self
+    }
+
+    pub fn build(self) -> Result<ServerStartArgs<'a>, ArgsError> {
+        if self.max_query_phones == 0 {
+            return Err(ArgsError::ZeroQueryPhones);
+        }
+        if self.fair_admission_phones > self.max_query_phones {
+            return Err(ArgsError::FairAdmissionExceedsCapacity {
+                fair_admission_phones: self.fair_admission_phones,
+                max_query_phones: self.max_query_phones,
+            });
+        }
+        let canonicalization_rules = match self.canonicalization_rules {
+            [] => ptr::null(),
+            rules => rules.as_ptr(),
+        };
+        let (reply_scratch, reply_scratch_size) = match self.reply_scratch {
+            Some(reply_scratch) if reply_scratch.len() < mem::size_of::<CdsReplyScratch>() => {
+                return Err(ArgsError::TooSmall {
+                    name: "reply_scratch",
+                    min_size: mem::size_of::<CdsReplyScratch>(),
+                    size: reply_scratch.len(),
+                });
+            }
+            Some(reply_scratch) => (reply_scratch.as_mut_ptr(), reply_scratch.len()),
+            None => (ptr::null_mut(), 0),
+        };
+        Ok(ServerStartArgs {
+            raw: SgxsdServerInitArgs {
+                max_query_phones: self.max_query_phones,
+                max_ratelimit_states: self.max_ratelimit_states,
+                max_untrusted_read_bytes: self.max_untrusted_read_bytes,
+                fair_admission_phones: self.fair_admission_phones,
+                miss_rate_alert_ppm: self.miss_rate_alert_ppm,
+                require_sorted_query: self.require_sorted_query.into(),
+                canonicalization_rules,
+                canonicalization_rules_size: self.canonicalization_rules.len(),
+                reply_scratch,
+                reply_scratch_size,
+                reply_padding_size: self.reply_padding_size,
+                dedup_query_phones: self.dedup_query_phones.into(),
+                max_phones_per_request: self.max_phones_per_request,
+                interactive_reserve_phones: self.interactive_reserve_phones,
+            },
+            _buffers: PhantomData,
+        })
+    }
+}
+
+impl<'a> ServerStartArgs<'a> {
+    pub fn raw(&self) -> &SgxsdServerInitArgs {
+        &self.raw
+    }
+}
+
+//
+// ServerCallArgsBuilder impls
+//
+
+impl<'a> ServerCallArgsBuilder<'a> {
+    pub fn new(query_phone_count: u32) -> Self {
+        Self {
+            query_phone_count,
+            ..Default::default()
+        }
+    }
+
+    /// The encrypted query of `QUERY_COMMITMENT_NONCE_SIZE` nonce bytes followed by the phones, with its IV and MAC.
+    pub fn query(mut self, iv: SgxsdAesGcmIv, mac: SgxsdAesGcmMac, data: &'a mut [u8]) -> Self {
+        self.query_iv = iv;
+        self.query_mac = mac;
+        self.query_data = Some(data);
+        self
+    }
+
+    pub fn query_commitment(mut self, query_commitment: QueryCommitment) -> Self {
+        self.query_commitment = Some(query_commitment);
+        self
+    }
+
+    pub fn ratelimit_state(mut self, uuid: SgxsdUuid, data: &'a mut [u8]) -> Self {
+        self.ratelimit_state = Some((uuid, data));
+        self
+    }
+
+    /// Host clock at which the call was admitted, only used for queue age metrics.
+    pub fn admission_ticks(mut self, admission_ticks: u64) -> Self {
+        self.admission_ticks = admission_ticks;
+        self
+    }
+
+    /// The `CDS_REPLY_FLAG_*` bits the client named for its reply; 0 unless set, which gets the reply clients always got.
+    pub fn reply_flags(mut self, reply_flags: ReplyFlags) -> Self {
+        self.reply_flags = reply_flags;
+        self
+    }
+
+    /// What the reply holds for each phone, as asked for by the client; `CDS_REPLY_LAYOUT_UUID` unless set.
+    pub fn reply_layout(mut self, reply_layout: ReplyLayout) -> Self {
+        self.reply_layout = reply_layout;
+        self
+    }
+
+    /// The order the reply holds its results in, as asked for by the client; `CDS_REPLY_ORDER_QUERY` unless set.
+    pub fn reply_order(mut self, reply_order: ReplyOrder) -> Self {
+        self.reply_order = reply_order;
+        self
+    }
+
+    /// How urgently the host wants the query looked up; `CDS_REQUEST_PRIORITY_INTERACTIVE` unless set.
+    pub fn priority(mut self, priority: RequestPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Hands the query in as a fragment of a larger one named by `token`, in calls from the same session; only the
+    /// first fragment starts with the nonce, and the query is only looked up once the one without `more_fragments`
+    /// has arrived.
+    pub fn query_fragment(mut self, token: NonZeroU64, more_fragments: bool) -> Self {
+        self.query_fragment = Some((token, more_fragments));
+        self
+    }
+
+    /// The change token of the client's previous reply, for a query in `CDS_REPLY_LAYOUT_UUID_CHANGES` asking only for
+    /// what changed since; 0 unless set, which gets every entry.
+    pub fn since_change_token(mut self, since_change_token: u64) -> Self {
+        self.since_change_token = since_change_token;
+        self
+    }
+
+    /// The wire format of the query, as named by the client; `CDS_PROTOCOL_VERSION_0` unless set. A version the enclave
+    /// doesn't know is refused with `CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION`.
+    pub fn protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// The size of each key of the query, as named by the client; 0 unless set, which has the enclave tell it by the size
+    /// of the query as for phones. Queries of 128-bit identifiers must name theirs.
+    pub fn query_key_size(mut self, query_key_size: u32) -> Self {
+        self.query_key_size = query_key_size;
+        self
+    }
+
+    /// Commits to the query this call carries a chunk of `chunk_size` bytes at a time, the last possibly shorter, so that
+    /// the enclave refuses it at the first chunk that doesn't match; the query commitment then commits to the chunk
+    /// commitments of every fragment in turn rather than to the query itself.
+    pub fn query_chunk_commitments(mut self, chunk_size: u32, chunk_commitments: &'a [QueryCommitment]) -> Self {
+        self.query_chunk_commitments = Some((chunk_size, chunk_commitments));
+        self
+    }
+
+    /// The id the client gave the query, so that a retry of it by the same session while it is still pending shares its
+    /// reply instead of being looked up again; 0 unless set, which never matches.
+    pub fn request_id(mut self, request_id: u64) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn build(self) -> Result<ServerCallArgs<'a>, ArgsError> {
+        if self.query_phone_count == 0 {
+            return Err(ArgsError::ZeroQueryPhones);
+        }
+        let query_data = self.query_data.ok_or(ArgsError::Missing { name: "query" })?;
+        let query_commitment = self.query_commitment.ok_or(ArgsError::Missing { name: "query_commitment" })?;
+
+        let expected_query_size = match self.query_key_size {
+            0 => query_size(self.query_phone_count),
+            query_key_size => query_size_for_keys(self.query_phone_count, query_key_size),
+        };
+        let expected_query_size = expected_query_size.ok_or(ArgsError::TooLarge {
+            name: "query",
+            size: query_data.len(),
+        })?;
+        let continued_fragment_size = expected_query_size - QUERY_COMMITMENT_NONCE_SIZE;
+        // a query of phones sent as deltas is sized by its phones, and left to the enclave to check
+        let continued_fragment = self.query_fragment.is_some() && query_data.len() == continued_fragment_size;
+        if self.protocol_version != CDS_PROTOCOL_VERSION_1 && query_data.len() != expected_query_size && !continued_fragment {
+            return Err(ArgsError::QuerySizeMismatch {
+                query_phone_count: self.query_phone_count,
+                expected: expected_query_size,
+                actual: query_data.len(),
+            });
+        }
+        let query_size = u32::try_from(query_data.len()).map_err(|_| ArgsError::TooLarge {
+            name: "query",
+            size: query_data.len(),
+        })?;
+
+        let (ratelimit_state_uuid, ratelimit_state_size, ratelimit_state_data) = match self.ratelimit_state {
+            Some((uuid, data)) => {
+                let size = u32::try_from(data.len()).map_err(|_| ArgsError::TooLarge {
+                    name: "ratelimit_state",
+                    size: data.len(),
+                })?;
+                (uuid, size, data.as_mut_ptr())
+            }
+            None => (Default::default(), 0, ptr::null_mut()),
+        };
+
+        let (query_chunk_commitments, query_chunk_size, query_chunk_commitment_count) = match self.query_chunk_commitments {
+            Some((0, _)) => return Err(ArgsError::Missing { name: "query_chunk_size" }),
+            Some((chunk_size, chunk_commitments)) => {
+                let chunk_count = query_data.chunks(chunk_size as usize).len();
+                if chunk_commitments.len() != chunk_count {
+                    return Err(ArgsError::FieldSize {
+                        name: "query_chunk_commitments",
+                        expected: chunk_count * mem::size_of::<QueryCommitment>(),
+                        actual: chunk_commitments.len() * mem::size_of::<QueryCommitment>(),
+                    });
+                }
+                let chunk_count = u32::try_from(chunk_count).map_err(|_| ArgsError::TooLarge {
+                    name: "query_chunk_commitments",
+                    size: chunk_commitments.len() * mem::size_of::<QueryCommitment>(),
+                })?;
+                (chunk_commitments.as_ptr() as *const u8, chunk_size, chunk_count)
+            }
+            None => (ptr::null(), 0, 0),
+        };
+
+        Ok(ServerCallArgs {
+            raw: SgxsdServerCallArgs {
+                query_phone_count: self.query_phone_count,
+                ratelimit_state_size,
+                ratelimit_state_uuid,
+                ratelimit_state_data,
+                query: CDSEncryptedMsg {
+                    iv: self.query_iv,
+                    mac: self.query_mac,
+                    size: query_size,
+                    data: query_data.as_mut_ptr(),
+                },
+                query_commitment,
+                admission_ticks: self.admission_ticks,
+                reply_flags: self.reply_flags,
+                reply_reserved: 0,
+                reply_layout: self.reply_layout,
+                query_more_fragments: self.query_fragment.map_or(false, |(_, more_fragments)| more_fragments).into(),
+                query_fragment_token: self.query_fragment.map_or(0, |(token, _)| token.get()),
+                since_change_token: self.since_change_token,
+                protocol_version: self.protocol_version,
+                query_key_size: self.query_key_size,
+                query_chunk_commitments,
+                query_chunk_size,
+                query_chunk_commitment_count,
+                request_id: self.request_id,
+                reply_order: self.reply_order,
+                priority: self.priority,
+            },
+            _buffers: PhantomData,
+        })
+    }
+}
+
+impl<'a> ServerCallArgs<'a> {
+    pub fn raw(&self) -> &SgxsdServerCallArgs {
+        &self.raw
+    }
+}
+
+//
+// helpers
+//
+
+/// Size in bytes of the encrypted query for `query_phone_count` phones.
+pub fn query_size(query_phone_count: u32) -> Option<usize> {
+    query_size_for_keys(query_phone_count, mem::size_of::<Phone>().try_into().ok()?)
+}
+
+/// Size in bytes of the encrypted query for `query_key_count` keys of `query_key_size` bytes each.
+pub fn query_size_for_keys(query_key_count: u32, query_key_size: u32) -> Option<usize> {
+    let query_key_count: usize = query_key_count.try_into().ok()?;
+    let query_key_size: usize = query_key_size.try_into().ok()?;
+    (query_key_count.checked_mul(query_key_size)?).checked_add(QUERY_COMMITMENT_NONCE_SIZE)
+}
+
+pub fn aes_gcm_iv_from_slice(name: &'static str, data: &[u8]) -> Result<SgxsdAesGcmIv, ArgsError> {
+    Ok(SgxsdAesGcmIv {
+        data: fixed_size_field(name, data)?,
+    })
+}
+
+pub fn aes_gcm_mac_from_slice(name: &'static str, data: &[u8]) -> Result<SgxsdAesGcmMac, ArgsError> {
+    Ok(SgxsdAesGcmMac {
+        data: fixed_size_field(name, data)?,
+    })
+}
+
+pub fn query_commitment_from_slice(data: &[u8]) -> Result<QueryCommitment, ArgsError> {
+    fixed_size_field("query_commitment", data)
+}
+
+fn fixed_size_field<T>(name: &'static str, data: &[u8]) -> Result<T, ArgsError>
+where for<'b> T: TryFrom<&'b [u8]> {
+    T::try_from(data).map_err(|_| ArgsError::FieldSize {
+        name,
+        expected: mem::size_of::<T>(),
+        actual: data.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sgxsd::{
+        CDS_PROTOCOL_VERSION_0, CDS_PROTOCOL_VERSION_1, CDS_REPLY_FLAG_BATCH_KEY, CDS_REPLY_FLAG_QUERY_NONCE, CDS_REPLY_LAYOUT_ACI_PNI, CDS_REPLY_LAYOUT_UUID_CHANGES, CDS_REPLY_ORDER_QUERY,
+        CDS_REQUEST_PRIORITY_BULK, CDS_REQUEST_PRIORITY_INTERACTIVE, SGXSD_AES_GCM_IV_SIZE, SGXSD_AES_GCM_MAC_SIZE,
+    };
+    use super::*;
+
+    #[test]
+    fn test_start_args() {
+        let rules = [1, 2, 3];
+        let args = ServerStartArgsBuilder::new(10)
+            .fair_admission_phones(4)
+            .miss_rate_alert_ppm(50_000)
+            .require_sorted_query(true)
+            .reply_padding_size(256)
+            .dedup_query_phones(true)
+            .max_phones_per_request(6)
+            .interactive_reserve_phones(3)
+            .canonicalization_rules(&rules)
+            .build()
+            .unwrap();
+        assert_eq!(args.raw().max_query_phones, 10);
+        assert_eq!(args.raw().fair_admission_phones, 4);
+        assert_eq!(args.raw().miss_rate_alert_ppm, 50_000);
+        assert_eq!(args.raw().require_sorted_query, 1);
+        assert_eq!(args.raw().reply_padding_size, 256);
+        assert_eq!(args.raw().max_phones_per_request, 6);
+        assert_eq!(args.raw().interactive_reserve_phones, 3);
+        assert_eq!(args.raw().dedup_query_phones, 1);
+        assert_eq!(args.raw().canonicalization_rules, rules.as_ptr());
+        assert_eq!(args.raw().canonicalization_rules_size, rules.len());
+
+        let args = ServerStartArgsBuilder::new(10).build().unwrap();
+        assert!(args.raw().canonicalization_rules.is_null());
+        assert!(args.raw().reply_scratch.is_null());
+
+        let mut reply_scratch = vec![0; 4096];
+        let reply_scratch_ptr = reply_scratch.as_mut_ptr();
+        let args = ServerStartArgsBuilder::new(10).reply_scratch(&mut reply_scratch).build().unwrap();
+        assert_eq!(args.raw().reply_scratch, reply_scratch_ptr);
+        assert_eq!(args.raw().reply_scratch_size, 4096);
+
+        assert_eq!(
+            ServerStartArgsBuilder::new(10).reply_scratch(&mut [0; 4]).build().err(),
+            Some(ArgsError::TooSmall {
+                name: "reply_scratch",
+                min_size: 8,
+                size: 4,
+            })
+        );
+
+        assert_eq!(ServerStartArgsBuilder::new(0).build().err(), Some(ArgsError::ZeroQueryPhones));
+        assert_eq!(
+            ServerStartArgsBuilder::new(10).fair_admission_phones(11).build().err(),
+            Some(ArgsError::FairAdmissionExceedsCapacity {
+                fair_admission_phones: 11,
+                max_query_phones: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_call_args() {
+        let mut query = vec![0; QUERY_COMMITMENT_NONCE_SIZE + 2 * mem::size_of::<Phone>()];
+        let query_ptr = query.as_ptr();
+        let args = ServerCallArgsBuilder::new(2)
+            .query(Default::default(), Default::default(), &mut query)
+            .query_commitment([7; 32])
+            .admission_ticks(5)
+            .reply_layout(CDS_REPLY_LAYOUT_ACI_PNI)
+            .build()
+            .unwrap();
+        assert_eq!(args.raw().query.data as *const u8, query_ptr);
+        assert_eq!(args.raw().query.size, 48);
+        assert_eq!(args.raw().query_commitment, [7; 32]);
+        assert_eq!(args.raw().admission_ticks, 5);
+        assert_eq!(args.raw().reply_flags, 0);
+        assert_eq!(args.raw().reply_layout, CDS_REPLY_LAYOUT_ACI_PNI);
+        assert!(args.raw().ratelimit_state_data.is_null());
+        assert_eq!(args.raw().query_fragment_token, 0);
+        assert_eq!(args.raw().since_change_token, 0);
+        assert_eq!(args.raw().protocol_version, CDS_PROTOCOL_VERSION_0);
+        assert_eq!(args.raw().query_key_size, 0);
+        assert!(args.raw().query_chunk_commitments.is_null());
+        assert_eq!(args.raw().request_id, 0);
+        assert_eq!(args.raw().reply_order, CDS_REPLY_ORDER_QUERY);
+        assert_eq!(args.raw().priority, CDS_REQUEST_PRIORITY_INTERACTIVE);
+
+        // a client naming reply flags has them handed on as they are
+        let mut query = vec![0; QUERY_COMMITMENT_NONCE_SIZE + mem::size_of::<Phone>()];
+        let args = ServerCallArgsBuilder::new(1)
+            .query(Default::default(), Default::default(), &mut query)
+            .query_commitment([7; 32])
+            .reply_flags(CDS_REPLY_FLAG_BATCH_KEY | CDS_REPLY_FLAG_QUERY_NONCE)
+            .build()
+            .unwrap();
+        assert_eq!(args.raw().reply_flags, CDS_REPLY_FLAG_BATCH_KEY | CDS_REPLY_FLAG_QUERY_NONCE);
+
+        // a fragment after the first carries no nonce
+        let mut query = vec![0; 2 * mem::size_of::<Phone>()];
+        let args = ServerCallArgsBuilder::new(2)
+            .query(Default::default(), Default::default(), &mut query)
+            .query_commitment([7; 32])
+            .query_fragment(NonZeroU64::new(9).unwrap(), true)
+            .reply_layout(CDS_REPLY_LAYOUT_UUID_CHANGES)
+            .since_change_token(3)
+            .request_id(11)
+            .priority(CDS_REQUEST_PRIORITY_BULK)
+            .build()
+            .unwrap();
+        assert_eq!(args.raw().query.size, 16);
+        assert_eq!(args.raw().since_change_token, 3);
+        assert_eq!(args.raw().request_id, 11);
+        assert_eq!(args.raw().priority, CDS_REQUEST_PRIORITY_BULK);
+        assert_eq!(args.raw().query_fragment_token, 9);
+        assert_eq!(args.raw().query_more_fragments, 1);
+
+        // a query of identifiers is sized by the keys it names
+        let mut query = vec![0; QUERY_COMMITMENT_NONCE_SIZE + 2 * 16];
+        let args = ServerCallArgsBuilder::new(2)
+            .query(Default::default(), Default::default(), &mut query)
+            .query_commitment([7; 32])
+            .query_key_size(16)
+            .build()
+            .unwrap();
+        assert_eq!(args.raw().query.size, 64);
+        assert_eq!(args.raw().query_key_size, 16);
+
+        // a query of phones sent as deltas is smaller than one sent whole
+        let mut query = vec![0; QUERY_COMMITMENT_NONCE_SIZE + 3];
+        let args = ServerCallArgsBuilder::new(2)
+            .query(Default::default(), Default::default(), &mut query)
+            .query_commitment([7; 32])
+            .protocol_version(CDS_PROTOCOL_VERSION_1)
+            .build()
+            .unwrap();
+        assert_eq!(args.raw().query.size, 35);
+        assert_eq!(args.raw().protocol_version, CDS_PROTOCOL_VERSION_1);
+
+        // a query committed to a chunk at a time, the last chunk shorter than the rest
+        let mut query = vec![0; QUERY_COMMITMENT_NONCE_SIZE + 2 * mem::size_of::<Phone>()];
+        let chunk_commitments = [[1; 32], [2; 32]];
+        let args = ServerCallArgsBuilder::new(2)
+            .query(Default::default(), Default::default(), &mut query)
+            .query_commitment([7; 32])
+            .query_chunk_commitments(32, &chunk_commitments)
+            .build()
+            .unwrap();
+        assert_eq!(args.raw().query_chunk_commitments, chunk_commitments.as_ptr() as *const u8);
+        assert_eq!(args.raw().query_chunk_size, 32);
+        assert_eq!(args.raw().query_chunk_commitment_count, 2);
+        assert_eq!(
+            ServerCallArgsBuilder::new(2)
+                .query(Default::default(), Default::default(), &mut query)
+                .query_commitment([7; 32])
+                .query_chunk_commitments(16, &chunk_commitments)
+                .build()
+                .err(),
+            Some(ArgsError::FieldSize {
+                name: "query_chunk_commitments",
+                expected: 96,
+                actual: 64,
+            })
+        );
+
+        let mut query = vec![0; QUERY_COMMITMENT_NONCE_SIZE + mem::size_of::<Phone>()];
+        assert_eq!(
+            ServerCallArgsBuilder::new(2)
+                .query(Default::default(), Default::default(), &mut query)
+                .query_commitment([7; 32])
+                .build()
+                .err(),
+            Some(ArgsError::QuerySizeMismatch {
+                query_phone_count: 2,
+                expected: 48,
+                actual: 40,
+            })
+        );
+        assert_eq!(
+            ServerCallArgsBuilder::new(1)
+                .query(Default::default(), Default::default(), &mut query)
+                .build()
+                .err(),
+            Some(ArgsError::Missing { name: "query_commitment" })
+        );
+        assert_eq!(ServerCallArgsBuilder::new(0).build().err(), Some(ArgsError::ZeroQueryPhones));
+    }
+
+    #[test]
+    fn test_fixed_size_fields() {
+        assert_eq!(aes_gcm_iv_from_slice("query_iv", &[1; 12]).unwrap().data, [1; SGXSD_AES_GCM_IV_SIZE as usize]);
+        assert_eq!(aes_gcm_mac_from_slice("query_mac", &[2; 16]).unwrap().data, [2; SGXSD_AES_GCM_MAC_SIZE as usize]);
+        assert_eq!(
+            query_commitment_from_slice(&[3; 31]).err(),
+            Some(ArgsError::FieldSize {
+                name: "query_commitment",
+                expected: 32,
+                actual: 31,
+            })
+        );
+    }
+}
@@ -22,7 +22,7 @@ use super::sgxsd::*;
 
 #[no_mangle]
 pub extern "C" fn sgxsd_ocall_reply(
-    p_header: *const SgxsdMessageHeader,
+    p_header: *const SgxsdReplyHeader,
     p_data: *const u8,
     data_size: usize,
     raw_tag: sgxsd_msg_tag_t,
@@ -38,6 +38,7 @@ pub extern "C" fn sgxsd_ocall_reply(
             callback(Ok(MessageReply {
                 iv: header.iv,
                 mac: header.mac,
+                batch_pubkey: header.batch_pubkey.x,
                 data,
             }));
             SgxStatus::Success.into()
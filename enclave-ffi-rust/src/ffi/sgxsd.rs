@@ -20,30 +20,37 @@ use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::mem;
 use std::os::raw::*;
+use std::time::{Duration, Instant};
 
 use sgx_sdk_ffi::*;
 
 use super::bindgen_wrapper::{
-    sgx_destroy_enclave, sgx_report_attestation_status, sgx_status_t, sgxsd_enclave_get_next_report, sgxsd_enclave_negotiate_request,
-    sgxsd_enclave_node_init, sgxsd_enclave_server_call, sgxsd_enclave_server_start, sgxsd_enclave_server_stop,
-    sgxsd_enclave_set_current_quote, sgxsd_msg_tag__bindgen_ty_1, sgxsd_msg_tag_t, sgxsd_node_init_args_t, CDS_ERROR_INVALID_REQUEST_SIZE,
-    CDS_ERROR_QUERY_COMMITMENT_MISMATCH,
+    cds_scratch_reply_t, sgx_destroy_enclave, sgx_report_attestation_status, sgx_status_t, sgxsd_enclave_close_session, sgxsd_enclave_commit_directory, sgxsd_enclave_flush_replies, sgxsd_enclave_get_incident_record, sgxsd_enclave_get_next_report, sgxsd_enclave_run_benchmark, sgxsd_enclave_sample_directory,
+    sgxsd_enclave_negotiate_request, sgxsd_enclave_node_init, sgxsd_enclave_open_session, sgxsd_enclave_server_call, sgxsd_enclave_server_cancel_call, sgxsd_enclave_server_get_metrics, sgxsd_enclave_server_start, sgxsd_enclave_server_stop,
+    sgxsd_enclave_set_current_quote, sgxsd_enclave_set_session_denylist, sgxsd_msg_tag__bindgen_ty_1, sgxsd_msg_tag_t, sgxsd_node_init_args_t, CDS_ERROR_BATCH_FULL, CDS_ERROR_CANARY_MISMATCH, CDS_ERROR_DIRECTORY_DIGEST_MISMATCH,
+    CDS_ERROR_DIRECTORY_EPOCH_MISMATCH, CDS_ERROR_DUPLICATE_QUERY_PHONE, CDS_ERROR_ENCLAVE_HALTED, CDS_ERROR_FAIR_SHARE_EXCEEDED, CDS_ERROR_INVALID_CANONICALIZATION_RULES, CDS_ERROR_INVALID_REQUEST_SIZE, CDS_ERROR_QUERY_COMMITMENT_MISMATCH, CDS_ERROR_QUERY_NOT_SORTED, CDS_ERROR_SESSION_DENIED, CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION,
+    CDS_ERROR_INVALID_QUERY_PHONE, CDS_ERROR_REQUEST_DEFERRED, CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED, CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED, CDS_MAX_INCIDENT_RECORD_SIZE,
 };
 
 pub use super::bindgen_wrapper::{
-    cds_encrypted_msg_t as CDSEncryptedMsg, phone_t as Phone, sgx_platform_info_t as SgxPlatformInfo,
+    cds_benchmark_profile_t as BenchmarkProfile, cds_encrypted_msg_t as CDSEncryptedMsg, cds_protocol_version_t as ProtocolVersion, cds_reply_flag_t as ReplyFlags, cds_reply_layout_t as ReplyLayout, cds_reply_order_t as ReplyOrder, cds_reply_scratch_t as CdsReplyScratch,
+    cds_request_priority_t as RequestPriority, phone_t as Phone, sgx_platform_info_t as SgxPlatformInfo,
     sgx_update_info_bit_t as SgxUpdateInfo, sgxsd_aes_gcm_iv_t as SgxsdAesGcmIv, sgxsd_aes_gcm_mac_t as SgxsdAesGcmMac,
     sgxsd_curve25519_public_key_t as SgxsdCurve25519PublicKey, sgxsd_msg_header_t as SgxsdMessageHeader,
-    sgxsd_pending_request_id_t as SgxsdPendingRequestId, sgxsd_request_negotiation_request as SgxsdRequestNegotiationRequest,
+    sgxsd_pending_request_id_t as SgxsdPendingRequestId, sgxsd_reply_header_t as SgxsdReplyHeader, sgxsd_request_negotiation_request as SgxsdRequestNegotiationRequest,
     sgxsd_request_negotiation_response as SgxsdRequestNegotiationResponse, sgxsd_server_handle_call_args_t as SgxsdServerCallArgs,
-    sgxsd_server_init_args_t as SgxsdServerInitArgs, sgxsd_server_state_handle_t as SgxsdServerStateHandle,
-    sgxsd_server_terminate_args as ServerStopArgs, uuid_t as SgxsdUuid, SGXSD_AES_GCM_IV_SIZE, SGXSD_AES_GCM_KEY_SIZE,
-    SGXSD_AES_GCM_MAC_SIZE, SGXSD_CURVE25519_KEY_SIZE, SGXSD_SHA256_HASH_SIZE,
+    sgxsd_directory_commit_args_t as DirectoryCommitArgs, sgxsd_directory_sample_args_t as DirectorySampleArgs,
+    sgxsd_directory_sample_t as DirectorySample, sgxsd_server_init_args_t as SgxsdServerInitArgs, sgxsd_server_metrics_t as SgxsdServerMetrics, sgxsd_server_state_handle_t as SgxsdServerStateHandle,
+    sgxsd_server_terminate_args as ServerStopArgs, sgxsd_session_summary_t as SgxsdSessionSummary, uuid_t as SgxsdUuid, SGXSD_AES_GCM_IV_SIZE, SGXSD_AES_GCM_KEY_SIZE,
+    SGXSD_AES_GCM_MAC_SIZE, SGXSD_CURVE25519_KEY_SIZE, SGXSD_SHA256_HASH_SIZE, CDS_BENCHMARK_AES_GCM, CDS_BENCHMARK_DIVREM, CDS_BENCHMARK_LOOKUP_CHUNK,
+    CDS_MAX_BENCHMARK_ITERATIONS, CDS_PROTOCOL_VERSION_0, CDS_PROTOCOL_VERSION_1, CDS_REPLY_FLAG_BATCH_KEY, CDS_REPLY_FLAG_QUERY_NONCE, CDS_REPLY_LAYOUT_ACI_PNI, CDS_REPLY_LAYOUT_UUID, CDS_REPLY_LAYOUT_UUID_CHANGES,
+    CDS_REPLY_LAYOUT_UUID_FLAGS, CDS_REPLY_ORDER_QUERY, CDS_REQUEST_PRIORITY_BULK, CDS_REQUEST_PRIORITY_INTERACTIVE,
 };
 
 pub struct MessageReply {
     pub iv: SgxsdAesGcmIv,
     pub mac: SgxsdAesGcmMac,
+    pub batch_pubkey: [u8; SGXSD_CURVE25519_KEY_SIZE as usize],
     pub data: Vec<u8>,
 }
 
@@ -129,6 +136,21 @@ impl fmt::Display for SgxsdError {
 pub enum CdsError {
     InvalidRequestSize = CDS_ERROR_INVALID_REQUEST_SIZE,
     QueryCommitmentMismatch = CDS_ERROR_QUERY_COMMITMENT_MISMATCH,
+    UntrustedReadLimitExceeded = CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED,
+    InvalidCanonicalizationRules = CDS_ERROR_INVALID_CANONICALIZATION_RULES,
+    DirectoryDigestMismatch = CDS_ERROR_DIRECTORY_DIGEST_MISMATCH,
+    DirectoryEpochMismatch = CDS_ERROR_DIRECTORY_EPOCH_MISMATCH,
+    EnclaveHalted = CDS_ERROR_ENCLAVE_HALTED,
+    FairShareExceeded = CDS_ERROR_FAIR_SHARE_EXCEEDED,
+    CanaryMismatch = CDS_ERROR_CANARY_MISMATCH,
+    BatchFull = CDS_ERROR_BATCH_FULL,
+    SessionDenied = CDS_ERROR_SESSION_DENIED,
+    QueryNotSorted = CDS_ERROR_QUERY_NOT_SORTED,
+    UnsupportedProtocolVersion = CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION,
+    DuplicateQueryPhone = CDS_ERROR_DUPLICATE_QUERY_PHONE,
+    RequestPhoneLimitExceeded = CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED,
+    InvalidQueryPhone = CDS_ERROR_INVALID_QUERY_PHONE,
+    RequestDeferred = CDS_ERROR_REQUEST_DEFERRED,
 }
 
 impl TryFrom<u32> for CdsError {
@@ -138,6 +160,21 @@ impl TryFrom<u32> for CdsError {
         match value {
             x if x == CdsError::InvalidRequestSize as u32 => Ok(CdsError::InvalidRequestSize),
             x if x == CdsError::QueryCommitmentMismatch as u32 => Ok(CdsError::QueryCommitmentMismatch),
+            x if x == CdsError::UntrustedReadLimitExceeded as u32 => Ok(CdsError::UntrustedReadLimitExceeded),
+            x if x == CdsError::InvalidCanonicalizationRules as u32 => Ok(CdsError::InvalidCanonicalizationRules),
+            x if x == CdsError::DirectoryDigestMismatch as u32 => Ok(CdsError::DirectoryDigestMismatch),
+            x if x == CdsError::DirectoryEpochMismatch as u32 => Ok(CdsError::DirectoryEpochMismatch),
+            x if x == CdsError::EnclaveHalted as u32 => Ok(CdsError::EnclaveHalted),
+            x if x == CdsError::FairShareExceeded as u32 => Ok(CdsError::FairShareExceeded),
+            x if x == CdsError::CanaryMismatch as u32 => Ok(CdsError::CanaryMismatch),
+            x if x == CdsError::BatchFull as u32 => Ok(CdsError::BatchFull),
+            x if x == CdsError::SessionDenied as u32 => Ok(CdsError::SessionDenied),
+            x if x == CdsError::QueryNotSorted as u32 => Ok(CdsError::QueryNotSorted),
+            x if x == CdsError::UnsupportedProtocolVersion as u32 => Ok(CdsError::UnsupportedProtocolVersion),
+            x if x == CdsError::DuplicateQueryPhone as u32 => Ok(CdsError::DuplicateQueryPhone),
+            x if x == CdsError::RequestPhoneLimitExceeded as u32 => Ok(CdsError::RequestPhoneLimitExceeded),
+            x if x == CdsError::InvalidQueryPhone as u32 => Ok(CdsError::InvalidQueryPhone),
+            x if x == CdsError::RequestDeferred as u32 => Ok(CdsError::RequestDeferred),
             _ => Err(()),
         }
     }
@@ -259,9 +296,10 @@ where
     }
 }
 
-pub fn sgxsd_node_init(enclave_id: SgxEnclaveId, pending_requests_table_order: u8) -> SgxsdResult<()> {
+pub fn sgxsd_node_init(enclave_id: SgxEnclaveId, pending_requests_table_order: u8, max_retry_replies: u32) -> SgxsdResult<()> {
     let args = sgxsd_node_init_args_t {
         pending_requests_table_order,
+        max_retry_replies,
     };
     let () = sgxsd_res(
         |res| unsafe { sgxsd_enclave_node_init(enclave_id, res, &args) },
@@ -282,6 +320,29 @@ pub fn sgxsd_negotiate_request(
     Ok(response)
 }
 
+pub fn sgxsd_open_session(
+    enclave_id: SgxEnclaveId,
+    request: &SgxsdRequestNegotiationRequest,
+) -> SgxsdResult<SgxsdRequestNegotiationResponse> {
+    let mut response: SgxsdRequestNegotiationResponse = Default::default();
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_open_session(enclave_id, res, request, &mut response) },
+        "sgxsd_enclave_open_session",
+    )?;
+    Ok(response)
+}
+
+/// Closes a session, returning the summary of its usage encrypted to its client, which is left zeroed if the session
+/// had been closed to its client for failing to decrypt.
+pub fn sgxsd_close_session(enclave_id: SgxEnclaveId, session_id: &SgxsdPendingRequestId) -> SgxsdResult<SgxsdSessionSummary> {
+    let mut summary: SgxsdSessionSummary = Default::default();
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_close_session(enclave_id, res, session_id, &mut summary) },
+        "sgxsd_enclave_close_session",
+    )?;
+    Ok(summary)
+}
+
 pub fn sgxsd_get_next_quote(enclave_id: SgxEnclaveId, spid: &[u8; 16], sig_rl: &[u8]) -> SgxsdResult<SgxsdQuote> {
     let (gid, qe_target_info) = sgx_sdk_ffi::init_quote().sgxsd_context("sgx_init_quote")?;
     let mut report: SgxReport = Default::default();
@@ -344,14 +405,136 @@ pub fn sgxsd_server_call(
     Ok(())
 }
 
-pub fn sgxsd_server_stop(enclave_id: SgxEnclaveId, args: &ServerStopArgs, state_handle: SgxsdServerStateHandle) -> SgxsdResult<()> {
+/// Stops a server, looking up its batch in at most `args.max_chunks` chunks (or all of them, if 0). Returns 0 once the
+/// server has stopped, or else the token to pass as `args.continuation_token` to the next call to carry on with it.
+/// Retrying a call with the token it was given just returns the latest token, without replying to any request again.
+pub fn sgxsd_server_stop(enclave_id: SgxEnclaveId, args: &ServerStopArgs, state_handle: SgxsdServerStateHandle) -> SgxsdResult<u64> {
+    let mut continuation_token = 0;
     let () = sgxsd_res(
-        |res| unsafe { sgxsd_enclave_server_stop(enclave_id, res, args, state_handle) },
+        |res| unsafe { sgxsd_enclave_server_stop(enclave_id, res, args, &mut continuation_token, state_handle) },
         "sgxsd_enclave_server_stop",
     )?;
+    Ok(continuation_token)
+}
+
+pub fn sgxsd_server_get_metrics(enclave_id: SgxEnclaveId, now_ticks: u64, state_handle: SgxsdServerStateHandle) -> SgxsdResult<SgxsdServerMetrics> {
+    let mut metrics: SgxsdServerMetrics = Default::default();
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_server_get_metrics(enclave_id, res, now_ticks, &mut metrics, state_handle) },
+        "sgxsd_enclave_server_get_metrics",
+    )?;
+    Ok(metrics)
+}
+
+/// Cancels a call still waiting in a server's batch, sending no reply to it. Fails once the server has started stopping,
+/// or if no call with `msg_tag` is waiting.
+pub fn sgxsd_server_cancel_call(enclave_id: SgxEnclaveId, msg_tag: sgxsd_msg_tag_t, state_handle: SgxsdServerStateHandle) -> SgxsdResult<()> {
+    sgxsd_res(
+        |res| unsafe { sgxsd_enclave_server_cancel_call(enclave_id, res, msg_tag, state_handle) },
+        "sgxsd_enclave_server_cancel_call",
+    )
+}
+
+/// Retries sending the replies whose ocall failed, returning how many are still waiting to be sent.
+pub fn sgxsd_flush_replies(enclave_id: SgxEnclaveId) -> SgxsdResult<u64> {
+    let mut pending_reply_count: u64 = 0;
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_flush_replies(enclave_id, res, &mut pending_reply_count) },
+        "sgxsd_enclave_flush_replies",
+    )?;
+    Ok(pending_reply_count)
+}
+
+/// Sends the replies the last stop call of a server wrote into its reply scratch region, then marks the region empty,
+/// returning how many were sent.
+///
+/// # Safety
+///
+/// `reply_scratch` must be the reply scratch region the server was started with, and not have been written to since its
+/// last stop call other than by this function.
+pub unsafe fn sgxsd_dispatch_reply_scratch(reply_scratch: &mut [u8]) -> usize {
+    let header_size = mem::size_of::<CdsReplyScratch>();
+    let reply_header_size = mem::size_of::<cds_scratch_reply_t>();
+    let replies_size = match reply_scratch.get(..header_size) {
+        Some(header) => std::ptr::read_unaligned(header.as_ptr() as *const CdsReplyScratch).size as usize,
+        None => return 0,
+    };
+    let replies = reply_scratch.get(header_size..header_size.saturating_add(replies_size)).unwrap_or_default();
+
+    let mut reply_count = 0;
+    let mut offset = 0;
+    while let Some(reply_header) = replies.get(offset..offset + reply_header_size) {
+        let reply_header = std::ptr::read_unaligned(reply_header.as_ptr() as *const cds_scratch_reply_t);
+        let data_start = offset + reply_header_size;
+        let data_end = data_start + reply_header.data_size as usize;
+        let data = match replies.get(data_start..data_end) {
+            Some(data) => data.to_vec(),
+            None => break,
+        };
+        if let Some(MessageTag { callback }) = MessageTag::from_tag(reply_header.tag) {
+            callback(Ok(MessageReply {
+                iv: reply_header.header.iv,
+                mac: reply_header.header.mac,
+                batch_pubkey: reply_header.header.batch_pubkey.x,
+                data,
+            }));
+            reply_count += 1;
+        }
+        // each reply is padded to keep the next one 8-byte aligned
+        offset = (data_end + 7) & !7;
+    }
+
+    reply_scratch[..header_size].copy_from_slice(&0u64.to_ne_bytes());
+    reply_count
+}
+
+pub fn sgxsd_commit_directory(enclave_id: SgxEnclaveId, args: &DirectoryCommitArgs) -> SgxsdResult<()> {
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_commit_directory(enclave_id, res, args) },
+        "sgxsd_enclave_commit_directory",
+    )?;
     Ok(())
 }
 
+pub fn sgxsd_sample_directory(enclave_id: SgxEnclaveId, args: &DirectorySampleArgs, sample_count: usize) -> SgxsdResult<Vec<DirectorySample>> {
+    let mut samples = vec![Default::default(); sample_count];
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_sample_directory(enclave_id, res, args, samples.as_mut_ptr(), samples.len()) },
+        "sgxsd_enclave_sample_directory",
+    )?;
+    Ok(samples)
+}
+
+pub fn sgxsd_set_session_denylist(enclave_id: SgxEnclaveId, session_ids: &[SgxsdCurve25519PublicKey]) -> SgxsdResult<()> {
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_set_session_denylist(enclave_id, res, session_ids.as_ptr(), session_ids.len()) },
+        "sgxsd_enclave_set_session_denylist",
+    )?;
+    Ok(())
+}
+
+pub fn sgxsd_get_incident_record(enclave_id: SgxEnclaveId) -> SgxsdResult<Vec<u8>> {
+    let mut record = vec![0; CDS_MAX_INCIDENT_RECORD_SIZE as usize];
+    let mut record_len = 0;
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_get_incident_record(enclave_id, res, record.as_mut_ptr(), record.len(), &mut record_len) },
+        "sgxsd_enclave_get_incident_record",
+    )?;
+    record.truncate(record_len);
+    Ok(record)
+}
+
+/// Runs `iterations` iterations of a synthetic workload in the enclave, returning the time taken per iteration as
+/// measured from outside it, since the enclave has no trusted clock to time itself with.
+pub fn sgxsd_run_benchmark(enclave_id: SgxEnclaveId, profile: BenchmarkProfile, iterations: u32) -> SgxsdResult<Duration> {
+    let start = Instant::now();
+    let () = sgxsd_res(
+        |res| unsafe { sgxsd_enclave_run_benchmark(enclave_id, res, profile, iterations) },
+        "sgxsd_enclave_run_benchmark",
+    )?;
+    Ok(start.elapsed() / iterations.max(1))
+}
+
 pub enum AttestationStatus {
     NoUpdateNeeded,
     UpdateNeeded(SgxUpdateInfo),
@@ -404,6 +587,51 @@ mod tests {
         let code = CDS_ERROR_QUERY_COMMITMENT_MISMATCH;
         assert_eq!(CdsError::try_from(code), Ok(CdsError::QueryCommitmentMismatch));
 
+        let code = CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::UntrustedReadLimitExceeded));
+
+        let code = CDS_ERROR_INVALID_CANONICALIZATION_RULES;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::InvalidCanonicalizationRules));
+
+        let code = CDS_ERROR_DIRECTORY_DIGEST_MISMATCH;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::DirectoryDigestMismatch));
+
+        let code = CDS_ERROR_DIRECTORY_EPOCH_MISMATCH;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::DirectoryEpochMismatch));
+
+        let code = CDS_ERROR_ENCLAVE_HALTED;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::EnclaveHalted));
+
+        let code = CDS_ERROR_FAIR_SHARE_EXCEEDED;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::FairShareExceeded));
+
+        let code = CDS_ERROR_CANARY_MISMATCH;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::CanaryMismatch));
+
+        let code = CDS_ERROR_BATCH_FULL;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::BatchFull));
+
+        let code = CDS_ERROR_SESSION_DENIED;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::SessionDenied));
+
+        let code = CDS_ERROR_QUERY_NOT_SORTED;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::QueryNotSorted));
+
+        let code = CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::UnsupportedProtocolVersion));
+
+        let code = CDS_ERROR_DUPLICATE_QUERY_PHONE;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::DuplicateQueryPhone));
+
+        let code = CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::RequestPhoneLimitExceeded));
+
+        let code = CDS_ERROR_INVALID_QUERY_PHONE;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::InvalidQueryPhone));
+
+        let code = CDS_ERROR_REQUEST_DEFERRED;
+        assert_eq!(CdsError::try_from(code), Ok(CdsError::RequestDeferred));
+
         assert_eq!(CdsError::try_from(0), Err(()));
     }
 
@@ -17,4 +17,5 @@
 
 mod ffi;
 
+pub use ffi::args;
 pub use ffi::sgxsd;
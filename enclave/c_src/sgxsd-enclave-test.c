@@ -30,6 +30,7 @@
 #include "sgx_tcrypto.h"
 #include "sgx_spinlock.h"
 #include "sgx_quote.h"
+#include "sgx_utils.h"
 
 #include "bearssl.h"
 #include "sgxsd-enclave.h"
@@ -40,6 +41,8 @@ sgx_status_t sgxsd_enclave_node_init(const sgxsd_node_init_args_t* p_args);
 sgx_status_t sgxsd_enclave_get_next_report(sgx_target_info_t qe_target_info, sgx_report_t *p_report);
 sgx_status_t sgxsd_enclave_set_current_quote();
 sgx_status_t sgxsd_enclave_negotiate_request(const sgxsd_request_negotiation_request_t *p_request, sgxsd_request_negotiation_response_t *p_response);
+sgx_status_t sgxsd_enclave_open_session(const sgxsd_request_negotiation_request_t *p_request, sgxsd_request_negotiation_response_t *p_response);
+sgx_status_t sgxsd_enclave_close_session(const sgxsd_pending_request_id_t *p_session_id, sgxsd_session_summary_t *p_summary);
 sgx_status_t sgxsd_enclave_server_start(const sgxsd_server_init_args_t* p_args, sgxsd_server_state_handle_t state_handle);
 sgx_status_t sgxsd_enclave_ratelimit_fingerprint(uint8_t fingerprint_key[32],
                                                  const sgxsd_server_handle_call_args_t *call_args,
@@ -49,18 +52,28 @@ sgx_status_t sgxsd_enclave_ratelimit_fingerprint(uint8_t fingerprint_key[32],
                                                  sgxsd_server_state_handle_t state_handle,
                                                  uint8_t *fingerprint, size_t fingerprint_size);
         sgx_status_t sgxsd_enclave_server_call(const sgxsd_server_handle_call_args_t* p_args, const sgxsd_msg_header_t* msg_header, const uint8_t* msg_data, size_t msg_size, sgxsd_msg_tag_t msg_tag, sgxsd_server_state_handle_t state_handle);
-sgx_status_t sgxsd_enclave_server_stop(const sgxsd_server_terminate_args_t* p_args, sgxsd_server_state_handle_t state_handle);
+sgx_status_t sgxsd_enclave_server_stop(const sgxsd_server_terminate_args_t* p_args, uint64_t* p_continuation_token, sgxsd_server_state_handle_t state_handle);
+sgx_status_t sgxsd_enclave_server_get_metrics(uint64_t now_ticks, sgxsd_server_metrics_t *p_metrics, sgxsd_server_state_handle_t state_handle);
+sgx_status_t sgxsd_enclave_server_cancel_call(sgxsd_msg_tag_t msg_tag, sgxsd_server_state_handle_t state_handle);
+sgx_status_t sgxsd_enclave_flush_replies(uint64_t *p_pending_reply_count);
+sgx_status_t sgxsd_enclave_commit_directory(const sgxsd_directory_commit_args_t *p_args);
+sgx_status_t sgxsd_enclave_sample_directory(const sgxsd_directory_sample_args_t *p_args, sgxsd_directory_sample_t *p_samples, size_t sample_count);
+sgx_status_t sgxsd_enclave_set_session_denylist(const sgxsd_curve25519_public_key_t *p_session_ids, size_t session_id_count);
+sgx_status_t sgxsd_enclave_get_incident_record(uint8_t *p_record, size_t record_size, size_t *p_record_len);
+sgx_status_t sgxsd_enclave_new_reply_batch(sgxsd_reply_batch_t *p_batch);
 
 extern void *g_sgxsd_enclave_pending_requests;
 
-sgx_status_t sgxsd_ocall_reply(sgx_status_t* retval, const sgxsd_msg_header_t* reply_header, const uint8_t* reply_data, size_t reply_data_size, sgxsd_msg_tag_t msg_tag);
+sgx_status_t sgxsd_ocall_reply(sgx_status_t* retval, const sgxsd_reply_header_t* reply_header, const uint8_t* reply_data, size_t reply_data_size, sgxsd_msg_tag_t msg_tag);
 
 void test_read_rand(void *dst, size_t size);
 void expect_sgx_read_rand(sgx_status_t res, unsigned char **p_rand, size_t expected_length_in_bytes);
 void expect_sgxsd_enclave_server_init(sgx_status_t res, void *expected_args, size_t expected_args_size);
 void expect_sgxsd_enclave_server_handle_call(sgx_status_t res, sgxsd_server_handle_call_args_t *expected_args,
                                              sgxsd_msg_buf_t expected_msg, sgxsd_msg_from_t expected_from);
-void expect_sgxsd_enclave_server_terminate(sgx_status_t res, void *expected_args, size_t expected_args_size);
+void expect_sgxsd_enclave_server_terminate(sgx_status_t res, void *expected_args, size_t expected_args_size, uint64_t continuation_token);
+void expect_sgxsd_enclave_server_metrics(sgx_status_t res, uint64_t expected_now_ticks, sgxsd_server_metrics_t *expected_p_metrics);
+void expect_sgxsd_enclave_server_cancel(sgx_status_t res, uint64_t expected_tag);
 void expect_sgxsd_aes_gcm_encrypt(sgx_status_t res,
                                   const sgxsd_aes_gcm_key_t *expected_p_key,
                                   void *expected_p_src, uint32_t expected_src_len, bool capture_src,
@@ -79,6 +92,7 @@ void expect_sgxsd_ocall_reply(sgx_status_t ocall_res, sgx_status_t res,
                               const void *p_expected_dst, size_t expected_reply_data_size,
                               const void *expected_iv, size_t expected_iv_len,
                               const sgxsd_aes_gcm_mac_t *p_expected_out_mac,
+                              const sgxsd_curve25519_public_key_t *p_expected_batch_pubkey,
                               uint64_t expected_tag);
 void expect_sgxsd_ocall_noreply(sgx_status_t ocall_res, sgx_status_t res,
                                 uint64_t expected_tag);
@@ -106,6 +120,7 @@ sgxsd_server_state_handle_t valid_server_handle = 0;
 sgxsd_server_state_handle_t invalid_server_handle = UINT64_MAX;
 void *test_args;
 size_t test_args_size;
+uint8_t test_server_config[8];
 sgxsd_msg_buf_t test_msg_buf;
 
 sgxsd_msg_header_t test_msg_header;
@@ -113,12 +128,14 @@ sgxsd_msg_header_t test_msg_header;
 sgxsd_msg_buf_t null_msg_buf  = { .data = NULL, .size = 0 };
 sgxsd_msg_buf_t empty_msg_buf = { .data = NULL, .size = 0 };
 sgxsd_msg_from_t valid_msg_from;
+sgxsd_reply_batch_t test_reply_batch;
 
 sgxsd_aes_gcm_iv_t *test_zero_iv;
 
 sgxsd_server_handle_call_args_t *old_call_args;
 uint8_t *call_data;
 uint8_t *fingerprint_out;
+static uint8_t valid_fingerprint_key[32] = {1,2,3,4,5,6,7};
 
 static void setup_tests(void **state) {
   print_message("using seed: 0x%08lx\n", test_drand48_seed);
@@ -127,6 +144,7 @@ static void setup_tests(void **state) {
   test_node_init_args = test_malloc(sizeof(*test_node_init_args));
   *test_node_init_args = (sgxsd_node_init_args_t) {
     .pending_requests_table_order = 0,
+    .max_retry_replies = 1,
   };
 
   empty_msg_buf.data = malloc(0);
@@ -156,6 +174,8 @@ static void setup_tests(void **state) {
 
   test_read_rand(&valid_msg_from, sizeof(valid_msg_from));
   valid_msg_from.valid = true;
+  valid_msg_from.client_pubkey = p_test_request_negotiation_request->client_pubkey;
+  test_read_rand(&test_reply_batch, sizeof(test_reply_batch));
 
   test_zero_iv = test_malloc(sizeof(*test_zero_iv));
   memset(test_zero_iv, 0, sizeof(*test_zero_iv));
@@ -289,7 +309,10 @@ static void test_sgxsd_negotiate_request_generate_keypair_rand_error(void **stat
                    (p_test_request_negotiation_request, &(sgxsd_request_negotiation_response_t) {0}));
 }
 
-static void test_sgxsd_negotiate_request(uint8_t *expected_pending_request_id, sgxsd_pending_request_id_t *p_pending_request_id) {
+typedef sgx_status_t (*test_negotiate_fn_t)(const sgxsd_request_negotiation_request_t *, sgxsd_request_negotiation_response_t *);
+
+static void test_sgxsd_negotiate(test_negotiate_fn_t negotiate,
+                                 uint8_t *expected_pending_request_id, sgxsd_pending_request_id_t *p_pending_request_id) {
   expect_sgx_read_rand(SGX_SUCCESS, NULL, sizeof((sgxsd_curve25519_private_key_t*){0}->x));
   uint8_t *expected_p_iv_data;
   expect_sgx_read_rand(SGX_SUCCESS, &expected_p_iv_data, sizeof(sgxsd_aes_gcm_iv_t));
@@ -304,9 +327,212 @@ static void test_sgxsd_negotiate_request(uint8_t *expected_pending_request_id, s
   expect_sgxsd_aes_gcm_encrypt(SGX_SUCCESS, NULL,
                                p_pending_request_id, sizeof(*p_pending_request_id), true, &p_expected_dst,
                                test_zero_iv, NULL, 0, &p_expected_out_mac);
-  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_negotiate_request
+  assert_int_equal(SGX_SUCCESS, negotiate(p_test_request_negotiation_request, &(sgxsd_request_negotiation_response_t) {0}));
+}
+static void test_sgxsd_negotiate_request(uint8_t *expected_pending_request_id, sgxsd_pending_request_id_t *p_pending_request_id) {
+  test_sgxsd_negotiate(sgxsd_enclave_negotiate_request, expected_pending_request_id, p_pending_request_id);
+}
+
+//
+// session tests
+//
+
+static void test_sgxsd_open_session_node_uninitialized(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_open_session
                    (p_test_request_negotiation_request, &(sgxsd_request_negotiation_response_t) {0}));
 }
+static void test_sgxsd_close_session_node_uninitialized(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_close_session
+                   (&test_msg_header.pending_request_id, &(sgxsd_session_summary_t) {0}));
+}
+static void test_sgxsd_open_session_null_request(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_open_session
+                   (NULL, &(sgxsd_request_negotiation_response_t) {0}));
+}
+static void test_sgxsd_open_session_null_response(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_open_session
+                   (p_test_request_negotiation_request, NULL));
+}
+static void test_sgxsd_close_session_null_id(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_close_session(NULL, &(sgxsd_session_summary_t) {0}));
+}
+static void test_sgxsd_close_session_null_summary(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_close_session(&test_msg_header.pending_request_id, NULL));
+}
+static void expect_sgxsd_decrypt_session_id(const uint8_t *expected_session_id) {
+  void *p_expected_session_id_data;
+  expect_sgxsd_aes_gcm_decrypt(SGX_SUCCESS, NULL,
+                               &test_msg_header.pending_request_id.data, sizeof(test_msg_header.pending_request_id.data),
+                               &p_expected_session_id_data,
+                               &test_msg_header.pending_request_id.iv,
+                               NULL, 0,
+                               &test_msg_header.pending_request_id.mac);
+  memcpy(p_expected_session_id_data, expected_session_id, sizeof(test_msg_header.pending_request_id.data));
+}
+static void test_sgxsd_close_session_pending_request(void **state) {
+  uint8_t expected_pending_request_id[sizeof(test_msg_header.pending_request_id.data)];
+  test_sgxsd_negotiate_request(&expected_pending_request_id[0], &test_msg_header.pending_request_id);
+
+  expect_sgxsd_decrypt_session_id(&expected_pending_request_id[0]);
+  assert_int_equal(SGXSD_ERROR_SESSION_NOT_FOUND, sgxsd_enclave_close_session
+                   (&test_msg_header.pending_request_id, &(sgxsd_session_summary_t) {0}));
+}
+static void test_sgxsd_close_session(const uint8_t *expected_session_id, const sgxsd_session_usage_t *p_expected_usage) {
+  expect_sgxsd_decrypt_session_id(expected_session_id);
+
+  uint8_t *expected_iv_data;
+  expect_sgx_read_rand(SGX_SUCCESS, &expected_iv_data, sizeof(((sgxsd_aes_gcm_iv_t *) 0)->data));
+  sgxsd_aes_gcm_iv_t *expected_iv = (sgxsd_aes_gcm_iv_t *) expected_iv_data;
+  expected_iv->data[0] |= 1;
+  void *p_expected_dst;
+  sgxsd_aes_gcm_mac_t *p_expected_out_mac;
+  expect_sgxsd_aes_gcm_encrypt(SGX_SUCCESS, NULL,
+                               (void *) p_expected_usage, sizeof(*p_expected_usage), false, &p_expected_dst,
+                               expected_iv, &test_msg_header.pending_request_id, sizeof(test_msg_header.pending_request_id),
+                               &p_expected_out_mac);
+
+  sgxsd_session_summary_t summary;
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_close_session(&test_msg_header.pending_request_id, &summary));
+  assert_memory_equal(summary.data, p_expected_dst, sizeof(summary.data));
+  assert_memory_equal(summary.iv.data, expected_iv->data, sizeof(summary.iv.data));
+  assert_memory_equal(summary.mac.data, p_expected_out_mac->data, sizeof(summary.mac.data));
+}
+static void test_sgxsd_session_call_handled(sgx_status_t res, const uint8_t *expected_session_id, uint8_t msg_counter,
+                                           sgx_status_t decrypt_msg_res, bool handled, sgx_status_t handle_call_res) {
+  memset(&test_msg_header.iv, 0, sizeof(test_msg_header.iv));
+  test_msg_header.iv.data[0] = msg_counter;
+
+  expect_sgxsd_decrypt_session_id(expected_session_id);
+
+  void *p_expected_decrypted_msg_buf_data;
+  expect_sgxsd_aes_gcm_decrypt(decrypt_msg_res, NULL,
+                               test_msg_buf.data, test_msg_buf.size,
+                               &p_expected_decrypted_msg_buf_data,
+                               &test_msg_header.iv,
+                               &test_msg_header.pending_request_id, sizeof(test_msg_header.pending_request_id),
+                               &test_msg_header.mac);
+  sgxsd_msg_buf_t expected_decrypted_msg_buf = { .data = p_expected_decrypted_msg_buf_data, .size = test_msg_buf.size };
+  if (handled) {
+    expect_sgxsd_enclave_server_handle_call(handle_call_res, old_call_args, expected_decrypted_msg_buf, valid_msg_from);
+  }
+  assert_int_equal(res, sgxsd_enclave_server_call
+                   (old_call_args, &test_msg_header, test_msg_buf.data, test_msg_buf.size, valid_msg_from.tag,
+                    valid_server_handle));
+}
+static void test_sgxsd_session_call(sgx_status_t res, const uint8_t *expected_session_id, uint8_t msg_counter) {
+  test_sgxsd_session_call_handled(res, expected_session_id, msg_counter, SGX_SUCCESS, res == SGX_SUCCESS, SGX_SUCCESS);
+}
+static void test_sgxsd_session_fingerprint(const uint8_t *expected_session_id) {
+  expect_sgxsd_decrypt_session_id(expected_session_id);
+  void *p_expected_decrypted_msg_buf_data;
+  expect_sgxsd_aes_gcm_decrypt(SGX_SUCCESS, NULL,
+                               test_msg_buf.data, test_msg_buf.size,
+                               &p_expected_decrypted_msg_buf_data,
+                               &test_msg_header.iv,
+                               &test_msg_header.pending_request_id, sizeof(test_msg_header.pending_request_id),
+                               &test_msg_header.mac);
+  sgxsd_msg_buf_t expected_decrypted_msg_buf = { .data = p_expected_decrypted_msg_buf_data, .size = test_msg_buf.size };
+  expect_sgxsd_enclave_create_ratelimit_fingerprint(SGX_SUCCESS, valid_fingerprint_key, old_call_args,
+                                                    expected_decrypted_msg_buf, valid_msg_from,
+                                                    old_call_args->query_phone_count);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_ratelimit_fingerprint
+                   (valid_fingerprint_key, old_call_args, &test_msg_header, test_msg_buf.data, test_msg_buf.size,
+                    valid_msg_from.tag, valid_server_handle, fingerprint_out, old_call_args->query_phone_count));
+}
+static void test_sgxsd_session_valid(void **state) {
+  uint8_t expected_session_id[sizeof(test_msg_header.pending_request_id.data)];
+  test_sgxsd_negotiate(sgxsd_enclave_open_session, &expected_session_id[0], &test_msg_header.pending_request_id);
+
+  // a session outlives its calls, unlike a pending request
+  test_sgxsd_session_call(SGX_SUCCESS, &expected_session_id[0], 1);
+  test_sgxsd_session_call(SGX_SUCCESS, &expected_session_id[0], 3);
+  test_sgxsd_session_call(SGX_SUCCESS, &expected_session_id[0], 2);
+
+  test_sgxsd_close_session(&expected_session_id[0], &(sgxsd_session_usage_t) {
+      .request_count     = 3,
+      .query_phone_count = 3 * old_call_args->query_phone_count,
+    });
+
+  expect_sgxsd_decrypt_session_id(&expected_session_id[0]);
+  assert_int_equal(SGXSD_ERROR_SESSION_NOT_FOUND, sgxsd_enclave_close_session
+                   (&test_msg_header.pending_request_id, &(sgxsd_session_summary_t) {0}));
+}
+static void test_sgxsd_session_ratelimit_usage(void **state) {
+  uint8_t expected_session_id[sizeof(test_msg_header.pending_request_id.data)];
+  test_sgxsd_negotiate(sgxsd_enclave_open_session, &expected_session_id[0], &test_msg_header.pending_request_id);
+
+  test_sgxsd_session_fingerprint(&expected_session_id[0]);
+
+  // a failed call isn't counted
+  test_sgxsd_session_call_handled(SGX_ERROR_UNEXPECTED, &expected_session_id[0], 1, SGX_SUCCESS, true, SGX_ERROR_UNEXPECTED);
+  test_sgxsd_session_call(SGX_SUCCESS, &expected_session_id[0], 2);
+
+  test_sgxsd_close_session(&expected_session_id[0], &(sgxsd_session_usage_t) {
+      .request_count         = 1,
+      .query_phone_count     = old_call_args->query_phone_count,
+      .ratelimit_phone_count = old_call_args->query_phone_count,
+    });
+}
+static void test_sgxsd_session_replay(void **state) {
+  uint8_t expected_session_id[sizeof(test_msg_header.pending_request_id.data)];
+  test_sgxsd_negotiate(sgxsd_enclave_open_session, &expected_session_id[0], &test_msg_header.pending_request_id);
+
+  test_sgxsd_session_call(SGXSD_ERROR_SESSION_REPLAYED, &expected_session_id[0], 0);
+  test_sgxsd_session_call(SGX_SUCCESS, &expected_session_id[0], 1);
+  test_sgxsd_session_call(SGXSD_ERROR_SESSION_REPLAYED, &expected_session_id[0], 1);
+
+  test_sgxsd_close_session(&expected_session_id[0], &(sgxsd_session_usage_t) {
+      .request_count     = 1,
+      .query_phone_count = old_call_args->query_phone_count,
+    });
+}
+static void test_sgxsd_session_decrypt_failures(void **state) {
+  uint8_t expected_session_id[sizeof(test_msg_header.pending_request_id.data)];
+  test_sgxsd_negotiate(sgxsd_enclave_open_session, &expected_session_id[0], &test_msg_header.pending_request_id);
+
+  // failures only count while consecutive
+  uint8_t msg_counter = 1;
+  for (int failure_idx = 1; failure_idx < SGXSD_SESSION_MAX_DECRYPT_FAILURES; failure_idx++) {
+    test_sgxsd_session_call_handled(SGX_ERROR_MAC_MISMATCH, &expected_session_id[0], msg_counter,
+                                    SGX_ERROR_MAC_MISMATCH, false, SGX_SUCCESS);
+  }
+  test_sgxsd_session_call(SGX_SUCCESS, &expected_session_id[0], msg_counter++);
+
+  // a message decrypted to compute its ratelimit fingerprint clears them too
+  for (int failure_idx = 1; failure_idx < SGXSD_SESSION_MAX_DECRYPT_FAILURES; failure_idx++) {
+    test_sgxsd_session_call_handled(SGX_ERROR_MAC_MISMATCH, &expected_session_id[0], msg_counter,
+                                    SGX_ERROR_MAC_MISMATCH, false, SGX_SUCCESS);
+  }
+  test_sgxsd_session_fingerprint(&expected_session_id[0]);
+
+  // as does one whose query then fails to decrypt, which isn't a failure itself, as it doesn't probe the session key
+  for (int failure_idx = 1; failure_idx < SGXSD_SESSION_MAX_DECRYPT_FAILURES; failure_idx++) {
+    test_sgxsd_session_call_handled(SGX_ERROR_MAC_MISMATCH, &expected_session_id[0], msg_counter,
+                                    SGX_ERROR_MAC_MISMATCH, false, SGX_SUCCESS);
+  }
+  test_sgxsd_session_call_handled(SGX_ERROR_MAC_MISMATCH, &expected_session_id[0], msg_counter++,
+                                  SGX_SUCCESS, true, SGX_ERROR_MAC_MISMATCH);
+
+  for (int failure_idx = 1; failure_idx < SGXSD_SESSION_MAX_DECRYPT_FAILURES; failure_idx++) {
+    test_sgxsd_session_call_handled(SGX_ERROR_MAC_MISMATCH, &expected_session_id[0], msg_counter,
+                                    SGX_ERROR_MAC_MISMATCH, false, SGX_SUCCESS);
+  }
+  test_sgxsd_session_call_handled(SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED, &expected_session_id[0], msg_counter,
+                                  SGX_ERROR_MAC_MISMATCH, false, SGX_SUCCESS);
+
+  // the session's key is gone, so its messages aren't decrypted any more
+  expect_sgxsd_decrypt_session_id(&expected_session_id[0]);
+  assert_int_equal(SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED, sgxsd_enclave_server_call
+                   (old_call_args, &test_msg_header, test_msg_buf.data, test_msg_buf.size, valid_msg_from.tag,
+                    valid_server_handle));
+
+  // nor is its summary encrypted
+  expect_sgxsd_decrypt_session_id(&expected_session_id[0]);
+  sgxsd_session_summary_t summary;
+  memset(&summary, 0xff, sizeof(summary));
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_close_session(&test_msg_header.pending_request_id, &summary));
+  assert_memory_equal(&summary, &(sgxsd_session_summary_t) {0}, sizeof(summary));
+}
 
 //
 // server start tests
@@ -329,6 +555,36 @@ static void test_sgxsd_server_start_valid(void **state) {
   expect_sgxsd_enclave_server_init(SGX_SUCCESS, test_args, test_args_size);
   assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_start(test_args, valid_server_handle));
 }
+static void test_sgxsd_server_start_other_config(void **state) {
+  sgxsd_server_state_handle_t other_server_handle = valid_server_handle + 1;
+
+  // servers running at once share the config quotes attest to
+  test_server_config[0] ^= 1;
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_start(test_args, other_server_handle));
+
+  // which is free to change once they've all stopped
+  uint64_t continuation_token;
+  expect_sgxsd_enclave_server_terminate(SGX_SUCCESS, test_args, test_args_size, 0);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_stop(test_args, &continuation_token, valid_server_handle));
+  expect_sgxsd_enclave_server_init(SGX_SUCCESS, test_args, test_args_size);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_start(test_args, other_server_handle));
+  test_server_config[0] ^= 1;
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_start(test_args, valid_server_handle));
+
+  expect_sgxsd_enclave_server_terminate(SGX_SUCCESS, test_args, test_args_size, 0);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_stop(test_args, &continuation_token, other_server_handle));
+  expect_sgxsd_enclave_server_init(SGX_SUCCESS, test_args, test_args_size);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_start(test_args, valid_server_handle));
+}
+static void test_sgxsd_server_start_same_config(void **state) {
+  sgxsd_server_state_handle_t other_server_handle = valid_server_handle + 1;
+  expect_sgxsd_enclave_server_init(SGX_SUCCESS, test_args, test_args_size);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_start(test_args, other_server_handle));
+
+  uint64_t continuation_token;
+  expect_sgxsd_enclave_server_terminate(SGX_SUCCESS, test_args, test_args_size, 0);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_stop(test_args, &continuation_token, other_server_handle));
+}
 
 //
 // server call tests
@@ -431,8 +687,6 @@ static void test_sgxsd_server_call_replay(void **state) {
 // ratelimit fingerprint tests
 //
 
-static uint8_t valid_fingerprint_key[32] = {1,2,3,4,5,6,7};
-
 static void test_sgxsd_ratelimit_fingerprint_golden_path(void **state) {
     sgxsd_msg_buf_t msg = test_msg_buf;
     uint8_t expected_pending_request_id[sizeof(test_msg_header.pending_request_id.data)];
@@ -494,26 +748,210 @@ static void test_sgxsd_ratelimit_fingerprint_call_still_valid(void **state) {
     test_sgxsd_server_call_valid(state);
 }
 
+//
+// server get_metrics tests
+//
+
+static void test_sgxsd_server_get_metrics_node_uninitialized(void **state) {
+  sgxsd_server_metrics_t metrics;
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_get_metrics(0, &metrics, valid_server_handle));
+}
+static void test_sgxsd_server_get_metrics_invalid_handle(void **state) {
+  sgxsd_server_metrics_t metrics;
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_server_get_metrics(0, &metrics, invalid_server_handle));
+}
+static void test_sgxsd_server_get_metrics_not_started(void **state) {
+  sgxsd_server_metrics_t metrics;
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_get_metrics(0, &metrics, valid_server_handle));
+}
+static void test_sgxsd_server_get_metrics_null_metrics(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_server_get_metrics(0, NULL, valid_server_handle));
+}
+static void test_sgxsd_server_get_metrics_valid(void **state) {
+  sgxsd_server_metrics_t metrics;
+  expect_sgxsd_enclave_server_metrics(SGX_SUCCESS, 42, &metrics);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_get_metrics(42, &metrics, valid_server_handle));
+}
+
+//
+// server cancel_call tests
+//
+
+static void test_sgxsd_server_cancel_call_node_uninitialized(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_cancel_call((sgxsd_msg_tag_t) { .tag = 42 }, valid_server_handle));
+}
+static void test_sgxsd_server_cancel_call_invalid_handle(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_server_cancel_call((sgxsd_msg_tag_t) { .tag = 42 }, invalid_server_handle));
+}
+static void test_sgxsd_server_cancel_call_not_started(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_cancel_call((sgxsd_msg_tag_t) { .tag = 42 }, valid_server_handle));
+}
+static void test_sgxsd_server_cancel_call_not_found(void **state) {
+  expect_sgxsd_enclave_server_cancel(SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND, 42);
+  assert_int_equal(SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND,
+                   sgxsd_enclave_server_cancel_call((sgxsd_msg_tag_t) { .tag = 42 }, valid_server_handle));
+}
+static void test_sgxsd_server_cancel_call_valid(void **state) {
+  expect_sgxsd_enclave_server_cancel(SGX_SUCCESS, 42);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_cancel_call((sgxsd_msg_tag_t) { .tag = 42 }, valid_server_handle));
+}
+
+//
+// commit_directory tests
+//
+
+static void test_sgxsd_commit_directory_node_uninitialized(void **state) {
+  sgxsd_directory_commit_args_t args = {0};
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_commit_directory(&args));
+}
+static void test_sgxsd_commit_directory_null_args(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_commit_directory(NULL));
+}
+static void test_sgxsd_commit_directory_error(void **state) {
+  sgxsd_directory_commit_args_t args = { .epoch = 1 };
+  expect_value(sgxsd_enclave_directory_commit, p_args, &args);
+  will_return(sgxsd_enclave_directory_commit, CDS_ERROR_DIRECTORY_DIGEST_MISMATCH);
+  assert_int_equal(CDS_ERROR_DIRECTORY_DIGEST_MISMATCH, sgxsd_enclave_commit_directory(&args));
+}
+
+//
+// sample_directory tests
+//
+
+static void test_sgxsd_sample_directory_node_uninitialized(void **state) {
+  sgxsd_directory_sample_args_t args = {0};
+  sgxsd_directory_sample_t samples[1];
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_sample_directory(&args, samples, 1));
+}
+static void test_sgxsd_sample_directory_null_args(void **state) {
+  sgxsd_directory_sample_args_t args = {0};
+  sgxsd_directory_sample_t samples[1];
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_sample_directory(NULL, samples, 1));
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_sample_directory(&args, NULL, 1));
+}
+static void test_sgxsd_sample_directory_valid(void **state) {
+  sgxsd_directory_sample_args_t args = { .epoch = 1 };
+  sgxsd_directory_sample_t samples[2];
+  expect_value(sgxsd_enclave_directory_sample, p_args, &args);
+  expect_value(sgxsd_enclave_directory_sample, p_samples, samples);
+  expect_value(sgxsd_enclave_directory_sample, sample_count, 2);
+  will_return(sgxsd_enclave_directory_sample, SGX_SUCCESS);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_sample_directory(&args, samples, 2));
+}
+
+//
+// set_session_denylist tests
+//
+
+static void test_sgxsd_set_session_denylist_node_uninitialized(void **state) {
+  sgxsd_curve25519_public_key_t session_ids[1] = {{{0}}};
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_set_session_denylist(session_ids, 1));
+}
+static void test_sgxsd_set_session_denylist_null_args(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_set_session_denylist(NULL, 1));
+}
+static void test_sgxsd_set_session_denylist_valid(void **state) {
+  sgxsd_curve25519_public_key_t session_ids[2] = {{{0}}};
+  expect_value(sgxsd_enclave_session_denylist, p_session_ids, session_ids);
+  expect_value(sgxsd_enclave_session_denylist, session_id_count, 2);
+  will_return(sgxsd_enclave_session_denylist, SGX_SUCCESS);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_set_session_denylist(session_ids, 2));
+
+  expect_value(sgxsd_enclave_session_denylist, p_session_ids, NULL);
+  expect_value(sgxsd_enclave_session_denylist, session_id_count, 0);
+  will_return(sgxsd_enclave_session_denylist, SGX_SUCCESS);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_set_session_denylist(NULL, 0));
+}
+
+//
+// get_incident_record tests
+//
+
+static void test_sgxsd_get_incident_record_null_args(void **state) {
+  uint8_t record[CDS_MAX_INCIDENT_RECORD_SIZE];
+  size_t record_len;
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_get_incident_record(NULL, sizeof(record), &record_len));
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_get_incident_record(record, sizeof(record), NULL));
+}
+static void test_sgxsd_get_incident_record_valid(void **state) {
+  uint8_t record[CDS_MAX_INCIDENT_RECORD_SIZE];
+  size_t record_len;
+  expect_value(sgxsd_enclave_incident_record, p_record, record);
+  expect_value(sgxsd_enclave_incident_record, record_size, sizeof(record));
+  expect_value(sgxsd_enclave_incident_record, p_record_len, &record_len);
+  will_return(sgxsd_enclave_incident_record, SGX_SUCCESS);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_get_incident_record(record, sizeof(record), &record_len));
+}
+
+//
+// sealing key tests
+//
+
+static void test_sgxsd_get_sealing_key_null_key(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_get_sealing_key(NULL));
+}
+static void test_sgxsd_get_sealing_key_error(void **state) {
+  sgxsd_aes_gcm_key_t key;
+  will_return(sgx_get_key, SGX_ERROR_UNEXPECTED);
+  assert_int_equal(SGX_ERROR_UNEXPECTED, sgxsd_enclave_get_sealing_key(&key));
+}
+static void test_sgxsd_get_sealing_key_valid(void **state) {
+  sgxsd_aes_gcm_key_t key = { .data = { 0 } };
+  sgxsd_aes_gcm_key_t key_2 = { .data = { 0 } };
+  sgxsd_aes_gcm_key_t zero_key = { .data = { 0 } };
+  will_return(sgx_get_key, SGX_SUCCESS);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_get_sealing_key(&key));
+  will_return(sgx_get_key, SGX_SUCCESS);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_get_sealing_key(&key_2));
+  assert_memory_equal(key.data, key_2.data, sizeof(key.data));
+  assert_memory_not_equal(key.data, zero_key.data, sizeof(key.data));
+}
+
 //
 // server stop tests
 //
 
 static void test_sgxsd_server_stop_node_uninitialized(void **state) {
-  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_stop(NULL, valid_server_handle));
+  uint64_t continuation_token;
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_stop(NULL, &continuation_token, valid_server_handle));
 }
 static void test_sgxsd_server_stop_invalid_handle(void **state) {
   assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_server_start(NULL, invalid_server_handle));
 }
 static void test_sgxsd_server_stop_already_stopped(void **state) {
-  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_stop(NULL, valid_server_handle));
+  uint64_t continuation_token;
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_stop(NULL, &continuation_token, valid_server_handle));
+}
+static void test_sgxsd_server_stop_null_continuation_token(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_server_stop(test_args, NULL, valid_server_handle));
 }
 static void test_sgxsd_server_stop_terminate_error(void **state) {
-  expect_sgxsd_enclave_server_terminate(SGX_ERROR_UNEXPECTED, test_args, test_args_size);
-  assert_int_equal(SGX_ERROR_UNEXPECTED, sgxsd_enclave_server_stop(test_args, valid_server_handle));
+  uint64_t continuation_token;
+  expect_sgxsd_enclave_server_terminate(SGX_ERROR_UNEXPECTED, test_args, test_args_size, 0);
+  assert_int_equal(SGX_ERROR_UNEXPECTED, sgxsd_enclave_server_stop(test_args, &continuation_token, valid_server_handle));
 }
 static void test_sgxsd_server_stop_valid(void **state) {
-  expect_sgxsd_enclave_server_terminate(SGX_SUCCESS, test_args, test_args_size);
-  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_stop(test_args, valid_server_handle));
+  uint64_t continuation_token = 1;
+  expect_sgxsd_enclave_server_terminate(SGX_SUCCESS, test_args, test_args_size, 0);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_stop(test_args, &continuation_token, valid_server_handle));
+  assert_int_equal(0, continuation_token);
+}
+static void test_sgxsd_server_stop_continued(void **state) {
+  uint64_t continuation_token = 0;
+  expect_sgxsd_enclave_server_terminate(SGX_SUCCESS, test_args, test_args_size, 1);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_stop(test_args, &continuation_token, valid_server_handle));
+  assert_int_equal(1, continuation_token);
+
+  // a server part way through terminating can't take more calls or be started again, only be stopped the rest of the way
+  test_sgxsd_negotiate_request(NULL, &test_msg_header.pending_request_id);
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_call
+                   (old_call_args, &test_msg_header, test_msg_buf.data, test_msg_buf.size, valid_msg_from.tag,
+                    valid_server_handle));
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_start(test_args, valid_server_handle));
+
+  expect_sgxsd_enclave_server_terminate(SGX_SUCCESS, test_args, test_args_size, 2);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_stop(test_args, &continuation_token, valid_server_handle));
+  assert_int_equal(2, continuation_token);
 }
 
 //
@@ -536,15 +974,16 @@ static void test_sgxsd_server_reply(sgx_status_t res,
   sgxsd_aes_gcm_mac_t *p_expected_out_mac;
   expect_sgxsd_aes_gcm_encrypt(encrypt_res, NULL,
                                reply_buf.data, reply_buf.size, false, &p_expected_dst,
-                               expected_iv, NULL, 0, &p_expected_out_mac);
+                               expected_iv, &test_reply_batch.pubkey, sizeof(test_reply_batch.pubkey),
+                               &p_expected_out_mac);
   if (encrypt_res == SGX_SUCCESS) {
     expect_sgxsd_ocall_reply(reply_ocall_res, reply_res, p_expected_dst, reply_buf.size,
                              expected_iv, sizeof(expected_iv->data),
-                             p_expected_out_mac,
+                             p_expected_out_mac, &test_reply_batch.pubkey,
                              p_msg_from->tag.tag);
   }
 
-  assert_int_equal(res, sgxsd_enclave_server_reply(reply_buf, p_msg_from));
+  assert_int_equal(res, sgxsd_enclave_server_reply(reply_buf, p_msg_from, &test_reply_batch));
 }
 
 static void test_sgxsd_server_noreply(sgx_status_t res, sgx_status_t reply_ocall_res, sgx_status_t reply_res,
@@ -561,20 +1000,90 @@ static void test_sgxsd_server_noreply(sgx_status_t res, sgx_status_t reply_ocall
 static void test_sgxsd_server_reply_invalid_buf(void **state) {
   sgxsd_msg_from_t msg_from = valid_msg_from;
   assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_server_reply
-                   ((sgxsd_msg_buf_t) { .data = NULL, .size = test_msg_buf.size }, &msg_from));
+                   ((sgxsd_msg_buf_t) { .data = NULL, .size = test_msg_buf.size }, &msg_from, &test_reply_batch));
+}
+static void test_sgxsd_server_reply_null_batch(void **state) {
+  // without a batch the reply is encrypted under the server key, with no AAD and a zero batch public key
+  sgxsd_msg_from_t msg_from = valid_msg_from;
+  uint8_t *expected_iv_data;
+  expect_sgx_read_rand(SGX_SUCCESS, &expected_iv_data, sizeof(((sgxsd_aes_gcm_iv_t *) 0)->data));
+  sgxsd_aes_gcm_iv_t *expected_iv = (sgxsd_aes_gcm_iv_t *) expected_iv_data;
+  expected_iv->data[0] |= 1;
+  void *p_expected_dst;
+  sgxsd_aes_gcm_mac_t *p_expected_out_mac;
+  expect_sgxsd_aes_gcm_encrypt(SGX_SUCCESS, &valid_msg_from.server_key,
+                               test_msg_buf.data, test_msg_buf.size, false, &p_expected_dst,
+                               expected_iv, NULL, 0,
+                               &p_expected_out_mac);
+  sgxsd_curve25519_public_key_t zero_batch_pubkey = { .x = { 0 } };
+  expect_sgxsd_ocall_reply(SGX_SUCCESS, SGX_SUCCESS, p_expected_dst, test_msg_buf.size,
+                           expected_iv, sizeof(expected_iv->data),
+                           p_expected_out_mac, &zero_batch_pubkey, msg_from.tag.tag);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_reply(test_msg_buf, &msg_from, NULL));
 }
 static void test_sgxsd_server_reply_rand_error(void **state) {
   sgxsd_msg_from_t msg_from = valid_msg_from;
   expect_sgx_read_rand(SGX_ERROR_UNEXPECTED, NULL, sizeof(((sgxsd_aes_gcm_iv_t*)0)->data));
-  assert_int_equal(SGX_ERROR_UNEXPECTED, sgxsd_enclave_server_reply(test_msg_buf, &msg_from));
+  assert_int_equal(SGX_ERROR_UNEXPECTED, sgxsd_enclave_server_reply(test_msg_buf, &msg_from, &test_reply_batch));
 }
 static void test_sgxsd_server_reply_encrypt_error(void **state) {
   test_sgxsd_server_reply(SGX_ERROR_UNEXPECTED, SGX_ERROR_UNEXPECTED, SGX_SUCCESS, SGX_SUCCESS, test_msg_buf, NULL);
 }
 static void test_sgxsd_server_reply_ocall_error(void **state) {
-  test_sgxsd_server_reply(SGX_ERROR_UNEXPECTED, SGX_SUCCESS, SGX_ERROR_UNEXPECTED, SGX_SUCCESS, test_msg_buf, NULL);
+  // the host has taken the tag of a reply it returned an error for, so it isn't kept to be retried
   test_sgxsd_server_reply(SGX_ERROR_UNEXPECTED, SGX_SUCCESS, SGX_SUCCESS, SGX_ERROR_UNEXPECTED, test_msg_buf, NULL);
 }
+static void test_sgxsd_server_reply_retried(void **state) {
+  sgxsd_msg_from_t msg_from = valid_msg_from;
+  uint8_t *expected_iv_data;
+  expect_sgx_read_rand(SGX_SUCCESS, &expected_iv_data, sizeof(((sgxsd_aes_gcm_iv_t *) 0)->data));
+  sgxsd_aes_gcm_iv_t expected_iv;
+  memcpy(expected_iv.data, expected_iv_data, sizeof(expected_iv.data));
+  expected_iv.data[0] |= 1;
+  void *p_expected_dst;
+  sgxsd_aes_gcm_mac_t *p_expected_out_mac;
+  expect_sgxsd_aes_gcm_encrypt(SGX_SUCCESS, NULL,
+                               test_msg_buf.data, test_msg_buf.size, false, &p_expected_dst,
+                               &expected_iv, &test_reply_batch.pubkey, sizeof(test_reply_batch.pubkey),
+                               &p_expected_out_mac);
+  sgxsd_aes_gcm_mac_t expected_out_mac = *p_expected_out_mac;
+  uint8_t *expected_dst = test_malloc(test_msg_buf.size);
+  memcpy(expected_dst, p_expected_dst, test_msg_buf.size);
+  expect_sgxsd_ocall_reply(SGX_ERROR_UNEXPECTED, SGX_SUCCESS, expected_dst, test_msg_buf.size,
+                           &expected_iv, sizeof(expected_iv.data),
+                           &expected_out_mac, &test_reply_batch.pubkey, msg_from.tag.tag);
+
+  // a reply whose ocall failed is kept, and the server told it was sent
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_server_reply(test_msg_buf, &msg_from, &test_reply_batch));
+
+  // until the buffer is full
+  test_sgxsd_server_reply(SGX_ERROR_UNEXPECTED, SGX_SUCCESS, SGX_ERROR_UNEXPECTED, SGX_SUCCESS, test_msg_buf, NULL);
+
+  // a flush whose ocall fails again keeps the reply too
+  uint64_t pending_reply_count = UINT64_MAX;
+  expect_sgxsd_ocall_reply(SGX_ERROR_UNEXPECTED, SGX_SUCCESS, expected_dst, test_msg_buf.size,
+                           &expected_iv, sizeof(expected_iv.data),
+                           &expected_out_mac, &test_reply_batch.pubkey, valid_msg_from.tag.tag);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_flush_replies(&pending_reply_count));
+  assert_int_equal(1, pending_reply_count);
+
+  expect_sgxsd_ocall_reply(SGX_SUCCESS, SGX_SUCCESS, expected_dst, test_msg_buf.size,
+                           &expected_iv, sizeof(expected_iv.data),
+                           &expected_out_mac, &test_reply_batch.pubkey, valid_msg_from.tag.tag);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_flush_replies(&pending_reply_count));
+  assert_int_equal(0, pending_reply_count);
+
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_flush_replies(&pending_reply_count));
+  assert_int_equal(0, pending_reply_count);
+  test_free(expected_dst);
+}
+static void test_sgxsd_flush_replies_node_uninitialized(void **state) {
+  uint64_t pending_reply_count;
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_flush_replies(&pending_reply_count));
+}
+static void test_sgxsd_flush_replies_null_count(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_flush_replies(NULL));
+}
 static void test_sgxsd_server_reply_empty(void **state) {
   test_sgxsd_server_reply(SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, null_msg_buf, NULL);
   test_sgxsd_server_reply(SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, empty_msg_buf, NULL);
@@ -586,10 +1095,28 @@ static void test_sgxsd_server_reply_twice(void **state) {
   sgxsd_msg_from_t msg_from = valid_msg_from;
   test_sgxsd_server_reply(SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, test_msg_buf, &msg_from);
 
-  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_reply(test_msg_buf, &msg_from));
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_reply(test_msg_buf, &msg_from, &test_reply_batch));
   assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_noreply(&msg_from));
 }
 
+static void test_sgxsd_new_reply_batch_null_batch(void **state) {
+  assert_int_equal(SGX_ERROR_INVALID_PARAMETER, sgxsd_enclave_new_reply_batch(NULL));
+}
+static void test_sgxsd_new_reply_batch_rand_error(void **state) {
+  expect_sgx_read_rand(SGX_ERROR_UNEXPECTED, NULL, SGXSD_CURVE25519_KEY_SIZE);
+  sgxsd_reply_batch_t batch;
+  assert_int_equal(SGX_ERROR_UNEXPECTED, sgxsd_enclave_new_reply_batch(&batch));
+}
+static void test_sgxsd_new_reply_batch_valid(void **state) {
+  sgxsd_reply_batch_t first_batch;
+  expect_sgx_read_rand(SGX_SUCCESS, NULL, SGXSD_CURVE25519_KEY_SIZE);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_new_reply_batch(&first_batch));
+  sgxsd_reply_batch_t second_batch;
+  expect_sgx_read_rand(SGX_SUCCESS, NULL, SGXSD_CURVE25519_KEY_SIZE);
+  assert_int_equal(SGX_SUCCESS, sgxsd_enclave_new_reply_batch(&second_batch));
+  assert_memory_not_equal(first_batch.pubkey.x, second_batch.pubkey.x, sizeof(first_batch.pubkey.x));
+}
+
 static void test_sgxsd_server_noreply_valid(void **state) {
   test_sgxsd_server_noreply(SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, NULL);
 }
@@ -598,7 +1125,7 @@ static void test_sgxsd_server_noreply_twice(void **state) {
   sgxsd_msg_from_t msg_from = valid_msg_from;
   test_sgxsd_server_noreply(SGX_SUCCESS, SGX_SUCCESS, SGX_SUCCESS, &msg_from);
   assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_noreply(&msg_from));
-  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_reply(test_msg_buf, &msg_from));
+  assert_int_equal(SGX_ERROR_INVALID_STATE, sgxsd_enclave_server_reply(test_msg_buf, &msg_from, &test_reply_batch));
 }
 
 int main(int argc, char *argv[]) {
@@ -609,9 +1136,17 @@ int main(int argc, char *argv[]) {
     unit_test(test_sgxsd_get_next_report_node_uninitialized),
     unit_test(test_sgxsd_set_current_quote_node_uninitialized),
     unit_test(test_sgxsd_negotiate_request_node_uninitialized),
+    unit_test(test_sgxsd_open_session_node_uninitialized),
+    unit_test(test_sgxsd_close_session_node_uninitialized),
     unit_test(test_sgxsd_server_start_node_uninitialized),
     unit_test(test_sgxsd_server_call_node_uninitialized),
     unit_test(test_sgxsd_server_stop_node_uninitialized),
+    unit_test(test_sgxsd_server_get_metrics_node_uninitialized),
+    unit_test(test_sgxsd_server_cancel_call_node_uninitialized),
+    unit_test(test_sgxsd_commit_directory_node_uninitialized),
+    unit_test(test_sgxsd_flush_replies_node_uninitialized),
+    unit_test(test_sgxsd_sample_directory_node_uninitialized),
+    unit_test(test_sgxsd_set_session_denylist_node_uninitialized),
 
     // node init tests
     unit_test(test_sgxsd_node_init_rand_error),
@@ -628,11 +1163,24 @@ int main(int argc, char *argv[]) {
     unit_test(test_sgxsd_negotiate_request_null_response),
     unit_test(test_sgxsd_negotiate_request_generate_keypair_rand_error),
 
+    // session tests
+    unit_test(test_sgxsd_open_session_null_request),
+    unit_test(test_sgxsd_open_session_null_response),
+    unit_test(test_sgxsd_close_session_null_id),
+    unit_test(test_sgxsd_close_session_null_summary),
+    unit_test(test_sgxsd_close_session_pending_request),
+    unit_test_setup_teardown(test_sgxsd_session_valid, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+    unit_test_setup_teardown(test_sgxsd_session_ratelimit_usage, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+    unit_test_setup_teardown(test_sgxsd_session_replay, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+    unit_test_setup_teardown(test_sgxsd_session_decrypt_failures, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+
     // server start tests
     unit_test(test_sgxsd_server_start_invalid_handle),
     unit_test_setup_teardown(test_sgxsd_server_start_already_started, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
     unit_test_setup_teardown(test_sgxsd_server_start_init_error, test_noop, test_sgxsd_server_stop_already_stopped),
     unit_test_setup_teardown(test_sgxsd_server_start_valid, test_noop, test_sgxsd_server_stop_valid),
+    unit_test_setup_teardown(test_sgxsd_server_start_other_config, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+    unit_test_setup_teardown(test_sgxsd_server_start_same_config, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
 
     // rate limit fingerprint tests
     unit_test_setup_teardown(test_sgxsd_ratelimit_fingerprint_golden_path, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
@@ -649,20 +1197,61 @@ int main(int argc, char *argv[]) {
     unit_test_setup_teardown(test_sgxsd_server_call_valid, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
     unit_test_setup_teardown(test_sgxsd_server_call_replay, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
 
+    // server get_metrics tests
+    unit_test(test_sgxsd_server_get_metrics_invalid_handle),
+    unit_test(test_sgxsd_server_get_metrics_not_started),
+    unit_test_setup_teardown(test_sgxsd_server_get_metrics_null_metrics, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+    unit_test_setup_teardown(test_sgxsd_server_get_metrics_valid, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+
+    // server cancel_call tests
+    unit_test(test_sgxsd_server_cancel_call_invalid_handle),
+    unit_test(test_sgxsd_server_cancel_call_not_started),
+    unit_test_setup_teardown(test_sgxsd_server_cancel_call_not_found, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+    unit_test_setup_teardown(test_sgxsd_server_cancel_call_valid, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
+
+    // commit_directory tests
+    unit_test(test_sgxsd_commit_directory_null_args),
+    unit_test(test_sgxsd_commit_directory_error),
+
+    // sample_directory tests
+    unit_test(test_sgxsd_sample_directory_null_args),
+    unit_test(test_sgxsd_sample_directory_valid),
+
+    // set_session_denylist tests
+    unit_test(test_sgxsd_set_session_denylist_null_args),
+    unit_test(test_sgxsd_set_session_denylist_valid),
+
+    // get_incident_record tests
+    unit_test(test_sgxsd_get_incident_record_null_args),
+    unit_test(test_sgxsd_get_incident_record_valid),
+
+    // sealing key tests
+    unit_test(test_sgxsd_get_sealing_key_null_key),
+    unit_test(test_sgxsd_get_sealing_key_error),
+    unit_test(test_sgxsd_get_sealing_key_valid),
+
     // server stop tests
     unit_test(test_sgxsd_server_stop_invalid_handle),
     unit_test(test_sgxsd_server_stop_already_stopped),
+    unit_test_setup_teardown(test_sgxsd_server_stop_null_continuation_token, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
     unit_test_setup_teardown(test_sgxsd_server_stop_terminate_error, test_sgxsd_server_start_valid, test_sgxsd_server_stop_already_stopped),
     unit_test_setup_teardown(test_sgxsd_server_stop_valid, test_sgxsd_server_start_valid, test_sgxsd_server_stop_already_stopped),
+    unit_test_setup_teardown(test_sgxsd_server_stop_continued, test_sgxsd_server_start_valid, test_sgxsd_server_stop_valid),
 
     // reply tests
     unit_test(test_sgxsd_server_reply_invalid_buf),
+    unit_test(test_sgxsd_server_reply_null_batch),
     unit_test(test_sgxsd_server_reply_rand_error),
     unit_test(test_sgxsd_server_reply_encrypt_error),
     unit_test(test_sgxsd_server_reply_ocall_error),
+    unit_test(test_sgxsd_server_reply_retried),
+    unit_test(test_sgxsd_flush_replies_null_count),
     unit_test(test_sgxsd_server_reply_empty),
     unit_test(test_sgxsd_server_reply_valid),
     unit_test(test_sgxsd_server_reply_twice),
+    unit_test(test_sgxsd_new_reply_batch_null_batch),
+    unit_test(test_sgxsd_new_reply_batch_rand_error),
+    unit_test(test_sgxsd_new_reply_batch_valid),
     unit_test(test_sgxsd_server_noreply_valid),
     unit_test(test_sgxsd_server_noreply_twice),
     unit_test_teardown(node_tests, teardown_node_tests),
@@ -870,6 +1459,7 @@ void expect_sgxsd_ocall_reply(sgx_status_t ocall_res, sgx_status_t res,
                               const void *p_expected_dst, size_t expected_reply_data_size,
                               const void *expected_iv, size_t expected_iv_len,
                               const sgxsd_aes_gcm_mac_t *p_expected_out_mac,
+                              const sgxsd_curve25519_public_key_t *p_expected_batch_pubkey,
                               uint64_t expected_tag) {
   expect_value(sgxsd_ocall_reply, reply_data_size, expected_reply_data_size);
   if (expected_reply_data_size != 0) {
@@ -881,6 +1471,7 @@ void expect_sgxsd_ocall_reply(sgx_status_t ocall_res, sgx_status_t res,
   expect_not_value(sgxsd_ocall_reply, reply_header, NULL);
   expect_memory(sgxsd_ocall_reply, reply_header->iv.data, expected_iv, expected_iv_len);
   expect_memory(sgxsd_ocall_reply, reply_header->mac.data, p_expected_out_mac, sizeof(p_expected_out_mac->data));
+  expect_memory(sgxsd_ocall_reply, reply_header->batch_pubkey.x, p_expected_batch_pubkey->x, sizeof(p_expected_batch_pubkey->x));
   expect_value(sgxsd_ocall_reply, msg_tag.tag, expected_tag);
   will_return(sgxsd_ocall_reply, res);
   will_return(sgxsd_ocall_reply, ocall_res);
@@ -895,7 +1486,7 @@ void expect_sgxsd_ocall_noreply(sgx_status_t ocall_res, sgx_status_t res, uint64
   will_return(sgxsd_ocall_reply, ocall_res);
 }
 
-sgx_status_t sgxsd_ocall_reply(sgx_status_t* retval, const sgxsd_msg_header_t *reply_header,
+sgx_status_t sgxsd_ocall_reply(sgx_status_t* retval, const sgxsd_reply_header_t *reply_header,
                                const uint8_t *reply_data, size_t reply_data_size,
                                sgxsd_msg_tag_t msg_tag) {
   check_expected(reply_data_size);
@@ -904,6 +1495,7 @@ sgx_status_t sgxsd_ocall_reply(sgx_status_t* retval, const sgxsd_msg_header_t *r
   if (reply_header != NULL) {
     check_expected(reply_header->iv.data);
     check_expected(reply_header->mac.data);
+    check_expected(reply_header->batch_pubkey.x);
   }
   check_expected(msg_tag.tag);
   *retval = (sgx_status_t) mock();
@@ -928,13 +1520,21 @@ uint32_t sgx_spin_unlock(sgx_spinlock_t *lock) {
   return 0;
 }
 
+// not sha256, but enough of a hash that tests can tell differing inputs apart
 void br_sha256_init(br_sha256_context *sha_context) {
+  memset(sha_context, 0, sizeof(*sha_context));
 }
 
 void br_sha256_update(br_sha256_context *sha_context, const void *p_src, size_t src_len) {
+  const uint8_t *p_src_bytes = p_src;
+  for (size_t src_idx = 0; src_idx < src_len; src_idx++) {
+    uint32_t *p_val = &sha_context->val[sha_context->count++ % 8];
+    *p_val = *p_val * 31 + p_src_bytes[src_idx] + 1;
+  }
 }
 
 void br_sha256_out(const br_sha256_context *sha_handle, void *p_hash) {
+  memcpy(p_hash, sha_handle->val, sizeof(sha_handle->val));
 }
 
 sgx_status_t sgx_sha256_close(sgx_sha_state_handle_t sha_handle) {
@@ -949,6 +1549,13 @@ sgx_status_t sgx_verify_report(const sgx_report_t *report) {
   return (sgx_status_t) SGX_SUCCESS;
 }
 
+sgx_status_t sgx_get_key(const sgx_key_request_t *key_request, sgx_key_128bit_t *key) {
+  assert_int_equal(key_request->key_name, SGX_KEYSELECT_SEAL);
+  assert_int_equal(key_request->key_policy, SGX_KEYPOLICY_MRENCLAVE);
+  memset(key, 7, sizeof(*key));
+  return (sgx_status_t) mock();
+}
+
 void expect_sgxsd_enclave_server_init(sgx_status_t res, void *expected_args, size_t expected_args_size) {
   expect_memory(sgxsd_enclave_server_init, args, expected_args, expected_args_size);
   expect_not_value(sgxsd_enclave_server_init, vpp_state, NULL);
@@ -977,9 +1584,19 @@ void expect_sgxsd_enclave_server_handle_call(sgx_status_t res, sgxsd_server_hand
     expect_any(sgxsd_enclave_server_handle_call, msg.data);
   }
   expect_memory(sgxsd_enclave_server_handle_call, &from.tag, &expected_from.tag, sizeof(expected_from.tag));
+  expect_memory(sgxsd_enclave_server_handle_call, &from.client_pubkey, &expected_from.client_pubkey, sizeof(expected_from.client_pubkey));
   expect_not_value(sgxsd_enclave_server_handle_call, vpp_state, NULL);
   will_return(sgxsd_enclave_server_handle_call, res);
 }
+sgx_status_t sgxsd_enclave_server_config(const sgxsd_server_init_args_t *args, uint8_t *p_config, size_t config_size,
+                                         size_t *p_config_len) {
+  assert_int_not_equal(p_config_len, NULL);
+  assert_in_range(sizeof(test_server_config), 0, config_size);
+  memcpy(p_config, test_server_config, sizeof(test_server_config));
+  *p_config_len = sizeof(test_server_config);
+  return SGX_SUCCESS;
+}
+
 sgx_status_t sgxsd_enclave_server_handle_call(const sgxsd_server_handle_call_args_t *args, sgxsd_msg_buf_t msg,
                                               sgxsd_msg_from_t from, sgxsd_server_state_t **vpp_state) {
   check_expected(args->query_phone_count);
@@ -992,6 +1609,7 @@ sgx_status_t sgxsd_enclave_server_handle_call(const sgxsd_server_handle_call_arg
   check_expected(msg.size);
   check_expected(msg.data);
   check_expected(&from.tag);
+  check_expected(&from.client_pubkey);
   check_expected(vpp_state);
   return (sgx_status_t) mock();
 }
@@ -1052,13 +1670,72 @@ sgx_status_t sgxsd_enclave_create_ratelimit_fingerprint(uint8_t fingerprint_key[
 }
 
 
-void expect_sgxsd_enclave_server_terminate(sgx_status_t res, void *expected_args, size_t expected_args_size) {
+void expect_sgxsd_enclave_server_terminate(sgx_status_t res, void *expected_args, size_t expected_args_size, uint64_t continuation_token) {
   expect_memory(sgxsd_enclave_server_terminate, args, expected_args, expected_args_size);
-  expect_any(sgxsd_enclave_server_terminate, vp_state);
+  expect_not_value(sgxsd_enclave_server_terminate, p_continuation_token, NULL);
+  expect_not_value(sgxsd_enclave_server_terminate, vpp_state, NULL);
+  will_return(sgxsd_enclave_server_terminate, continuation_token);
   will_return(sgxsd_enclave_server_terminate, res);
 }
-sgx_status_t sgxsd_enclave_server_terminate(const sgxsd_server_terminate_args_t *args, sgxsd_server_state_t *vp_state) {
+sgx_status_t sgxsd_enclave_server_terminate(const sgxsd_server_terminate_args_t *args, uint64_t *p_continuation_token,
+                                            sgxsd_server_state_t **vpp_state) {
   check_expected(args);
+  check_expected(p_continuation_token);
+  check_expected(vpp_state);
+  *p_continuation_token = (uint64_t) mock();
+  if (*p_continuation_token == 0) {
+    *vpp_state = NULL;
+  }
+  return (sgx_status_t) mock();
+}
+
+void expect_sgxsd_enclave_server_metrics(sgx_status_t res, uint64_t expected_now_ticks, sgxsd_server_metrics_t *expected_p_metrics) {
+  expect_value(sgxsd_enclave_server_metrics, now_ticks, expected_now_ticks);
+  expect_value(sgxsd_enclave_server_metrics, p_metrics, expected_p_metrics);
+  expect_any(sgxsd_enclave_server_metrics, vp_state);
+  will_return(sgxsd_enclave_server_metrics, res);
+}
+sgx_status_t sgxsd_enclave_server_metrics(uint64_t now_ticks, sgxsd_server_metrics_t *p_metrics, const sgxsd_server_state_t *vp_state) {
+  check_expected(now_ticks);
+  check_expected(p_metrics);
+  check_expected(vp_state);
+  return (sgx_status_t) mock();
+}
+
+void expect_sgxsd_enclave_server_cancel(sgx_status_t res, uint64_t expected_tag) {
+  expect_value(sgxsd_enclave_server_cancel, tag, expected_tag);
+  expect_any(sgxsd_enclave_server_cancel, vp_state);
+  will_return(sgxsd_enclave_server_cancel, res);
+}
+sgx_status_t sgxsd_enclave_server_cancel(sgxsd_msg_tag_t msg_tag, sgxsd_server_state_t *vp_state) {
+  uint64_t tag = msg_tag.tag;
+  check_expected(tag);
   check_expected(vp_state);
   return (sgx_status_t) mock();
 }
+
+sgx_status_t sgxsd_enclave_directory_commit(const sgxsd_directory_commit_args_t *p_args) {
+  check_expected(p_args);
+  return (sgx_status_t) mock();
+}
+
+sgx_status_t sgxsd_enclave_directory_sample(const sgxsd_directory_sample_args_t *p_args,
+                                            sgxsd_directory_sample_t *p_samples, size_t sample_count) {
+  check_expected(p_args);
+  check_expected(p_samples);
+  check_expected(sample_count);
+  return (sgx_status_t) mock();
+}
+
+sgx_status_t sgxsd_enclave_session_denylist(const sgxsd_curve25519_public_key_t *p_session_ids, size_t session_id_count) {
+  check_expected(p_session_ids);
+  check_expected(session_id_count);
+  return (sgx_status_t) mock();
+}
+
+sgx_status_t sgxsd_enclave_incident_record(uint8_t *p_record, size_t record_size, size_t *p_record_len) {
+  check_expected(p_record);
+  check_expected(record_size);
+  check_expected(p_record_len);
+  return (sgx_status_t) mock();
+}
@@ -55,10 +55,31 @@ typedef struct sgxsd_sha256_hash {
 typedef struct sgxsd_pending_request {
     uint64_t id_val;
     sgxsd_sha256_hash_t hkdf_prk;
+    sgxsd_curve25519_public_key_t client_pubkey;
 } sgxsd_pending_request_t;
 
+typedef struct sgxsd_session {
+    uint64_t id_val;
+    uint64_t last_used;
+    uint64_t replay_window_top;
+    uint64_t replay_window_bits;
+    uint64_t decrypt_failures;
+    sgxsd_session_usage_t usage;
+    sgxsd_sha256_hash_t hkdf_prk;
+    sgxsd_curve25519_public_key_t client_pubkey;
+} sgxsd_session_t;
+
+typedef struct sgxsd_retry_reply {
+    bool valid;
+    sgxsd_reply_header_t header;
+    sgxsd_msg_tag_t tag;
+    uint8_t *data;
+    size_t size;
+} sgxsd_retry_reply_t;
+
 typedef struct sgxsd_server_state_desc {
     bool valid;
+    bool stopping;
     sgx_spinlock_t lock;
     sgxsd_server_state_t *p_state;
 } sgxsd_server_state_desc_t;
@@ -67,9 +88,22 @@ typedef struct sgxsd_server_state_desc {
 #define SGXSD_ENCLAVE_MAX_SERVERS 256
 #endif
 
-sgx_status_t SGX_CDECL sgxsd_ocall_reply(sgx_status_t* retval, const sgxsd_msg_header_t* reply_header, const uint8_t* reply_data, size_t reply_data_size, sgxsd_msg_tag_t msg_tag);
+#ifndef SGXSD_ENCLAVE_MAX_SERVER_CONFIG_SIZE
+#define SGXSD_ENCLAVE_MAX_SERVER_CONFIG_SIZE 256
+#endif
+
+#ifndef SGXSD_ENCLAVE_MAX_SESSIONS
+#define SGXSD_ENCLAVE_MAX_SESSIONS 1024
+#endif
+
+// session ids share the encrypted id format and key of pending request ids, and are told apart by the top bit
+#define SGXSD_SESSION_ID_FLAG ((uint64_t){1} << 63)
+#define SGXSD_SESSION_REPLAY_WINDOW_SIZE (sizeof(uint64_t) * 8)
+
+sgx_status_t SGX_CDECL sgxsd_ocall_reply(sgx_status_t* retval, const sgxsd_reply_header_t* reply_header, const uint8_t* reply_data, size_t reply_data_size, sgxsd_msg_tag_t msg_tag);
 
 sgx_status_t sgxsd_enclave_generate_curve25519_keypair(sgxsd_curve25519_key_pair_t *p_keypair);
+void sgxsd_enclave_hash_config(sgxsd_sha256_hash_t *p_hash, const sgxsd_node_init_args_t *p_args);
 
 //
 // static variables
@@ -78,6 +112,13 @@ sgx_status_t sgxsd_enclave_generate_curve25519_keypair(sgxsd_curve25519_key_pair
 bool g_sgxsd_enclave_node_initialized = false;
 sgx_spinlock_t g_sgxsd_enclave_node_init_lock;
 
+// hash of the node configuration, and of the server config shared by the servers running, reported after the public
+// key in the report data of each quote
+sgxsd_sha256_hash_t g_sgxsd_enclave_node_config_hash;
+sgxsd_sha256_hash_t g_sgxsd_enclave_config_hash;
+size_t g_sgxsd_enclave_config_server_count;
+sgx_spinlock_t g_sgxsd_enclave_config_hash_lock;
+
 sgxsd_sha256_hash_t g_sgxsd_enclave_read_rand_state;
 sgx_spinlock_t g_sgxsd_enclave_read_rand_lock;
 
@@ -91,6 +132,15 @@ uint64_t g_sgxsd_enclave_last_pending_request_id_val;
 sgxsd_aes_gcm_key_t g_sgxsd_enclave_pending_request_id_key;
 sgx_spinlock_t g_sgxsd_enclave_pending_requests_lock;
 
+sgxsd_retry_reply_t *g_sgxsd_enclave_retry_replies;
+uint32_t g_sgxsd_enclave_max_retry_replies;
+sgx_spinlock_t g_sgxsd_enclave_retry_replies_lock;
+
+sgxsd_session_t g_sgxsd_enclave_sessions[SGXSD_ENCLAVE_MAX_SESSIONS];
+uint64_t g_sgxsd_enclave_last_session_id_val;
+uint64_t g_sgxsd_enclave_session_clock;
+sgx_spinlock_t g_sgxsd_enclave_sessions_lock;
+
 static const sgxsd_server_state_handle_t g_sgxsd_enclave_max_servers = SGXSD_ENCLAVE_MAX_SERVERS;
 sgxsd_server_state_desc_t g_sgxsd_enclave_server_states[SGXSD_ENCLAVE_MAX_SERVERS];
 
@@ -160,6 +210,19 @@ sgx_status_t sgxsd_enclave_node_init_locked(const sgxsd_node_init_args_t *p_args
         return SGX_ERROR_OUT_OF_MEMORY;
     }
 
+    if (p_args->max_retry_replies != 0) {
+        g_sgxsd_enclave_retry_replies = calloc(p_args->max_retry_replies, sizeof(*g_sgxsd_enclave_retry_replies));
+        if (g_sgxsd_enclave_retry_replies == NULL) {
+            free(g_sgxsd_enclave_pending_requests);
+            g_sgxsd_enclave_pending_requests = NULL;
+            return SGX_ERROR_OUT_OF_MEMORY;
+        }
+    }
+    g_sgxsd_enclave_max_retry_replies = p_args->max_retry_replies;
+
+    sgxsd_enclave_hash_config(&g_sgxsd_enclave_node_config_hash, p_args);
+    g_sgxsd_enclave_config_hash = g_sgxsd_enclave_node_config_hash;
+
     g_sgxsd_enclave_node_initialized = true;
     return SGX_SUCCESS;
 }
@@ -282,6 +345,34 @@ void sgxsd_enclave_hmac_sha256(sgxsd_sha256_hash_t *p_hash, uint32_t buf_count,
     memset_s(i_key_pad, sizeof(i_key_pad), 0, sizeof(i_key_pad));
     memset_s(o_key_pad, sizeof(o_key_pad), 0, sizeof(o_key_pad));
 }
+
+void sgxsd_enclave_hash_config(sgxsd_sha256_hash_t *p_hash, const sgxsd_node_init_args_t *p_args) {
+    // hash a fixed little-endian encoding rather than the struct, so padding and layout don't change the hash.
+    // compile-time limits are already covered by MRENCLAVE, so aren't hashed
+    static const uint8_t label[] = "sgxsd node config v1";
+    uint8_t encoded_args[1 + sizeof(uint32_t)] = {
+        p_args->pending_requests_table_order,
+        (uint8_t) p_args->max_retry_replies,
+        (uint8_t) (p_args->max_retry_replies >> 8),
+        (uint8_t) (p_args->max_retry_replies >> 16),
+        (uint8_t) (p_args->max_retry_replies >> 24),
+    };
+    sgxsd_enclave_sha256(p_hash, 2, (sgxsd_sha256_buf_t[]) {
+        { label, sizeof(label) },
+        { encoded_args, sizeof(encoded_args) },
+    });
+}
+
+static
+void sgxsd_enclave_hash_server_config(sgxsd_sha256_hash_t *p_hash, const uint8_t *p_config, size_t config_len) {
+    // the node configuration is hashed in, so the hash reported while a server runs covers both
+    static const uint8_t label[] = "sgxsd server config v1";
+    sgxsd_enclave_sha256(p_hash, 3, (sgxsd_sha256_buf_t[]) {
+        { label, sizeof(label) },
+        { g_sgxsd_enclave_node_config_hash.data, sizeof(g_sgxsd_enclave_node_config_hash.data) },
+        { p_config, (uint32_t) config_len },
+    });
+}
 typedef struct sgxsd_ra_hkdf_buf {
     sgxsd_sha256_hash_t t_n;
     uint8_t n;
@@ -331,6 +422,46 @@ sgx_status_t sgxsd_enclave_read_rand(sgxsd_rand_buf_t *p_privkey) {
     return SGX_SUCCESS;
 }
 
+sgx_status_t sgxsd_enclave_get_sealing_key(sgxsd_aes_gcm_key_t *p_key) {
+    if (p_key == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+
+    // the seal key is bound to the security version the enclave and platform currently run at
+    sgx_report_t report;
+    sgx_status_t create_report_res = sgx_create_report(NULL, NULL, &report);
+    if (create_report_res != SGX_SUCCESS) {
+        return create_report_res;
+    }
+    sgx_key_request_t key_request = {
+        .key_name = SGX_KEYSELECT_SEAL,
+        .key_policy = SGX_KEYPOLICY_MRENCLAVE,
+        .isv_svn = report.body.isv_svn,
+        .cpu_svn = report.body.cpu_svn,
+        .attribute_mask = { .flags = SGX_FLAGS_INITTED | SGX_FLAGS_DEBUG, .xfrm = 0 },
+        .misc_mask = 0xF0000000,
+    };
+    sgx_key_128bit_t seal_key;
+    sgx_status_t get_key_res = sgx_get_key(&key_request, &seal_key);
+    if (get_key_res != SGX_SUCCESS) {
+        memset_s(&seal_key, sizeof(seal_key), 0, sizeof(seal_key));
+        return get_key_res;
+    }
+
+    // stretch the 128-bit seal key to an AES-256 key
+    sgxsd_sha256_hash_t key_hash;
+    sgxsd_enclave_sha256(&key_hash, 1, (sgxsd_sha256_buf_t[]) {
+        { &seal_key, sizeof(seal_key) },
+    });
+    memcpy(p_key->data, key_hash.data, sizeof(p_key->data));
+    _Static_assert(sizeof(p_key->data) == sizeof(key_hash.data), "p_key overflow");
+
+    memset_s(&key_hash, sizeof(key_hash), 0, sizeof(key_hash));
+    memset_s(&seal_key, sizeof(seal_key), 0, sizeof(seal_key));
+
+    return SGX_SUCCESS;
+}
+
 int curve25519_donna(uint8_t *, const uint8_t *, const uint8_t *);
 
 sgx_status_t sgxsd_enclave_generate_curve25519_keypair(sgxsd_curve25519_key_pair_t *p_keypair) {
@@ -367,10 +498,13 @@ sgx_status_t sgxsd_enclave_get_next_report(sgx_target_info_t qe_target_info, sgx
         return generate_keypair_res;
     }
 
-    // construct report data with new curve25519 public key
+    // construct report data with new curve25519 public key, followed by the config hash
     sgx_report_data_t report_data = { .d = { 0 } };
     memcpy(report_data.d, new_dh_keypair.pubkey.x, sizeof(new_dh_keypair.pubkey.x));
-    _Static_assert(sizeof(report_data.d) >= sizeof(new_dh_keypair.pubkey.x), "sgx_report_data_t.d overflow");
+    sgxsd_spin_lock(&g_sgxsd_enclave_config_hash_lock);
+    memcpy(report_data.d + sizeof(new_dh_keypair.pubkey.x), g_sgxsd_enclave_config_hash.data, sizeof(g_sgxsd_enclave_config_hash.data));
+    sgxsd_spin_unlock(&g_sgxsd_enclave_config_hash_lock);
+    _Static_assert(sizeof(report_data.d) >= sizeof(new_dh_keypair.pubkey.x) + sizeof(g_sgxsd_enclave_config_hash.data), "sgx_report_data_t.d overflow");
 
     sgx_status_t create_report_res = sgx_create_report(&qe_target_info, &report_data, p_report);
     if (create_report_res != SGX_SUCCESS) {
@@ -400,20 +534,7 @@ sgx_status_t sgxsd_enclave_set_current_quote() {
     return SGX_SUCCESS;
 }
 
-sgx_status_t sgxsd_enclave_add_pending_request(sgxsd_pending_request_id_t *p_pending_request_id, const sgxsd_pending_request_t *p_pending_request) {
-    uint64_t pending_request_count_mask = ((uint64_t){1} << g_sgxsd_enclave_pending_requests_table_order) - 1;
-
-    sgxsd_spin_lock(&g_sgxsd_enclave_pending_requests_lock);
-
-    g_sgxsd_enclave_last_pending_request_id_val += 1;
-    uint64_t pending_request_id_val = g_sgxsd_enclave_last_pending_request_id_val;
-    sgxsd_pending_request_t *p_pending_requests_entry = &g_sgxsd_enclave_pending_requests[pending_request_id_val & pending_request_count_mask];
-    p_pending_requests_entry->id_val = pending_request_id_val;
-    memcpy(&p_pending_requests_entry->hkdf_prk, &p_pending_request->hkdf_prk, sizeof(p_pending_requests_entry->hkdf_prk));
-    _Static_assert(sizeof(p_pending_requests_entry->hkdf_prk) == sizeof(p_pending_request->hkdf_prk), "overflow");
-
-    sgxsd_spin_unlock(&g_sgxsd_enclave_pending_requests_lock);
-
+sgx_status_t sgxsd_enclave_encrypt_request_id(uint64_t id_val, sgxsd_pending_request_id_t *p_pending_request_id) {
     sgx_status_t iv_rand_res = sgx_read_rand((uint8_t *) &p_pending_request_id->iv, sizeof(p_pending_request_id->iv));
     if (iv_rand_res != SGX_SUCCESS) {
         return iv_rand_res;
@@ -421,12 +542,12 @@ sgx_status_t sgxsd_enclave_add_pending_request(sgxsd_pending_request_id_t *p_pen
 
     sgx_status_t encrypt_res =
         sgxsd_aes_gcm_encrypt(&g_sgxsd_enclave_pending_request_id_key, /* p_key */
-                              &pending_request_id_val, sizeof(pending_request_id_val), /* p_src, src_len */
+                              &id_val, sizeof(id_val), /* p_src, src_len */
                               &p_pending_request_id->data, /* p_dst */
                               &p_pending_request_id->iv, /* p_iv */
                               NULL, 0, /* p_aad, aad_len */
                               &p_pending_request_id->mac /* p_out_mac */);
-    _Static_assert(sizeof(pending_request_id_val) == sizeof(p_pending_request_id->data), "pending_request_id overflow");
+    _Static_assert(sizeof(id_val) == sizeof(p_pending_request_id->data), "pending_request_id overflow");
     if (encrypt_res != SGX_SUCCESS) {
         return SGX_ERROR_UNEXPECTED;
     }
@@ -434,20 +555,42 @@ sgx_status_t sgxsd_enclave_add_pending_request(sgxsd_pending_request_id_t *p_pen
     return SGX_SUCCESS;
 }
 
-sgx_status_t sgxsd_enclave_get_pending_request(const sgxsd_pending_request_id_t *p_pending_request_id, sgxsd_pending_request_t *p_pending_request) {
-    uint64_t pending_request_id_val = 0;
+sgx_status_t sgxsd_enclave_decrypt_request_id(const sgxsd_pending_request_id_t *p_pending_request_id, uint64_t *p_id_val) {
+    uint64_t id_val = 0;
     sgx_status_t decrypt_res =
             sgxsd_aes_gcm_decrypt(&g_sgxsd_enclave_pending_request_id_key, /* p_key */
                                   &p_pending_request_id->data, sizeof(p_pending_request_id->data), /* p_src, src_len */
-                                  &pending_request_id_val, /* p_dst */
+                                  &id_val, /* p_dst */
                                   &p_pending_request_id->iv, /* p_iv */
                                   NULL, 0, /* p_aad, aad_len */
                                   &p_pending_request_id->mac /* p_in_mac */);
-    _Static_assert(sizeof(p_pending_request_id->data) == sizeof(pending_request_id_val), "pending_request_id_val overflow");
+    _Static_assert(sizeof(p_pending_request_id->data) == sizeof(id_val), "pending_request_id_val overflow");
     if (decrypt_res != SGX_SUCCESS) {
         return decrypt_res;
     }
+    *p_id_val = id_val;
+    return SGX_SUCCESS;
+}
+
+sgx_status_t sgxsd_enclave_add_pending_request(sgxsd_pending_request_id_t *p_pending_request_id, const sgxsd_pending_request_t *p_pending_request) {
+    uint64_t pending_request_count_mask = ((uint64_t){1} << g_sgxsd_enclave_pending_requests_table_order) - 1;
 
+    sgxsd_spin_lock(&g_sgxsd_enclave_pending_requests_lock);
+
+    g_sgxsd_enclave_last_pending_request_id_val += 1;
+    uint64_t pending_request_id_val = g_sgxsd_enclave_last_pending_request_id_val;
+    sgxsd_pending_request_t *p_pending_requests_entry = &g_sgxsd_enclave_pending_requests[pending_request_id_val & pending_request_count_mask];
+    p_pending_requests_entry->id_val = pending_request_id_val;
+    memcpy(&p_pending_requests_entry->hkdf_prk, &p_pending_request->hkdf_prk, sizeof(p_pending_requests_entry->hkdf_prk));
+    _Static_assert(sizeof(p_pending_requests_entry->hkdf_prk) == sizeof(p_pending_request->hkdf_prk), "overflow");
+    p_pending_requests_entry->client_pubkey = p_pending_request->client_pubkey;
+
+    sgxsd_spin_unlock(&g_sgxsd_enclave_pending_requests_lock);
+
+    return sgxsd_enclave_encrypt_request_id(pending_request_id_val, p_pending_request_id);
+}
+
+sgx_status_t sgxsd_enclave_find_pending_request_val(uint64_t pending_request_id_val, bool remove, sgxsd_pending_request_t *p_pending_request) {
     sgxsd_spin_lock(&g_sgxsd_enclave_pending_requests_lock);
     uint64_t pending_request_count_mask = ((uint64_t){1} << g_sgxsd_enclave_pending_requests_table_order) - 1;
     sgxsd_pending_request_t *p_found_pending_request =
@@ -456,6 +599,9 @@ sgx_status_t sgxsd_enclave_get_pending_request(const sgxsd_pending_request_id_t
     sgx_status_t res;
     if (p_found_pending_request->id_val == pending_request_id_val) {
         *p_pending_request = *p_found_pending_request;
+        if (remove) {
+            memset_s(p_found_pending_request, sizeof(*p_found_pending_request), 0, sizeof(*p_found_pending_request));
+        }
         res = SGX_SUCCESS;
     } else {
         res = SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND;
@@ -465,35 +611,248 @@ sgx_status_t sgxsd_enclave_get_pending_request(const sgxsd_pending_request_id_t
     return res;
 }
 
-sgx_status_t sgxsd_enclave_remove_pending_request(const sgxsd_pending_request_id_t *p_pending_request_id, sgxsd_pending_request_t *p_pending_request) {
-    uint64_t pending_request_id_val = 0;
-    sgx_status_t decrypt_res =
-        sgxsd_aes_gcm_decrypt(&g_sgxsd_enclave_pending_request_id_key, /* p_key */
-                              &p_pending_request_id->data, sizeof(p_pending_request_id->data), /* p_src, src_len */
-                              &pending_request_id_val, /* p_dst */
-                              &p_pending_request_id->iv, /* p_iv */
-                              NULL, 0, /* p_aad, aad_len */
-                              &p_pending_request_id->mac /* p_in_mac */);
-    _Static_assert(sizeof(p_pending_request_id->data) == sizeof(pending_request_id_val), "pending_request_id_val overflow");
+//
+// sessions
+//
+
+static
+sgxsd_session_t *sgxsd_enclave_find_session_locked(uint64_t session_id_val) {
+    // scan the whole table so the lookup time doesn't depend on which slot the session is in
+    sgxsd_session_t *p_found_session = NULL;
+    for (size_t session_idx = 0; session_idx < SGXSD_ENCLAVE_MAX_SESSIONS; session_idx++) {
+        sgxsd_session_t *p_session = &g_sgxsd_enclave_sessions[session_idx];
+        if (p_session->id_val == session_id_val) {
+            p_found_session = p_session;
+        }
+    }
+    return p_found_session;
+}
+
+sgx_status_t sgxsd_enclave_add_session(sgxsd_pending_request_id_t *p_session_id, const sgxsd_pending_request_t *p_pending_request) {
+    sgxsd_spin_lock(&g_sgxsd_enclave_sessions_lock);
+
+    // take a free slot if there is one, otherwise evict the least recently used session
+    sgxsd_session_t *p_session = &g_sgxsd_enclave_sessions[0];
+    for (size_t session_idx = 1; session_idx < SGXSD_ENCLAVE_MAX_SESSIONS; session_idx++) {
+        sgxsd_session_t *p_candidate_session = &g_sgxsd_enclave_sessions[session_idx];
+        if (p_session->id_val != 0 &&
+            (p_candidate_session->id_val == 0 || p_candidate_session->last_used < p_session->last_used)) {
+            p_session = p_candidate_session;
+        }
+    }
+
+    g_sgxsd_enclave_last_session_id_val += 1;
+    uint64_t session_id_val = SGXSD_SESSION_ID_FLAG | g_sgxsd_enclave_last_session_id_val;
+    g_sgxsd_enclave_session_clock += 1;
+    *p_session = (sgxsd_session_t) {
+        .id_val    = session_id_val,
+        .last_used = g_sgxsd_enclave_session_clock,
+    };
+    memcpy(&p_session->hkdf_prk, &p_pending_request->hkdf_prk, sizeof(p_session->hkdf_prk));
+    _Static_assert(sizeof(p_session->hkdf_prk) == sizeof(p_pending_request->hkdf_prk), "overflow");
+    p_session->client_pubkey = p_pending_request->client_pubkey;
+
+    sgxsd_spin_unlock(&g_sgxsd_enclave_sessions_lock);
+
+    return sgxsd_enclave_encrypt_request_id(session_id_val, p_session_id);
+}
+
+sgx_status_t sgxsd_enclave_get_session_val(uint64_t session_id_val, sgxsd_pending_request_t *p_pending_request) {
+    sgxsd_spin_lock(&g_sgxsd_enclave_sessions_lock);
+
+    sgx_status_t res;
+    sgxsd_session_t *p_session = sgxsd_enclave_find_session_locked(session_id_val);
+    if (p_session != NULL && p_session->decrypt_failures >= SGXSD_SESSION_MAX_DECRYPT_FAILURES) {
+        res = SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED;
+    } else if (p_session != NULL) {
+        g_sgxsd_enclave_session_clock += 1;
+        p_session->last_used = g_sgxsd_enclave_session_clock;
+        *p_pending_request = (sgxsd_pending_request_t) {
+            .id_val        = session_id_val,
+            .hkdf_prk      = p_session->hkdf_prk,
+            .client_pubkey = p_session->client_pubkey,
+        };
+        res = SGX_SUCCESS;
+    } else {
+        res = SGXSD_ERROR_SESSION_NOT_FOUND;
+    }
+
+    sgxsd_spin_unlock(&g_sgxsd_enclave_sessions_lock);
+    return res;
+}
+
+// the first 8 bytes of the IV of each message sent in a session carry a little-endian message counter, which must
+// be nonzero and not seen before within the replay window
+sgx_status_t sgxsd_enclave_check_session_replay(uint64_t session_id_val, const sgxsd_aes_gcm_iv_t *p_msg_iv) {
+    uint64_t msg_counter = 0;
+    for (size_t byte_idx = 0; byte_idx < sizeof(msg_counter); byte_idx++) {
+        msg_counter |= (uint64_t) p_msg_iv->data[byte_idx] << (byte_idx * 8);
+    }
+    _Static_assert(sizeof(msg_counter) <= sizeof(p_msg_iv->data), "msg_counter overflow");
+    if (msg_counter == 0) {
+        return SGXSD_ERROR_SESSION_REPLAYED;
+    }
+
+    sgxsd_spin_lock(&g_sgxsd_enclave_sessions_lock);
+
+    sgx_status_t res;
+    sgxsd_session_t *p_session = sgxsd_enclave_find_session_locked(session_id_val);
+    if (p_session == NULL) {
+        res = SGXSD_ERROR_SESSION_NOT_FOUND;
+    } else if (msg_counter > p_session->replay_window_top) {
+        uint64_t shift = msg_counter - p_session->replay_window_top;
+        p_session->replay_window_bits = shift < SGXSD_SESSION_REPLAY_WINDOW_SIZE? p_session->replay_window_bits << shift : 0;
+        p_session->replay_window_bits |= 1;
+        p_session->replay_window_top = msg_counter;
+        res = SGX_SUCCESS;
+    } else {
+        uint64_t offset = p_session->replay_window_top - msg_counter;
+        if (offset >= SGXSD_SESSION_REPLAY_WINDOW_SIZE ||
+            (p_session->replay_window_bits & ((uint64_t){1} << offset)) != 0) {
+            res = SGXSD_ERROR_SESSION_REPLAYED;
+        } else {
+            p_session->replay_window_bits |= (uint64_t){1} << offset;
+            res = SGX_SUCCESS;
+        }
+    }
+
+    sgxsd_spin_unlock(&g_sgxsd_enclave_sessions_lock);
+    return res;
+}
+
+// a session whose messages keep failing to decrypt is being used to probe its key, so past a limit its key is erased
+// and the client has to negotiate a new session; the slot is kept until evicted so the client is told why. returns
+// whether the limit was reached.
+bool sgxsd_enclave_record_session_decrypt(uint64_t session_id_val, bool decrypted) {
+    sgxsd_spin_lock(&g_sgxsd_enclave_sessions_lock);
+
+    bool renegotiation_required = false;
+    sgxsd_session_t *p_session = sgxsd_enclave_find_session_locked(session_id_val);
+    if (p_session != NULL && decrypted) {
+        p_session->decrypt_failures = 0;
+    } else if (p_session != NULL) {
+        p_session->decrypt_failures += 1;
+        if (p_session->decrypt_failures >= SGXSD_SESSION_MAX_DECRYPT_FAILURES) {
+            memset_s(&p_session->hkdf_prk, sizeof(p_session->hkdf_prk), 0, sizeof(p_session->hkdf_prk));
+            renegotiation_required = true;
+        }
+    }
+
+    sgxsd_spin_unlock(&g_sgxsd_enclave_sessions_lock);
+    return renegotiation_required;
+}
+
+// count what a session was used for, once the call using it has succeeded
+void sgxsd_enclave_record_session_usage(uint64_t session_id_val, const sgxsd_session_usage_t *p_usage) {
+    sgxsd_spin_lock(&g_sgxsd_enclave_sessions_lock);
+
+    sgxsd_session_t *p_session = sgxsd_enclave_find_session_locked(session_id_val);
+    if (p_session != NULL) {
+        p_session->usage.request_count += p_usage->request_count;
+        p_session->usage.query_phone_count += p_usage->query_phone_count;
+        p_session->usage.ratelimit_phone_count += p_usage->ratelimit_phone_count;
+    }
+
+    sgxsd_spin_unlock(&g_sgxsd_enclave_sessions_lock);
+}
+
+// look up the keys for an encrypted request id, which refers either to an open session or to a pending request; a
+// pending request is single-use and is removed by the lookup unless peek is set, while a session stays open
+sgx_status_t sgxsd_enclave_find_request(const sgxsd_pending_request_id_t *p_pending_request_id, bool peek, sgxsd_pending_request_t *p_pending_request) {
+    uint64_t id_val = 0;
+    sgx_status_t decrypt_res = sgxsd_enclave_decrypt_request_id(p_pending_request_id, &id_val);
+    if (decrypt_res != SGX_SUCCESS) {
+        return decrypt_res;
+    }
+    if ((id_val & SGXSD_SESSION_ID_FLAG) != 0) {
+        return sgxsd_enclave_get_session_val(id_val, p_pending_request);
+    } else {
+        return sgxsd_enclave_find_pending_request_val(id_val, !peek, p_pending_request);
+    }
+}
+
+void sgxsd_enclave_derive_request_keys(sgxsd_pending_request_t *p_pending_request,
+                                       sgxsd_aes_gcm_key_t *p_client_key, sgxsd_aes_gcm_key_t *p_server_key);
+
+static
+sgx_status_t sgxsd_enclave_encrypt_session_summary(sgxsd_pending_request_t *p_pending_request,
+                                                   const sgxsd_pending_request_id_t *p_session_id,
+                                                   const sgxsd_session_usage_t *p_usage,
+                                                   sgxsd_session_summary_t *p_summary) {
+    // set one bit of IV to 1, as for replies, to prevent collision with request negotiation response IV=0
+    sgx_status_t rand_res = sgx_read_rand(p_summary->iv.data, sizeof(p_summary->iv.data));
+    if (rand_res != SGX_SUCCESS) {
+        return rand_res;
+    }
+    p_summary->iv.data[0] |= 1;
+
+    sgxsd_aes_gcm_key_t server_key;
+    sgxsd_enclave_derive_request_keys(p_pending_request, NULL, &server_key);
+
+    _Static_assert(sizeof(p_summary->data) == sizeof(*p_usage), "session summary overflow");
+    sgx_status_t encrypt_res =
+        sgxsd_aes_gcm_encrypt(&server_key, /* p_key */
+                              p_usage, sizeof(*p_usage), /* p_src, src_len */
+                              p_summary->data, /* p_dst */
+                              &p_summary->iv, /* p_iv */
+                              p_session_id, sizeof(*p_session_id), /* p_aad, aad_len */
+                              &p_summary->mac /* p_out_mac */);
+
+    // erase server sending AES-GCM key
+    memset_s(&server_key, sizeof(server_key), 0, sizeof(server_key));
+
+    if (encrypt_res != SGX_SUCCESS) {
+        memset(p_summary, 0, sizeof(*p_summary));
+    }
+    return encrypt_res;
+}
+
+sgx_status_t sgxsd_enclave_close_session(const sgxsd_pending_request_id_t *p_session_id, sgxsd_session_summary_t *p_summary) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+
+    // validate parameters
+    if (p_session_id == NULL || p_summary == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    memset(p_summary, 0, sizeof(*p_summary));
+
+    uint64_t session_id_val = 0;
+    sgx_status_t decrypt_res = sgxsd_enclave_decrypt_request_id(p_session_id, &session_id_val);
     if (decrypt_res != SGX_SUCCESS) {
         return decrypt_res;
     }
+    if ((session_id_val & SGXSD_SESSION_ID_FLAG) == 0) {
+        return SGXSD_ERROR_SESSION_NOT_FOUND;
+    }
 
-    sgxsd_spin_lock(&g_sgxsd_enclave_pending_requests_lock);
-    uint64_t pending_request_count_mask = ((uint64_t){1} << g_sgxsd_enclave_pending_requests_table_order) - 1;
-    sgxsd_pending_request_t *p_found_pending_request =
-            &g_sgxsd_enclave_pending_requests[pending_request_id_val & pending_request_count_mask];
+    sgxsd_spin_lock(&g_sgxsd_enclave_sessions_lock);
 
     sgx_status_t res;
-    if (p_found_pending_request->id_val == pending_request_id_val) {
-        *p_pending_request = *p_found_pending_request;
-        memset_s(p_found_pending_request, sizeof(*p_found_pending_request), 0, sizeof(*p_found_pending_request));
+    bool burned = false;
+    sgxsd_session_usage_t usage = { .request_count = 0 };
+    sgxsd_pending_request_t pending_request = { .id_val = 0 };
+    sgxsd_session_t *p_session = sgxsd_enclave_find_session_locked(session_id_val);
+    if (p_session != NULL) {
+        burned = p_session->decrypt_failures >= SGXSD_SESSION_MAX_DECRYPT_FAILURES;
+        usage = p_session->usage;
+        pending_request.hkdf_prk = p_session->hkdf_prk;
+        memset_s(p_session, sizeof(*p_session), 0, sizeof(*p_session));
         res = SGX_SUCCESS;
     } else {
-        res = SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND;
+        res = SGXSD_ERROR_SESSION_NOT_FOUND;
     }
 
-    sgxsd_spin_unlock(&g_sgxsd_enclave_pending_requests_lock);
+    sgxsd_spin_unlock(&g_sgxsd_enclave_sessions_lock);
+
+    // the key of a session closed for failing to decrypt has been erased, so its summary is left zeroed
+    if (res == SGX_SUCCESS && !burned) {
+        res = sgxsd_enclave_encrypt_session_summary(&pending_request, p_session_id, &usage, p_summary);
+    }
+
+    // erase HKDF PRK
+    memset_s(&pending_request, sizeof(pending_request), 0, sizeof(pending_request));
     return res;
 }
 
@@ -519,8 +878,20 @@ void sgxsd_enclave_derive_request_keys(sgxsd_pending_request_t *p_pending_reques
     memset_s(&hkdf_buf, sizeof(hkdf_buf), 0, sizeof(hkdf_buf));
 }
 
+static
+sgx_status_t sgxsd_enclave_negotiate(const sgxsd_request_negotiation_request_t *p_request,
+                                     sgxsd_request_negotiation_response_t *p_response, bool open_session);
 sgx_status_t sgxsd_enclave_negotiate_request(const sgxsd_request_negotiation_request_t *p_request,
                                              sgxsd_request_negotiation_response_t *p_response) {
+    return sgxsd_enclave_negotiate(p_request, p_response, false);
+}
+sgx_status_t sgxsd_enclave_open_session(const sgxsd_request_negotiation_request_t *p_request,
+                                        sgxsd_request_negotiation_response_t *p_response) {
+    return sgxsd_enclave_negotiate(p_request, p_response, true);
+}
+static
+sgx_status_t sgxsd_enclave_negotiate(const sgxsd_request_negotiation_request_t *p_request,
+                                     sgxsd_request_negotiation_response_t *p_response, bool open_session) {
     if (!g_sgxsd_enclave_node_initialized) {
         return SGX_ERROR_INVALID_STATE;
     }
@@ -580,6 +951,9 @@ sgx_status_t sgxsd_enclave_negotiate_request(const sgxsd_request_negotiation_req
     memset_s(&static_dh_key, sizeof(static_dh_key), 0, sizeof(static_dh_key));
     memset_s(&hkdf_salt, sizeof(hkdf_salt), 0, sizeof(hkdf_salt));
 
+    // keep the client public key to derive reply keys with
+    pending_request.client_pubkey = p_request->client_pubkey;
+
     // set IV to 0 for the request id encryption in response
     memset_s(p_response->encrypted_pending_request_id.iv.data,
              sizeof(p_response->encrypted_pending_request_id.iv.data),
@@ -590,10 +964,14 @@ sgx_status_t sgxsd_enclave_negotiate_request(const sgxsd_request_negotiation_req
     sgxsd_aes_gcm_key_t server_aes_gcm_key;
     sgxsd_enclave_derive_request_keys(&pending_request, NULL, &server_aes_gcm_key);
 
-    // add pending request and have get its assigned ID
+    // add pending request or open session and have get its assigned ID
     sgxsd_pending_request_id_t pending_request_id;
-    sgx_status_t add_pending_request_res =
-        sgxsd_enclave_add_pending_request(&pending_request_id, &pending_request);
+    sgx_status_t add_pending_request_res;
+    if (open_session) {
+        add_pending_request_res = sgxsd_enclave_add_session(&pending_request_id, &pending_request);
+    } else {
+        add_pending_request_res = sgxsd_enclave_add_pending_request(&pending_request_id, &pending_request);
+    }
     if (add_pending_request_res != SGX_SUCCESS) {
         // erase server sending AES-GCM key
         memset_s(&server_aes_gcm_key, sizeof(server_aes_gcm_key), 0, sizeof(server_aes_gcm_key));
@@ -627,9 +1005,78 @@ sgx_status_t sgxsd_enclave_negotiate_request(const sgxsd_request_negotiation_req
     return SGX_SUCCESS;
 }
 
-sgx_status_t sgxsd_enclave_server_reply_noerase(sgxsd_msg_buf_t reply_buf, const sgxsd_msg_from_t *p_from);
-sgx_status_t sgxsd_enclave_server_reply(sgxsd_msg_buf_t reply_buf, sgxsd_msg_from_t *p_from) {
-    sgx_status_t res = sgxsd_enclave_server_reply_noerase(reply_buf, p_from);
+sgx_status_t sgxsd_enclave_new_reply_batch(sgxsd_reply_batch_t *p_batch) {
+    if (p_batch == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+
+    // generate ephemeral ecdh keypair for the batch
+    sgxsd_curve25519_key_pair_t batch_keypair;
+    sgx_status_t generate_keypair_res = sgxsd_enclave_generate_curve25519_keypair(&batch_keypair);
+    if (generate_keypair_res == SGX_SUCCESS) {
+        memcpy(p_batch->privkey, batch_keypair.privkey.x, sizeof(p_batch->privkey));
+        _Static_assert(sizeof(p_batch->privkey) == sizeof(batch_keypair.privkey.x), "overflow");
+        p_batch->pubkey = batch_keypair.pubkey;
+    }
+
+    // erase ephemeral ecdh private key
+    memset_s(&batch_keypair, sizeof(batch_keypair), 0, sizeof(batch_keypair));
+    return generate_keypair_res;
+}
+
+static
+void sgxsd_enclave_derive_reply_key(const sgxsd_msg_from_t *p_from, const sgxsd_reply_batch_t *p_batch,
+                                    sgxsd_aes_gcm_key_t *p_reply_key) {
+    // derive batch ecdh shared secret
+    sgxsd_curve25519_public_key_t batch_dh_key;
+    curve25519_donna(batch_dh_key.x, p_batch->privkey, p_from->client_pubkey.x);
+
+    // derive HKDF PRK from salt = batch_pubkey and IKM = (batch_dh_secret || server_key)
+    sgxsd_sha256_hash_t hkdf_prk;
+    sgxsd_enclave_hmac_sha256(&hkdf_prk, 3, (sgxsd_sha256_buf_t[]) {
+        { p_batch->pubkey.x, sizeof(p_batch->pubkey.x) },
+        { batch_dh_key.x, sizeof(batch_dh_key.x) },
+        { p_from->server_key.data, sizeof(p_from->server_key.data) },
+    });
+    memset_s(&batch_dh_key, sizeof(batch_dh_key), 0, sizeof(batch_dh_key));
+
+    // HKDF T(1) = reply AES-GCM key
+    sgxsd_ra_hkdf_buf_t hkdf_buf = { .n = 0 };
+    sgxsd_enclave_ra_hkdf_round(&hkdf_prk, &hkdf_buf);
+    memmove(p_reply_key->data, hkdf_buf.t_n.data, sizeof(p_reply_key->data));
+    _Static_assert(sizeof(p_reply_key->data) <= sizeof(hkdf_buf.t_n.data), "AES key smaller than HKDF output size");
+
+    // erase HKDF state
+    memset_s(&hkdf_buf, sizeof(hkdf_buf), 0, sizeof(hkdf_buf));
+    memset_s(&hkdf_prk, sizeof(hkdf_prk), 0, sizeof(hkdf_prk));
+}
+
+sgx_status_t sgxsd_enclave_server_reply_noerase(sgxsd_msg_buf_t reply_buf, const sgxsd_msg_from_t *p_from,
+                                                const sgxsd_reply_batch_t *p_batch);
+sgx_status_t sgxsd_enclave_encrypt_reply(sgxsd_msg_buf_t reply_buf, const sgxsd_msg_from_t *p_from,
+                                         const sgxsd_reply_batch_t *p_batch, sgxsd_reply_header_t *p_reply_header);
+
+// keep an encrypted reply in a free slot of the retry buffer, returning false if there is none
+static
+bool sgxsd_enclave_stash_reply(const sgxsd_retry_reply_t *p_retry_reply) {
+    sgxsd_spin_lock(&g_sgxsd_enclave_retry_replies_lock);
+
+    bool stashed = false;
+    for (uint32_t retry_reply_idx = 0; retry_reply_idx < g_sgxsd_enclave_max_retry_replies && !stashed; retry_reply_idx++) {
+        sgxsd_retry_reply_t *p_slot = &g_sgxsd_enclave_retry_replies[retry_reply_idx];
+        if (!p_slot->valid) {
+            *p_slot = *p_retry_reply;
+            stashed = true;
+        }
+    }
+
+    sgxsd_spin_unlock(&g_sgxsd_enclave_retry_replies_lock);
+    return stashed;
+}
+
+sgx_status_t sgxsd_enclave_server_reply(sgxsd_msg_buf_t reply_buf, sgxsd_msg_from_t *p_from,
+                                        const sgxsd_reply_batch_t *p_batch) {
+    sgx_status_t res = sgxsd_enclave_server_reply_noerase(reply_buf, p_from, p_batch);
     if (reply_buf.data != NULL) {
         memset_s(reply_buf.data, reply_buf.size, 0, reply_buf.size);
     }
@@ -638,15 +1085,34 @@ sgx_status_t sgxsd_enclave_server_reply(sgxsd_msg_buf_t reply_buf, sgxsd_msg_fro
     }
     return res;
 }
-sgx_status_t sgxsd_enclave_server_reply_noerase(sgxsd_msg_buf_t reply_buf, const sgxsd_msg_from_t *p_from) {
+sgx_status_t sgxsd_enclave_server_seal_reply(sgxsd_msg_buf_t reply_buf, sgxsd_msg_from_t *p_from,
+                                             const sgxsd_reply_batch_t *p_batch, sgxsd_reply_header_t *p_reply_header) {
+    sgx_status_t res = sgxsd_enclave_encrypt_reply(reply_buf, p_from, p_batch, p_reply_header);
+    if (res != SGX_SUCCESS && reply_buf.data != NULL) {
+        memset_s(reply_buf.data, reply_buf.size, 0, reply_buf.size);
+    }
+    if (p_from != NULL) {
+        memset_s(p_from, sizeof(*p_from), 0, sizeof(*p_from));
+    }
+    return res;
+}
+sgx_status_t sgxsd_enclave_encrypt_reply(sgxsd_msg_buf_t reply_buf, const sgxsd_msg_from_t *p_from,
+                                         const sgxsd_reply_batch_t *p_batch, sgxsd_reply_header_t *p_reply_header) {
     if (p_from == NULL) {
         return SGX_ERROR_INVALID_PARAMETER;
     }
     if (!p_from->valid) {
         return SGX_ERROR_INVALID_STATE;
     }
+    if (p_reply_header == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
 
-    sgxsd_msg_header_t reply_header;
+    // a reply to a client that didn't negotiate batch reply keys has a zero batch public key in its header
+    sgxsd_reply_header_t reply_header = { .batch_pubkey = { .x = { 0 } } };
+    if (p_batch != NULL) {
+        reply_header.batch_pubkey = p_batch->pubkey;
+    }
     if (reply_buf.data == NULL && reply_buf.size != 0) {
         return SGX_ERROR_INVALID_PARAMETER;
     }
@@ -660,27 +1126,105 @@ sgx_status_t sgxsd_enclave_server_reply_noerase(sgxsd_msg_buf_t reply_buf, const
     // set one bit of IV to 1 for the reply message encryption, to prevent collision with request negotiation response IV=0
     reply_header.iv.data[0] |= 1;
 
-    // encrypt the reply message
-    sgx_status_t encrypt_res =
-        sgxsd_aes_gcm_encrypt(&p_from->server_key, /* p_key */
-                              reply_buf.data, reply_buf.size, /* p_src, src_len */
-                              reply_buf.data, /* p_dst */
-                              &reply_header.iv, /* p_iv */
-                              NULL, 0, /* p_aad, aad_len */
-                              &reply_header.mac /* p_out_mac */);
+    // encrypt the reply message under a key private to the batch, authenticating the batch public key, or under the
+    // request's server key as before if there is no batch
+    sgx_status_t encrypt_res;
+    if (p_batch != NULL) {
+        sgxsd_aes_gcm_key_t reply_key;
+        sgxsd_enclave_derive_reply_key(p_from, p_batch, &reply_key);
+        encrypt_res =
+            sgxsd_aes_gcm_encrypt(&reply_key, /* p_key */
+                                  reply_buf.data, reply_buf.size, /* p_src, src_len */
+                                  reply_buf.data, /* p_dst */
+                                  &reply_header.iv, /* p_iv */
+                                  &reply_header.batch_pubkey, sizeof(reply_header.batch_pubkey), /* p_aad, aad_len */
+                                  &reply_header.mac /* p_out_mac */);
+        memset_s(&reply_key, sizeof(reply_key), 0, sizeof(reply_key));
+    } else {
+        encrypt_res =
+            sgxsd_aes_gcm_encrypt(&p_from->server_key, /* p_key */
+                                  reply_buf.data, reply_buf.size, /* p_src, src_len */
+                                  reply_buf.data, /* p_dst */
+                                  &reply_header.iv, /* p_iv */
+                                  NULL, 0, /* p_aad, aad_len */
+                                  &reply_header.mac /* p_out_mac */);
+    }
     if (encrypt_res != SGX_SUCCESS) {
         return SGX_ERROR_UNEXPECTED;
     }
 
+    *p_reply_header = reply_header;
+    return SGX_SUCCESS;
+}
+sgx_status_t sgxsd_enclave_server_reply_noerase(sgxsd_msg_buf_t reply_buf, const sgxsd_msg_from_t *p_from,
+                                                const sgxsd_reply_batch_t *p_batch) {
+    sgxsd_reply_header_t reply_header;
+    sgx_status_t encrypt_reply_res = sgxsd_enclave_encrypt_reply(reply_buf, p_from, p_batch, &reply_header);
+    if (encrypt_reply_res != SGX_SUCCESS) {
+        return encrypt_reply_res;
+    }
+
     // send encrypted reply to the untrusted code
     sgx_status_t reply_res;
     sgx_status_t reply_ocall_res =
         sgxsd_ocall_reply(&reply_res, &reply_header, reply_buf.data, reply_buf.size, p_from->tag);
     if (reply_ocall_res == SGX_SUCCESS) {
         return reply_res;
-    } else {
+    }
+
+    // the host never saw a reply whose ocall failed, so it still holds the tag, and the reply is already encrypted to
+    // the client: keep it for the host to flush rather than let the client time out
+    sgxsd_retry_reply_t retry_reply = {
+        .valid  = true,
+        .header = reply_header,
+        .tag    = p_from->tag,
+        .size   = reply_buf.size,
+    };
+    if (reply_buf.size != 0) {
+        retry_reply.data = malloc(reply_buf.size);
+        if (retry_reply.data == NULL) {
+            return reply_ocall_res;
+        }
+        memcpy(retry_reply.data, reply_buf.data, reply_buf.size);
+    }
+    if (!sgxsd_enclave_stash_reply(&retry_reply)) {
+        free(retry_reply.data);
         return reply_ocall_res;
     }
+    return SGX_SUCCESS;
+}
+
+sgx_status_t sgxsd_enclave_flush_replies(uint64_t *p_pending_reply_count) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    if (p_pending_reply_count == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+
+    // take each reply out of the buffer while it is sent, so the lock isn't held over the ocall
+    uint64_t pending_reply_count = 0;
+    for (uint32_t retry_reply_idx = 0; retry_reply_idx < g_sgxsd_enclave_max_retry_replies; retry_reply_idx++) {
+        sgxsd_spin_lock(&g_sgxsd_enclave_retry_replies_lock);
+        sgxsd_retry_reply_t retry_reply = g_sgxsd_enclave_retry_replies[retry_reply_idx];
+        g_sgxsd_enclave_retry_replies[retry_reply_idx] = (sgxsd_retry_reply_t) { .valid = false };
+        sgxsd_spin_unlock(&g_sgxsd_enclave_retry_replies_lock);
+
+        if (!retry_reply.valid) {
+            continue;
+        }
+        sgx_status_t reply_res;
+        sgx_status_t reply_ocall_res =
+            sgxsd_ocall_reply(&reply_res, &retry_reply.header, retry_reply.data, retry_reply.size, retry_reply.tag);
+        if (reply_ocall_res != SGX_SUCCESS && sgxsd_enclave_stash_reply(&retry_reply)) {
+            pending_reply_count += 1;
+        } else {
+            free(retry_reply.data);
+        }
+    }
+
+    *p_pending_reply_count = pending_reply_count;
+    return SGX_SUCCESS;
 }
 
 sgx_status_t sgxsd_enclave_server_noreply(sgxsd_msg_from_t *p_from) {
@@ -701,6 +1245,27 @@ sgx_status_t sgxsd_enclave_server_noreply(sgxsd_msg_from_t *p_from) {
     }
 }
 
+bool sgxsd_enclave_acquire_server_config(const sgxsd_sha256_hash_t *p_config_hash) {
+    sgxsd_spin_lock(&g_sgxsd_enclave_config_hash_lock);
+    bool acquired = g_sgxsd_enclave_config_server_count == 0 ||
+        memcmp(g_sgxsd_enclave_config_hash.data, p_config_hash->data, sizeof(p_config_hash->data)) == 0;
+    if (acquired) {
+        g_sgxsd_enclave_config_hash = *p_config_hash;
+        g_sgxsd_enclave_config_server_count += 1;
+    }
+    sgxsd_spin_unlock(&g_sgxsd_enclave_config_hash_lock);
+    return acquired;
+}
+void sgxsd_enclave_release_server_config() {
+    // once the last server has stopped, quotes go back to attesting to the node config alone
+    sgxsd_spin_lock(&g_sgxsd_enclave_config_hash_lock);
+    g_sgxsd_enclave_config_server_count -= 1;
+    if (g_sgxsd_enclave_config_server_count == 0) {
+        g_sgxsd_enclave_config_hash = g_sgxsd_enclave_node_config_hash;
+    }
+    sgxsd_spin_unlock(&g_sgxsd_enclave_config_hash_lock);
+}
+
 sgx_status_t sgxsd_enclave_server_start_locked(const sgxsd_server_init_args_t *p_args, sgxsd_server_state_desc_t *p_state_desc);
 sgx_status_t sgxsd_enclave_server_start(const sgxsd_server_init_args_t *p_args, sgxsd_server_state_handle_t state_handle) {
     if (!g_sgxsd_enclave_node_initialized) {
@@ -722,11 +1287,29 @@ sgx_status_t sgxsd_enclave_server_start_locked(const sgxsd_server_init_args_t *p
     if (p_state_desc->valid) {
         return SGX_ERROR_INVALID_STATE;
     }
+
+    uint8_t config[SGXSD_ENCLAVE_MAX_SERVER_CONFIG_SIZE];
+    size_t config_len = 0;
+    sgx_status_t config_res = sgxsd_enclave_server_config(p_args, config, sizeof(config), &config_len);
+    if (config_res != SGX_SUCCESS) {
+        return config_res;
+    }
+    if (config_len > sizeof(config)) {
+        return SGX_ERROR_UNEXPECTED;
+    }
+    sgxsd_sha256_hash_t config_hash;
+    sgxsd_enclave_hash_server_config(&config_hash, config, config_len);
+
+    // a quote attests to one server config, so servers running at once must share it
+    if (!sgxsd_enclave_acquire_server_config(&config_hash)) {
+        return SGX_ERROR_INVALID_STATE;
+    }
     sgx_status_t init_res = sgxsd_enclave_server_init(p_args, &p_state_desc->p_state);
     if (init_res == SGX_SUCCESS) {
         p_state_desc->valid = true;
         return SGX_SUCCESS;
     } else {
+        sgxsd_enclave_release_server_config();
         return init_res;
     }
 }
@@ -758,7 +1341,7 @@ sgx_status_t sgxsd_enclave_server_call_locked(const sgxsd_server_handle_call_arg
                                               const sgxsd_msg_header_t *p_msg_header,
                                               uint8_t *msg_data, size_t msg_data_size,
                                               sgxsd_msg_tag_t msg_tag, sgxsd_server_state_desc_t *p_state_desc) {
-    if (!p_state_desc->valid) {
+    if (!p_state_desc->valid || p_state_desc->stopping) {
         return SGX_ERROR_INVALID_STATE;
     }
     if (p_msg_header == NULL) {
@@ -768,18 +1351,20 @@ sgx_status_t sgxsd_enclave_server_call_locked(const sgxsd_server_handle_call_arg
         return SGX_ERROR_INVALID_PARAMETER;
     }
 
-    // get pending request by ID
+    // get pending request or session by ID
     sgxsd_pending_request_t pending_request;
-    sgx_status_t remove_pending_request_res = sgxsd_enclave_remove_pending_request(&p_msg_header->pending_request_id, &pending_request);
-    if (remove_pending_request_res != SGX_SUCCESS) {
-        return remove_pending_request_res;
+    sgx_status_t find_request_res = sgxsd_enclave_find_request(&p_msg_header->pending_request_id, false, &pending_request);
+    if (find_request_res != SGX_SUCCESS) {
+        return find_request_res;
     }
+    uint64_t session_id_val = pending_request.id_val & SGXSD_SESSION_ID_FLAG? pending_request.id_val : 0;
 
     // derive server and client sending AES-GCM keys
     sgxsd_aes_gcm_key_t client_key;
     sgxsd_msg_from_t msg_from = {
         .valid = true,
         .tag = msg_tag,
+        .client_pubkey = pending_request.client_pubkey,
     };
     sgxsd_enclave_derive_request_keys(&pending_request, &client_key, &msg_from.server_key);
 
@@ -807,11 +1392,29 @@ sgx_status_t sgxsd_enclave_server_call_locked(const sgxsd_server_handle_call_arg
     if (decrypt_msg_res != SGX_SUCCESS) {
         // erase copy of plaintext ticket keys on stack
         memset_s(&msg_from, sizeof(msg_from), 0, sizeof(msg_from));
+        if (session_id_val != 0 && decrypt_msg_res == SGX_ERROR_MAC_MISMATCH &&
+            sgxsd_enclave_record_session_decrypt(session_id_val, false)) {
+            return SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED;
+        }
         if (decrypt_msg_res == SGX_ERROR_INVALID_PARAMETER) {
             return SGX_ERROR_UNEXPECTED;
         }
         return decrypt_msg_res;
     }
+    // only the session key is being probed, so a message that decrypts under it clears the failures, whatever its query
+    if (session_id_val != 0) {
+        sgxsd_enclave_record_session_decrypt(session_id_val, true);
+    }
+
+    // reject messages replayed within a session, now that the message counter in the IV is authenticated
+    if (session_id_val != 0) {
+        sgx_status_t replay_res = sgxsd_enclave_check_session_replay(session_id_val, &p_msg_header->iv);
+        if (replay_res != SGX_SUCCESS) {
+            memset_s(decrypted_msg.data, decrypted_msg.size, 0, decrypted_msg.size);
+            memset_s(&msg_from, sizeof(msg_from), 0, sizeof(msg_from));
+            return replay_res;
+        }
+    }
 
     // call the server_handle_call callback
     sgx_status_t server_call_res =
@@ -823,11 +1426,20 @@ sgx_status_t sgxsd_enclave_server_call_locked(const sgxsd_server_handle_call_arg
     // erase copy of plaintext ticket keys on stack
     memset_s(&msg_from, sizeof(msg_from), 0, sizeof(msg_from));
 
+    if (session_id_val != 0 && server_call_res == SGX_SUCCESS) {
+        sgxsd_enclave_record_session_usage(session_id_val, &(sgxsd_session_usage_t) {
+            .request_count     = 1,
+            .query_phone_count = p_args != NULL? p_args->query_phone_count : 0,
+        });
+    }
+
     return server_call_res;
 }
 
-sgx_status_t sgxsd_enclave_server_stop_locked(const sgxsd_server_terminate_args_t *p_args, sgxsd_server_state_desc_t *p_state_desc);
-sgx_status_t sgxsd_enclave_server_stop(const sgxsd_server_terminate_args_t *p_args, sgxsd_server_state_handle_t state_handle) {
+sgx_status_t sgxsd_enclave_server_stop_locked(const sgxsd_server_terminate_args_t *p_args, uint64_t *p_continuation_token,
+                                              sgxsd_server_state_desc_t *p_state_desc);
+sgx_status_t sgxsd_enclave_server_stop(const sgxsd_server_terminate_args_t *p_args, uint64_t *p_continuation_token,
+                                       sgxsd_server_state_handle_t state_handle) {
     if (!g_sgxsd_enclave_node_initialized) {
         return SGX_ERROR_INVALID_STATE;
     }
@@ -837,21 +1449,142 @@ sgx_status_t sgxsd_enclave_server_stop(const sgxsd_server_terminate_args_t *p_ar
     sgxsd_server_state_desc_t *p_state_desc = &g_sgxsd_enclave_server_states[state_handle];
     sgxsd_spin_lock(&p_state_desc->lock);
 
-    sgx_status_t res = sgxsd_enclave_server_stop_locked(p_args, p_state_desc);
+    sgx_status_t res = sgxsd_enclave_server_stop_locked(p_args, p_continuation_token, p_state_desc);
 
     sgxsd_spin_unlock(&p_state_desc->lock);
     return res;
 }
-sgx_status_t sgxsd_enclave_server_stop_locked(const sgxsd_server_terminate_args_t *p_args, sgxsd_server_state_desc_t *p_state_desc) {
+sgx_status_t sgxsd_enclave_server_stop_locked(const sgxsd_server_terminate_args_t *p_args, uint64_t *p_continuation_token,
+                                              sgxsd_server_state_desc_t *p_state_desc) {
     if (!p_state_desc->valid) {
         return SGX_ERROR_INVALID_STATE;
     }
+    if (p_continuation_token == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
 
     sgxsd_server_state_t *p_state = p_state_desc->p_state;
     // zero out old state to prevent replay / rewind
     memset_s(p_state_desc, sizeof(*p_state_desc), 0, sizeof(*p_state_desc));
 
-    return sgxsd_enclave_server_terminate(p_args, p_state);
+    *p_continuation_token = 0;
+    sgx_status_t terminate_res = sgxsd_enclave_server_terminate(p_args, p_continuation_token, &p_state);
+
+    // a server handing back the rest of its terminate to a later stop call stays started, but takes no more calls
+    if (terminate_res == SGX_SUCCESS && p_state != NULL) {
+        p_state_desc->p_state = p_state;
+        p_state_desc->stopping = true;
+        p_state_desc->valid = true;
+    } else {
+        sgxsd_enclave_release_server_config();
+    }
+    return terminate_res;
+}
+
+sgx_status_t sgxsd_enclave_server_get_metrics_locked(uint64_t now_ticks, sgxsd_server_metrics_t *p_metrics,
+                                                     sgxsd_server_state_desc_t *p_state_desc);
+sgx_status_t sgxsd_enclave_server_get_metrics(uint64_t now_ticks, sgxsd_server_metrics_t *p_metrics,
+                                              sgxsd_server_state_handle_t state_handle) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    if (state_handle >= g_sgxsd_enclave_max_servers) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    sgxsd_server_state_desc_t *p_state_desc = &g_sgxsd_enclave_server_states[state_handle];
+    sgxsd_spin_lock(&p_state_desc->lock);
+
+    sgx_status_t res = sgxsd_enclave_server_get_metrics_locked(now_ticks, p_metrics, p_state_desc);
+
+    sgxsd_spin_unlock(&p_state_desc->lock);
+    return res;
+}
+sgx_status_t sgxsd_enclave_server_get_metrics_locked(uint64_t now_ticks, sgxsd_server_metrics_t *p_metrics,
+                                                     sgxsd_server_state_desc_t *p_state_desc) {
+    if (!p_state_desc->valid) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    if (p_metrics == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    return sgxsd_enclave_server_metrics(now_ticks, p_metrics, p_state_desc->p_state);
+}
+
+sgx_status_t sgxsd_enclave_server_cancel_call_locked(sgxsd_msg_tag_t msg_tag, sgxsd_server_state_desc_t *p_state_desc);
+sgx_status_t sgxsd_enclave_server_cancel_call(sgxsd_msg_tag_t msg_tag, sgxsd_server_state_handle_t state_handle) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    if (state_handle >= g_sgxsd_enclave_max_servers) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    sgxsd_server_state_desc_t *p_state_desc = &g_sgxsd_enclave_server_states[state_handle];
+    sgxsd_spin_lock(&p_state_desc->lock);
+
+    sgx_status_t res = sgxsd_enclave_server_cancel_call_locked(msg_tag, p_state_desc);
+
+    sgxsd_spin_unlock(&p_state_desc->lock);
+    return res;
+}
+sgx_status_t sgxsd_enclave_server_cancel_call_locked(sgxsd_msg_tag_t msg_tag, sgxsd_server_state_desc_t *p_state_desc) {
+    // the calls of a server being stopped may already be part way through being looked up
+    if (!p_state_desc->valid || p_state_desc->stopping) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    return sgxsd_enclave_server_cancel(msg_tag, p_state_desc->p_state);
+}
+
+sgx_status_t sgxsd_enclave_commit_directory(const sgxsd_directory_commit_args_t *p_args) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    if (p_args == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    // the callback serializes commits itself, as it hashes the whole directory before swapping it in
+    return sgxsd_enclave_directory_commit(p_args);
+}
+
+sgx_status_t sgxsd_enclave_sample_directory(const sgxsd_directory_sample_args_t *p_args,
+                                            sgxsd_directory_sample_t *p_samples, size_t sample_count) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    if (p_args == NULL || p_samples == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    return sgxsd_enclave_directory_sample(p_args, p_samples, sample_count);
+}
+
+sgx_status_t sgxsd_enclave_set_session_denylist(const sgxsd_curve25519_public_key_t *p_session_ids,
+                                                size_t session_id_count) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    // an empty list clears the denylist
+    if (p_session_ids == NULL && session_id_count != 0) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    return sgxsd_enclave_session_denylist(p_session_ids, session_id_count);
+}
+
+sgx_status_t sgxsd_enclave_get_incident_record(uint8_t *p_record, size_t record_size, size_t *p_record_len) {
+    if (p_record == NULL || p_record_len == NULL) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    // available whether or not the node was initialized, as a halted enclave refuses everything else
+    return sgxsd_enclave_incident_record(p_record, record_size, p_record_len);
+}
+
+sgx_status_t sgxsd_enclave_run_benchmark(uint32_t profile, uint32_t iterations) {
+    if (!g_sgxsd_enclave_node_initialized) {
+        return SGX_ERROR_INVALID_STATE;
+    }
+    // bounded so that a benchmark can't hold an enclave thread of a production node for long
+    if (iterations > CDS_MAX_BENCHMARK_ITERATIONS) {
+        return SGX_ERROR_INVALID_PARAMETER;
+    }
+    return sgxsd_enclave_benchmark(profile, iterations);
 }
 
 sgx_status_t sgxsd_enclave_ratelimit_fingerprint_locked(uint8_t fingerprint_key[32],
@@ -905,10 +1638,11 @@ sgx_status_t sgxsd_enclave_ratelimit_fingerprint_locked(uint8_t fingerprint_key[
 
     // get pending request by ID
     sgxsd_pending_request_t pending_request;
-    sgx_status_t get_pending_request_res = sgxsd_enclave_get_pending_request(&p_msg_header->pending_request_id, &pending_request);
+    sgx_status_t get_pending_request_res = sgxsd_enclave_find_request(&p_msg_header->pending_request_id, true, &pending_request);
     if (get_pending_request_res != SGX_SUCCESS) {
         return get_pending_request_res;
     }
+    uint64_t session_id_val = pending_request.id_val & SGXSD_SESSION_ID_FLAG? pending_request.id_val : 0;
 
     // derive server and client sending AES-GCM keys
     sgxsd_aes_gcm_key_t client_key;
@@ -942,11 +1676,18 @@ sgx_status_t sgxsd_enclave_ratelimit_fingerprint_locked(uint8_t fingerprint_key[
     if (decrypt_msg_res != SGX_SUCCESS) {
         // erase copy of plaintext ticket keys on stack
         memset_s(&msg_from, sizeof(msg_from), 0, sizeof(msg_from));
+        if (session_id_val != 0 && decrypt_msg_res == SGX_ERROR_MAC_MISMATCH &&
+            sgxsd_enclave_record_session_decrypt(session_id_val, false)) {
+            return SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED;
+        }
         if (decrypt_msg_res == SGX_ERROR_INVALID_PARAMETER) {
             return SGX_ERROR_UNEXPECTED;
         }
         return decrypt_msg_res;
     }
+    if (session_id_val != 0) {
+        sgxsd_enclave_record_session_decrypt(session_id_val, true);
+    }
 
     // call the server_handle_call callback
     sgx_status_t res =
@@ -958,5 +1699,10 @@ sgx_status_t sgxsd_enclave_ratelimit_fingerprint_locked(uint8_t fingerprint_key[
     // erase copy of plaintext ticket keys on stack
     memset_s(&msg_from, sizeof(msg_from), 0, sizeof(msg_from));
 
+    if (session_id_val != 0 && res == SGX_SUCCESS) {
+        sgxsd_enclave_record_session_usage(session_id_val, &(sgxsd_session_usage_t) {
+            .ratelimit_phone_count = call_args != NULL? call_args->query_phone_count : 0,
+        });
+    }
     return res;
 }
\ No newline at end of file
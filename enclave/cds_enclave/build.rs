@@ -7,6 +7,7 @@
 
 use std::env;
 use std::error::Error;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
 
@@ -36,6 +37,16 @@ fn main() -> Result<(), Box<dyn Error>> {
         .generate()?
         .write_to_file("../include/cds-enclave-hash.h");
 
+    // The canonicalization rules blob handed to the enclave at startup must hash to this digest, which
+    // is thereby covered by the enclave signature.
+    println!("cargo:rerun-if-env-changed=CDS_CANONICALIZATION_RULES_SHA256");
+    let canonicalization_rules_digest = match env::var("CDS_CANONICALIZATION_RULES_SHA256") {
+        Ok(digest_hex) => format!("Some({:?})", parse_sha256_hex(&digest_hex)?),
+        Err(_) => "None".to_string(),
+    };
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").ok_or("OUT_DIR not set")?);
+    fs::write(out_dir.join("canonicalization_rules_digest.rs"), canonicalization_rules_digest)?;
+
     cc::Build::new()
         .compiler("clang")
         .file("c_src/cds-enclave-hash.rs.s")
@@ -46,6 +57,18 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn parse_sha256_hex(digest_hex: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let digest_hex = digest_hex.trim();
+    if digest_hex.len() != 64 || !digest_hex.is_ascii() {
+        return Err(format!("invalid SHA-256 digest: {}", digest_hex).into());
+    }
+    let mut digest = [0; 32];
+    for (digest_byte, byte_hex) in digest.iter_mut().zip(digest_hex.as_bytes().chunks(2)) {
+        *digest_byte = u8::from_str_radix(std::str::from_utf8(byte_hex)?, 16)?;
+    }
+    Ok(digest)
+}
+
 fn run_rustc(in_file: impl AsRef<Path>, out_file: impl AsRef<Path>) -> Result<(), ExitStatus> {
     println!("cargo:rerun-if-changed={}", in_file.as_ref().display());
     let rustc_path: PathBuf = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()).into();
@@ -10,6 +10,7 @@
 #[allow(dead_code, non_camel_case_types, non_upper_case_globals, non_snake_case, improper_ctypes, clippy::all, clippy::pedantic, clippy::integer_arithmetic)]
 mod bindgen_wrapper;
 pub mod hash_lookup;
+mod layout;
 #[cfg(not(any(test, feature = "test", feature = "benchmark")))]
 mod panic;
 pub mod sgxsd;
@@ -260,6 +260,16 @@ pub const CDS_HASH_LOOKUP_ERROR_LAST: u32 = 3;
 pub const CDS_HASH_LOOKUP_ERROR_RDRAND: u32 = 2;
 pub const CDS_HASH_LOOKUP_SUCCESS: u32 = 0;
 pub const CDS_MAX_HASH_TABLE_ORDER: u32 = 13;
+pub const CDS_DIRECTORY_METADATA_SIZE: u32 = 4;
+pub const CDS_USERNAME_HASH_SIZE: u32 = 32;
+pub const CDS_CHANGE_TOKEN_SIZE: u32 = 8;
+pub const CDS_DIRECTORY_GENERATION_SIZE: u32 = 8;
+pub const CDS_QUEUE_AGE_HISTOGRAM_BUCKETS: u32 = 16;
+pub const CDS_REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS: u32 = 6;
+pub const CDS_MAX_INCIDENT_RECORD_SIZE: u32 = 256;
+pub const CDS_MAX_DIRECTORY_SAMPLE_COUNT: u32 = 4096;
+pub const CDS_MAX_SESSION_DENYLIST_COUNT: u32 = 65536;
+pub const CDS_MAX_BENCHMARK_ITERATIONS: u32 = 1024;
 pub const CHAR_BIT: u32 = 8;
 pub const SCHAR_MAX: u32 = 127;
 pub const SCHAR_MIN: i32 = -128;
@@ -2624,6 +2634,57 @@ impl Default for sgxsd_msg_header {
 pub type sgxsd_msg_header_t = sgxsd_msg_header;
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_reply_header {
+    pub iv: sgxsd_aes_gcm_iv_t,
+    pub mac: sgxsd_aes_gcm_mac_t,
+    pub batch_pubkey: sgxsd_curve25519_public_key_t,
+}
+#[test]
+fn bindgen_test_layout_sgxsd_reply_header() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_reply_header>(),
+        60usize,
+        concat!("Size of: ", stringify!(sgxsd_reply_header))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_reply_header>(),
+        1usize,
+        concat!("Alignment of ", stringify!(sgxsd_reply_header))
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_header>())).iv as *const _ as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_header),
+            "::",
+            stringify!(iv)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_header>())).mac as *const _ as usize },
+        12usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_header),
+            "::",
+            stringify!(mac)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_header>())).batch_pubkey as *const _ as usize },
+        28usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_header),
+            "::",
+            stringify!(batch_pubkey)
+        )
+    );
+}
+pub type sgxsd_reply_header_t = sgxsd_reply_header;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct sgxsd_node_init_args {
     pub pending_requests_table_order: u8,
 }
@@ -2829,6 +2890,9 @@ extern "C" {
     ) -> sgxsd_status_t;
 }
 pub const SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND: sgxsd_status_code = 65537;
+pub const SGXSD_ERROR_SESSION_NOT_FOUND: sgxsd_status_code = 65538;
+pub const SGXSD_ERROR_SESSION_REPLAYED: sgxsd_status_code = 65539;
+pub const SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED: sgxsd_status_code = 65540;
 pub type sgxsd_status_code = u32;
 pub use self::sgxsd_status_code as sgxsd_status_code_t;
 pub type __m64 = [libc::c_longlong; 1usize];
@@ -3355,21 +3419,33 @@ impl Default for cds_encrypted_msg {
 }
 pub type cds_encrypted_msg_t = cds_encrypted_msg;
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct sgxsd_server_init_args {
     pub max_query_phones: u32,
     pub max_ratelimit_states: u32,
+    pub max_untrusted_read_bytes: u32,
+    pub fair_admission_phones: u32,
+    pub miss_rate_alert_ppm: u32,
+    pub require_sorted_query: u32,
+    pub canonicalization_rules: *mut u8,
+    pub canonicalization_rules_size: usize,
+    pub reply_scratch: *mut u8,
+    pub reply_scratch_size: usize,
+    pub reply_padding_size: u32,
+    pub dedup_query_phones: u32,
+    pub max_phones_per_request: u32,
+    pub interactive_reserve_phones: u32,
 }
 #[test]
 fn bindgen_test_layout_sgxsd_server_init_args() {
     assert_eq!(
         ::core::mem::size_of::<sgxsd_server_init_args>(),
-        8usize,
+        72usize,
         concat!("Size of: ", stringify!(sgxsd_server_init_args))
     );
     assert_eq!(
         ::core::mem::align_of::<sgxsd_server_init_args>(),
-        4usize,
+        8usize,
         concat!("Alignment of ", stringify!(sgxsd_server_init_args))
     );
     assert_eq!(
@@ -3398,6 +3474,166 @@ fn bindgen_test_layout_sgxsd_server_init_args() {
             stringify!(max_ratelimit_states)
         )
     );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).max_untrusted_read_bytes as *const _
+                as usize
+        },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(max_untrusted_read_bytes)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).fair_admission_phones as *const _
+                as usize
+        },
+        12usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(fair_admission_phones)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).miss_rate_alert_ppm as *const _
+                as usize
+        },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(miss_rate_alert_ppm)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).require_sorted_query as *const _
+                as usize
+        },
+        20usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(require_sorted_query)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).canonicalization_rules as *const _
+                as usize
+        },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(canonicalization_rules)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).canonicalization_rules_size as *const _
+                as usize
+        },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(canonicalization_rules_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).reply_scratch as *const _ as usize
+        },
+        40usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(reply_scratch)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).reply_scratch_size as *const _
+                as usize
+        },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(reply_scratch_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).reply_padding_size as *const _
+                as usize
+        },
+        56usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(reply_padding_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).dedup_query_phones as *const _
+                as usize
+        },
+        60usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(dedup_query_phones)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).max_phones_per_request as *const _
+                as usize
+        },
+        64usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(max_phones_per_request)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_init_args>())).interactive_reserve_phones as *const _
+                as usize
+        },
+        68usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_init_args),
+            "::",
+            stringify!(interactive_reserve_phones)
+        )
+    );
+}
+impl Default for sgxsd_server_init_args {
+    fn default() -> Self {
+        unsafe { ::core::mem::zeroed() }
+    }
 }
 pub type sgxsd_server_init_args_t = sgxsd_server_init_args;
 pub type cds_start_args_t = sgxsd_server_init_args;
@@ -3409,12 +3645,27 @@ pub struct sgxsd_server_handle_call_args {
     pub ratelimit_state_data: *mut u8,
     pub query: cds_encrypted_msg_t,
     pub query_commitment: [u8; 32usize],
+    pub admission_ticks: u64,
+    pub reply_flags: u32,
+    pub reply_reserved: u32,
+    pub reply_layout: u32,
+    pub query_more_fragments: u32,
+    pub query_fragment_token: u64,
+    pub since_change_token: u64,
+    pub protocol_version: u32,
+    pub query_key_size: u32,
+    pub query_chunk_commitments: *const u8,
+    pub query_chunk_size: u32,
+    pub query_chunk_commitment_count: u32,
+    pub request_id: u64,
+    pub reply_order: u32,
+    pub priority: u32,
 }
 #[test]
 fn bindgen_test_layout_sgxsd_server_handle_call_args() {
     assert_eq!(
         ::core::mem::size_of::<sgxsd_server_handle_call_args>(),
-        104usize,
+        184usize,
         concat!("Size of: ", stringify!(sgxsd_server_handle_call_args))
     );
     assert_eq!(
@@ -3499,80 +3750,807 @@ fn bindgen_test_layout_sgxsd_server_handle_call_args() {
             stringify!(query_commitment)
         )
     );
-}
-impl Default for sgxsd_server_handle_call_args {
-    fn default() -> Self {
-        unsafe { ::core::mem::zeroed() }
-    }
-}
-pub type sgxsd_server_handle_call_args_t = sgxsd_server_handle_call_args;
-pub type cds_call_args_t = sgxsd_server_handle_call_args;
-#[repr(C)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct sgxsd_server_terminate_args {
-    pub in_phones: *mut phone_t,
-    pub in_phone_count: usize,
-    pub in_uuids: *mut uuid_t,
-}
-#[test]
-fn bindgen_test_layout_sgxsd_server_terminate_args() {
     assert_eq!(
-        ::core::mem::size_of::<sgxsd_server_terminate_args>(),
-        24usize,
-        concat!("Size of: ", stringify!(sgxsd_server_terminate_args))
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).admission_ticks as *const _
+                as usize
+        },
+        104usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(admission_ticks)
+        )
     );
     assert_eq!(
-        ::core::mem::align_of::<sgxsd_server_terminate_args>(),
-        8usize,
-        concat!("Alignment of ", stringify!(sgxsd_server_terminate_args))
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).reply_flags as *const _
+                as usize
+        },
+        112usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(reply_flags)
+        )
     );
     assert_eq!(
         unsafe {
-            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_phones as *const _ as usize
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).reply_reserved as *const _
+                as usize
         },
-        0usize,
+        116usize,
         concat!(
             "Offset of field: ",
-            stringify!(sgxsd_server_terminate_args),
+            stringify!(sgxsd_server_handle_call_args),
             "::",
-            stringify!(in_phones)
+            stringify!(reply_reserved)
         )
     );
     assert_eq!(
         unsafe {
-            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_phone_count as *const _
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).reply_layout as *const _
                 as usize
         },
-        8usize,
+        120usize,
         concat!(
             "Offset of field: ",
-            stringify!(sgxsd_server_terminate_args),
+            stringify!(sgxsd_server_handle_call_args),
             "::",
-            stringify!(in_phone_count)
+            stringify!(reply_layout)
         )
     );
     assert_eq!(
         unsafe {
-            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_uuids as *const _ as usize
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).query_more_fragments as *const _
+                as usize
         },
-        16usize,
+        124usize,
         concat!(
             "Offset of field: ",
-            stringify!(sgxsd_server_terminate_args),
+            stringify!(sgxsd_server_handle_call_args),
             "::",
-            stringify!(in_uuids)
+            stringify!(query_more_fragments)
         )
     );
-}
-impl Default for sgxsd_server_terminate_args {
-    fn default() -> Self {
-        unsafe { ::core::mem::zeroed() }
-    }
-}
-pub type sgxsd_server_terminate_args_t = sgxsd_server_terminate_args;
-pub type cds_stop_args_t = sgxsd_server_terminate_args;
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).query_fragment_token as *const _
+                as usize
+        },
+        128usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(query_fragment_token)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).since_change_token as *const _
+                as usize
+        },
+        136usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(since_change_token)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).protocol_version as *const _
+                as usize
+        },
+        144usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(protocol_version)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).query_key_size as *const _
+                as usize
+        },
+        148usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(query_key_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).query_chunk_commitments as *const _
+                as usize
+        },
+        152usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(query_chunk_commitments)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).query_chunk_size as *const _
+                as usize
+        },
+        160usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(query_chunk_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).query_chunk_commitment_count as *const _
+                as usize
+        },
+        164usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(query_chunk_commitment_count)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).request_id as *const _
+                as usize
+        },
+        168usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(request_id)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).reply_order as *const _
+                as usize
+        },
+        176usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(reply_order)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_handle_call_args>())).priority as *const _
+                as usize
+        },
+        180usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_handle_call_args),
+            "::",
+            stringify!(priority)
+        )
+    );
+}
+impl Default for sgxsd_server_handle_call_args {
+    fn default() -> Self {
+        unsafe { ::core::mem::zeroed() }
+    }
+}
+pub type sgxsd_server_handle_call_args_t = sgxsd_server_handle_call_args;
+pub type cds_call_args_t = sgxsd_server_handle_call_args;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_server_terminate_args {
+    pub in_phones: *mut phone_t,
+    pub in_phone_count: usize,
+    pub in_uuids: *mut uuid_t,
+    pub in_metadata: *mut u8,
+    pub in_metadata_size: usize,
+    pub directory_epoch: u64,
+    pub max_chunks: u64,
+    pub continuation_token: u64,
+    pub in_allowlist_phones: *mut phone_t,
+    pub in_allowlist_phone_count: usize,
+    pub in_pni_uuids: *mut uuid_t,
+    pub in_username_hashes: *mut u8,
+    pub in_username_uuids: *mut uuid_t,
+    pub in_username_count: usize,
+    pub in_change_epochs: *mut u64,
+    pub directory_generation: u64,
+    pub in_flags: *mut u8,
+}
+#[test]
+fn bindgen_test_layout_sgxsd_server_terminate_args() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_server_terminate_args>(),
+        136usize,
+        concat!("Size of: ", stringify!(sgxsd_server_terminate_args))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_server_terminate_args>(),
+        8usize,
+        concat!("Alignment of ", stringify!(sgxsd_server_terminate_args))
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_phones as *const _ as usize
+        },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_phones)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_phone_count as *const _
+                as usize
+        },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_phone_count)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_uuids as *const _ as usize
+        },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_uuids)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_metadata as *const _ as usize
+        },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_metadata)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_metadata_size as *const _
+                as usize
+        },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_metadata_size)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).directory_epoch as *const _
+                as usize
+        },
+        40usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(directory_epoch)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).max_chunks as *const _
+                as usize
+        },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(max_chunks)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).continuation_token as *const _
+                as usize
+        },
+        56usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(continuation_token)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_allowlist_phones as *const _
+                as usize
+        },
+        64usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_allowlist_phones)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_allowlist_phone_count
+                as *const _ as usize
+        },
+        72usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_allowlist_phone_count)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_pni_uuids as *const _
+                as usize
+        },
+        80usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_pni_uuids)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_username_hashes as *const _
+                as usize
+        },
+        88usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_username_hashes)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_username_uuids as *const _
+                as usize
+        },
+        96usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_username_uuids)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_username_count as *const _
+                as usize
+        },
+        104usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_username_count)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_change_epochs as *const _
+                as usize
+        },
+        112usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_change_epochs)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).directory_generation as *const _
+                as usize
+        },
+        120usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(directory_generation)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_terminate_args>())).in_flags as *const _
+                as usize
+        },
+        128usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_terminate_args),
+            "::",
+            stringify!(in_flags)
+        )
+    );
+}
+impl Default for sgxsd_server_terminate_args {
+    fn default() -> Self {
+        unsafe { ::core::mem::zeroed() }
+    }
+}
+pub type sgxsd_server_terminate_args_t = sgxsd_server_terminate_args;
+pub type cds_stop_args_t = sgxsd_server_terminate_args;
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_directory_commit_args {
+    pub epoch: u64,
+    pub in_phones: *const phone_t,
+    pub in_phone_count: usize,
+    pub in_uuids: *const uuid_t,
+    pub expected_digest: [u8; 32usize],
+}
+#[test]
+fn bindgen_test_layout_sgxsd_directory_commit_args() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_directory_commit_args>(),
+        64usize,
+        concat!("Size of: ", stringify!(sgxsd_directory_commit_args))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_directory_commit_args>(),
+        8usize,
+        concat!("Alignment of ", stringify!(sgxsd_directory_commit_args))
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_commit_args>())).epoch as *const _ as usize
+        },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_commit_args),
+            "::",
+            stringify!(epoch)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_commit_args>())).in_phones as *const _ as usize
+        },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_commit_args),
+            "::",
+            stringify!(in_phones)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_commit_args>())).in_phone_count as *const _ as usize
+        },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_commit_args),
+            "::",
+            stringify!(in_phone_count)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_commit_args>())).in_uuids as *const _ as usize
+        },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_commit_args),
+            "::",
+            stringify!(in_uuids)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_commit_args>())).expected_digest as *const _ as usize
+        },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_commit_args),
+            "::",
+            stringify!(expected_digest)
+        )
+    );
+}
+impl Default for sgxsd_directory_commit_args {
+    fn default() -> Self {
+        unsafe { ::core::mem::zeroed() }
+    }
+}
+pub type sgxsd_directory_commit_args_t = sgxsd_directory_commit_args;
+pub type cds_directory_commit_args_t = sgxsd_directory_commit_args;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_directory_sample_args {
+    pub epoch: u64,
+    pub sample_key: [u8; 32usize],
+}
+#[test]
+fn bindgen_test_layout_sgxsd_directory_sample_args() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_directory_sample_args>(),
+        40usize,
+        concat!("Size of: ", stringify!(sgxsd_directory_sample_args))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_directory_sample_args>(),
+        8usize,
+        concat!("Alignment of ", stringify!(sgxsd_directory_sample_args))
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_sample_args>())).epoch as *const _ as usize
+        },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_sample_args),
+            "::",
+            stringify!(epoch)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_sample_args>())).sample_key as *const _ as usize
+        },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_sample_args),
+            "::",
+            stringify!(sample_key)
+        )
+    );
+}
+pub type sgxsd_directory_sample_args_t = sgxsd_directory_sample_args;
+pub type cds_directory_sample_args_t = sgxsd_directory_sample_args;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_directory_sample {
+    pub phone_hash: [u8; 32usize],
+    pub uuid: uuid_t,
+}
+#[test]
+fn bindgen_test_layout_sgxsd_directory_sample() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_directory_sample>(),
+        48usize,
+        concat!("Size of: ", stringify!(sgxsd_directory_sample))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_directory_sample>(),
+        8usize,
+        concat!("Alignment of ", stringify!(sgxsd_directory_sample))
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_sample>())).phone_hash as *const _ as usize
+        },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_sample),
+            "::",
+            stringify!(phone_hash)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_directory_sample>())).uuid as *const _ as usize
+        },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_directory_sample),
+            "::",
+            stringify!(uuid)
+        )
+    );
+}
+pub type sgxsd_directory_sample_t = sgxsd_directory_sample;
+pub type cds_directory_sample_t = sgxsd_directory_sample;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_server_metrics {
+    pub pending_request_count: u64,
+    pub max_queue_age_ticks: u64,
+    pub queue_age_histogram: [u64; 16usize],
+    pub lookup_miss_rate_ewma_ppm: u64,
+    pub lookup_miss_rate_alert: u64,
+    pub request_phone_count_histogram: [u64; 6usize],
+    pub held_phone_count: u64,
+}
+#[test]
+fn bindgen_test_layout_sgxsd_server_metrics() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_server_metrics>(),
+        216usize,
+        concat!("Size of: ", stringify!(sgxsd_server_metrics))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_server_metrics>(),
+        8usize,
+        concat!("Alignment of ", stringify!(sgxsd_server_metrics))
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_metrics>())).pending_request_count as *const _
+                as usize
+        },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_metrics),
+            "::",
+            stringify!(pending_request_count)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_metrics>())).max_queue_age_ticks as *const _
+                as usize
+        },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_metrics),
+            "::",
+            stringify!(max_queue_age_ticks)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_metrics>())).queue_age_histogram as *const _
+                as usize
+        },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_metrics),
+            "::",
+            stringify!(queue_age_histogram)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_metrics>())).lookup_miss_rate_ewma_ppm as *const _
+                as usize
+        },
+        144usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_metrics),
+            "::",
+            stringify!(lookup_miss_rate_ewma_ppm)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_metrics>())).lookup_miss_rate_alert as *const _
+                as usize
+        },
+        152usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_metrics),
+            "::",
+            stringify!(lookup_miss_rate_alert)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_metrics>())).request_phone_count_histogram as *const _
+                as usize
+        },
+        160usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_metrics),
+            "::",
+            stringify!(request_phone_count_histogram)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_server_metrics>())).held_phone_count as *const _
+                as usize
+        },
+        208usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_server_metrics),
+            "::",
+            stringify!(held_phone_count)
+        )
+    );
+}
+pub type sgxsd_server_metrics_t = sgxsd_server_metrics;
+pub type cds_server_metrics_t = sgxsd_server_metrics;
+pub const CDS_BENCHMARK_LOOKUP_CHUNK: cds_benchmark_profile = 1;
+pub const CDS_BENCHMARK_AES_GCM: cds_benchmark_profile = 2;
+pub const CDS_BENCHMARK_DIVREM: cds_benchmark_profile = 3;
+pub type cds_benchmark_profile = u32;
+pub use self::cds_benchmark_profile as cds_benchmark_profile_t;
+pub const CDS_REPLY_LAYOUT_UUID: cds_reply_layout = 0;
+pub const CDS_REPLY_LAYOUT_ACI_PNI: cds_reply_layout = 1;
+pub const CDS_REPLY_LAYOUT_UUID_CHANGES: cds_reply_layout = 2;
+pub const CDS_REPLY_LAYOUT_UUID_FLAGS: cds_reply_layout = 3;
+pub type cds_reply_layout = u32;
+pub use self::cds_reply_layout as cds_reply_layout_t;
+pub const CDS_REPLY_ORDER_QUERY: cds_reply_order = 0;
+pub type cds_reply_order = u32;
+pub use self::cds_reply_order as cds_reply_order_t;
+pub const CDS_REQUEST_PRIORITY_INTERACTIVE: cds_request_priority = 0;
+pub const CDS_REQUEST_PRIORITY_BULK: cds_request_priority = 1;
+pub type cds_request_priority = u32;
+pub use self::cds_request_priority as cds_request_priority_t;
+pub const CDS_PROTOCOL_VERSION_0: cds_protocol_version = 0;
+pub const CDS_PROTOCOL_VERSION_1: cds_protocol_version = 1;
+pub type cds_protocol_version = u32;
+pub use self::cds_protocol_version as cds_protocol_version_t;
+pub const CDS_REPLY_FLAG_BATCH_KEY: cds_reply_flag = 1;
+pub const CDS_REPLY_FLAG_QUERY_NONCE: cds_reply_flag = 2;
+pub type cds_reply_flag = u32;
+pub use self::cds_reply_flag as cds_reply_flag_t;
 pub const CDS_ERROR_INVALID_REQUEST_SIZE: cds_status_code = 131073;
 pub const CDS_ERROR_QUERY_COMMITMENT_MISMATCH: cds_status_code = 131074;
+pub const CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED: cds_status_code = 131075;
+pub const CDS_ERROR_INVALID_CANONICALIZATION_RULES: cds_status_code = 131076;
+pub const CDS_ERROR_DIRECTORY_DIGEST_MISMATCH: cds_status_code = 131077;
+pub const CDS_ERROR_DIRECTORY_EPOCH_MISMATCH: cds_status_code = 131078;
+pub const CDS_ERROR_ENCLAVE_HALTED: cds_status_code = 131079;
+pub const CDS_ERROR_FAIR_SHARE_EXCEEDED: cds_status_code = 131080;
+pub const CDS_ERROR_CANARY_MISMATCH: cds_status_code = 131081;
+pub const CDS_ERROR_BATCH_FULL: cds_status_code = 131082;
+pub const CDS_ERROR_SESSION_DENIED: cds_status_code = 131083;
+pub const CDS_ERROR_QUERY_NOT_SORTED: cds_status_code = 131084;
+pub const CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION: cds_status_code = 131085;
+pub const CDS_ERROR_DUPLICATE_QUERY_PHONE: cds_status_code = 131086;
+pub const CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED: cds_status_code = 131087;
+pub const CDS_ERROR_INVALID_QUERY_PHONE: cds_status_code = 131088;
+pub const CDS_ERROR_REQUEST_DEFERRED: cds_status_code = 131089;
 pub type cds_status_code = u32;
 pub use self::cds_status_code as cds_status_code_t;
 extern "C" {
@@ -3629,7 +4607,7 @@ extern "C" {
 extern "C" {
     pub fn sgxsd_ocall_reply(
         retval: *mut sgx_status_t,
-        reply_header: *const sgxsd_msg_header_t,
+        reply_header: *const sgxsd_reply_header_t,
         reply_data: *const u8,
         reply_data_size: usize,
         msg_tag: sgxsd_msg_tag_t,
@@ -7,6 +7,7 @@
 
 use alloc::vec::Vec;
 use core::ffi::c_void;
+use core::convert::TryInto;
 use core::mem::size_of;
 use core::num::NonZeroU128;
 use core::{u32, u8};
@@ -17,13 +18,24 @@ use sgx_ffi::util::memset_s;
 use super::bindgen_wrapper::{
     cds_hash_lookup, phone_t, uuid_t, HashSlot, HashSlotResult, CDS_HASH_LOOKUP_ERROR_HASH_TABLE_OVERFLOW,
     CDS_HASH_LOOKUP_ERROR_INVALID_PARAMETER, CDS_HASH_LOOKUP_ERROR_LAST, CDS_HASH_LOOKUP_ERROR_RDRAND, CDS_HASH_LOOKUP_SUCCESS,
-    CDS_MAX_HASH_TABLE_ORDER,
+    CDS_DIRECTORY_METADATA_SIZE, CDS_MAX_HASH_TABLE_ORDER, CDS_USERNAME_HASH_SIZE,
 };
 
 pub use super::bindgen_wrapper::{phone_t as Phone, uuid_t as Uuid};
 
 pub const MAX_HASH_TABLE_ORDER: u32 = CDS_MAX_HASH_TABLE_ORDER;
 pub const MAX_HASH_TABLE_SIZE: usize = 1 << MAX_HASH_TABLE_ORDER;
+pub const METADATA_SIZE: usize = CDS_DIRECTORY_METADATA_SIZE as usize;
+pub const CHANGE_EPOCH_SIZE: usize = size_of::<u64>();
+pub const FLAGS_SIZE: usize = size_of::<u8>();
+pub const USERNAME_HASH_SIZE: usize = CDS_USERNAME_HASH_SIZE as usize;
+
+pub type UsernameHash = [u8; USERNAME_HASH_SIZE];
+
+const WORD_LOOKUP_CHUNK_SIZE: usize = 1 << 16;
+const ALLOWLIST_LOOKUP_CHUNK_SIZE: usize = 1 << 16;
+const UUID_LOOKUP_CHUNK_SIZE: usize = 1 << 16;
+const USERNAME_LOOKUP_CHUNK_SIZE: usize = 1 << 16;
 
 #[no_mangle]
 pub extern "C" fn cds_c_hash_lookup(
@@ -99,6 +111,310 @@ pub unsafe fn hash_lookup(
     Err(SGX_ERROR_UNEXPECTED)
 }
 
+/// Looks up the metadata word of each query phone, writing `METADATA_SIZE` bytes per query phone to
+/// `query_phone_results`, or zeroes for phones not in the directory.
+///
+/// safety: in_phones and in_metadata must be valid for reads of phone_count entries
+pub unsafe fn metadata_lookup(
+    in_phones: *const u8,
+    in_metadata: *const u8,
+    phone_count: usize,
+    query_phones: &[phone_t],
+    query_phone_results: &mut [u8],
+) -> Result<(), SgxStatus>
+{
+    word_lookup(in_phones, in_metadata, METADATA_SIZE, phone_count, query_phones, query_phone_results)
+}
+
+/// Looks up the directory epoch at which the entry of each query phone last changed, writing `CHANGE_EPOCH_SIZE` bytes
+/// per query phone to `query_phone_results`, or zeroes for phones not in the directory.
+///
+/// safety: in_phones and in_change_epochs must be valid for reads of phone_count entries
+pub unsafe fn change_epoch_lookup(
+    in_phones: *const u8,
+    in_change_epochs: *const u8,
+    phone_count: usize,
+    query_phones: &[phone_t],
+    query_phone_results: &mut [u8],
+) -> Result<(), SgxStatus>
+{
+    word_lookup(in_phones, in_change_epochs, CHANGE_EPOCH_SIZE, phone_count, query_phones, query_phone_results)
+}
+
+/// Looks up the flags byte of the entry of each query phone, writing `FLAGS_SIZE` bytes per query phone to
+/// `query_phone_results`, or zeroes for phones not in the directory.
+///
+/// safety: in_phones and in_flags must be valid for reads of phone_count entries
+pub unsafe fn flags_lookup(
+    in_phones: *const u8,
+    in_flags: *const u8,
+    phone_count: usize,
+    query_phones: &[phone_t],
+    query_phone_results: &mut [u8],
+) -> Result<(), SgxStatus>
+{
+    word_lookup(in_phones, in_flags, FLAGS_SIZE, phone_count, query_phones, query_phone_results)
+}
+
+/// safety: in_phones and in_words must be valid for reads of phone_count entries of word_size bytes
+unsafe fn word_lookup(
+    in_phones: *const u8,
+    in_words: *const u8,
+    word_size: usize,
+    phone_count: usize,
+    query_phones: &[phone_t],
+    query_phone_results: &mut [u8],
+) -> Result<(), SgxStatus>
+{
+    if word_size == 0 || word_size > size_of::<u64>() || query_phone_results.len() != query_phones.len().saturating_mul(word_size) {
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    slice_memset_s(query_phone_results, 0);
+
+    // the hash lookup only returns uuids, so widen the words of each chunk of the directory into uuids, look the chunk
+    // up, and merge the results: a phone is found in at most one chunk, and is zero in all the others
+    let mut in_word_uuids: Vec<uuid_t> = new_vec_memset_s(WORD_LOOKUP_CHUNK_SIZE.min(phone_count), 0u8);
+    let mut chunk_results: Vec<u8> = new_vec_memset_s(query_phones.len().saturating_mul(size_of::<uuid_t>()), 0u8);
+    let mut res = Ok(());
+    for chunk_start in (0..phone_count).step_by(WORD_LOOKUP_CHUNK_SIZE) {
+        let chunk_len = phone_count.saturating_sub(chunk_start).min(WORD_LOOKUP_CHUNK_SIZE);
+        let in_words_chunk_ptr = in_words.add(chunk_start.saturating_mul(word_size));
+        let in_words_chunk = core::slice::from_raw_parts(in_words_chunk_ptr, chunk_len.saturating_mul(word_size));
+        for (in_word_uuid, word) in in_word_uuids.iter_mut().zip(in_words_chunk.chunks_exact(word_size)) {
+            let mut word_bytes = [0; size_of::<u64>()];
+            if let Some(word_bytes) = word_bytes.get_mut(..word_size) {
+                word_bytes.copy_from_slice(word);
+            }
+            in_word_uuid.data64 = [u64::from_ne_bytes(word_bytes), 0];
+        }
+
+        res = hash_lookup(
+            in_phones.add(chunk_start.saturating_mul(size_of::<phone_t>())),
+            in_word_uuids.as_ptr() as *const u8,
+            chunk_len,
+            query_phones,
+            &mut chunk_results,
+        );
+        if res.is_err() {
+            break;
+        }
+
+        let chunk_words = chunk_results.chunks_exact(size_of::<uuid_t>());
+        for (query_phone_result, chunk_result) in query_phone_results.chunks_exact_mut(word_size).zip(chunk_words) {
+            for (result_byte, chunk_result_byte) in query_phone_result.iter_mut().zip(chunk_result) {
+                *result_byte |= chunk_result_byte;
+            }
+        }
+    }
+
+    slice_memset_s(&mut chunk_results, 0);
+    res
+}
+
+/// Writes a mask of `size_of::<uuid_t>()` bytes for each query phone to `query_phone_results`, all ones for phones in
+/// the allowlist and zeroes for the rest, to be and-ed into their results without branching on them.
+///
+/// safety: in_allowlist_phones must be valid for reads of allowlist_phone_count entries
+pub unsafe fn allowlist_lookup(
+    in_allowlist_phones: *const u8,
+    allowlist_phone_count: usize,
+    query_phones: &[phone_t],
+    query_phone_results: &mut [u8],
+) -> Result<(), SgxStatus>
+{
+    if query_phone_results.len() != query_phones.len().saturating_mul(size_of::<uuid_t>()) {
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    slice_memset_s(query_phone_results, 0);
+
+    // look each chunk of the allowlist up as a directory whose uuids are all ones, and merge the results as for metadata
+    let in_allowlist_uuids: Vec<uuid_t> = new_vec_memset_s(ALLOWLIST_LOOKUP_CHUNK_SIZE.min(allowlist_phone_count), u8::MAX);
+    let mut chunk_results: Vec<u8> = new_vec_memset_s(query_phones.len().saturating_mul(size_of::<uuid_t>()), 0u8);
+    let mut res = Ok(());
+    for chunk_start in (0..allowlist_phone_count).step_by(ALLOWLIST_LOOKUP_CHUNK_SIZE) {
+        let chunk_len = allowlist_phone_count.saturating_sub(chunk_start).min(ALLOWLIST_LOOKUP_CHUNK_SIZE);
+        res = hash_lookup(
+            in_allowlist_phones.add(chunk_start.saturating_mul(size_of::<phone_t>())),
+            in_allowlist_uuids.as_ptr() as *const u8,
+            chunk_len,
+            query_phones,
+            &mut chunk_results,
+        );
+        if res.is_err() {
+            break;
+        }
+
+        for (result_byte, chunk_result_byte) in query_phone_results.iter_mut().zip(&chunk_results) {
+            *result_byte |= chunk_result_byte;
+        }
+    }
+
+    slice_memset_s(&mut chunk_results, 0);
+    res
+}
+
+/// Looks up each query uuid among the uuids of the directory, writing it back to `query_uuid_results` if it's there,
+/// or zeroes if it isn't.
+///
+/// safety: in_uuids must be valid for reads of uuid_count entries
+pub unsafe fn uuid_lookup(
+    in_uuids: *const u8,
+    uuid_count: usize,
+    query_uuids: &[uuid_t],
+    query_uuid_results: &mut [u8],
+) -> Result<(), SgxStatus>
+{
+    if query_uuid_results.len() != query_uuids.len().saturating_mul(size_of::<uuid_t>()) {
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    slice_memset_s(query_uuid_results, 0);
+
+    // the hash lookup is keyed by phones, so look each chunk of the directory up keyed by the first word of its uuids
+    // instead, merging the results as for metadata, then clear those that don't match the whole query uuid
+    let mut query_keys: Vec<phone_t> = query_uuids.iter().map(|query_uuid| query_uuid.data64[0]).collect();
+    let mut in_uuid_keys: Vec<phone_t> = new_vec_memset_s(UUID_LOOKUP_CHUNK_SIZE.min(uuid_count), 0u8);
+    let mut chunk_results: Vec<u8> = new_vec_memset_s(query_uuids.len().saturating_mul(size_of::<uuid_t>()), 0u8);
+    let mut res = Ok(());
+    for chunk_start in (0..uuid_count).step_by(UUID_LOOKUP_CHUNK_SIZE) {
+        let chunk_len = uuid_count.saturating_sub(chunk_start).min(UUID_LOOKUP_CHUNK_SIZE);
+        let in_uuids_chunk = in_uuids.add(chunk_start.saturating_mul(size_of::<uuid_t>()));
+        let in_uuids_chunk_slice = core::slice::from_raw_parts(in_uuids_chunk as *const uuid_t, chunk_len);
+        for (in_uuid_key, in_uuid) in in_uuid_keys.iter_mut().zip(in_uuids_chunk_slice) {
+            *in_uuid_key = in_uuid.data64[0];
+        }
+
+        res = hash_lookup(
+            in_uuid_keys.as_ptr() as *const u8,
+            in_uuids_chunk,
+            chunk_len,
+            &query_keys,
+            &mut chunk_results,
+        );
+        if res.is_err() {
+            break;
+        }
+
+        for (result_byte, chunk_result_byte) in query_uuid_results.iter_mut().zip(&chunk_results) {
+            *result_byte |= chunk_result_byte;
+        }
+    }
+
+    for (query_uuid_result, query_uuid) in query_uuid_results.chunks_exact_mut(size_of::<uuid_t>()).zip(query_uuids) {
+        let mut result_diff = 0;
+        for (result_word, query_word) in query_uuid_result.chunks_exact(size_of::<u64>()).zip(&query_uuid.data64) {
+            result_diff |= u64::from_ne_bytes(result_word.try_into().unwrap_or_default()) ^ query_word;
+        }
+        // all ones if the result matches the query uuid, without branching on it
+        let result_mask = ((result_diff | result_diff.wrapping_neg()) >> 63).wrapping_sub(1) as u8;
+        for result_byte in query_uuid_result.iter_mut() {
+            *result_byte &= result_mask;
+        }
+    }
+
+    slice_memset_s(&mut query_keys, 0);
+    slice_memset_s(&mut in_uuid_keys, 0);
+    slice_memset_s(&mut chunk_results, 0);
+    res
+}
+
+/// Looks up each query username hash in the username table, writing the uuid of its entry to
+/// `query_username_results` if it's there, or zeroes if it isn't.
+///
+/// safety: in_username_hashes and in_username_uuids must be valid for reads of username_count entries
+pub unsafe fn username_lookup(
+    in_username_hashes: *const u8,
+    in_username_uuids: *const u8,
+    username_count: usize,
+    query_username_hashes: &[UsernameHash],
+    query_username_results: &mut [u8],
+) -> Result<(), SgxStatus>
+{
+    let results_size = query_username_hashes.len().saturating_mul(size_of::<uuid_t>());
+    if query_username_results.len() != results_size {
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    slice_memset_s(query_username_results, 0);
+
+    // as for uuids, look each chunk of the table up keyed by the first word of its hashes, then look the rest of the
+    // hashes up the same way, widened into uuids, to clear the results whose whole hash doesn't match the query hash
+    let mut query_words: Vec<[u64; 4]> = query_username_hashes.iter().map(username_hash_words).collect();
+    let mut query_keys: Vec<phone_t> = query_words.iter().map(|query_word| query_word[0]).collect();
+    let chunk_capacity = USERNAME_LOOKUP_CHUNK_SIZE.min(username_count);
+    let mut in_keys: Vec<phone_t> = new_vec_memset_s(chunk_capacity, 0u8);
+    let mut in_tails: [Vec<uuid_t>; 2] = [new_vec_memset_s(chunk_capacity, 0u8), new_vec_memset_s(chunk_capacity, 0u8)];
+    let mut tail_results: [Vec<u8>; 2] = [new_vec_memset_s(results_size, 0u8), new_vec_memset_s(results_size, 0u8)];
+    let mut chunk_results: Vec<u8> = new_vec_memset_s(results_size, 0u8);
+    let mut res = Ok(());
+    for chunk_start in (0..username_count).step_by(USERNAME_LOOKUP_CHUNK_SIZE) {
+        let chunk_len = username_count.saturating_sub(chunk_start).min(USERNAME_LOOKUP_CHUNK_SIZE);
+        let in_hashes_chunk = in_username_hashes.add(chunk_start.saturating_mul(USERNAME_HASH_SIZE)) as *const UsernameHash;
+        let in_hashes_chunk_slice = core::slice::from_raw_parts(in_hashes_chunk, chunk_len);
+        let [in_tails_0, in_tails_1] = &mut in_tails;
+        let in_entries = in_keys.iter_mut().zip(in_tails_0.iter_mut()).zip(in_tails_1.iter_mut());
+        for (((in_key, in_tail_0), in_tail_1), in_hash) in in_entries.zip(in_hashes_chunk_slice) {
+            let in_words = username_hash_words(in_hash);
+            *in_key = in_words[0];
+            in_tail_0.data64 = [in_words[1], in_words[2]];
+            in_tail_1.data64 = [in_words[3], 0];
+        }
+
+        let in_uuids_chunk = in_username_uuids.add(chunk_start.saturating_mul(size_of::<uuid_t>()));
+        let [tail_results_0, tail_results_1] = &mut tail_results;
+        let lookups = [
+            (in_uuids_chunk, &mut *query_username_results),
+            (in_tails_0.as_ptr() as *const u8, &mut tail_results_0[..]),
+            (in_tails_1.as_ptr() as *const u8, &mut tail_results_1[..]),
+        ];
+        for (in_values, results) in lookups {
+            res = hash_lookup(in_keys.as_ptr() as *const u8, in_values, chunk_len, &query_keys, &mut chunk_results);
+            if res.is_err() {
+                break;
+            }
+            for (result_byte, chunk_result_byte) in results.iter_mut().zip(&chunk_results) {
+                *result_byte |= chunk_result_byte;
+            }
+        }
+        if res.is_err() {
+            break;
+        }
+    }
+
+    let [tail_results_0, tail_results_1] = &tail_results;
+    let query_tail_results = tail_results_0.chunks_exact(size_of::<uuid_t>()).zip(tail_results_1.chunks_exact(size_of::<uuid_t>()));
+    let query_results = query_username_results.chunks_exact_mut(size_of::<uuid_t>()).zip(query_tail_results);
+    for ((query_username_result, (tail_result_0, tail_result_1)), query_hash_words) in query_results.zip(&query_words) {
+        let mut result_diff = 0;
+        let tail_result_words = tail_result_0.chunks_exact(size_of::<u64>()).chain(tail_result_1.chunks_exact(size_of::<u64>()));
+        let query_tail_words = [query_hash_words[1], query_hash_words[2], query_hash_words[3], 0];
+        for (tail_result_word, query_tail_word) in tail_result_words.zip(&query_tail_words) {
+            result_diff |= u64::from_ne_bytes(tail_result_word.try_into().unwrap_or_default()) ^ query_tail_word;
+        }
+        // all ones if the rest of the hash matches the query hash, without branching on it
+        let result_mask = ((result_diff | result_diff.wrapping_neg()) >> 63).wrapping_sub(1) as u8;
+        for result_byte in query_username_result.iter_mut() {
+            *result_byte &= result_mask;
+        }
+    }
+
+    slice_memset_s(&mut query_words, 0);
+    slice_memset_s(&mut query_keys, 0);
+    slice_memset_s(&mut in_keys, 0);
+    for (in_tails_n, tail_results_n) in in_tails.iter_mut().zip(tail_results.iter_mut()) {
+        slice_memset_s(in_tails_n, 0);
+        slice_memset_s(tail_results_n, 0);
+    }
+    slice_memset_s(&mut chunk_results, 0);
+    res
+}
+
+fn username_hash_words(username_hash: &UsernameHash) -> [u64; 4] {
+    let mut words = [0; 4];
+    for (word, word_bytes) in words.iter_mut().zip(username_hash.chunks_exact(size_of::<u64>())) {
+        *word = u64::from_ne_bytes(word_bytes.try_into().unwrap_or_default());
+    }
+    words
+}
+
 //
 // Uuid impls
 //
@@ -241,6 +557,175 @@ mod test {
         static ref TEST_DATA: TestData = TestData::new([0; 32]);
     }
 
+    #[test]
+    fn cds_metadata_lookup_across_chunks() {
+        let in_phone_count = WORD_LOOKUP_CHUNK_SIZE + 3;
+        let in_phones = &TEST_DATA.in_phones[..in_phone_count];
+        let in_metadata: Vec<u32> = (0..in_phone_count as u32).map(|index| index.wrapping_mul(0x9e37_79b9) | 1).collect();
+
+        let query_phones = vec![in_phones[0], in_phones[WORD_LOOKUP_CHUNK_SIZE - 1], 1, in_phones[WORD_LOOKUP_CHUNK_SIZE + 2]];
+        let expected_results: Vec<u8> = vec![in_metadata[0], in_metadata[WORD_LOOKUP_CHUNK_SIZE - 1], 0, in_metadata[WORD_LOOKUP_CHUNK_SIZE + 2]]
+            .into_iter()
+            .flat_map(|metadata| metadata.to_ne_bytes().to_vec())
+            .collect();
+
+        let mut query_phone_results = vec![0xff; query_phones.len() * METADATA_SIZE];
+        unsafe {
+            metadata_lookup(
+                in_phones.as_ptr() as *const u8,
+                in_metadata.as_ptr() as *const u8,
+                in_phone_count,
+                &query_phones,
+                &mut query_phone_results,
+            )
+            .unwrap();
+        }
+        assert_eq!(query_phone_results, expected_results);
+    }
+
+    #[test]
+    fn cds_change_epoch_lookup_across_chunks() {
+        let in_phone_count = WORD_LOOKUP_CHUNK_SIZE + 3;
+        let in_phones = &TEST_DATA.in_phones[..in_phone_count];
+        let in_change_epochs: Vec<u64> = (0..in_phone_count as u64).map(|index| index.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1).collect();
+
+        let query_phones = vec![in_phones[1], 1, in_phones[WORD_LOOKUP_CHUNK_SIZE + 1]];
+        let expected_results: Vec<u8> = vec![in_change_epochs[1], 0, in_change_epochs[WORD_LOOKUP_CHUNK_SIZE + 1]]
+            .into_iter()
+            .flat_map(|change_epoch| change_epoch.to_ne_bytes().to_vec())
+            .collect();
+
+        let mut query_phone_results = vec![0xff; query_phones.len() * CHANGE_EPOCH_SIZE];
+        unsafe {
+            change_epoch_lookup(
+                in_phones.as_ptr() as *const u8,
+                in_change_epochs.as_ptr() as *const u8,
+                in_phone_count,
+                &query_phones,
+                &mut query_phone_results,
+            )
+            .unwrap();
+        }
+        assert_eq!(query_phone_results, expected_results);
+    }
+
+    #[test]
+    fn cds_flags_lookup_across_chunks() {
+        let in_phone_count = WORD_LOOKUP_CHUNK_SIZE + 3;
+        let in_phones = &TEST_DATA.in_phones[..in_phone_count];
+        let in_flags: Vec<u8> = (0..in_phone_count).map(|index| (index as u8).wrapping_mul(0x9d) | 1).collect();
+
+        let query_phones = vec![in_phones[2], 1, in_phones[WORD_LOOKUP_CHUNK_SIZE], in_phones[WORD_LOOKUP_CHUNK_SIZE + 2]];
+        let expected_results = vec![in_flags[2], 0, in_flags[WORD_LOOKUP_CHUNK_SIZE], in_flags[WORD_LOOKUP_CHUNK_SIZE + 2]];
+
+        let mut query_phone_results = vec![0xff; query_phones.len() * FLAGS_SIZE];
+        unsafe {
+            flags_lookup(
+                in_phones.as_ptr() as *const u8,
+                in_flags.as_ptr(),
+                in_phone_count,
+                &query_phones,
+                &mut query_phone_results,
+            )
+            .unwrap();
+        }
+        assert_eq!(query_phone_results, expected_results);
+    }
+
+    #[test]
+    fn cds_allowlist_lookup_across_chunks() {
+        let allowlist_phone_count = ALLOWLIST_LOOKUP_CHUNK_SIZE + 3;
+        let allowlist_phones = &TEST_DATA.in_phones[..allowlist_phone_count];
+
+        let query_phones = vec![
+            allowlist_phones[0],
+            1,
+            allowlist_phones[ALLOWLIST_LOOKUP_CHUNK_SIZE + 2],
+            TEST_DATA.in_phones[allowlist_phone_count],
+        ];
+        let expected_results: Vec<u8> = vec![u8::MAX, 0, u8::MAX, 0]
+            .into_iter()
+            .flat_map(|mask| vec![mask; size_of::<Uuid>()])
+            .collect();
+
+        let mut query_phone_results = vec![0x5a; query_phones.len() * size_of::<Uuid>()];
+        unsafe {
+            allowlist_lookup(
+                allowlist_phones.as_ptr() as *const u8,
+                allowlist_phone_count,
+                &query_phones,
+                &mut query_phone_results,
+            )
+            .unwrap();
+        }
+        assert_eq!(query_phone_results, expected_results);
+    }
+
+    #[test]
+    fn cds_uuid_lookup_across_chunks() {
+        let in_uuid_count = UUID_LOOKUP_CHUNK_SIZE + 3;
+        let in_uuids = &TEST_DATA.in_uuids[..in_uuid_count];
+
+        // a uuid sharing only its first word with one in the directory isn't found
+        let mut partial_uuid = in_uuids[1];
+        partial_uuid.data64[1] ^= 1;
+        let query_uuids = vec![in_uuids[0], partial_uuid, in_uuids[UUID_LOOKUP_CHUNK_SIZE + 2], TEST_DATA.in_uuids[in_uuid_count]];
+        let expected_results: Vec<u8> = vec![in_uuids[0], Uuid::default(), in_uuids[UUID_LOOKUP_CHUNK_SIZE + 2], Uuid::default()]
+            .into_iter()
+            .flat_map(|uuid| uuid.data64.iter().flat_map(|word| word.to_ne_bytes().to_vec()).collect::<Vec<u8>>())
+            .collect();
+
+        let mut query_uuid_results = vec![0x5a; query_uuids.len() * size_of::<Uuid>()];
+        unsafe {
+            uuid_lookup(in_uuids.as_ptr() as *const u8, in_uuid_count, &query_uuids, &mut query_uuid_results).unwrap();
+        }
+        assert_eq!(query_uuid_results, expected_results);
+    }
+
+    #[test]
+    fn cds_username_lookup_across_chunks() {
+        let in_username_count = USERNAME_LOOKUP_CHUNK_SIZE + 3;
+        let in_uuids = &TEST_DATA.in_uuids[..in_username_count];
+        let in_username_hashes: Vec<UsernameHash> = TEST_DATA.in_uuids[in_username_count..]
+            .chunks_exact(2)
+            .take(in_username_count)
+            .map(|uuids| {
+                let mut username_hash = [0; USERNAME_HASH_SIZE];
+                for (hash_word, word) in username_hash.chunks_exact_mut(8).zip(uuids.iter().flat_map(|uuid| uuid.data64.iter())) {
+                    hash_word.copy_from_slice(&word.to_ne_bytes());
+                }
+                username_hash
+            })
+            .collect();
+
+        // a hash sharing only its first words with one in the table isn't found
+        let mut partial_username_hash = in_username_hashes[1];
+        partial_username_hash[USERNAME_HASH_SIZE - 1] ^= 1;
+        let query_username_hashes = vec![
+            in_username_hashes[0],
+            partial_username_hash,
+            in_username_hashes[USERNAME_LOOKUP_CHUNK_SIZE + 2],
+            [0x5a; USERNAME_HASH_SIZE],
+        ];
+        let expected_results: Vec<u8> = vec![in_uuids[0], Uuid::default(), in_uuids[USERNAME_LOOKUP_CHUNK_SIZE + 2], Uuid::default()]
+            .into_iter()
+            .flat_map(|uuid| uuid.data64.iter().flat_map(|word| word.to_ne_bytes().to_vec()).collect::<Vec<u8>>())
+            .collect();
+
+        let mut query_username_results = vec![0x5a; query_username_hashes.len() * size_of::<Uuid>()];
+        unsafe {
+            username_lookup(
+                in_username_hashes.as_ptr() as *const u8,
+                in_uuids.as_ptr() as *const u8,
+                in_username_count,
+                &query_username_hashes,
+                &mut query_username_results,
+            )
+            .unwrap();
+        }
+        assert_eq!(query_username_results, expected_results);
+    }
+
     #[test]
     fn cds_hash_lookup_batch_too_large() {
         assert_eq!(
@@ -0,0 +1,97 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Compile-time layout of the structs passed across the enclave boundary.
+//!
+//! These are the offsets the untrusted host and the EDL marshalling code rely on; they must only
+//! change together with `cds.h` and the host SDK.
+
+use super::hash_lookup::Uuid;
+use super::sgxsd::{CallArgs, EncryptedMessage, StartArgs, StopArgs};
+
+assert_ffi_layout!(Uuid {
+    size: 16,
+    align: 8,
+    data64: 0
+});
+
+assert_ffi_layout!(EncryptedMessage {
+    size: 40,
+    align: 8,
+    iv: 0,
+    mac: 12,
+    size: 28,
+    data: 32,
+});
+
+assert_ffi_layout!(StartArgs {
+    size: 72,
+    align: 8,
+    max_query_phones: 0,
+    max_ratelimit_states: 4,
+    max_untrusted_read_bytes: 8,
+    fair_admission_phones: 12,
+    miss_rate_alert_ppm: 16,
+    require_sorted_query: 20,
+    canonicalization_rules: 24,
+    canonicalization_rules_size: 32,
+    reply_scratch: 40,
+    reply_scratch_size: 48,
+    reply_padding_size: 56,
+    dedup_query_phones: 60,
+    max_phones_per_request: 64,
+    interactive_reserve_phones: 68,
+});
+
+// The ratelimit_state_* fields form the header of the untrusted ratelimit state blob.
+assert_ffi_layout!(CallArgs {
+    size: 184,
+    align: 8,
+    query_phone_count: 0,
+    ratelimit_state_size: 4,
+    ratelimit_state_uuid: 8,
+    ratelimit_state_data: 24,
+    query: 32,
+    query_commitment: 72,
+    admission_ticks: 104,
+    reply_flags: 112,
+    reply_reserved: 116,
+    reply_layout: 120,
+    query_more_fragments: 124,
+    query_fragment_token: 128,
+    since_change_token: 136,
+    protocol_version: 144,
+    query_key_size: 148,
+    query_chunk_commitments: 152,
+    query_chunk_size: 160,
+    query_chunk_commitment_count: 164,
+    request_id: 168,
+    reply_order: 176,
+    priority: 180,
+});
+
+assert_ffi_layout!(StopArgs {
+    size: 136,
+    align: 8,
+    in_phones: 0,
+    in_phone_count: 8,
+    in_uuids: 16,
+    in_metadata: 24,
+    in_metadata_size: 32,
+    directory_epoch: 40,
+    max_chunks: 48,
+    continuation_token: 56,
+    in_allowlist_phones: 64,
+    in_allowlist_phone_count: 72,
+    in_pni_uuids: 80,
+    in_username_hashes: 88,
+    in_username_uuids: 96,
+    in_username_count: 104,
+    in_change_epochs: 112,
+    directory_generation: 120,
+    in_flags: 128,
+});
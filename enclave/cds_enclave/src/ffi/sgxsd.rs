@@ -6,7 +6,12 @@
 //
 
 pub use super::bindgen_wrapper::{
-    cds_call_args_t as CallArgs, cds_encrypted_msg_t as EncryptedMessage, cds_start_args_t as StartArgs,
-    cds_stop_args_t as StopArgs, CDS_ERROR_INVALID_REQUEST_SIZE,
-    CDS_ERROR_QUERY_COMMITMENT_MISMATCH, SGXSD_AES_GCM_KEY_SIZE, SGXSD_AES_GCM_MAC_SIZE,
+    cds_benchmark_profile_t as BenchmarkProfileId, cds_call_args_t as CallArgs, cds_directory_commit_args_t as DirectoryCommitArgs, cds_directory_sample_args_t as DirectorySampleArgs,
+    cds_directory_sample_t as DirectorySample, cds_encrypted_msg_t as EncryptedMessage, cds_protocol_version_t as ProtocolVersionId, cds_reply_layout_t as ReplyLayoutId, cds_reply_order_t as ReplyOrderId,
+    cds_request_priority_t as RequestPriorityId, cds_server_metrics_t as ServerMetrics, cds_start_args_t as StartArgs, cds_stop_args_t as StopArgs, CDS_BENCHMARK_AES_GCM, CDS_BENCHMARK_DIVREM, CDS_BENCHMARK_LOOKUP_CHUNK, CDS_CHANGE_TOKEN_SIZE, CDS_DIRECTORY_GENERATION_SIZE, CDS_ERROR_BATCH_FULL, CDS_ERROR_CANARY_MISMATCH, CDS_ERROR_DIRECTORY_DIGEST_MISMATCH, CDS_ERROR_DIRECTORY_EPOCH_MISMATCH,
+    CDS_ERROR_DUPLICATE_QUERY_PHONE, CDS_ERROR_ENCLAVE_HALTED, CDS_ERROR_FAIR_SHARE_EXCEEDED, CDS_ERROR_INVALID_CANONICALIZATION_RULES, CDS_ERROR_INVALID_QUERY_PHONE, CDS_ERROR_INVALID_REQUEST_SIZE, CDS_ERROR_QUERY_COMMITMENT_MISMATCH, CDS_ERROR_QUERY_NOT_SORTED, CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION,
+    CDS_ERROR_REQUEST_DEFERRED, CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED, CDS_ERROR_SESSION_DENIED, CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED, CDS_MAX_BENCHMARK_ITERATIONS, CDS_MAX_DIRECTORY_SAMPLE_COUNT, CDS_MAX_INCIDENT_RECORD_SIZE,
+    CDS_MAX_SESSION_DENYLIST_COUNT, CDS_PROTOCOL_VERSION_0, CDS_PROTOCOL_VERSION_1, CDS_QUEUE_AGE_HISTOGRAM_BUCKETS, CDS_REPLY_FLAG_BATCH_KEY, CDS_REPLY_FLAG_QUERY_NONCE, CDS_REPLY_LAYOUT_ACI_PNI, CDS_REPLY_LAYOUT_UUID, CDS_REPLY_LAYOUT_UUID_CHANGES, CDS_REPLY_LAYOUT_UUID_FLAGS, CDS_REPLY_ORDER_QUERY, CDS_REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS,
+    CDS_REQUEST_PRIORITY_BULK, CDS_REQUEST_PRIORITY_INTERACTIVE, sgxsd_curve25519_public_key_t as Curve25519PublicKey, SGXSD_AES_GCM_IV_SIZE, SGXSD_AES_GCM_KEY_SIZE, SGXSD_AES_GCM_MAC_SIZE,
+    SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND,
 };
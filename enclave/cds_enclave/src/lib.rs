@@ -62,12 +62,20 @@ mod hasher;
 mod service;
 
 pub mod external {
+    use alloc::vec::Vec;
     use sgx_ffi::sgx::{SgxStatus, SGX_SUCCESS, SGX_ERROR_INVALID_PARAMETER};
+    use sgx_ffi::untrusted_slice::UntrustedReadLimit;
+    use sgx_ffi::util::ToUsize;
     use sgxsd_ffi::ecalls::{SgxsdServer, ECallSlice};
 
     use super::service::main;
-    use sgxsd_ffi::SHA256HMACContext;
-    use crate::ffi::sgxsd::CallArgs;
+    use crate::service::benchmark::BenchmarkProfile;
+    use crate::ffi::sgxsd::BenchmarkProfileId;
+    use sgxsd_ffi::{RdRand, SHA256HMACContext};
+    use crate::ffi::sgxsd::{CallArgs, Curve25519PublicKey, DirectoryCommitArgs, DirectorySample, DirectorySampleArgs, CDS_MAX_DIRECTORY_SAMPLE_COUNT};
+    use crate::service::denylist::{SessionId, SESSION_DENYLIST};
+    use crate::service::directory::ACTIVE_DIRECTORY;
+    use crate::service::incident::INCIDENT_LATCH;
     use crate::service::main::SgxsdServerState;
     use core::{slice, ptr};
 
@@ -77,9 +85,26 @@ pub mod external {
         pp_state: *mut *mut main::SgxsdServerState,
     ) -> SgxStatus
     {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
         sgxsd_ffi::ecalls::sgxsd_enclave_server_init(p_args, pp_state)
     }
 
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_server_config(
+        p_args: *const <main::SgxsdServerState as SgxsdServer>::InitArgs,
+        p_config: *mut u8,
+        config_size: usize,
+        p_config_len: *mut usize,
+    ) -> SgxStatus
+    {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        sgxsd_ffi::ecalls::sgxsd_enclave_server_config::<main::SgxsdServerState>(p_args, p_config, config_size, p_config_len)
+    }
+
     #[no_mangle]
     pub extern "C" fn sgxsd_enclave_server_handle_call(
         p_args: *const <main::SgxsdServerState as SgxsdServer>::HandleCallArgs,
@@ -88,16 +113,133 @@ pub mod external {
         pp_state: *mut *mut main::SgxsdServerState,
     ) -> SgxStatus
     {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
         sgxsd_ffi::ecalls::sgxsd_enclave_server_handle_call(p_args, msg_buf, &mut from, pp_state)
     }
 
     #[no_mangle]
     pub extern "C" fn sgxsd_enclave_server_terminate(
         p_args: *const <main::SgxsdServerState as SgxsdServer>::TerminateArgs,
+        p_continuation_token: *mut u64,
+        pp_state: *mut *mut main::SgxsdServerState,
+    ) -> SgxStatus
+    {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        sgxsd_ffi::ecalls::sgxsd_enclave_server_terminate(p_args, p_continuation_token, pp_state)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_server_metrics(
+        now_ticks: u64,
+        p_metrics: *mut <main::SgxsdServerState as SgxsdServer>::Metrics,
+        p_state: *const main::SgxsdServerState,
+    ) -> SgxStatus
+    {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        sgxsd_ffi::ecalls::sgxsd_enclave_server_metrics(now_ticks, p_metrics, p_state)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_server_cancel(
+        msg_tag: sgxsd_ffi::ecalls::sgxsd_msg_tag_t,
         p_state: *mut main::SgxsdServerState,
     ) -> SgxStatus
     {
-        sgxsd_ffi::ecalls::sgxsd_enclave_server_terminate(p_args, p_state)
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        sgxsd_ffi::ecalls::sgxsd_enclave_server_cancel(msg_tag, p_state)
+    }
+
+    // args is checked to be non-null by sgxsd_enclave_commit_directory.
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_directory_commit(args: &DirectoryCommitArgs) -> SgxStatus {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        match ACTIVE_DIRECTORY.commit(args) {
+            Ok(()) => SGX_SUCCESS,
+            Err(error) => error,
+        }
+    }
+
+    // args and p_samples are checked to be non-null by sgxsd_enclave_sample_directory.
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_directory_sample(args: &DirectorySampleArgs, p_samples: *mut DirectorySample, sample_count: usize) -> SgxStatus {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        let p_samples = match ptr::NonNull::new(p_samples) {
+            Some(p_samples) if sample_count <= CDS_MAX_DIRECTORY_SAMPLE_COUNT.to_usize() => p_samples,
+            _ => return SGX_ERROR_INVALID_PARAMETER,
+        };
+        let samples = unsafe { slice::from_raw_parts_mut(p_samples.as_ptr(), sample_count) };
+        match ACTIVE_DIRECTORY.sample(args, samples, &mut RdRand) {
+            Ok(()) => SGX_SUCCESS,
+            Err(error) => error,
+        }
+    }
+
+    // p_session_ids is checked to be non-null by sgxsd_enclave_set_session_denylist unless session_id_count is zero.
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_session_denylist(p_session_ids: *const Curve25519PublicKey, session_id_count: usize) -> SgxStatus {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        let session_ids: Vec<SessionId> = match ptr::NonNull::new(p_session_ids as *mut Curve25519PublicKey) {
+            Some(p_session_ids) => unsafe { slice::from_raw_parts(p_session_ids.as_ptr(), session_id_count) }
+                .iter()
+                .map(|session_id| session_id.x)
+                .collect(),
+            None => Vec::new(),
+        };
+        match SESSION_DENYLIST.replace(session_ids) {
+            Ok(()) => SGX_SUCCESS,
+            Err(error) => error,
+        }
+    }
+
+    // p_record_len is checked to be non-null by sgxsd_enclave_get_incident_record.
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_incident_record(p_record: *mut u8, record_size: usize, p_record_len: &mut usize) -> SgxStatus {
+        let sealing_key = match sgxsd_ffi::sealed::sealing_key() {
+            Ok(sealing_key) => sealing_key,
+            Err(error) => return error,
+        };
+        let record = match INCIDENT_LATCH.sealed_record(sealing_key.get()) {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                *p_record_len = 0;
+                return SGX_SUCCESS;
+            }
+            Err(error) => return error,
+        };
+        let p_record = match ptr::NonNull::new(p_record) {
+            Some(p_record) if record.len() <= record_size => p_record,
+            _ => return SGX_ERROR_INVALID_PARAMETER,
+        };
+        let record_out = unsafe { slice::from_raw_parts_mut(p_record.as_ptr(), record.len()) };
+        record_out.copy_from_slice(&record);
+        *p_record_len = record.len();
+        SGX_SUCCESS
+    }
+
+    // iterations is checked to be at most CDS_MAX_BENCHMARK_ITERATIONS by sgxsd_enclave_run_benchmark.
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_benchmark(profile: BenchmarkProfileId, iterations: u32) -> SgxStatus {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
+        match BenchmarkProfile::from_id(profile).and_then(|profile| profile.run(iterations)) {
+            Ok(()) => SGX_SUCCESS,
+            Err(error) => error,
+        }
     }
 
     // fingerprint must be allocated by the caller, and should be the same size as call_args.query_phone_count.
@@ -111,8 +253,11 @@ pub mod external {
         fingerprint_size: usize,
     ) -> SgxStatus
     {
+        if let Err(error) = INCIDENT_LATCH.check_service() {
+            return error;
+        }
         let request_data = ECallSlice(ptr::NonNull::new(msg_buf.data as *mut _), msg_buf.size as usize);
-        let request = match SgxsdServerState::decode_phone_list(call_args, request_data.as_ref()) {
+        let request = match SgxsdServerState::decode_phone_list(call_args, request_data.as_ref(), &UntrustedReadLimit::unlimited()) {
             Ok(request) => request,
             Err(error) => {
                 return error
@@ -125,7 +270,7 @@ pub mod external {
             if (i >= fingerprint_size) {
                 return SGX_ERROR_INVALID_PARAMETER;
             }
-            ctx.update(&phone.to_le_bytes());
+            ctx.update(&phone.get().to_le_bytes());
             let phone_out = &mut [0; SHA256HMACContext::hash_len()];
             ctx.result(phone_out);
             fingerprint[i] = phone_out[0];
@@ -140,7 +285,7 @@ pub mod test {
     pub use crate::ffi::hash_lookup;
     use mockers::{Scenario, Sequence};
     use crate::external::sgxsd_enclave_create_ratelimit_fingerprint;
-    use crate::ffi::sgxsd::{CallArgs, EncryptedMessage};
+    use crate::ffi::sgxsd::{CallArgs, EncryptedMessage, CDS_REPLY_LAYOUT_UUID};
     use core::ptr;
     use sgxsd_ffi::{mocks, SHA256Context, SHA256HMACContext};
     use mockers::matchers::{check, any};
@@ -162,7 +307,7 @@ pub mod test {
 
         let mut phone_list: Vec<u8> = vec![3; 32]; // These first 32-bytes are the nonce for the commitment
         let raw_phones: Vec<u64> = vec![15558275309, 18002738255];
-        let actual_phone_bytes: Vec<[u8; 8]> = raw_phones.into_iter().map(|x| x.to_le_bytes()).collect();
+        let actual_phone_bytes: Vec<[u8; 8]> = raw_phones.into_iter().map(|x| x.to_be_bytes()).collect();
         let fake_phone_bytes: Vec<u8> = actual_phone_bytes.clone().into_iter().map(|v| v.to_vec()).flatten().collect();
 
         phone_list.append(&mut fake_phone_bytes.clone());
@@ -178,6 +323,21 @@ pub mod test {
             ratelimit_state_data: ptr::null_mut(),
             query: query,
             query_commitment: commitment,
+            admission_ticks: 0,
+            reply_flags: 0,
+            reply_reserved: 0,
+            reply_layout: CDS_REPLY_LAYOUT_UUID,
+            query_more_fragments: 0,
+            query_fragment_token: 0,
+            since_change_token: 0,
+            protocol_version: 0,
+            query_key_size: 0,
+            query_chunk_commitments: ptr::null(),
+            query_chunk_size: 0,
+            query_chunk_commitment_count: 0,
+            request_id: 0,
+            reply_order: 0,
+            priority: 0,
         };
 
         let mut fake_request_data = [1; 32];
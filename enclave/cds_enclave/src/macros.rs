@@ -17,3 +17,19 @@ macro_rules! static_unreachable {
         unreachable!()
     }};
 }
+
+/// Checks at compile time that an FFI struct shared with the C/EDL side has the given size,
+/// alignment, and field offsets, so that layout drift fails the build instead of misparsing host
+/// input at runtime.
+macro_rules! assert_ffi_layout {
+    ($type:ty { size: $size:expr, align: $align:expr $(, $field:ident: $offset:expr)* $(,)? }) => {
+        const _: () = {
+            assert!(::core::mem::size_of::<$type>() == $size, concat!("size of ", stringify!($type)));
+            assert!(::core::mem::align_of::<$type>() == $align, concat!("alignment of ", stringify!($type)));
+            $(assert!(
+                ::core::mem::offset_of!($type, $field) == $offset,
+                concat!("offset of ", stringify!($type), "::", stringify!($field))
+            );)*
+        };
+    };
+}
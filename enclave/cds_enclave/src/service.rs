@@ -5,4 +5,17 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 //
 
+pub mod benchmark;
+pub mod canonicalize;
+pub mod chunks;
+pub mod delta;
+pub mod denylist;
+pub mod directory;
+pub mod distinct;
+pub mod freshness;
+pub mod incident;
 pub mod main;
+pub mod reply;
+pub mod reply_scratch;
+pub mod sealed_results;
+pub mod sort;
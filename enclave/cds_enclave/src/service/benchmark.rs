@@ -0,0 +1,156 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Synthetic workloads for calibrating batch sizing against the production enclave.
+//!
+//! The enclave has no trusted clock, so it doesn't time anything itself: the host times a call running some number of
+//! iterations of one workload and divides by it. Each workload only touches random data generated inside the enclave
+//! for the call, never the directory or any client data, and the number of iterations per call is bounded by
+//! `CDS_MAX_BENCHMARK_ITERATIONS`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+use core::num::NonZeroU64;
+use core::ptr;
+
+use sgx_ffi::sgx::*;
+use sgxsd_ffi::{AesGcmIv, AesGcmKey, AesGcmMac, RdRand};
+
+use crate::ffi::hash_lookup::*;
+use crate::ffi::sgxsd::*;
+
+//
+// public API
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BenchmarkProfile {
+    LookupChunk,
+    AesGcm,
+    Divrem,
+}
+
+//
+// internal
+//
+
+const LOOKUP_DIRECTORY_PHONES: usize = 1 << 16;
+const LOOKUP_DIRECTORY_UUIDS_SIZE: usize = LOOKUP_DIRECTORY_PHONES * mem::size_of::<Uuid>();
+const LOOKUP_CHUNK_PHONES: usize = 1 << 10;
+const LOOKUP_CHUNK_RESULTS_SIZE: usize = LOOKUP_CHUNK_PHONES * mem::size_of::<Uuid>();
+
+const AES_GCM_BUFFER_SIZE: usize = 1 << 16;
+
+const DIVREM_BATCH_SIZE: usize = 1 << 16;
+
+//
+// BenchmarkProfile impls
+//
+
+impl BenchmarkProfile {
+    pub fn from_id(profile: BenchmarkProfileId) -> Result<Self, SgxStatus> {
+        match profile {
+            CDS_BENCHMARK_LOOKUP_CHUNK => Ok(Self::LookupChunk),
+            CDS_BENCHMARK_AES_GCM => Ok(Self::AesGcm),
+            CDS_BENCHMARK_DIVREM => Ok(Self::Divrem),
+            _ => Err(SGX_ERROR_INVALID_PARAMETER),
+        }
+    }
+
+    /// Runs `iterations` iterations of the workload, after generating its data.
+    pub fn run(self, iterations: u32) -> Result<(), SgxStatus> {
+        if iterations > CDS_MAX_BENCHMARK_ITERATIONS {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        match self {
+            Self::LookupChunk => run_lookup_chunk(iterations),
+            Self::AesGcm => run_aes_gcm(iterations),
+            Self::Divrem => {
+                run_divrem(iterations);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn run_lookup_chunk(iterations: u32) -> Result<(), SgxStatus> {
+    let in_phones: Vec<Phone> = rand_u64s(LOOKUP_DIRECTORY_PHONES);
+    let in_uuids: Vec<u8> = RdRand.rand_bytes(vec![0; LOOKUP_DIRECTORY_UUIDS_SIZE]);
+    let query_phones: Vec<Phone> = rand_u64s(LOOKUP_CHUNK_PHONES);
+    let mut query_phone_results = vec![0; LOOKUP_CHUNK_RESULTS_SIZE];
+    for _ in 0..iterations {
+        unsafe {
+            hash_lookup(
+                in_phones.as_ptr() as *const u8,
+                in_uuids.as_ptr(),
+                in_phones.len(),
+                &query_phones,
+                &mut query_phone_results,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn run_aes_gcm(iterations: u32) -> Result<(), SgxStatus> {
+    let key = AesGcmKey::default();
+    let iv = AesGcmIv {
+        data: RdRand.rand_bytes(Default::default()),
+    };
+    let mut data = RdRand.rand_bytes(vec![0; AES_GCM_BUFFER_SIZE]);
+    let mut mac = AesGcmMac::default();
+    for _ in 0..iterations {
+        key.encrypt(&mut data, &[], &iv, &mut mac)?;
+    }
+    Ok(())
+}
+
+fn run_divrem(iterations: u32) {
+    let dividends = rand_u64s(DIVREM_BATCH_SIZE);
+    let divisors: Vec<NonZeroU64> = (rand_u64s(DIVREM_BATCH_SIZE).into_iter())
+        .filter_map(|divisor| NonZeroU64::new(divisor >> 32 | 1))
+        .collect();
+    let mut accumulator = 0u64;
+    for _ in 0..iterations {
+        for (&dividend, &divisor) in dividends.iter().zip(&divisors) {
+            accumulator ^= (dividend / divisor) ^ (dividend % divisor);
+        }
+        // keep the divisions from being optimized away
+        unsafe { ptr::write_volatile(&mut accumulator, accumulator) };
+    }
+}
+
+fn rand_u64s(count: usize) -> Vec<u64> {
+    let mut values = vec![0u64; count];
+    for value in &mut values {
+        *value = u64::from_ne_bytes(RdRand.rand_bytes([0; 8]));
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_id() {
+        assert_eq!(BenchmarkProfile::from_id(CDS_BENCHMARK_LOOKUP_CHUNK), Ok(BenchmarkProfile::LookupChunk));
+        assert_eq!(BenchmarkProfile::from_id(CDS_BENCHMARK_AES_GCM), Ok(BenchmarkProfile::AesGcm));
+        assert_eq!(BenchmarkProfile::from_id(CDS_BENCHMARK_DIVREM), Ok(BenchmarkProfile::Divrem));
+        assert_eq!(BenchmarkProfile::from_id(0), Err(SGX_ERROR_INVALID_PARAMETER));
+    }
+
+    #[test]
+    fn test_run() {
+        for profile in &[BenchmarkProfile::LookupChunk, BenchmarkProfile::AesGcm, BenchmarkProfile::Divrem] {
+            assert_eq!(profile.run(0), Ok(()));
+            assert_eq!(profile.run(2), Ok(()));
+            assert_eq!(profile.run(CDS_MAX_BENCHMARK_ITERATIONS + 1), Err(SGX_ERROR_INVALID_PARAMETER));
+        }
+    }
+}
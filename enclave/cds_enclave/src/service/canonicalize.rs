@@ -0,0 +1,314 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Table-driven normalization of query phones before lookup.
+//!
+//! Each rule rewrites a leading digit prefix of phones with a given number of digits, e.g. dropping a
+//! national trunk zero that some clients leave after the country code. The rules blob is supplied by the
+//! untrusted side at startup and only accepted if its SHA-256 digest matches the one pinned into the
+//! enclave at build time from `CDS_CANONICALIZATION_RULES_SHA256`, so it is covered by the enclave
+//! signature. Layout (integers little-endian):
+//!
+//! ```text
+//! version:u8 rule_count:u16 rules[rule_count]
+//! rule: phone_digits:u8 match_prefix_digits:u8 replace_prefix_digits:u8 match_prefix:u64 replace_prefix:u64
+//! ```
+//!
+//! Every phone is run against every rule without branching on the phone, so that the time spent
+//! canonicalizing a query says nothing about which rules, if any, applied to it.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::mem;
+
+use sgx_ffi::sgx::*;
+use sgxsd_ffi::SHA256Context;
+
+use crate::ffi::hash_lookup::Phone;
+use crate::ffi::sgxsd::*;
+
+//
+// public API
+//
+
+pub const CANONICALIZATION_RULES_VERSION: u8 = 1;
+pub const MAX_CANONICALIZATION_RULES: usize = 256;
+
+pub type CanonicalizationRulesDigest = [u8; SHA256Context::hash_len()];
+
+pub const CANONICALIZATION_RULES_DIGEST: Option<CanonicalizationRulesDigest> =
+    include!(concat!(env!("OUT_DIR"), "/canonicalization_rules_digest.rs"));
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CanonicalizationRules {
+    rules: Vec<CanonicalizationRule>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CanonicalizationRule {
+    phone_digits:   u64,
+    match_prefix:   u64,
+    replace_prefix: u64,
+    suffix_modulus: u64,
+}
+
+//
+// internal
+//
+
+// E.164 numbers have at most 15 digits, which leaves room for a rule to lengthen them
+const MAX_PHONE_DIGITS: u8 = 19;
+
+const RULE_SIZE: usize = 3 + 2 * mem::size_of::<u64>();
+
+//
+// CanonicalizationRules impls
+//
+
+impl CanonicalizationRules {
+    pub fn parse(data: &[u8], pinned_digest: Option<&CanonicalizationRulesDigest>) -> Result<Self, SgxStatus> {
+        if data.is_empty() {
+            return Ok(Default::default());
+        }
+        let pinned_digest = pinned_digest.ok_or(CDS_ERROR_INVALID_CANONICALIZATION_RULES)?;
+
+        let mut context: SHA256Context = Default::default();
+        context.update(data);
+        let mut digest: CanonicalizationRulesDigest = [0; SHA256Context::hash_len()];
+        context.result(&mut digest);
+        if &digest != pinned_digest {
+            return Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES);
+        }
+
+        let (header, rules_data) = split(data, 3)?;
+        let (version, rule_count) = match header {
+            [version, rule_count_lo, rule_count_hi] => (*version, u16::from_le_bytes([*rule_count_lo, *rule_count_hi])),
+            _ => return Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES),
+        };
+        if version != CANONICALIZATION_RULES_VERSION ||
+            usize::from(rule_count) > MAX_CANONICALIZATION_RULES ||
+            rules_data.len() != usize::from(rule_count) * RULE_SIZE
+        {
+            return Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES);
+        }
+
+        let rules = (rules_data.chunks_exact(RULE_SIZE))
+            .map(CanonicalizationRule::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Rewrites `phone` with the first rule matching it. Phones are carried in the byte order the
+    /// client encoded them in, which is big-endian.
+    pub fn canonicalize(&self, phone: Phone) -> Phone {
+        let number = u64::from_be(phone);
+        let number_digits = digit_count(number);
+
+        let mut canonical_number = number;
+        let mut matched_mask = 0;
+        for rule in &self.rules {
+            let rule_mask = eq_mask(number_digits, rule.phone_digits) &
+                eq_mask(number.wrapping_div(rule.suffix_modulus), rule.match_prefix) &
+                !matched_mask;
+            let replaced_number = (rule.replace_prefix)
+                .wrapping_mul(rule.suffix_modulus)
+                .wrapping_add(number.wrapping_rem(rule.suffix_modulus));
+            canonical_number = (replaced_number & rule_mask) | (canonical_number & !rule_mask);
+            matched_mask |= rule_mask;
+        }
+        canonical_number.to_be()
+    }
+}
+
+//
+// CanonicalizationRule impls
+//
+
+impl CanonicalizationRule {
+    fn parse(data: &[u8]) -> Result<Self, SgxStatus> {
+        let (digit_counts, prefixes) = split(data, 3)?;
+        let (phone_digits, match_prefix_digits, replace_prefix_digits) = match digit_counts {
+            [phone_digits, match_prefix_digits, replace_prefix_digits] => (*phone_digits, *match_prefix_digits, *replace_prefix_digits),
+            _ => return Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES),
+        };
+        let (match_prefix, replace_prefix) = split(prefixes, mem::size_of::<u64>())?;
+        let match_prefix = u64::from_le_bytes(match_prefix.try_into().map_err(|_| CDS_ERROR_INVALID_CANONICALIZATION_RULES)?);
+        let replace_prefix = u64::from_le_bytes(replace_prefix.try_into().map_err(|_| CDS_ERROR_INVALID_CANONICALIZATION_RULES)?);
+
+        // the digits following the matched prefix are kept, and the rewritten phone must still fit
+        let suffix_digits = (phone_digits)
+            .checked_sub(match_prefix_digits)
+            .ok_or(CDS_ERROR_INVALID_CANONICALIZATION_RULES)?;
+        let canonical_digits = (suffix_digits)
+            .checked_add(replace_prefix_digits)
+            .ok_or(CDS_ERROR_INVALID_CANONICALIZATION_RULES)?;
+        if phone_digits > MAX_PHONE_DIGITS ||
+            canonical_digits > MAX_PHONE_DIGITS ||
+            digit_count(match_prefix) != u64::from(match_prefix_digits) ||
+            digit_count(replace_prefix) != u64::from(replace_prefix_digits) ||
+            match_prefix == 0 ||
+            replace_prefix == 0
+        {
+            return Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES);
+        }
+
+        Ok(Self {
+            phone_digits: phone_digits.into(),
+            match_prefix,
+            replace_prefix,
+            suffix_modulus: 10u64.pow(suffix_digits.into()),
+        })
+    }
+}
+
+//
+// helpers
+//
+
+fn split(data: &[u8], mid: usize) -> Result<(&[u8], &[u8]), SgxStatus> {
+    if mid <= data.len() {
+        Ok(data.split_at(mid))
+    } else {
+        Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES)
+    }
+}
+
+fn digit_count(number: u64) -> u64 {
+    let mut digits: u64 = 1;
+    let mut power_of_ten: u64 = 1;
+    for _ in 1..=MAX_PHONE_DIGITS {
+        power_of_ten = power_of_ten.wrapping_mul(10);
+        digits = digits.wrapping_add(u64::from(number >= power_of_ten));
+    }
+    digits
+}
+
+// all ones if equal, otherwise zero
+const fn eq_mask(left: u64, right: u64) -> u64 {
+    let difference = left ^ right;
+    ((difference | difference.wrapping_neg()) >> 63).wrapping_sub(1)
+}
+
+//
+// tests
+//
+
+#[cfg(test)]
+mod tests {
+    use mockers::matchers::*;
+    use mockers::*;
+
+    use super::*;
+
+    const MOCK_DIGEST: CanonicalizationRulesDigest = [0x5a; 32];
+
+    fn encode_rules(rules: &[(u8, u8, u8, u64, u64)]) -> Vec<u8> {
+        let mut data = vec![CANONICALIZATION_RULES_VERSION];
+        data.extend_from_slice(&(rules.len() as u16).to_le_bytes());
+        for (phone_digits, match_prefix_digits, replace_prefix_digits, match_prefix, replace_prefix) in rules {
+            data.extend_from_slice(&[*phone_digits, *match_prefix_digits, *replace_prefix_digits]);
+            data.extend_from_slice(&match_prefix.to_le_bytes());
+            data.extend_from_slice(&replace_prefix.to_le_bytes());
+        }
+        data
+    }
+
+    fn parse_with_mock_digest(data: &[u8]) -> Result<CanonicalizationRules, SgxStatus> {
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(MOCK_DIGEST).times(..));
+        let rules = CanonicalizationRules::parse(data, Some(&MOCK_DIGEST));
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+        rules
+    }
+
+    fn canonicalize(rules: &CanonicalizationRules, number: u64) -> u64 {
+        u64::from_be(rules.canonicalize(number.to_be()))
+    }
+
+    #[test]
+    fn test_empty_rules() {
+        let rules = CanonicalizationRules::parse(&[], None).unwrap();
+        assert!(rules.rules.is_empty());
+        assert_eq!(canonicalize(&rules, 4402079460000), 4402079460000);
+    }
+
+    #[test]
+    fn test_unpinned_rules() {
+        assert_eq!(
+            CanonicalizationRules::parse(&encode_rules(&[]), None),
+            Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES)
+        );
+    }
+
+    #[test]
+    fn test_digest_mismatch() {
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone([0; 32]).times(..));
+        assert_eq!(
+            CanonicalizationRules::parse(&encode_rules(&[]), Some(&MOCK_DIGEST)),
+            Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES)
+        );
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let mut truncated = encode_rules(&[(13, 3, 2, 440, 44)]);
+        truncated.pop();
+        let mut bad_version = encode_rules(&[]);
+        bad_version[0] = CANONICALIZATION_RULES_VERSION + 1;
+
+        for data in &[
+            truncated,
+            bad_version,
+            encode_rules(&[(13, 3, 2, 44, 44)]),
+            encode_rules(&[(13, 3, 2, 440, 440)]),
+            encode_rules(&[(13, 14, 2, 440, 44)]),
+            encode_rules(&[(20, 3, 2, 440, 44)]),
+            encode_rules(&[(19, 1, 2, 4, 44)]),
+        ] {
+            assert_eq!(parse_with_mock_digest(data), Err(CDS_ERROR_INVALID_CANONICALIZATION_RULES));
+        }
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let rules = parse_with_mock_digest(&encode_rules(&[
+            // +44 0xxxxxxxxxx -> +44 xxxxxxxxxx
+            (13, 3, 2, 440, 44),
+            // shadowed by the rule above
+            (13, 2, 2, 44, 33),
+            // +39 3xxxxxxxxx -> +39 03xxxxxxxxx
+            (12, 3, 4, 393, 3903),
+        ]))
+        .unwrap();
+        assert!(!rules.rules.is_empty());
+
+        assert_eq!(canonicalize(&rules, 4402079460000), 442079460000);
+        assert_eq!(canonicalize(&rules, 4412079460000), 3312079460000);
+        assert_eq!(canonicalize(&rules, 393123456789), 3903123456789);
+        assert_eq!(canonicalize(&rules, 442079460000), 442079460000);
+        assert_eq!(canonicalize(&rules, 14155550100), 14155550100);
+        assert_eq!(canonicalize(&rules, u64::max_value()), u64::max_value());
+    }
+
+    #[test]
+    fn test_digit_count() {
+        assert_eq!(digit_count(0), 1);
+        assert_eq!(digit_count(9), 1);
+        assert_eq!(digit_count(10), 2);
+        assert_eq!(digit_count(14155550100), 11);
+        assert_eq!(digit_count(9_999_999_999_999_999_999), 19);
+        assert_eq!(digit_count(u64::max_value()), 20);
+    }
+}
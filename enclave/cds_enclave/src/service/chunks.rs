@@ -0,0 +1,115 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Scheduling of the lookup chunks of a batch over its stop calls.
+//!
+//! The phones of a batch are looked up a chunk at a time: first the chunks of its distinct phones, if it's looked up
+//! through them, then those of its query phones. A stop call looks up as many of the chunks left as it names, and the
+//! number looked up so far is the continuation token the host hands back with the next one.
+
+use core::ops::Range;
+
+use sgx_ffi::util::{ToU64, ToUsize};
+
+use crate::ffi::hash_lookup::*;
+
+//
+// public API
+//
+
+pub struct LookupChunks {
+    next_chunk: usize,
+    distinct_chunk_count: usize,
+    query_chunk_count: usize,
+}
+
+/// A chunk of a lookup, by its index among the distinct or the query phones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupChunk {
+    Distinct(usize),
+    Query(usize),
+}
+
+//
+// LookupChunks impls
+//
+
+impl LookupChunks {
+    pub fn new(distinct_chunk_count: usize, query_phone_count: usize) -> Self {
+        Self {
+            next_chunk: 0,
+            distinct_chunk_count,
+            query_chunk_count: query_phone_count.saturating_add(MAX_HASH_TABLE_SIZE - 1) / MAX_HASH_TABLE_SIZE,
+        }
+    }
+
+    pub fn continuation_token(&self) -> u64 {
+        self.next_chunk.to_u64()
+    }
+
+    /// The chunks a stop call looks up, in order, at most `max_chunks` of them unless it's 0.
+    pub fn schedule(&self, max_chunks: u32) -> impl Iterator<Item = LookupChunk> {
+        let max_chunks = match max_chunks {
+            0 => usize::max_value(),
+            max_chunks => max_chunks.to_usize(),
+        };
+        let distinct_chunk_count = self.distinct_chunk_count;
+        let chunk_count = distinct_chunk_count.saturating_add(self.query_chunk_count);
+        (self.next_chunk..chunk_count).take(max_chunks).map(move |chunk| match chunk.checked_sub(distinct_chunk_count) {
+            None => LookupChunk::Distinct(chunk),
+            Some(query_chunk) => LookupChunk::Query(query_chunk),
+        })
+    }
+
+    /// Records that the next chunk has been looked up.
+    pub fn advance(&mut self) {
+        self.next_chunk = self.next_chunk.saturating_add(1);
+    }
+
+    /// Where the phones of a query chunk sit among the `query_phone_count` phones of the batch.
+    pub fn query_phones(query_chunk: usize, query_phone_count: usize) -> Range<usize> {
+        let start = query_chunk.saturating_mul(MAX_HASH_TABLE_SIZE).min(query_phone_count);
+        start..start.saturating_add(MAX_HASH_TABLE_SIZE).min(query_phone_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_schedule() {
+        let mut chunks = LookupChunks::new(2, MAX_HASH_TABLE_SIZE + 1);
+        let schedule: Vec<_> = chunks.schedule(0).collect();
+        assert_eq!(
+            schedule,
+            vec![LookupChunk::Distinct(0), LookupChunk::Distinct(1), LookupChunk::Query(0), LookupChunk::Query(1)]
+        );
+
+        // a stop call naming fewer chunks carries on from where the last one got to
+        let schedule: Vec<_> = chunks.schedule(3).collect();
+        assert_eq!(schedule, vec![LookupChunk::Distinct(0), LookupChunk::Distinct(1), LookupChunk::Query(0)]);
+        for _ in schedule {
+            chunks.advance();
+        }
+        assert_eq!(chunks.continuation_token(), 3);
+        let schedule: Vec<_> = chunks.schedule(3).collect();
+        assert_eq!(schedule, vec![LookupChunk::Query(1)]);
+
+        assert_eq!(LookupChunks::new(0, 0).schedule(0).count(), 0);
+    }
+
+    #[test]
+    fn test_query_phones() {
+        let query_phone_count = MAX_HASH_TABLE_SIZE + 1;
+        assert_eq!(LookupChunks::query_phones(0, query_phone_count), 0..MAX_HASH_TABLE_SIZE);
+        assert_eq!(LookupChunks::query_phones(1, query_phone_count), MAX_HASH_TABLE_SIZE..query_phone_count);
+    }
+}
@@ -0,0 +1,152 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Decoding of phones sent as varint deltas.
+//!
+//! Clients mostly send their phones in ascending order with small gaps between them, so a query may instead carry each
+//! phone as its difference from the one before it, in as few bytes as that takes: seven bits to a byte, least
+//! significant first, with the top bit set on every byte but the last (LEB128). Where each phone ends can't be found by
+//! branching on the bytes, so each byte is decoded in turn into a place of its own, holding the phone it ends or a filler
+//! if it ends none, and the phones are then moved to the front with a bitonic sort. All that's given away is the number
+//! of bytes, which the size of the query gives away anyway.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem;
+
+use sgx_ffi::sgx::*;
+use sgx_ffi::util::{memset_s, ToUsize};
+
+use crate::ffi::hash_lookup::*;
+use crate::ffi::sgxsd::*;
+use crate::service::sort::*;
+
+//
+// public API
+//
+
+/// The most bytes a phone's delta may take, enough for any E.164 number.
+pub const MAX_BYTES_PER_DELTA: usize = 8;
+
+/// Decodes the deltas of as many phones as `phones_data` has room for, writing them to it big-endian, as clients send
+/// phones and the directory holds them. Fails with `CDS_ERROR_INVALID_REQUEST_SIZE` if the deltas don't hold exactly that
+/// many phones; the phones themselves are left to be checked by the caller.
+pub fn decode_phones(deltas: &[u8], phones_data: &mut [u8]) -> Result<(), SgxStatus> {
+    let phones_data_chunks = phones_data.chunks_exact(BYTES_PER_PHONE);
+    if !phones_data_chunks.remainder().is_empty() {
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    let phone_count = phones_data_chunks.len();
+    if deltas.len() < phone_count || deltas.len() > phone_count.saturating_mul(MAX_BYTES_PER_DELTA) {
+        return Err(CDS_ERROR_INVALID_REQUEST_SIZE);
+    }
+
+    let padded_len = deltas.len().checked_next_power_of_two().ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+    let mut ends: Vec<u64> = vec![NO_PHONE; padded_len];
+    let mut phone = 0u64;
+    let mut delta = 0u64;
+    let mut shift = 0u32;
+    let mut end_count = 0usize;
+    let mut invalid = 0u64;
+    for (byte, end) in deltas.iter().zip(ends.iter_mut()) {
+        invalid |= u64::from(shift >= MAX_DELTA_SHIFT);
+        delta |= u64::from(byte & 0x7f).wrapping_shl(shift);
+
+        // all ones if this byte is the last of a delta, without branching on it
+        let last_byte = (byte >> 7) ^ 1;
+        let last = u64::from(last_byte);
+        let last_mask = last.wrapping_neg();
+        phone = phone.wrapping_add(delta & last_mask);
+        *end = (phone & last_mask) | (NO_PHONE & !last_mask);
+        end_count = end_count.saturating_add(last.to_usize());
+        delta &= !last_mask;
+        shift = shift.saturating_add(7) & !u32::from(last_byte).wrapping_neg();
+    }
+    // the last byte must end a delta
+    invalid |= u64::from(shift != 0);
+
+    // no sum of deltas can wrap around before passing through phones too large to be E.164 numbers, which the caller
+    // refuses, so the phones are already in order and sort before the fillers
+    bitonic_sort(&mut ends);
+    for (phone_data, end) in phones_data.chunks_exact_mut(BYTES_PER_PHONE).zip(&ends) {
+        phone_data.copy_from_slice(&end.to_be_bytes());
+    }
+
+    let byte_len = ends.len().saturating_mul(mem::size_of::<u64>());
+    let clear_res = unsafe { memset_s(ends.as_mut_ptr() as *mut c_void, byte_len, 0, byte_len) };
+    assert_eq!(clear_res, 0);
+
+    if invalid != 0 || end_count != phone_count {
+        Err(CDS_ERROR_INVALID_REQUEST_SIZE)
+    } else {
+        Ok(())
+    }
+}
+
+//
+// internal
+//
+
+const BYTES_PER_PHONE: usize = mem::size_of::<Phone>();
+
+// the shift of the first byte past the longest delta allowed, seven bits for each of MAX_BYTES_PER_DELTA bytes
+const MAX_DELTA_SHIFT: u32 = 56;
+
+// stands in for each byte that doesn't end a delta, sorting after any phone
+const NO_PHONE: u64 = u64::max_value();
+
+#[cfg(test)]
+mod tests {
+    use core::convert::TryInto;
+
+    use super::*;
+
+    fn encode(phones: &[u64]) -> Vec<u8> {
+        let mut deltas = Vec::new();
+        let mut previous_phone = 0;
+        for phone in phones {
+            let mut delta = phone - previous_phone;
+            while delta >= 0x80 {
+                deltas.push((delta & 0x7f) as u8 | 0x80);
+                delta >>= 7;
+            }
+            deltas.push(delta as u8);
+            previous_phone = *phone;
+        }
+        deltas
+    }
+
+    fn decode(deltas: &[u8], phone_count: usize) -> Result<Vec<u64>, SgxStatus> {
+        let mut phones_data = vec![0xa5; phone_count * BYTES_PER_PHONE];
+        decode_phones(deltas, &mut phones_data)?;
+        Ok((phones_data.chunks_exact(BYTES_PER_PHONE))
+            .map(|phone_data| u64::from_be_bytes(phone_data.try_into().unwrap()))
+            .collect())
+    }
+
+    #[test]
+    fn test_decode_phones() {
+        let phones = vec![1, 127, 128, 16_505_550_100, 16_505_550_101, 999_999_999_999_999];
+        let deltas = encode(&phones);
+        assert_eq!(deltas.len(), 1 + 1 + 1 + 5 + 1 + 8);
+        assert_eq!(decode(&deltas, phones.len()), Ok(phones.clone()));
+
+        // too few or too many deltas for the phones there's room for
+        assert_eq!(decode(&deltas, phones.len() + 1), Err(CDS_ERROR_INVALID_REQUEST_SIZE));
+        assert_eq!(decode(&deltas, phones.len() - 1), Err(CDS_ERROR_INVALID_REQUEST_SIZE));
+
+        // a last delta cut short
+        let mut unfinished = deltas.clone();
+        *unfinished.last_mut().unwrap() |= 0x80;
+        assert_eq!(decode(&unfinished, phones.len()), Err(CDS_ERROR_INVALID_REQUEST_SIZE));
+
+        // a delta longer than any phone needs, even if it has room
+        let overlong = vec![0x81, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00, 0x01];
+        assert_eq!(decode(&overlong, 2), Err(CDS_ERROR_INVALID_REQUEST_SIZE));
+    }
+}
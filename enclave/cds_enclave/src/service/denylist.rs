@@ -0,0 +1,119 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Operator-provided denylist of client sessions.
+//!
+//! The untrusted side may replace the list of client public keys whose requests the enclave refuses, so
+//! that known-abusive clients can be cut off at the enclave itself. A request is compared against every
+//! entry of the list in constant time, so how long the check takes doesn't tell which entry it matched.
+//! The list can only ever deny service, which the untrusted side can do anyway by dropping requests, so
+//! it isn't authenticated.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem;
+use core::sync::atomic::{self, AtomicBool, Ordering};
+
+use sgx_ffi::sgx::*;
+use sgx_ffi::util::{consttime_eq, ToUsize};
+
+use crate::ffi::sgxsd::*;
+
+//
+// public API
+//
+
+// sessions are told apart by the public key the client negotiated them with
+pub type SessionId = [u8; 32];
+
+pub struct SessionDenylist {
+    locked:      AtomicBool,
+    session_ids: UnsafeCell<Vec<SessionId>>,
+}
+
+pub static SESSION_DENYLIST: SessionDenylist = SessionDenylist::new();
+
+//
+// SessionDenylist impls
+//
+
+// the list is only accessed with the lock held
+unsafe impl Sync for SessionDenylist {}
+
+impl SessionDenylist {
+    pub const fn new() -> Self {
+        Self {
+            locked:      AtomicBool::new(false),
+            session_ids: UnsafeCell::new(Vec::new()),
+        }
+    }
+
+    /// Replaces the whole denylist with `session_ids`, which may be empty to clear it.
+    pub fn replace(&self, mut session_ids: Vec<SessionId>) -> Result<(), SgxStatus> {
+        if session_ids.len() > CDS_MAX_SESSION_DENYLIST_COUNT.to_usize() {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        // the old list is freed outside the lock, so requests aren't held up by it
+        self.with_lock(|denied_session_ids| mem::swap(denied_session_ids, &mut session_ids));
+        Ok(())
+    }
+
+    /// Refuses `session_id` if it's in the denylist.
+    pub fn check(&self, session_id: &SessionId) -> Result<(), SgxStatus> {
+        let denied = self.with_lock(|denied_session_ids| {
+            (denied_session_ids.iter()).fold(false, |denied, denied_session_id| denied | consttime_eq(denied_session_id, session_id))
+        });
+        if denied {
+            Err(CDS_ERROR_SESSION_DENIED)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn with_lock<F, R>(&self, fun: F) -> R
+    where F: FnOnce(&mut Vec<SessionId>) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            atomic::spin_loop_hint();
+        }
+        let result = fun(unsafe { &mut *self.session_ids.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let denylist = SessionDenylist::new();
+        let session_a = [0xa; 32];
+        let session_b = [0xb; 32];
+        assert_eq!(denylist.check(&session_a), Ok(()));
+
+        denylist.replace(vec![[0xc; 32], session_a]).unwrap();
+        assert_eq!(denylist.check(&session_a), Err(CDS_ERROR_SESSION_DENIED));
+        assert_eq!(denylist.check(&session_b), Ok(()));
+
+        // a new list replaces the old one entirely
+        denylist.replace(vec![session_b]).unwrap();
+        assert_eq!(denylist.check(&session_a), Ok(()));
+        assert_eq!(denylist.check(&session_b), Err(CDS_ERROR_SESSION_DENIED));
+
+        denylist.replace(Vec::new()).unwrap();
+        assert_eq!(denylist.check(&session_b), Ok(()));
+    }
+
+    #[test]
+    fn test_replace_too_large() {
+        let denylist = SessionDenylist::new();
+        let session_ids = vec![[0xa; 32]; CDS_MAX_SESSION_DENYLIST_COUNT.to_usize() + 1];
+        assert_eq!(denylist.replace(session_ids), Err(SGX_ERROR_INVALID_PARAMETER));
+        assert_eq!(denylist.check(&[0xa; 32]), Ok(()));
+    }
+}
@@ -0,0 +1,609 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Gate on swapping in a newly loaded directory generation.
+//!
+//! The untrusted side loads each directory generation into its own buffers, then commits it under a new
+//! epoch along with the digest the operator signed off on for it. The enclave hashes the buffers itself
+//! and only activates the generation if the digests match; otherwise the previous generation stays
+//! active. Lookups naming a `directory_epoch` are then refused unless they point at exactly the
+//! buffers of the active generation. The digest covers the buffers as they were at commit time, so the
+//! untrusted side must not modify a generation after committing it. A lookup pins the generation it runs against
+//! until it's done, and commits are refused meanwhile, so a lookup suspended between stop calls can always be resumed.
+//!
+//! For spot-checks against the source database, the active generation can also be sampled: random occupied
+//! entries are returned with their phone numbers hashed under an operator-provided key, so no plaintext
+//! numbers leave the enclave. A phone is hashed as the little-endian bytes of its word in the directory, as
+//! ratelimit fingerprints hash query phones; the words hold numbers big-endian, so on the little-endian
+//! enclave those are the big-endian bytes of the number.
+//!
+//! As a self-check of the lookup, a few occupied entries of each committed generation are kept inside the
+//! enclave as canaries. Every batch against that generation looks them up first, and is aborted without any replies
+//! if they don't come back with the uuids they were committed with.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::convert::TryInto;
+use core::mem;
+use core::sync::atomic::{self, AtomicBool, Ordering};
+
+use rand_core::RngCore;
+use sgx_ffi::sgx::*;
+use sgx_ffi::untrusted_slice::UntrustedSlice;
+use sgx_ffi::util::ToU64;
+use sgxsd_ffi::{RdRand, SHA256Context, SHA256HMACContext};
+
+use crate::ffi::hash_lookup::*;
+use crate::ffi::sgxsd::*;
+use crate::service::incident::*;
+
+//
+// public API
+//
+
+pub type DirectoryDigest = [u8; SHA256Context::hash_len()];
+
+pub struct ActiveDirectory {
+    locked:    AtomicBool,
+    directory: UnsafeCell<Option<ActiveGeneration>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommittedDirectory {
+    pub epoch:          u64,
+    pub in_phones:      *const Phone,
+    pub in_phone_count: usize,
+    pub in_uuids:       *const Uuid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirectoryCanary {
+    pub phone: Phone,
+    pub uuid:  Uuid,
+}
+
+/// Keeps the active generation from being replaced by a commit until dropped.
+pub struct DirectoryPin<'a> {
+    directory: &'a ActiveDirectory,
+}
+
+pub static ACTIVE_DIRECTORY: ActiveDirectory = ActiveDirectory::new();
+
+//
+// internal
+//
+
+const CANARY_COUNT: usize = 8;
+
+// the phone of an empty slot in the directory buffers
+const EMPTY_PHONE: Phone = 0;
+
+// draws of a random slot a sample makes before giving up on a generation too sparse to find an occupied one in
+const MAX_SAMPLE_DRAWS: usize = 1 << 16;
+
+struct ActiveGeneration {
+    committed:      CommittedDirectory,
+    occupied_count: usize,
+    canaries:       Vec<DirectoryCanary>,
+    pin_count:      usize,
+}
+
+const DIGEST_READ_CHUNK_SIZE: usize = 64 * 1024;
+
+//
+// ActiveDirectory impls
+//
+
+// the directory is only accessed with the lock held, and its pointers are only dereferenced through UntrustedSlice
+unsafe impl Sync for ActiveDirectory {}
+
+impl ActiveDirectory {
+    pub const fn new() -> Self {
+        Self {
+            locked:    AtomicBool::new(false),
+            directory: UnsafeCell::new(None),
+        }
+    }
+
+    pub fn commit(&self, args: &DirectoryCommitArgs) -> Result<(), SgxStatus> {
+        let in_phones_size = (args.in_phone_count)
+            .checked_mul(mem::size_of::<Phone>())
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_uuids_size = (args.in_phone_count)
+            .checked_mul(mem::size_of::<Uuid>())
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_phones = UntrustedSlice::new(args.in_phones as *mut u8, in_phones_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_uuids = UntrustedSlice::new(args.in_uuids as *mut u8, in_uuids_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        INCIDENT_LATCH.check_disjoint(&[&in_phones, &in_uuids])?;
+        if args.epoch == 0 {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+
+        // hash outside the lock, as the directory may be large
+        let mut context: SHA256Context = Default::default();
+        context.update(&args.epoch.to_le_bytes());
+        let mut occupied_count: usize = 0;
+        update_digest(&mut context, &in_phones, |chunk| {
+            occupied_count = occupied_count.saturating_add(count_occupied(chunk));
+        })?;
+        update_digest(&mut context, &in_uuids, |_| ())?;
+        let mut digest: DirectoryDigest = Default::default();
+        context.result(&mut digest);
+        if digest != args.expected_digest {
+            return Err(CDS_ERROR_DIRECTORY_DIGEST_MISMATCH);
+        }
+
+        // pick the canaries from the buffers just hashed, so a generation that is modified after its commit can't
+        // vouch for itself, and only from their occupied entries, as an empty slot's lookup vouches for nothing
+        let mut canaries = Vec::with_capacity(CANARY_COUNT);
+        if occupied_count != 0 {
+            let mut ordinals: Vec<usize> = (0..CANARY_COUNT).map(|_| random_index(&mut RdRand, occupied_count)).collect();
+            ordinals.sort_unstable();
+            for (phone, uuid) in read_occupied_entries(&in_phones, &in_uuids, &ordinals)? {
+                canaries.push(DirectoryCanary { phone, uuid });
+            }
+        }
+
+        let committed = CommittedDirectory::from(args);
+        self.with_lock(|directory| match directory {
+            Some(active) if active.committed.epoch >= committed.epoch => Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH),
+            Some(active) if active.pin_count != 0 => Err(SGX_ERROR_INVALID_STATE),
+            _ => {
+                *directory = Some(ActiveGeneration {
+                    committed,
+                    occupied_count,
+                    canaries,
+                    pin_count: 0,
+                });
+                Ok(())
+            }
+        })
+    }
+
+    /// Fills `samples` with occupied entries of the active directory, which must be of `args.epoch`. Entries
+    /// are chosen independently and uniformly at random, so they may repeat.
+    pub fn sample(&self, args: &DirectorySampleArgs, samples: &mut [DirectorySample], rng: &mut impl RngCore) -> Result<(), SgxStatus> {
+        let active = self.with_lock(|directory| directory.as_ref().map(|active| (active.committed, active.occupied_count)));
+        let (directory, occupied_count) = match active {
+            Some((directory, occupied_count)) if directory.epoch == args.epoch => (directory, occupied_count),
+            _ => return Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH),
+        };
+        if occupied_count == 0 && !samples.is_empty() {
+            return Err(SGX_ERROR_INVALID_STATE);
+        }
+        let in_phones_size = (directory.in_phone_count)
+            .checked_mul(mem::size_of::<Phone>())
+            .ok_or(SGX_ERROR_UNEXPECTED)?;
+        let in_uuids_size = (directory.in_phone_count)
+            .checked_mul(mem::size_of::<Uuid>())
+            .ok_or(SGX_ERROR_UNEXPECTED)?;
+        let in_phones = UntrustedSlice::new(directory.in_phones as *mut u8, in_phones_size).map_err(|_| SGX_ERROR_UNEXPECTED)?;
+        let in_uuids = UntrustedSlice::new(directory.in_uuids as *mut u8, in_uuids_size).map_err(|_| SGX_ERROR_UNEXPECTED)?;
+
+        let mut hmac = SHA256HMACContext::new(args.sample_key);
+        let result = samples.iter_mut().try_for_each(|sample| {
+            // drawing slots until one is occupied keeps the choice uniform among the occupied entries
+            let (phone, uuid) = (0..MAX_SAMPLE_DRAWS)
+                .map(|_| read_entry(&in_phones, &in_uuids, random_index(rng, directory.in_phone_count)))
+                .find(|entry| entry.as_ref().map_or(true, |(phone, _)| *phone != EMPTY_PHONE))
+                .unwrap_or(Err(SGX_ERROR_INVALID_STATE))?;
+            hmac.reset();
+            hmac.update(&phone.to_le_bytes());
+            hmac.result(&mut sample.phone_hash);
+            sample.uuid = uuid;
+            Ok(())
+        });
+        hmac.clear();
+        result
+    }
+
+    /// Returns the canaries of the active directory, which must be exactly `expected`, pinning it active until the
+    /// returned pin is dropped.
+    pub fn pin_active(&self, expected: &CommittedDirectory) -> Result<(Vec<DirectoryCanary>, DirectoryPin<'_>), SgxStatus> {
+        self.with_lock(|directory| match directory {
+            Some(active) if active.committed == *expected => {
+                active.pin_count = active.pin_count.saturating_add(1);
+                Ok((active.canaries.clone(), DirectoryPin { directory: self }))
+            }
+            _ => Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH),
+        })
+    }
+
+    fn with_lock<F, R>(&self, fun: F) -> R
+    where F: FnOnce(&mut Option<ActiveGeneration>) -> R {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            atomic::spin_loop_hint();
+        }
+        let result = fun(unsafe { &mut *self.directory.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+//
+// DirectoryPin impls
+//
+
+impl Drop for DirectoryPin<'_> {
+    fn drop(&mut self) {
+        // no commit can have replaced the generation this pin was taken on while it was held
+        self.directory.with_lock(|directory| {
+            if let Some(active) = directory {
+                active.pin_count = active.pin_count.saturating_sub(1);
+            }
+        });
+    }
+}
+
+//
+// CommittedDirectory impls
+//
+
+impl From<&DirectoryCommitArgs> for CommittedDirectory {
+    fn from(args: &DirectoryCommitArgs) -> Self {
+        Self {
+            epoch:          args.epoch,
+            in_phones:      args.in_phones,
+            in_phone_count: args.in_phone_count,
+            in_uuids:       args.in_uuids,
+        }
+    }
+}
+
+//
+// DirectoryCanary impls
+//
+
+impl DirectoryCanary {
+    /// Checks the canaries against the results of looking up their phones, in order, against the directory.
+    pub fn verify(canaries: &[Self], results: &[u8]) -> Result<(), SgxStatus> {
+        if results.len() != canaries.len().saturating_mul(mem::size_of::<Uuid>()) {
+            return Err(SGX_ERROR_UNEXPECTED);
+        }
+        let mismatched = canaries.iter().zip(results.chunks_exact(mem::size_of::<Uuid>())).any(|(canary, result)| {
+            (canary.uuid.data64.iter())
+                .zip(result.chunks_exact(mem::size_of::<u64>()))
+                .any(|(word, result_word)| word.to_ne_bytes() != result_word)
+        });
+        if mismatched {
+            Err(CDS_ERROR_CANARY_MISMATCH)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+//
+// helpers
+//
+
+fn read_entry(in_phones: &UntrustedSlice<'_>, in_uuids: &UntrustedSlice<'_>, index: usize) -> Result<(Phone, Uuid), SgxStatus> {
+    let phone = (in_phones.offset(index.saturating_mul(mem::size_of::<Phone>())))
+        .read_bytes(mem::size_of::<Phone>())
+        .map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
+    let uuid_bytes = (in_uuids.offset(index.saturating_mul(mem::size_of::<Uuid>())))
+        .read_bytes(mem::size_of::<Uuid>())
+        .map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
+
+    // the directory holds entries in native byte order, which the enclave shares with the host
+    let phone = Phone::from_ne_bytes(phone.as_slice().try_into().map_err(|_| SGX_ERROR_UNEXPECTED)?);
+    let mut uuid = Uuid::default();
+    for (word, word_bytes) in uuid.data64.iter_mut().zip(uuid_bytes.chunks_exact(mem::size_of::<u64>())) {
+        *word = u64::from_ne_bytes(word_bytes.try_into().map_err(|_| SGX_ERROR_UNEXPECTED)?);
+    }
+    Ok((phone, uuid))
+}
+
+fn count_occupied(phones_data: &[u8]) -> usize {
+    (phones_data.chunks_exact(mem::size_of::<Phone>()))
+        .filter(|phone_data| phone_data.iter().any(|byte| *byte != 0))
+        .count()
+}
+
+// the entries that are the given ordinals, in ascending order, among the occupied entries of the directory
+fn read_occupied_entries(
+    in_phones: &UntrustedSlice<'_>,
+    in_uuids: &UntrustedSlice<'_>,
+    ordinals: &[usize],
+) -> Result<Vec<(Phone, Uuid)>, SgxStatus>
+{
+    let mut entries = Vec::with_capacity(ordinals.len());
+    let mut ordinals = ordinals.iter().peekable();
+    let mut occupied_ordinal: usize = 0;
+    let mut index: usize = 0;
+    let mut offset = 0;
+    while offset < in_phones.len() && ordinals.peek().is_some() {
+        let chunk_size = in_phones.len().saturating_sub(offset).min(DIGEST_READ_CHUNK_SIZE);
+        let chunk = (in_phones.offset(offset))
+            .read_bytes(chunk_size)
+            .map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
+        for phone_data in chunk.chunks_exact(mem::size_of::<Phone>()) {
+            if phone_data.iter().any(|byte| *byte != 0) {
+                while ordinals.peek() == Some(&&occupied_ordinal) {
+                    ordinals.next();
+                    entries.push(read_entry(in_phones, in_uuids, index)?);
+                }
+                occupied_ordinal = occupied_ordinal.saturating_add(1);
+            }
+            index = index.saturating_add(1);
+        }
+        offset = offset.saturating_add(chunk_size);
+    }
+
+    // the buffers were changed since they were hashed if an entry went missing or was emptied
+    if ordinals.peek().is_some() || entries.iter().any(|(phone, _)| *phone == EMPTY_PHONE) {
+        return Err(SGX_ERROR_INVALID_PARAMETER);
+    }
+    Ok(entries)
+}
+
+
+// len must be non-zero
+fn random_index(rng: &mut impl RngCore, len: usize) -> usize {
+    let len = len.to_u64();
+    // reject the values past the last whole multiple of len, so every index is equally likely
+    let reject_from = u64::max_value().saturating_sub(u64::max_value().checked_rem(len).unwrap_or_else(|| unreachable!()));
+    loop {
+        let value = rng.next_u64();
+        if value < reject_from {
+            let index = value.checked_rem(len).unwrap_or_else(|| unreachable!());
+            return index.try_into().unwrap_or_else(|_| unreachable!());
+        }
+    }
+}
+
+fn update_digest(context: &mut SHA256Context, data: &UntrustedSlice<'_>, mut on_chunk: impl FnMut(&[u8])) -> Result<(), SgxStatus> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let chunk_size = data.len().saturating_sub(offset).min(DIGEST_READ_CHUNK_SIZE);
+        let chunk = (data.offset(offset))
+            .read_bytes(chunk_size)
+            .map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
+        context.update(&chunk);
+        on_chunk(&chunk);
+        offset = offset.saturating_add(chunk_size);
+    }
+    Ok(())
+}
+
+//
+// tests
+//
+
+#[cfg(test)]
+mod tests {
+    use mockers::matchers::*;
+    use mockers::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    use super::*;
+
+    const MOCK_DIGEST: DirectoryDigest = [0x5a; 32];
+
+    fn commit_args(epoch: u64, in_phones: &[Phone], in_uuids: &[Uuid], expected_digest: DirectoryDigest) -> DirectoryCommitArgs {
+        DirectoryCommitArgs {
+            epoch,
+            in_phones: in_phones.as_ptr(),
+            in_phone_count: in_phones.len(),
+            in_uuids: in_uuids.as_ptr(),
+            expected_digest,
+        }
+    }
+
+    #[test]
+    fn test_commit() {
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(MOCK_DIGEST).times(..));
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+
+        let active_directory = ActiveDirectory::new();
+        let in_phones: Vec<Phone> = (0..4).map(|_| test_ffi::rand()).collect();
+        let in_uuids: Vec<Uuid> = (0..in_phones.len()).map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let first = commit_args(2, &in_phones, &in_uuids, MOCK_DIGEST);
+        assert_eq!(active_directory.pin_active(&(&first).into()).map(|(canaries, _)| canaries), Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH));
+        assert_eq!(active_directory.commit(&first), Ok(()));
+        assert_eq!(active_directory.pin_active(&(&first).into()).map(|(canaries, _)| canaries).map(drop), Ok(()));
+
+        // a generation the operator did not sign off on leaves the previous one active
+        let unsigned = commit_args(3, &in_phones[1..], &in_uuids[1..], [0; 32]);
+        assert_eq!(active_directory.commit(&unsigned), Err(CDS_ERROR_DIRECTORY_DIGEST_MISMATCH));
+        assert_eq!(active_directory.pin_active(&(&first).into()).map(|(canaries, _)| canaries).map(drop), Ok(()));
+        assert_eq!(active_directory.pin_active(&(&unsigned).into()).map(|(canaries, _)| canaries), Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH));
+
+        // epochs must increase
+        assert_eq!(active_directory.commit(&first), Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH));
+        assert_eq!(
+            active_directory.commit(&commit_args(1, &in_phones, &in_uuids, MOCK_DIGEST)),
+            Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH)
+        );
+        assert_eq!(
+            active_directory.commit(&commit_args(0, &in_phones, &in_uuids, MOCK_DIGEST)),
+            Err(SGX_ERROR_INVALID_PARAMETER)
+        );
+
+        let second = commit_args(3, &in_phones[1..], &in_uuids[1..], MOCK_DIGEST);
+        assert_eq!(active_directory.commit(&second), Ok(()));
+        assert_eq!(active_directory.pin_active(&(&first).into()).map(|(canaries, _)| canaries), Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH));
+        assert_eq!(active_directory.pin_active(&(&second).into()).map(|(canaries, _)| canaries).map(drop), Ok(()));
+
+        // canaries are entries of the generation they were committed with
+        let canaries = active_directory.pin_active(&(&second).into()).map(|(canaries, _)| canaries).unwrap();
+        assert_eq!(canaries.len(), CANARY_COUNT);
+        for canary in &canaries {
+            let index = in_phones.iter().position(|phone| *phone == canary.phone).unwrap();
+            assert!(index >= 1);
+            assert_eq!(canary.uuid, in_uuids[index]);
+        }
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+    }
+
+    #[test]
+    fn test_commit_canaries_skip_empty_slots() {
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(MOCK_DIGEST).times(..));
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+
+        let active_directory = ActiveDirectory::new();
+        let in_phones: Vec<Phone> = vec![EMPTY_PHONE, 0x1111, EMPTY_PHONE, EMPTY_PHONE, 0x2222, EMPTY_PHONE];
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|phone| Uuid { data64: [*phone, !*phone] }).collect();
+
+        let occupied = commit_args(2, &in_phones, &in_uuids, MOCK_DIGEST);
+        assert_eq!(active_directory.commit(&occupied), Ok(()));
+        let canaries = active_directory.pin_active(&(&occupied).into()).map(|(canaries, _)| canaries).unwrap();
+        assert_eq!(canaries.len(), CANARY_COUNT);
+        for canary in &canaries {
+            assert_ne!(canary.phone, EMPTY_PHONE);
+            assert_eq!(canary.uuid, Uuid { data64: [canary.phone, !canary.phone] });
+        }
+
+        // a generation with no occupied entries has nothing to vouch for it
+        let empty_phones = vec![EMPTY_PHONE; 4];
+        let empty = commit_args(3, &empty_phones, &in_uuids[..4], MOCK_DIGEST);
+        assert_eq!(active_directory.commit(&empty), Ok(()));
+        assert_eq!(active_directory.pin_active(&(&empty).into()).map(|(canaries, _)| canaries), Ok(Vec::new()));
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+    }
+
+    #[test]
+    fn test_commit_while_pinned() {
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(MOCK_DIGEST).times(..));
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+
+        let active_directory = ActiveDirectory::new();
+        let in_phones: Vec<Phone> = (0..4).map(|_| test_ffi::rand()).collect();
+        let in_uuids: Vec<Uuid> = (0..in_phones.len()).map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let first = commit_args(2, &in_phones, &in_uuids, MOCK_DIGEST);
+        let second = commit_args(3, &in_phones, &in_uuids, MOCK_DIGEST);
+        assert_eq!(active_directory.commit(&first), Ok(()));
+
+        // the pinned generation stays active until every pin on it is dropped
+        let (_, first_pin) = active_directory.pin_active(&(&first).into()).unwrap();
+        let (_, second_pin) = active_directory.pin_active(&(&first).into()).unwrap();
+        assert_eq!(active_directory.commit(&second), Err(SGX_ERROR_INVALID_STATE));
+        drop(first_pin);
+        assert_eq!(active_directory.commit(&second), Err(SGX_ERROR_INVALID_STATE));
+        assert_eq!(active_directory.pin_active(&(&first).into()).map(drop), Ok(()));
+        drop(second_pin);
+        assert_eq!(active_directory.commit(&second), Ok(()));
+        assert_eq!(active_directory.pin_active(&(&first).into()).map(drop), Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH));
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+    }
+
+    #[test]
+    fn test_sample() {
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(MOCK_DIGEST).times(..));
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+
+        // each uuid is derived from its phone, to check entries aren't torn
+        let in_phones: Vec<Phone> = (0..4).map(|_| test_ffi::rand()).collect();
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|phone| Uuid { data64: [*phone, !*phone] }).collect();
+        let active_directory = ActiveDirectory::new();
+        let mut rng = ChaChaRng::from_seed([7; 32]);
+        let mut samples = vec![DirectorySample::default(); 64];
+
+        let args = DirectorySampleArgs {
+            epoch:      2,
+            sample_key: [0x11; 32],
+        };
+        assert_eq!(active_directory.sample(&args, &mut samples, &mut rng), Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH));
+        assert_eq!(active_directory.commit(&commit_args(2, &in_phones, &in_uuids, MOCK_DIGEST)), Ok(()));
+        assert_eq!(
+            active_directory.sample(&DirectorySampleArgs { epoch: 1, ..args }, &mut samples, &mut rng),
+            Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH)
+        );
+
+        let hmac = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256HMAC, &scenario);
+        scenario.expect(hmac.hmac_key_init(check(|key: &&[u8]| *key == &[0x11; 32][..])).and_return(()));
+        let expected_phones = in_phones.clone();
+        scenario.expect(
+            hmac.hmac_update(check(move |data: &&[u8]| expected_phones.iter().any(|phone| *data == &phone.to_le_bytes()[..])))
+                .and_return_clone(())
+                .times(samples.len() as u32),
+        );
+        scenario.expect(hmac.hmac_out().and_return_clone([0x22; 32]).times(..));
+        assert_eq!(active_directory.sample(&args, &mut samples, &mut rng), Ok(()));
+
+        for sample in &samples {
+            assert_eq!(sample.phone_hash, [0x22; 32]);
+            assert!(in_uuids.contains(&sample.uuid));
+        }
+        assert!(samples.iter().any(|sample| sample.uuid != samples[0].uuid));
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256HMAC);
+    }
+
+    #[test]
+    fn test_sample_skips_empty_slots() {
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(MOCK_DIGEST).times(..));
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+
+        let occupied_phone: Phone = 0x1111;
+        let in_phones: Vec<Phone> = vec![EMPTY_PHONE, occupied_phone, EMPTY_PHONE, EMPTY_PHONE];
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|phone| Uuid { data64: [*phone, !*phone] }).collect();
+        let active_directory = ActiveDirectory::new();
+        let mut rng = ChaChaRng::from_seed([7; 32]);
+        let mut samples = vec![DirectorySample::default(); 16];
+        let args = DirectorySampleArgs {
+            epoch:      2,
+            sample_key: [0x11; 32],
+        };
+        assert_eq!(active_directory.commit(&commit_args(2, &in_phones, &in_uuids, MOCK_DIGEST)), Ok(()));
+
+        let hmac = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256HMAC, &scenario);
+        scenario.expect(hmac.hmac_key_init(any()).and_return_clone(()).times(..));
+        scenario.expect(
+            hmac.hmac_update(check(move |data: &&[u8]| *data == &occupied_phone.to_le_bytes()[..]))
+                .and_return_clone(())
+                .times(samples.len() as u32),
+        );
+        scenario.expect(hmac.hmac_out().and_return_clone([0x22; 32]).times(..));
+        assert_eq!(active_directory.sample(&args, &mut samples, &mut rng), Ok(()));
+        assert!(samples.iter().all(|sample| sample.uuid == in_uuids[1]));
+
+        // a generation with no occupied entries has nothing to sample
+        let empty_phones = vec![EMPTY_PHONE; 4];
+        assert_eq!(active_directory.commit(&commit_args(3, &empty_phones, &in_uuids, MOCK_DIGEST)), Ok(()));
+        assert_eq!(
+            active_directory.sample(&DirectorySampleArgs { epoch: 3, ..args }, &mut samples, &mut rng),
+            Err(SGX_ERROR_INVALID_STATE)
+        );
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256HMAC);
+    }
+}
@@ -0,0 +1,187 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Lookup of the distinct phones of a batch.
+//!
+//! Requests in the same batch often ask about the same phones, so the directory can be looked up for each distinct
+//! phone of the batch once, and the result of each query phone then copied out of that of its distinct phone. Neither
+//! step may give away which phones the requests share: the distinct phones are found with a bitonic sort, whose
+//! compare-exchanges are the same whatever the phones, and the results are copied out through the hash lookup, as if
+//! the distinct phones were a directory of their own. All that's given away is how many distinct phones there are, by
+//! the number of lookup chunks they take.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem;
+
+use sgx_ffi::sgx::*;
+use sgx_ffi::util::{memset_s, SecretAllocation, SecretBuffer, SecretValue, ToUsize};
+
+use crate::ffi::hash_lookup::*;
+use crate::service::sort::*;
+
+//
+// public API
+//
+
+pub struct DistinctLookup {
+    phones: Vec<Phone>,
+    results: SecretValue<SecretBuffer>,
+    bytes_per_result: usize,
+}
+
+//
+// internal
+//
+
+const BYTES_PER_UUID: usize = mem::size_of::<Uuid>();
+
+// pads the phones being sorted out to a power of two, and stands in for each repeat of a phone, sorting after any phone
+const NO_PHONE: Phone = Phone::max_value();
+
+//
+// DistinctLookup impls
+//
+
+impl DistinctLookup {
+    /// Finds the distinct phones of `query_phones`, with room for a result of `bytes_per_result` bytes for each.
+    pub fn new(query_phones: &[Phone], bytes_per_result: usize) -> Result<Self, SgxStatus> {
+        let padded_len = query_phones.len().checked_next_power_of_two().ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+        let mut phones = Vec::with_capacity(padded_len);
+        phones.extend_from_slice(query_phones);
+        phones.resize(padded_len, NO_PHONE);
+        bitonic_sort(&mut phones);
+
+        // replace each phone equal to the one before it, working backwards so each is compared to the phone sorted before it
+        for index in (1..phones.len()).rev() {
+            let (before, after) = phones.split_at_mut(index);
+            if let (Some(previous), Some(phone)) = (before.last(), after.first_mut()) {
+                let difference = *phone ^ *previous;
+                let repeat = ((difference | difference.wrapping_neg()) >> 63) ^ 1;
+                *phone |= repeat.wrapping_neg();
+            }
+        }
+        bitonic_sort(&mut phones);
+
+        let distinct_count = (phones.iter())
+            .map(|phone| {
+                let bits = !phone;
+                (bits | bits.wrapping_neg()) >> 63
+            })
+            .fold(0u64, u64::wrapping_add);
+        phones.truncate(distinct_count.to_usize());
+
+        let results_len = phones.len().checked_mul(bytes_per_result).ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+        Ok(Self {
+            phones,
+            // cache line aligned for the vectorized hash lookup writing into it
+            results: SecretValue::new(SecretBuffer::new(results_len, SecretAllocation::CacheLine)?),
+            bytes_per_result,
+        })
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.phones.chunks(MAX_HASH_TABLE_SIZE).len()
+    }
+
+    /// The distinct phones of a lookup chunk, and where their results go.
+    pub fn chunk_mut(&mut self, chunk_index: usize) -> Option<(&[Phone], &mut [u8])> {
+        let phones = self.phones.chunks(MAX_HASH_TABLE_SIZE).nth(chunk_index)?;
+        let results_start = chunk_index.saturating_mul(MAX_HASH_TABLE_SIZE).saturating_mul(self.bytes_per_result);
+        let results_len = phones.len().saturating_mul(self.bytes_per_result);
+        let results = (self.results.get_mut().get_mut(results_start..))?.get_mut(..results_len)?;
+        Some((phones, results))
+    }
+
+    /// Copies the result of each of `query_phones`, which must be among the distinct phones, into `query_phones_result`,
+    /// a uuid-sized word of each result at a time.
+    pub fn fan_out(&self, query_phones: &[Phone], query_phones_result: &mut [u8]) -> Result<(), SgxStatus> {
+        if query_phones_result.len() != query_phones.len().saturating_mul(self.bytes_per_result) {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        let mut in_words = SecretValue::new(vec![0u8; self.phones.len().saturating_mul(BYTES_PER_UUID)]);
+        let mut word_results = SecretValue::new(vec![0u8; query_phones.len().saturating_mul(BYTES_PER_UUID)]);
+        for word_start in (0..self.bytes_per_result).step_by(BYTES_PER_UUID) {
+            let word_len = self.bytes_per_result.saturating_sub(word_start).min(BYTES_PER_UUID);
+            let distinct_results = self.results.get().chunks_exact(self.bytes_per_result);
+            for (in_word, distinct_result) in in_words.get_mut().chunks_exact_mut(BYTES_PER_UUID).zip(distinct_results) {
+                let distinct_result_word = distinct_result.get(word_start..).unwrap_or_default();
+                for (in_word_byte, result_byte) in in_word.iter_mut().zip(distinct_result_word.iter().chain(core::iter::repeat(&0))) {
+                    *in_word_byte = *result_byte;
+                }
+            }
+            unsafe {
+                hash_lookup(
+                    self.phones.as_ptr() as *const u8,
+                    in_words.get().as_ptr(),
+                    self.phones.len(),
+                    query_phones,
+                    word_results.get_mut(),
+                )?;
+            }
+            let word_results_iter = word_results.get().chunks_exact(BYTES_PER_UUID);
+            for (query_phone_result, word_result) in query_phones_result.chunks_exact_mut(self.bytes_per_result).zip(word_results_iter) {
+                let query_phone_result_word = (query_phone_result.get_mut(word_start..)).and_then(|rest| rest.get_mut(..word_len));
+                if let (Some(query_phone_result_word), Some(word_result)) = (query_phone_result_word, word_result.get(..word_len)) {
+                    query_phone_result_word.copy_from_slice(word_result);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DistinctLookup {
+    fn drop(&mut self) {
+        let byte_len = self.phones.len().saturating_mul(mem::size_of::<Phone>());
+        let clear_res = unsafe { memset_s(self.phones.as_mut_ptr() as *mut c_void, byte_len, 0, byte_len) };
+        assert_eq!(clear_res, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distinct_phones() {
+        let query_phones: Vec<Phone> = vec![7, 3, 7, 1, 12, 3, 3, 9];
+        let distinct_lookup = DistinctLookup::new(&query_phones, BYTES_PER_UUID).unwrap();
+        assert_eq!(distinct_lookup.phones, vec![1, 3, 7, 9, 12]);
+        assert_eq!(distinct_lookup.chunk_count(), 1);
+
+        let distinct_lookup = DistinctLookup::new(&[], BYTES_PER_UUID).unwrap();
+        assert!(distinct_lookup.phones.is_empty());
+        assert_eq!(distinct_lookup.chunk_count(), 0);
+    }
+
+    #[test]
+    fn test_fan_out() {
+        // results wider than a uuid are copied out a word at a time, the last one partly
+        let bytes_per_result = BYTES_PER_UUID + 4;
+        let query_phones: Vec<Phone> = vec![5, 2, 5, 8, 2];
+        let mut distinct_lookup = DistinctLookup::new(&query_phones, bytes_per_result).unwrap();
+        let (distinct_phones, distinct_results) = distinct_lookup.chunk_mut(0).unwrap();
+        assert_eq!(distinct_phones, &[2, 5, 8]);
+        for (index, distinct_result) in distinct_results.chunks_exact_mut(bytes_per_result).enumerate() {
+            for (byte_index, byte) in distinct_result.iter_mut().enumerate() {
+                *byte = (index * bytes_per_result + byte_index + 1) as u8;
+            }
+        }
+        let expected_results: Vec<u8> = (query_phones.iter())
+            .flat_map(|phone| {
+                let index = [2, 5, 8].iter().position(|distinct_phone| distinct_phone == phone).unwrap();
+                (0..bytes_per_result).map(move |byte_index| (index * bytes_per_result + byte_index + 1) as u8)
+            })
+            .collect();
+
+        let mut query_phones_result = vec![0; query_phones.len() * bytes_per_result];
+        distinct_lookup.fan_out(&query_phones, &mut query_phones_result).unwrap();
+        assert_eq!(query_phones_result, expected_results);
+    }
+}
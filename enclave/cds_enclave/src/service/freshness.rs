@@ -0,0 +1,113 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Directory freshness feedback from lookup miss rates.
+//!
+//! Each finished batch folds the fraction of its queries the directory didn't find into an exponentially weighted
+//! moving average, in parts per million, which the host reads back with the server metrics. A sudden rise in it usually
+//! means the host is looking batches up in a stale or truncated directory. Only batches of at least a minimum size are
+//! sampled, since the miss rate of a batch of a few requests would say how many of those clients' contacts are
+//! registered.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+//
+// public API
+//
+
+pub struct LookupMissRate {
+    min_batch_phones: u64,
+    ewma_ppm:         AtomicU64,
+}
+
+pub static LOOKUP_MISS_RATE: LookupMissRate = LookupMissRate::new(MIN_SAMPLED_BATCH_PHONES);
+
+//
+// internal
+//
+
+const MIN_SAMPLED_BATCH_PHONES: u64 = 1024;
+
+const PARTS_PER_MILLION: u64 = 1_000_000;
+
+// each sample is weighted 1/2^EWMA_WEIGHT_SHIFT against the average so far
+const EWMA_WEIGHT_SHIFT: u32 = 3;
+
+const NO_SAMPLES: u64 = u64::max_value();
+
+//
+// LookupMissRate impls
+//
+
+impl LookupMissRate {
+    pub const fn new(min_batch_phones: u64) -> Self {
+        Self {
+            min_batch_phones,
+            ewma_ppm: AtomicU64::new(NO_SAMPLES),
+        }
+    }
+
+    /// Folds the miss rate of a finished batch of `phone_count` queries into the average.
+    pub fn record(&self, miss_count: u64, phone_count: u64) {
+        if phone_count < self.min_batch_phones {
+            return;
+        }
+        let sample_ppm = match miss_count.min(phone_count).saturating_mul(PARTS_PER_MILLION).checked_div(phone_count) {
+            Some(sample_ppm) => sample_ppm,
+            None => return,
+        };
+        let mut ewma_ppm = self.ewma_ppm.load(Ordering::Relaxed);
+        loop {
+            let new_ewma_ppm = if ewma_ppm == NO_SAMPLES {
+                sample_ppm
+            } else {
+                (ewma_ppm.saturating_mul((1 << EWMA_WEIGHT_SHIFT) - 1).saturating_add(sample_ppm)) >> EWMA_WEIGHT_SHIFT
+            };
+            match (self.ewma_ppm).compare_exchange_weak(ewma_ppm, new_ewma_ppm, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current_ewma_ppm) => ewma_ppm = current_ewma_ppm,
+            }
+        }
+    }
+
+    /// The average miss rate in parts per million, or `None` if no batch has been sampled yet.
+    pub fn ewma_ppm(&self) -> Option<u64> {
+        match self.ewma_ppm.load(Ordering::Relaxed) {
+            NO_SAMPLES => None,
+            ewma_ppm => Some(ewma_ppm),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let miss_rate = LookupMissRate::new(10);
+        assert_eq!(miss_rate.ewma_ppm(), None);
+
+        // batches below the minimum size aren't sampled
+        miss_rate.record(9, 9);
+        assert_eq!(miss_rate.ewma_ppm(), None);
+
+        // the first sample is taken as is
+        miss_rate.record(5, 10);
+        assert_eq!(miss_rate.ewma_ppm(), Some(500_000));
+
+        miss_rate.record(100, 100);
+        assert_eq!(miss_rate.ewma_ppm(), Some(562_500));
+
+        miss_rate.record(0, 100);
+        assert_eq!(miss_rate.ewma_ppm(), Some(492_187));
+
+        // more misses than queries count as all of them
+        miss_rate.record(u64::max_value(), 10);
+        assert_eq!(miss_rate.ewma_ppm(), Some(555_663));
+    }
+}
@@ -0,0 +1,197 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Fail-stop handling of host protocol violations.
+//!
+//! Enclaves built with the `strict` feature halt on the first violation of the host contract they detect,
+//! such as buffers pointing into the enclave, buffers overlapping one another, sizes that cannot describe a
+//! valid buffer, or calls made to a server while the lookup of its batch is under way. Every later call into
+//! the service is refused with `CDS_ERROR_ENCLAVE_HALTED` until the enclave is restarted, and the host can
+//! fetch a record of the first violation, sealed to the enclave, to keep for later inspection. Without the
+//! feature a violation only fails the call it was detected in. Sealed record payload (integers little-endian):
+//!
+//! ```text
+//! status:u32 violation:u32 violation_count:u64
+//! ```
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use sgx_ffi::sgx::*;
+use sgx_ffi::untrusted_slice::UntrustedSlice;
+use sgxsd_ffi::sealed::SealedBlob;
+
+use crate::ffi::sgxsd::*;
+
+//
+// public API
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostViolation {
+    UntrustedPointer   = 1,
+    OverlappingBuffers = 2,
+    BufferSize         = 3,
+    CallOrder          = 4,
+}
+
+pub struct IncidentLatch {
+    strict:          bool,
+    incident:        AtomicU64,
+    violation_count: AtomicU64,
+}
+
+pub static INCIDENT_LATCH: IncidentLatch = IncidentLatch::new(cfg!(feature = "strict"));
+
+//
+// internal
+//
+
+const INCIDENT_RECORD_KIND: u8 = 1;
+const INCIDENT_RECORD_KDF_LABEL: &[u8] = b"cds incident record";
+
+//
+// IncidentLatch impls
+//
+
+impl IncidentLatch {
+    pub const fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            incident: AtomicU64::new(0),
+            violation_count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn check_service(&self) -> Result<(), SgxStatus> {
+        if self.incident.load(Ordering::Acquire) != 0 {
+            Err(CDS_ERROR_ENCLAVE_HALTED)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Records a host protocol violation detected while handling a call, which fails with `error`.
+    pub fn violation(&self, violation: HostViolation, error: SgxStatus) -> SgxStatus {
+        if self.strict {
+            self.violation_count.fetch_add(1, Ordering::Relaxed);
+            let incident = (u64::from(violation as u32) << 32) | u64::from(error);
+            let _ = self.incident.compare_exchange(0, incident, Ordering::AcqRel, Ordering::Acquire);
+        }
+        error
+    }
+
+    /// Fails the call with `SGX_ERROR_INVALID_PARAMETER` if any two of `buffers` overlap. Only checked in
+    /// strict mode, as the buffers are only read from.
+    pub fn check_disjoint(&self, buffers: &[&UntrustedSlice<'_>]) -> Result<(), SgxStatus> {
+        if !self.strict {
+            return Ok(());
+        }
+        for (index, buffer) in buffers.iter().enumerate() {
+            for other in buffers.iter().skip(index.saturating_add(1)) {
+                if overlaps(buffer, other) {
+                    return Err(self.violation(HostViolation::OverlappingBuffers, SGX_ERROR_INVALID_PARAMETER));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the first violation recorded sealed with `sealing_key`, if any.
+    pub fn sealed_record(&self, sealing_key: &[u8; 32]) -> Result<Option<Vec<u8>>, SgxStatus> {
+        let incident = self.incident.load(Ordering::Acquire);
+        if incident == 0 {
+            return Ok(None);
+        }
+        let mut record = Vec::with_capacity(16);
+        record.extend_from_slice(&incident.to_le_bytes());
+        record.extend_from_slice(&self.violation_count.load(Ordering::Relaxed).to_le_bytes());
+        SealedBlob::seal(sealing_key, INCIDENT_RECORD_KIND, INCIDENT_RECORD_KDF_LABEL, &record).map(Some)
+    }
+}
+
+//
+// helpers
+//
+
+fn overlaps(a: &UntrustedSlice<'_>, b: &UntrustedSlice<'_>) -> bool {
+    let a_start = a.as_ptr() as usize;
+    let b_start = b.as_ptr() as usize;
+    a.len() != 0 && b.len() != 0 && a_start < b_start.saturating_add(b.len()) && b_start < a_start.saturating_add(a.len())
+}
+
+//
+// tests
+//
+
+#[cfg(test)]
+mod tests {
+    use mockers::matchers::*;
+    use mockers::*;
+
+    use super::*;
+
+    #[test]
+    fn test_best_effort() {
+        let latch = IncidentLatch::new(false);
+        assert_eq!(latch.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER), SGX_ERROR_INVALID_PARAMETER);
+        assert_eq!(latch.check_service(), Ok(()));
+        assert_eq!(latch.sealed_record(&[1; 32]), Ok(None));
+    }
+
+    #[test]
+    fn test_check_disjoint() {
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+
+        let mut data = [0u8; 16];
+        let first = UntrustedSlice::new(data.as_mut_ptr(), 8).unwrap();
+        let second = UntrustedSlice::new(data[8..].as_mut_ptr(), 8).unwrap();
+        let straddling = UntrustedSlice::new(data[4..].as_mut_ptr(), 8).unwrap();
+        let empty = UntrustedSlice::new(data[4..].as_mut_ptr(), 0).unwrap();
+
+        let best_effort = IncidentLatch::new(false);
+        assert_eq!(best_effort.check_disjoint(&[&first, &straddling]), Ok(()));
+
+        let strict = IncidentLatch::new(true);
+        assert_eq!(strict.check_disjoint(&[&first, &second, &empty]), Ok(()));
+        assert_eq!(strict.check_service(), Ok(()));
+        assert_eq!(strict.check_disjoint(&[&first, &second, &straddling]), Err(SGX_ERROR_INVALID_PARAMETER));
+        assert_eq!(strict.check_service(), Err(CDS_ERROR_ENCLAVE_HALTED));
+    }
+
+    #[test]
+    fn test_strict() {
+        let latch = IncidentLatch::new(true);
+        assert_eq!(latch.check_service(), Ok(()));
+        assert_eq!(latch.sealed_record(&[1; 32]), Ok(None));
+
+        assert_eq!(latch.violation(HostViolation::OverlappingBuffers, SGX_ERROR_INVALID_PARAMETER), SGX_ERROR_INVALID_PARAMETER);
+        assert_eq!(latch.violation(HostViolation::BufferSize, SGX_ERROR_UNEXPECTED), SGX_ERROR_UNEXPECTED);
+        assert_eq!(latch.check_service(), Err(CDS_ERROR_ENCLAVE_HALTED));
+
+        // only the first violation is recorded, along with how many were detected in all
+        let mut expected_record = Vec::new();
+        expected_record.extend_from_slice(&SGX_ERROR_INVALID_PARAMETER.to_le_bytes());
+        expected_record.extend_from_slice(&(HostViolation::OverlappingBuffers as u32).to_le_bytes());
+        expected_record.extend_from_slice(&2u64.to_le_bytes());
+
+        let scenario = Scenario::new();
+        let encrypt = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_AES_GCM_ENCRYPT, &scenario);
+        scenario.expect(
+            encrypt
+                .sgxsd_aes_gcm_encrypt(any(), check(move |src| *src == &expected_record[..]), any(), any())
+                .and_return(Ok(vec![0; 16])),
+        );
+
+        let record = latch.sealed_record(&[1; 32]).unwrap().unwrap();
+        assert_eq!(SealedBlob::parse(&record).unwrap().kind, INCIDENT_RECORD_KIND);
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_AES_GCM_ENCRYPT);
+    }
+}
@@ -6,6 +6,7 @@
 //
 
 use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::vec;
 use alloc::vec::Vec;
 use core::convert::TryInto;
@@ -16,21 +17,51 @@ use core::ops::{Deref, DerefMut};
 use core::slice;
 
 use sgx_ffi::sgx::*;
-use sgx_ffi::untrusted_slice::UntrustedSlice;
-use sgx_ffi::util::{memset_s, SecretValue, ToUsize};
+use sgx_ffi::untrusted_slice::{UntrustedReadLimit, UntrustedSlice};
+use sgx_ffi::util::{clear, memset_s, SecretAllocation, SecretBuffer, SecretValue, ToU64, ToUsize};
 use sgxsd_ffi::ecalls::*;
 use sgxsd_ffi::{AesGcmKey, SHA256Context};
 
 use crate::ffi::hash_lookup::*;
 use crate::ffi::sgxsd::*;
+use crate::service::canonicalize::*;
+use crate::service::chunks::*;
+use crate::service::delta::*;
+use crate::service::denylist::*;
+use crate::service::directory::*;
+use crate::service::distinct::*;
+use crate::service::freshness::*;
+use crate::service::incident::*;
+use crate::service::reply::*;
+use crate::service::reply_scratch::*;
+use crate::service::sealed_results::*;
+use crate::service::sort::*;
 
 //
 // public API
 //
 
 pub struct SgxsdServerState {
-    requests: Vec<PendingRequest>,
+    requests: VecDeque<PendingRequest>,
+    request_indices: BTreeMap<QueryId, usize>,
+    request_id_indices: BTreeMap<(SessionId, u64), RequestIdIndex>,
+    partial_requests: BTreeMap<(SessionId, u64), PartialRequest>,
     query_phones: PhoneList,
+    query_uuids: UuidList,
+    query_username_hashes: UsernameHashList,
+    session_phone_counts: BTreeMap<SessionId, usize>,
+    fair_admission_phones: usize,
+    max_phones_per_request: usize,
+    interactive_reserve_phones: usize,
+    max_untrusted_read_bytes: usize,
+    miss_rate_alert_ppm: u64,
+    require_sorted_query: bool,
+    reply_padding_size: usize,
+    dedup_query_phones: bool,
+    canonicalization_rules: CanonicalizationRules,
+    lookup: Option<BatchLookup>,
+    request_phone_count_histogram: [u64; REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS],
+    reply_scratch: ReplyScratch,
 }
 
 //
@@ -42,19 +73,137 @@ const BYTES_PER_UUID: usize = mem::size_of::<Uuid>();
 
 const COMMITMENT_NONCE_SIZE: usize = 32;
 
+// E.164 numbers have at most 15 digits
+const MAX_E164_NUMBER: u64 = 999_999_999_999_999;
+
+// looked up in place of each uuid key, whose result is then replaced by that of the uuid lookup
+const UUID_KEY_PLACEHOLDER_PHONE: u64 = 1;
+
+// a query may instead tag each key, zero-padded to the size of a uuid, with a byte telling whether it's a phone or a uuid
+const BYTES_PER_TAGGED_KEY: usize = BYTES_PER_UUID + 1;
+const QUERY_KEY_TAG_PHONE: u8 = 0;
+const QUERY_KEY_TAG_UUID: u8 = 1;
+
+// a query may instead be of 128-bit identifiers looked up as uuids, read as such only when the client names their size
+const BYTES_PER_IDENTIFIER: usize = BYTES_PER_UUID;
+const IDENTIFIER_QUERY_COMMITMENT_LABEL: &[u8] = b"cds identifier query";
+
+// a query may instead be of username hashes, looked up in the username table and committed to under their own label
+const BYTES_PER_USERNAME_HASH: usize = USERNAME_HASH_SIZE;
+const USERNAME_HASH_QUERY_COMMITMENT_LABEL: &[u8] = b"cds username hash query";
+
+// a query in CDS_PROTOCOL_VERSION_1 sends its phones as varint deltas, committed to as sent under their own label
+const DELTA_QUERY_COMMITMENT_LABEL: &[u8] = b"cds delta query";
+
+// a query may also be committed to a chunk at a time, so that a bad chunk is refused as soon as its call decrypts it
+const CHUNK_COMMITMENT_LABEL: &[u8] = b"cds query chunk";
+
+// the tag of a key followed by the words of the longest key, a username hash
+const SORT_KEY_WORDS: usize = 1 + BYTES_PER_USERNAME_HASH / BYTES_PER_PHONE;
+// pads the sort keys of a query out to a power of two for check_distinct; every key sorts before it, since a tag is a byte
+const NO_SORT_KEY: [u64; SORT_KEY_WORDS] = [u64::max_value(); SORT_KEY_WORDS];
+
+const QUEUE_AGE_HISTOGRAM_BUCKETS: usize = CDS_QUEUE_AGE_HISTOGRAM_BUCKETS as usize;
+const REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS: usize = CDS_REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS as usize;
+
 struct PhoneList(Vec<Phone>);
 
+// the uuid keys of a batch, kept only once a request with uuid keys is in it, with a zero uuid for each phone key
+struct UuidList(Vec<Uuid>);
+
+// the username hash keys of a batch, kept only once a request of username hashes is in it, zero for every other key
+struct UsernameHashList(Vec<UsernameHash>);
+
 struct PendingRequest {
     from: SgxsdMsgFrom,
+    response_key: Option<AesGcmKey>,
+    duplicates: Vec<DuplicateRequest>,
+    commitment_nonce: SecretValue<[u8; COMMITMENT_NONCE_SIZE]>,
     request_phone_count: u32,
+    reply_layout: ReplyLayout,
+    since_change_token: u64,
+    reply_flags: ReplyFlags,
+    admission_ticks: u64,
+}
+
+// the pending request a session gave a request id, with what a retry under that id must share with it
+struct RequestIdIndex {
+    request_index: usize,
+    query_commitment: [u8; SHA256Context::hash_len()],
+    reply_layout: ReplyLayoutId,
+    since_change_token: u64,
+    protocol_version: ProtocolVersionId,
+    reply_flags: u32,
+}
+
+// a byte-identical resubmission of a pending request, replied to alongside it, under its own response key if it sent one
+struct DuplicateRequest {
+    from: SgxsdMsgFrom,
+    response_key: Option<AesGcmKey>,
+}
+
+// the fragments of a query handed in so far by a session under a fragment token, decrypted and joined
+struct PartialRequest {
+    query_data: SecretValue<Box<[u8]>>,
+    query_phone_count: usize,
+    query_commitment: [u8; SHA256Context::hash_len()],
+    chunk_commitments: Option<Vec<u8>>,
+    reply_layout: ReplyLayoutId,
+    since_change_token: u64,
+}
+
+// the wire format of a query, as named by the client, each version of which has its own decoder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ProtocolVersion {
+    V0,
+    V1,
+}
+
+// the lookup of a batch the host has had stop part way through, to make other calls before it carries on with the rest
+struct BatchLookup {
+    directory: StopArgs,
+    directory_pin: Option<DirectoryPin<'static>>,
+    chunks: LookupChunks,
+    distinct: Option<DistinctLookup>,
+    reply_batch: ReplyBatch,
+    sealed_results: SealedResults,
+    in_query_phones_result: SecretValue<SecretBuffer>,
+    in_query_phones_result_done_len: usize,
+    in_query_phones_result_replied_len: usize,
+    miss_count: u64,
+    reply_error: Option<SgxStatus>,
+}
+
+// the directory pointers refer to untrusted memory, which is checked again on each stop call before being read
+unsafe impl Send for BatchLookup {}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct QueryId {
+    iv: [u8; SGXSD_AES_GCM_IV_SIZE as usize],
+    mac: [u8; SGXSD_AES_GCM_MAC_SIZE as usize],
+    commitment: [u8; SHA256Context::hash_len()],
+    reply_flags: u32,
+    reply_layout: ReplyLayoutId,
+    since_change_token: u64,
+    protocol_version: ProtocolVersionId,
+    query_key_size: u32,
 }
 
 pub struct Request {
     pub(crate) phones: RequestPhoneList,
 }
 
+/// A phone key of a query: a non-zero E.164 number, big-endian as clients send it and the directory holds it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct E164Phone(Phone);
+
+/// A uuid key of a query, which is never zero, since a zero uuid stands for a phone key in a batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountUuid(Uuid);
+
 pub struct RequestPhoneList {
     data: SecretValue<Box<[u8]>>,
+    bytes_per_key: usize,
 }
 
 //
@@ -62,42 +211,558 @@ pub struct RequestPhoneList {
 //
 
 impl SgxsdServerState {
-    fn decode_request<'a>(&mut self, args: &'a CallArgs, request_data: &[u8]) -> Result<Request, SgxStatus> {
-        if (args.query_phone_count == 0 || args.query_phone_count.to_usize() > self.query_phones.capacity() - self.query_phones.len()) {
+    // a query handed in as fragments is only decoded once its last fragment arrives; None for the calls before that
+    fn decode_request(
+        &mut self,
+        args: &CallArgs,
+        request_data: &[u8],
+        read_limit: &UntrustedReadLimit,
+        session_id: &SessionId,
+    ) -> Result<Option<Request>, SgxStatus>
+    {
+        // fragments are put together the same way in every version, which only tells how the whole query is decoded
+        let protocol_version = ProtocolVersion::from_id(args.protocol_version)?;
+        if args.query_phone_count == 0 {
             return Err(SGX_ERROR_INVALID_PARAMETER);
         }
-        return Self::decode_phone_list(args, request_data);
+        if args.query_fragment_token == 0 {
+            self.check_request_phone_limit(args.query_phone_count.to_usize())?;
+            // told apart from a malformed request, so the host can have the client retry in a later batch
+            if args.query_phone_count.to_usize() > self.query_phones.capacity().saturating_sub(self.held_phone_count()) {
+                return Err(CDS_ERROR_BATCH_FULL);
+            }
+            self.check_interactive_reserve(args.priority, args.query_phone_count.to_usize())?;
+            if args.query_more_fragments != 0 {
+                return Err(SGX_ERROR_INVALID_PARAMETER);
+            }
+            let mut request = Self::decode_phone_list(args, request_data, read_limit)?;
+            request.phones.canonicalize(&self.canonicalization_rules);
+            self.check_request(&request)?;
+            return Ok(Some(request));
+        }
+
+        // the fragments handed in so far are taken out of those held, and so dropped if this one is refused
+        let partial_request = self.partial_requests.remove(&(*session_id, args.query_fragment_token));
+        let partial_phone_count = partial_request.as_ref().map_or(0, |partial_request| partial_request.query_phone_count);
+        let query_phone_count = args.query_phone_count.to_usize().saturating_add(partial_phone_count);
+        self.check_request_phone_limit(query_phone_count)?;
+        if query_phone_count > self.query_phones.capacity().saturating_sub(self.held_phone_count()) {
+            return Err(CDS_ERROR_BATCH_FULL);
+        }
+        self.check_interactive_reserve(args.priority, query_phone_count)?;
+        let query_data = Self::read_query(args, read_limit)?;
+        let query_data = Self::decrypt_query(args, request_data, query_data)?;
+        let fragment_chunk_commitments = Self::verify_chunk_commitments(args, query_data.get(), read_limit)?;
+        let partial_request = PartialRequest::add_fragment(partial_request, args, query_data, fragment_chunk_commitments)?;
+        if args.query_more_fragments != 0 {
+            self.partial_requests.insert((*session_id, args.query_fragment_token), partial_request);
+            return Ok(None);
+        }
+
+        let chunk_commitments = partial_request.chunk_commitments.as_deref();
+        let mut request = match protocol_version {
+            ProtocolVersion::V0 => {
+                let query_data_len = partial_request.query_data.get().len();
+                let bytes_per_key = Self::query_bytes_per_key(query_data_len, partial_request.query_phone_count, args.query_key_size)?;
+                Self::decode_query(partial_request.query_data, bytes_per_key, &args.query_commitment, chunk_commitments)?
+            }
+            ProtocolVersion::V1 => {
+                Self::decode_delta_query(partial_request.query_data, partial_request.query_phone_count, args, chunk_commitments)?
+            }
+        };
+        request.phones.canonicalize(&self.canonicalization_rules);
+        self.check_request(&request)?;
+        Ok(Some(request))
     }
 
-    pub fn decode_phone_list<'a>(args: &'a CallArgs, request_data: &[u8]) -> Result<Request, SgxStatus> {
-        let query_data_slice = UntrustedSlice::new(args.query.data, args.query.size.to_usize()).map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
-        let mut query_phones = RequestPhoneList::new(
-            query_data_slice
-                .read_bytes(args.query.size.to_usize())
-                .map_err(|_| SGX_ERROR_INVALID_PARAMETER)?
-                .into_boxed_slice(),
-        );
-        let query_phones_data_len = (query_phones.data.get().len())
+    // keys in strictly ascending order are distinct already, so they needn't be sorted again to check that they are
+    fn check_request(&self, request: &Request) -> Result<(), SgxStatus> {
+        if self.require_sorted_query {
+            request.phones.check_sorted()
+        } else {
+            request.phones.check_distinct()
+        }
+    }
+
+    // told apart from a full batch, since a query over the limit won't fit in any later batch either
+    fn check_request_phone_limit(&self, query_phone_count: usize) -> Result<(), SgxStatus> {
+        match self.max_phones_per_request {
+            0 => Ok(()),
+            max_phones_per_request if query_phone_count > max_phones_per_request => Err(CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED),
+            _ => Ok(()),
+        }
+    }
+
+    // a bulk request is deferred rather than take up the capacity kept for interactive ones
+    fn check_interactive_reserve(&self, priority: RequestPriorityId, query_phone_count: usize) -> Result<(), SgxStatus> {
+        let remaining_capacity = self.query_phones.capacity().saturating_sub(self.held_phone_count());
+        if priority == CDS_REQUEST_PRIORITY_BULK && remaining_capacity.saturating_sub(query_phone_count) < self.interactive_reserve_phones {
+            Err(CDS_ERROR_REQUEST_DEFERRED)
+        } else {
+            Ok(())
+        }
+    }
+
+    // the phones of the batch, along with those of the fragments of queries not yet handed in whole
+    fn held_phone_count(&self) -> usize {
+        (self.partial_requests.values()).fold(self.query_phones.len(), |held_phone_count, partial_request| {
+            held_phone_count.saturating_add(partial_request.query_phone_count)
+        })
+    }
+
+    // near the end of a batch each session may hold at most an equal share of it, so no one client crowds out the others
+    fn check_fair_share(&self, session_id: &SessionId, request_phone_count: usize) -> Result<(), SgxStatus> {
+        let remaining_capacity = self.query_phones.capacity().saturating_sub(self.query_phones.len());
+        if remaining_capacity >= self.fair_admission_phones {
+            return Ok(());
+        }
+        let session_phone_count = self.session_phone_counts.get(session_id).copied();
+        let session_count = match session_phone_count {
+            Some(_) => self.session_phone_counts.len(),
+            None => self.session_phone_counts.len().saturating_add(1),
+        };
+        let fair_share = self.query_phones.capacity().checked_div(session_count).unwrap_or_default();
+        if session_phone_count.unwrap_or_default().saturating_add(request_phone_count) > fair_share {
+            Err(CDS_ERROR_FAIR_SHARE_EXCEEDED)
+        } else {
+            Ok(())
+        }
+    }
+
+    // the phones of a request count towards the share of the session it's to be replied to
+    fn charge_session_phones(&mut self, session_id: SessionId, request_phone_count: usize) {
+        let session_phone_count = self.session_phone_counts.entry(session_id).or_default();
+        *session_phone_count = session_phone_count.saturating_add(request_phone_count);
+    }
+
+    // a cancelled request no longer counts towards its session's share of the batch
+    fn release_session_phones(&mut self, from: &SgxsdMsgFrom, request_phone_count: usize) {
+        if let Some(session_id) = from.client_pubkey() {
+            if let Some(session_phone_count) = self.session_phone_counts.get_mut(session_id) {
+                *session_phone_count = session_phone_count.saturating_sub(request_phone_count);
+                if *session_phone_count == 0 {
+                    self.session_phone_counts.remove(session_id);
+                }
+            }
+        }
+    }
+
+    // the phone count of a request is public, since the host hands it in with the request, so it's counted as is
+    fn count_request_phones(&mut self, request_phone_count: usize) {
+        let mut bucket = 0;
+        let mut bucket_end = 10;
+        while request_phone_count >= bucket_end && bucket < REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS - 1 {
+            bucket = bucket.saturating_add(1);
+            bucket_end = bucket_end.saturating_mul(10);
+        }
+        if let Some(bucket_count) = self.request_phone_count_histogram.get_mut(bucket) {
+            *bucket_count = bucket_count.saturating_add(1);
+        }
+    }
+
+    // a resubmission shares the lookup and reply of the request it repeats, but must still decrypt with its own key
+    fn add_duplicate(
+        &mut self,
+        request_index: usize,
+        args: &CallArgs,
+        request_data: &[u8],
+        read_limit: &UntrustedReadLimit,
+        from: SgxsdMsgFrom,
+    ) -> Result<(), (SgxStatus, SgxsdMsgFrom)>
+    {
+        if let Err(error) = Self::decode_phone_list(args, request_data, read_limit) {
+            return Err((error, from));
+        }
+        let response_key = match Self::response_key(request_data) {
+            Ok(response_key) => response_key,
+            Err(error) => return Err((error, from)),
+        };
+        match self.requests.get_mut(request_index) {
+            Some(request) => {
+                request.duplicates.push(DuplicateRequest { from, response_key });
+                Ok(())
+            }
+            None => Err((SGX_ERROR_UNEXPECTED, from)),
+        }
+    }
+
+    fn untrusted_read_limit(&self) -> UntrustedReadLimit {
+        match self.max_untrusted_read_bytes {
+            0 => UntrustedReadLimit::unlimited(),
+            max_untrusted_read_bytes => UntrustedReadLimit::new(max_untrusted_read_bytes),
+        }
+    }
+
+    pub fn decode_phone_list<'a>(args: &'a CallArgs, request_data: &[u8], read_limit: &UntrustedReadLimit) -> Result<Request, SgxStatus> {
+        let query_data = Self::read_query(args, read_limit)?;
+        match ProtocolVersion::from_id(args.protocol_version)? {
+            ProtocolVersion::V0 => {
+                let bytes_per_key = Self::query_bytes_per_key(query_data.len(), args.query_phone_count.to_usize(), args.query_key_size)?;
+                let query_data = Self::decrypt_query(args, request_data, query_data)?;
+                let chunk_commitments = Self::verify_chunk_commitments(args, query_data.get(), read_limit)?;
+                Self::decode_query(query_data, bytes_per_key, &args.query_commitment, chunk_commitments.as_deref())
+            }
+            ProtocolVersion::V1 => {
+                let query_data = Self::decrypt_query(args, request_data, query_data)?;
+                let chunk_commitments = Self::verify_chunk_commitments(args, query_data.get(), read_limit)?;
+                Self::decode_delta_query(query_data, args.query_phone_count.to_usize(), args, chunk_commitments.as_deref())
+            }
+        }
+    }
+
+    fn read_query(args: &CallArgs, read_limit: &UntrustedReadLimit) -> Result<Box<[u8]>, SgxStatus> {
+        let query_data_slice = UntrustedSlice::new(args.query.data, args.query.size.to_usize())
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?
+            .with_read_limit(read_limit);
+        let query_data = query_data_slice
+            .read_bytes(args.query.size.to_usize())
+            .map_err(|_| match read_limit.exceeded() {
+                true => CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED,
+                false => SGX_ERROR_INVALID_PARAMETER,
+            })?
+            .into_boxed_slice();
+        Ok(query_data)
+    }
+
+    // whether the keys are tagged, or username hashes, is told by the size of the query unless the client names it
+    fn query_bytes_per_key(query_data_len: usize, query_phone_count: usize, query_key_size: u32) -> Result<usize, SgxStatus> {
+        let query_phones_data_len = query_data_len
             .checked_sub(COMMITMENT_NONCE_SIZE)
             .ok_or(CDS_ERROR_INVALID_REQUEST_SIZE)?;
+        if query_key_size != 0 {
+            let bytes_per_key = query_key_size.to_usize();
+            return match bytes_per_key {
+                BYTES_PER_PHONE | BYTES_PER_IDENTIFIER | BYTES_PER_TAGGED_KEY | BYTES_PER_USERNAME_HASH
+                    if query_phones_data_len == query_phone_count.saturating_mul(bytes_per_key) =>
+                {
+                    Ok(bytes_per_key)
+                }
+                _ => Err(CDS_ERROR_INVALID_REQUEST_SIZE),
+            };
+        }
+        let bytes_per_key = if query_phones_data_len == query_phone_count.saturating_mul(BYTES_PER_PHONE) {
+            BYTES_PER_PHONE
+        } else if query_phones_data_len == query_phone_count.saturating_mul(BYTES_PER_TAGGED_KEY) {
+            BYTES_PER_TAGGED_KEY
+        } else if query_phones_data_len == query_phone_count.saturating_mul(BYTES_PER_USERNAME_HASH) {
+            BYTES_PER_USERNAME_HASH
+        } else {
+            return Err(CDS_ERROR_INVALID_REQUEST_SIZE);
+        };
+        Ok(bytes_per_key)
+    }
 
-        if (request_data.len() != AesGcmKey::len()
-            || query_phones_data_len % BYTES_PER_PHONE != 0
-            || query_phones_data_len / BYTES_PER_PHONE != args.query_phone_count.to_usize())
-        {
+    fn decrypt_query(args: &CallArgs, request_data: &[u8], query_data: Box<[u8]>) -> Result<SecretValue<Box<[u8]>>, SgxStatus> {
+        let mut query_data = SecretValue::new(query_data);
+        let (query_key_data, _) = Self::split_request_data(request_data)?;
+
+        let query_key = AesGcmKey::new(query_key_data)?;
+        query_key.decrypt(&mut query_data.get_mut()[..], &[], &args.query.iv, &args.query.mac)?;
+        Ok(query_data)
+    }
+
+    // the request data is the query key, followed by the key to encrypt the reply under if the client keeps them apart
+    fn split_request_data(request_data: &[u8]) -> Result<(&[u8], Option<&[u8]>), SgxStatus> {
+        let key_len = AesGcmKey::len();
+        if request_data.len() == key_len {
+            Ok((request_data, None))
+        } else if request_data.len() == key_len.saturating_mul(2) {
+            let (query_key_data, response_key_data) = request_data.split_at(key_len);
+            Ok((query_key_data, Some(response_key_data)))
+        } else {
+            Err(CDS_ERROR_INVALID_REQUEST_SIZE)
+        }
+    }
+
+    fn response_key(request_data: &[u8]) -> Result<Option<AesGcmKey>, SgxStatus> {
+        match Self::split_request_data(request_data)? {
+            (_, Some(response_key_data)) => Ok(Some(AesGcmKey::new(response_key_data)?)),
+            (_, None) => Ok(None),
+        }
+    }
+
+    // the chunk commitments of a call's query once each chunk has been checked; None if it isn't committed by chunk
+    fn verify_chunk_commitments(args: &CallArgs, query_data: &[u8], read_limit: &UntrustedReadLimit) -> Result<Option<Vec<u8>>, SgxStatus> {
+        if args.query_chunk_commitments.is_null() {
+            return Ok(None);
+        }
+        let chunk_size = args.query_chunk_size.to_usize();
+        if chunk_size == 0 {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        let chunk_count = args.query_chunk_commitment_count.to_usize();
+        if chunk_count != query_data.chunks(chunk_size).len() {
             return Err(CDS_ERROR_INVALID_REQUEST_SIZE);
         }
+        let chunk_commitments_size = chunk_count
+            .checked_mul(SHA256Context::hash_len())
+            .ok_or(CDS_ERROR_INVALID_REQUEST_SIZE)?;
+        let chunk_commitments = UntrustedSlice::new(args.query_chunk_commitments as *mut u8, chunk_commitments_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?
+            .with_read_limit(read_limit)
+            .read_bytes(chunk_commitments_size)
+            .map_err(|_| match read_limit.exceeded() {
+                true => CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED,
+                false => SGX_ERROR_INVALID_PARAMETER,
+            })?;
+
+        for (chunk, chunk_commitment) in query_data.chunks(chunk_size).zip(chunk_commitments.chunks_exact(SHA256Context::hash_len())) {
+            let mut expected_commitment = [0; SHA256Context::hash_len()];
+            expected_commitment.copy_from_slice(chunk_commitment);
+            Self::verify_commitment(CHUNK_COMMITMENT_LABEL, chunk, &expected_commitment)?;
+        }
+        Ok(Some(chunk_commitments))
+    }
+
+    fn decode_query(
+        query_data: SecretValue<Box<[u8]>>,
+        bytes_per_key: usize,
+        query_commitment: &[u8; SHA256Context::hash_len()],
+        chunk_commitments: Option<&[u8]>,
+    ) -> Result<Request, SgxStatus>
+    {
+        let query_phones = RequestPhoneList::new(query_data, bytes_per_key);
+        let committed_data = chunk_commitments.unwrap_or(&query_phones.data.get()[..]);
+        Self::verify_commitment(query_phones.commitment_label(), committed_data, query_commitment)?;
+        query_phones.check_keys()?;
+
+        Ok(Request { phones: query_phones })
+    }
 
-        let query_key = AesGcmKey::new(request_data)?;
-        query_key.decrypt(&mut query_phones.data.get_mut()[..], &[], &args.query.iv, &args.query.mac)?;
+    // a query of phones sent as deltas is committed to as sent, then decoded into phones checked like any others
+    fn decode_delta_query(
+        query_data: SecretValue<Box<[u8]>>,
+        query_phone_count: usize,
+        args: &CallArgs,
+        chunk_commitments: Option<&[u8]>,
+    ) -> Result<Request, SgxStatus>
+    {
+        if args.query_key_size != 0 && args.query_key_size.to_usize() != BYTES_PER_PHONE {
+            return Err(CDS_ERROR_INVALID_REQUEST_SIZE);
+        }
+        let committed_data = chunk_commitments.unwrap_or(&query_data.get()[..]);
+        Self::verify_commitment(DELTA_QUERY_COMMITMENT_LABEL, committed_data, &args.query_commitment)?;
 
-        Self::verify_commitment(&query_phones.data.get()[..], &args.query_commitment)?;
+        let (nonce_data, deltas) = query_data.get().split_at(COMMITMENT_NONCE_SIZE.min(query_data.get().len()));
+        if nonce_data.len() != COMMITMENT_NONCE_SIZE {
+            return Err(CDS_ERROR_INVALID_REQUEST_SIZE);
+        }
+        let phones_data_len = query_phone_count.saturating_mul(BYTES_PER_PHONE).saturating_add(COMMITMENT_NONCE_SIZE);
+        let mut phones_data = SecretValue::new(vec![0u8; phones_data_len].into_boxed_slice());
+        let (phones_nonce_data, phones_keys_data) = phones_data.get_mut().split_at_mut(COMMITMENT_NONCE_SIZE);
+        phones_nonce_data.copy_from_slice(nonce_data);
+        decode_phones(deltas, phones_keys_data)?;
 
+        let query_phones = RequestPhoneList::new(phones_data, BYTES_PER_PHONE);
+        query_phones.check_keys()?;
         Ok(Request { phones: query_phones })
     }
 
-    fn verify_commitment(data: &[u8], expected_commitment: &[u8; SHA256Context::hash_len()]) -> Result<(), SgxStatus> {
+    // look up a uuid column of the directory, such as its PNIs, into the results as laid out for the batch
+    fn lookup_uuid_column(
+        in_phones: &UntrustedSlice<'_>,
+        in_column: &UntrustedSlice<'_>,
+        in_phone_count: usize,
+        query_phones: &[Phone],
+        column_start: usize,
+        bytes_per_result: usize,
+        query_phones_result: &mut [u8],
+    ) -> Result<(), SgxStatus>
+    {
+        let mut column_result = SecretValue::new(vec![0u8; query_phones.len().saturating_mul(BYTES_PER_UUID)]);
+        unsafe {
+            hash_lookup(
+                in_phones.as_ptr(),
+                in_column.as_ptr(),
+                in_phone_count,
+                query_phones,
+                column_result.get_mut(),
+            )?;
+        }
+        Self::copy_result_column(column_result.get(), column_start, BYTES_PER_UUID, bytes_per_result, query_phones_result);
+        Ok(())
+    }
+
+    fn lookup_metadata_column(
+        in_phones: &UntrustedSlice<'_>,
+        in_metadata: &UntrustedSlice<'_>,
+        in_phone_count: usize,
+        query_phones: &[Phone],
+        column_start: usize,
+        bytes_per_result: usize,
+        query_phones_result: &mut [u8],
+    ) -> Result<(), SgxStatus>
+    {
+        let mut metadata_result = SecretValue::new(vec![0u8; query_phones.len().saturating_mul(METADATA_SIZE)]);
+        unsafe {
+            metadata_lookup(
+                in_phones.as_ptr(),
+                in_metadata.as_ptr(),
+                in_phone_count,
+                query_phones,
+                metadata_result.get_mut(),
+            )?;
+        }
+        Self::copy_result_column(metadata_result.get(), column_start, METADATA_SIZE, bytes_per_result, query_phones_result);
+        Ok(())
+    }
+
+    fn lookup_flags_column(
+        in_phones: &UntrustedSlice<'_>,
+        in_flags: &UntrustedSlice<'_>,
+        in_phone_count: usize,
+        query_phones: &[Phone],
+        column_start: usize,
+        bytes_per_result: usize,
+        query_phones_result: &mut [u8],
+    ) -> Result<(), SgxStatus>
+    {
+        let mut flags_result = SecretValue::new(vec![0u8; query_phones.len().saturating_mul(FLAGS_SIZE)]);
+        unsafe {
+            flags_lookup(
+                in_phones.as_ptr(),
+                in_flags.as_ptr(),
+                in_phone_count,
+                query_phones,
+                flags_result.get_mut(),
+            )?;
+        }
+        Self::copy_result_column(flags_result.get(), column_start, FLAGS_SIZE, bytes_per_result, query_phones_result);
+        Ok(())
+    }
+
+    fn lookup_change_epoch_column(
+        in_phones: &UntrustedSlice<'_>,
+        in_change_epochs: &UntrustedSlice<'_>,
+        in_phone_count: usize,
+        query_phones: &[Phone],
+        column_start: usize,
+        bytes_per_result: usize,
+        query_phones_result: &mut [u8],
+    ) -> Result<(), SgxStatus>
+    {
+        let mut change_epoch_result = SecretValue::new(vec![0u8; query_phones.len().saturating_mul(CHANGE_EPOCH_SIZE)]);
+        unsafe {
+            change_epoch_lookup(
+                in_phones.as_ptr(),
+                in_change_epochs.as_ptr(),
+                in_phone_count,
+                query_phones,
+                change_epoch_result.get_mut(),
+            )?;
+        }
+        Self::copy_result_column(change_epoch_result.get(), column_start, CHANGE_EPOCH_SIZE, bytes_per_result, query_phones_result);
+        Ok(())
+    }
+
+    fn copy_result_column(column: &[u8], column_start: usize, column_size: usize, bytes_per_result: usize, query_phones_result: &mut [u8]) {
+        for (query_phone_result, value) in query_phones_result.chunks_exact_mut(bytes_per_result).zip(column.chunks_exact(column_size)) {
+            let query_phone_result_column = (query_phone_result.get_mut(column_start..)).and_then(|rest| rest.get_mut(..column_size));
+            if let Some(query_phone_result_column) = query_phone_result_column {
+                query_phone_result_column.copy_from_slice(value);
+            }
+        }
+    }
+
+    // clear the results of the phones missing from the allowlist, whether or not they were found in the directory
+    fn apply_allowlist(
+        in_allowlist_phones: &UntrustedSlice<'_>,
+        in_allowlist_phone_count: usize,
+        query_phones: &[Phone],
+        bytes_per_result: usize,
+        query_phones_result: &mut [u8],
+    ) -> Result<(), SgxStatus>
+    {
+        let mut allowlist_masks = SecretValue::new(vec![0u8; query_phones.len().saturating_mul(BYTES_PER_UUID)]);
+        unsafe {
+            allowlist_lookup(
+                in_allowlist_phones.as_ptr(),
+                in_allowlist_phone_count,
+                query_phones,
+                allowlist_masks.get_mut(),
+            )?;
+        }
+
+        let masks = allowlist_masks.get().chunks_exact(BYTES_PER_UUID);
+        for (query_phone_result, mask) in query_phones_result.chunks_exact_mut(bytes_per_result).zip(masks) {
+            for (result_byte, mask_byte) in query_phone_result.iter_mut().zip(mask.iter().cycle()) {
+                *result_byte &= mask_byte;
+            }
+        }
+        Ok(())
+    }
+
+    // replace the results of uuid keys with what the uuid lookup found, without branching on which keys are uuids
+    fn apply_uuid_lookup(
+        in_uuids: &UntrustedSlice<'_>,
+        in_phone_count: usize,
+        query_uuids: &[Uuid],
+        bytes_per_result: usize,
+        query_phones_result: &mut [u8],
+    ) -> Result<(), SgxStatus>
+    {
+        let mut uuids_result = SecretValue::new(vec![0u8; query_uuids.len().saturating_mul(BYTES_PER_UUID)]);
+        unsafe {
+            uuid_lookup(in_uuids.as_ptr(), in_phone_count, query_uuids, uuids_result.get_mut())?;
+        }
+
+        let results = query_uuids.iter().zip(uuids_result.get().chunks_exact(BYTES_PER_UUID));
+        for (query_phone_result, (query_uuid, uuid_result)) in query_phones_result.chunks_exact_mut(bytes_per_result).zip(results) {
+            let uuid_bits = query_uuid.data64[0] | query_uuid.data64[1];
+            let uuid_mask = 0u8.wrapping_sub(((uuid_bits | uuid_bits.wrapping_neg()) >> 63) as u8);
+            for (result_byte, uuid_result_byte) in query_phone_result.iter_mut().zip(uuid_result.iter().chain(iter::repeat(&0))) {
+                *result_byte = (*result_byte & !uuid_mask) | (uuid_result_byte & uuid_mask);
+            }
+        }
+        Ok(())
+    }
+
+    // replace the results of username hash keys with the uuid the username lookup found for them, as for uuid keys
+    fn apply_username_lookup(
+        in_username_hashes: &UntrustedSlice<'_>,
+        in_username_uuids: &UntrustedSlice<'_>,
+        in_username_count: usize,
+        query_username_hashes: &[UsernameHash],
+        bytes_per_result: usize,
+        query_phones_result: &mut [u8],
+    ) -> Result<(), SgxStatus>
+    {
+        let mut usernames_result = SecretValue::new(vec![0u8; query_username_hashes.len().saturating_mul(BYTES_PER_UUID)]);
+        unsafe {
+            username_lookup(
+                in_username_hashes.as_ptr(),
+                in_username_uuids.as_ptr(),
+                in_username_count,
+                query_username_hashes,
+                usernames_result.get_mut(),
+            )?;
+        }
+
+        let results = query_username_hashes.iter().zip(usernames_result.get().chunks_exact(BYTES_PER_UUID));
+        let query_results = query_phones_result.chunks_exact_mut(bytes_per_result).zip(results);
+        for (query_phone_result, (query_username_hash, username_result)) in query_results {
+            let hash_bits = query_username_hash.iter().fold(0u8, |hash_bits, byte| hash_bits | byte);
+            let hash_mask = 0u8.wrapping_sub((hash_bits | hash_bits.wrapping_neg()) >> 7);
+            for (result_byte, username_result_byte) in query_phone_result.iter_mut().zip(username_result.iter().chain(iter::repeat(&0))) {
+                *result_byte = (*result_byte & !hash_mask) | (username_result_byte & hash_mask);
+            }
+        }
+        Ok(())
+    }
+
+    // count the results with no uuid found, without branching on which ones they are
+    fn count_misses(query_phones_result: &[u8], bytes_per_result: usize) -> u64 {
+        (query_phones_result.chunks_exact(bytes_per_result))
+            .map(|query_phone_result| {
+                let uuid_bits = (query_phone_result.iter().take(BYTES_PER_UUID)).fold(0u64, |uuid_bits, byte| uuid_bits | u64::from(*byte));
+                ((uuid_bits | uuid_bits.wrapping_neg()) >> 63) ^ 1
+            })
+            .fold(0u64, u64::wrapping_add)
+    }
+
+    fn verify_commitment(label: &[u8], data: &[u8], expected_commitment: &[u8; SHA256Context::hash_len()]) -> Result<(), SgxStatus> {
         let mut context: SHA256Context = Default::default();
+        context.update(label);
         context.update(data);
 
         let mut commitment: [u8; SHA256Context::hash_len()] = [0; SHA256Context::hash_len()];
@@ -115,86 +780,742 @@ impl SgxsdServer for SgxsdServerState {
     type HandleCallArgs = CallArgs;
     type InitArgs = StartArgs;
     type TerminateArgs = StopArgs;
+    type Metrics = ServerMetrics;
 
     fn init(args: Option<&StartArgs>) -> Result<Self, SgxStatus> {
         let args = args.ok_or(SGX_ERROR_INVALID_PARAMETER)?;
 
+        let canonicalization_rules_data = UntrustedSlice::new(args.canonicalization_rules, args.canonicalization_rules_size)
+            .map_err(|_| SGX_ERROR_INVALID_PARAMETER)?
+            .read_bytes(args.canonicalization_rules_size)
+            .map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
+        let canonicalization_rules = CanonicalizationRules::parse(&canonicalization_rules_data, CANONICALIZATION_RULES_DIGEST.as_ref())?;
+        let reply_scratch = ReplyScratch::new(args.reply_scratch, args.reply_scratch_size)?;
+
         Ok(Self {
-            requests: Vec::with_capacity(args.max_query_phones.to_usize() / 4),
+            requests: VecDeque::with_capacity(args.max_query_phones.to_usize() / 4),
+            request_indices: Default::default(),
+            request_id_indices: Default::default(),
+            partial_requests: Default::default(),
             query_phones: PhoneList::new(args.max_query_phones.to_usize()),
+            query_uuids: UuidList::new(0),
+            query_username_hashes: UsernameHashList::new(0),
+            session_phone_counts: Default::default(),
+            fair_admission_phones: args.fair_admission_phones.to_usize(),
+            max_phones_per_request: args.max_phones_per_request.to_usize(),
+            interactive_reserve_phones: args.interactive_reserve_phones.to_usize(),
+            max_untrusted_read_bytes: args.max_untrusted_read_bytes.to_usize(),
+            miss_rate_alert_ppm: args.miss_rate_alert_ppm.into(),
+            require_sorted_query: args.require_sorted_query != 0,
+            reply_padding_size: args.reply_padding_size.to_usize(),
+            dedup_query_phones: args.dedup_query_phones != 0,
+            canonicalization_rules,
+            lookup: None,
+            request_phone_count_histogram: Default::default(),
+            reply_scratch,
         })
     }
 
+    fn config(args: Option<&StartArgs>) -> Result<Vec<u8>, SgxStatus> {
+        let args = args.ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+        // neither the canonicalization rules, fixed by the enclave's digest, nor the reply scratch region is part of the config
+        let config_values = [
+            args.max_query_phones,
+            args.max_ratelimit_states,
+            args.max_untrusted_read_bytes,
+            args.fair_admission_phones,
+            args.miss_rate_alert_ppm,
+            args.require_sorted_query,
+            args.reply_padding_size,
+            args.dedup_query_phones,
+            args.max_phones_per_request,
+            args.interactive_reserve_phones,
+        ];
+        Ok(config_values.iter().flat_map(|config_value| config_value.to_le_bytes().to_vec()).collect())
+    }
+
     fn handle_call(&mut self, args: Option<&CallArgs>, request_data: &[u8], from: SgxsdMsgFrom) -> Result<(), (SgxStatus, SgxsdMsgFrom)> {
         let args = match args {
             Some(args) => args,
             None => return Err((SGX_ERROR_INVALID_PARAMETER, from)),
         };
-        let request = match self.decode_request(args, request_data) {
-            Ok(request) => request,
+        // the host must finish the stop calls of a batch before handing in more requests
+        if self.lookup.is_some() {
+            return Err((INCIDENT_LATCH.violation(HostViolation::CallOrder, SGX_ERROR_INVALID_STATE), from));
+        }
+        if let Some(client_pubkey) = from.client_pubkey() {
+            if let Err(error) = SESSION_DENYLIST.check(client_pubkey) {
+                return Err((error, from));
+            }
+        }
+
+        let reply_layout = match ReplyLayout::from_id(args.reply_layout) {
+            Ok(reply_layout) => reply_layout,
+            Err(error) => return Err((error, from)),
+        };
+        // results are only ever replied in the order of the query, but a client asking for another isn't to misread them
+        if args.reply_order != CDS_REPLY_ORDER_QUERY {
+            return Err((SGX_ERROR_INVALID_PARAMETER, from));
+        }
+        if args.priority != CDS_REQUEST_PRIORITY_INTERACTIVE && args.priority != CDS_REQUEST_PRIORITY_BULK {
+            return Err((SGX_ERROR_INVALID_PARAMETER, from));
+        }
+        let reply_flags = match ReplyFlags::from_args(args) {
+            Ok(reply_flags) => reply_flags,
             Err(error) => return Err((error, from)),
         };
+        let read_limit = self.untrusted_read_limit();
+
+        // a byte-identical resubmission of a query already in this batch shares the earlier request's lookup and reply
+        let query_id = QueryId::new(args);
+        if let Some(&request_index) = self.request_indices.get(&query_id).filter(|_| args.query_fragment_token == 0) {
+            return self.add_duplicate(request_index, args, request_data, &read_limit, from);
+        }
+        // so does a retry the client re-encrypted, which it names by the request id it gave the query in the same session
+        let request_id = (from.client_pubkey())
+            .filter(|_| args.request_id != 0 && args.query_fragment_token == 0)
+            .map(|client_pubkey| (*client_pubkey, args.request_id));
+        if let Some(request_id_index) = request_id.as_ref().and_then(|request_id| self.request_id_indices.get(request_id)) {
+            if request_id_index.query_commitment != args.query_commitment {
+                return Err((CDS_ERROR_QUERY_COMMITMENT_MISMATCH, from));
+            }
+            if request_id_index.reply_layout != args.reply_layout ||
+                request_id_index.since_change_token != args.since_change_token ||
+                request_id_index.protocol_version != args.protocol_version ||
+                request_id_index.reply_flags != args.reply_flags
+            {
+                return Err((SGX_ERROR_INVALID_PARAMETER, from));
+            }
+            let request_index = request_id_index.request_index;
+            return self.add_duplicate(request_index, args, request_data, &read_limit, from);
+        }
 
+        let session_id = match from.client_pubkey() {
+            Some(client_pubkey) => *client_pubkey,
+            None => return Err((SGX_ERROR_INVALID_STATE, from)),
+        };
+        // the call handing in a fragment of a query other than its last is done with once the fragment is held
+        let request = match self.decode_request(args, request_data, &read_limit, &session_id) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(error) => return Err((error, from)),
+        };
+        let response_key = match Self::response_key(request_data) {
+            Ok(response_key) => response_key,
+            Err(error) => return Err((error, from)),
+        };
+        let reply_layout = match reply_layout.for_keys(request.phones.has_uuid_keys(), request.phones.is_username_hashes()) {
+            Ok(reply_layout) => reply_layout,
+            Err(error) => return Err((error, from)),
+        };
         let request_phones_iter = request.phones.iter();
+        if let Err(error) = self.check_fair_share(&session_id, request_phones_iter.len()) {
+            return Err((error, from));
+        }
         let request_phone_count = match request_phones_iter.len().try_into() {
             Ok(request_phone_count) => request_phone_count,
             Err(_) => return Err((SGX_ERROR_INVALID_PARAMETER, from)),
         };
-        self.query_phones.extend(request_phones_iter);
-        self.requests.push(PendingRequest { from, request_phone_count });
+        self.charge_session_phones(session_id, request_phones_iter.len());
+        self.count_request_phones(request_phones_iter.len());
+        if request.phones.has_uuid_keys() || !self.query_uuids.is_empty() {
+            if self.query_uuids.is_empty() {
+                self.query_uuids.reserve_exact(self.query_phones.capacity());
+            }
+            self.query_uuids.resize(self.query_phones.len(), Uuid::default());
+            self.query_uuids.extend(request.phones.uuids());
+        }
+        if request.phones.is_username_hashes() || !self.query_username_hashes.is_empty() {
+            if self.query_username_hashes.is_empty() {
+                self.query_username_hashes.reserve_exact(self.query_phones.capacity());
+            }
+            self.query_username_hashes.resize(self.query_phones.len(), [0; USERNAME_HASH_SIZE]);
+            self.query_username_hashes.extend(request.phones.username_hashes());
+        }
+        self.query_phones.extend(request_phones_iter.map(E164Phone::get));
+        if args.query_fragment_token == 0 {
+            self.request_indices.insert(query_id, self.requests.len());
+        }
+        if let Some(request_id) = request_id {
+            let request_id_index = RequestIdIndex {
+                request_index: self.requests.len(),
+                query_commitment: args.query_commitment,
+                reply_layout: args.reply_layout,
+                since_change_token: args.since_change_token,
+                protocol_version: args.protocol_version,
+                reply_flags: args.reply_flags,
+            };
+            self.request_id_indices.insert(request_id, request_id_index);
+        }
+        self.requests.push_back(PendingRequest {
+            from,
+            response_key,
+            duplicates: Vec::new(),
+            commitment_nonce: request.phones.commitment_nonce(),
+            request_phone_count,
+            reply_layout,
+            since_change_token: args.since_change_token,
+            reply_flags,
+            admission_ticks: args.admission_ticks,
+        });
         Ok(())
     }
 
-    fn terminate(self, args: Option<&StopArgs>) -> Result<(), SgxStatus> {
+    fn terminate(mut self, args: Option<&StopArgs>) -> Result<SgxsdTerminate<Self>, SgxStatus> {
         let args = args.ok_or(SGX_ERROR_INVALID_PARAMETER)?;
 
+        // a host retrying a stop call it lost the result of is only told where the lookup has got to
+        if let Some(lookup) = self.lookup.as_ref().filter(|lookup| lookup.retried_by(args)) {
+            let continuation_token = lookup.chunks.continuation_token();
+            return Ok(SgxsdTerminate::Suspended(self, continuation_token));
+        }
+
+        // a stop call carrying on with the lookup must hand back the last token and name the same directory
+        let lookup = self.lookup.take();
+        match &lookup {
+            Some(lookup) if !lookup.resumed_by(args) => return Err(SGX_ERROR_INVALID_PARAMETER),
+            None if args.continuation_token != 0 => return Err(SGX_ERROR_INVALID_PARAMETER),
+            _ => (),
+        }
+
+        // a lookup naming a directory epoch pins the buffers committed under it active until it's done
+        let (canaries, directory_pin) = if args.directory_epoch != 0 && lookup.is_none() {
+            let (canaries, directory_pin) = ACTIVE_DIRECTORY.pin_active(&CommittedDirectory {
+                epoch:          args.directory_epoch,
+                in_phones:      args.in_phones,
+                in_phone_count: args.in_phone_count,
+                in_uuids:       args.in_uuids,
+            })?;
+            (canaries, Some(directory_pin))
+        } else {
+            (Vec::new(), None)
+        };
+
         let in_phones_size = (args.in_phone_count)
             .checked_mul(BYTES_PER_PHONE)
-            .ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
         let in_uuids_size = (args.in_phone_count)
             .checked_mul(BYTES_PER_UUID)
-            .ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+
+        let in_phones = UntrustedSlice::new(args.in_phones as *mut u8, in_phones_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_uuids = UntrustedSlice::new(args.in_uuids as *mut u8, in_uuids_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
 
-        let in_phones = UntrustedSlice::new(args.in_phones as *mut u8, in_phones_size).map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
-        let in_uuids = UntrustedSlice::new(args.in_uuids as *mut u8, in_uuids_size).map_err(|_| SGX_ERROR_INVALID_PARAMETER)?;
+        // the directory optionally carries a metadata word per entry, which is returned after the uuid of each phone
+        let in_metadata_size = match args.in_metadata_size {
+            0 => 0,
+            METADATA_SIZE => METADATA_SIZE,
+            _ => return Err(INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER)),
+        };
+        let in_metadata_len = (args.in_phone_count)
+            .checked_mul(in_metadata_size)
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_metadata = UntrustedSlice::new(args.in_metadata as *mut u8, in_metadata_len)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+
+        // it may also carry the PNI of each entry, which isn't committed under a directory epoch either
+        let (bytes_per_pni, in_pni_uuids_size) = match args.in_pni_uuids.is_null() {
+            true => (0, 0),
+            false => (BYTES_PER_UUID, in_uuids_size),
+        };
+        let in_pni_uuids = UntrustedSlice::new(args.in_pni_uuids as *mut u8, in_pni_uuids_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        // and the epoch at which each entry last changed
+        let bytes_per_change_epoch = match args.in_change_epochs.is_null() {
+            true => 0,
+            false => CHANGE_EPOCH_SIZE,
+        };
+        let in_change_epochs_size = (args.in_phone_count)
+            .checked_mul(bytes_per_change_epoch)
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_change_epochs = UntrustedSlice::new(args.in_change_epochs as *mut u8, in_change_epochs_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        // and a byte of flags for each entry, for the requests asking for them
+        let bytes_per_flags = match args.in_flags.is_null() {
+            true => 0,
+            false => FLAGS_SIZE,
+        };
+        let in_flags_size = (args.in_phone_count)
+            .checked_mul(bytes_per_flags)
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_flags = UntrustedSlice::new(args.in_flags, in_flags_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
 
-        let query_phones_chunks = self.query_phones.chunks(MAX_HASH_TABLE_SIZE);
-        let in_query_phones_result_len = (self.query_phones)
-            .len()
+        // the host may also give an uncommitted allowlist, outside of which phones aren't found
+        let in_allowlist_phones_size = (args.in_allowlist_phone_count)
+            .checked_mul(BYTES_PER_PHONE)
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_allowlist_phones = UntrustedSlice::new(args.in_allowlist_phones as *mut u8, in_allowlist_phones_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        // and the uncommitted username table that queries of username hashes are looked up in
+        let in_username_hashes_size = (args.in_username_count)
+            .checked_mul(BYTES_PER_USERNAME_HASH)
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_username_uuids_size = (args.in_username_count)
             .checked_mul(BYTES_PER_UUID)
-            .ok_or(SGX_ERROR_INVALID_PARAMETER)?;
-        let mut in_query_phones_result = SecretValue::new(vec![0u8; in_query_phones_result_len]);
-        let in_query_phones_result_chunks = (in_query_phones_result.get_mut()).chunks_mut(MAX_HASH_TABLE_SIZE * BYTES_PER_UUID);
-        for (query_phones_chunk, in_query_phones_result_chunk) in query_phones_chunks.zip(in_query_phones_result_chunks) {
+            .ok_or_else(|| INCIDENT_LATCH.violation(HostViolation::BufferSize, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_username_hashes = UntrustedSlice::new(args.in_username_hashes, in_username_hashes_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        let in_username_uuids = UntrustedSlice::new(args.in_username_uuids as *mut u8, in_username_uuids_size)
+            .map_err(|_| INCIDENT_LATCH.violation(HostViolation::UntrustedPointer, SGX_ERROR_INVALID_PARAMETER))?;
+        INCIDENT_LATCH.check_disjoint(&[
+            &in_phones,
+            &in_uuids,
+            &in_pni_uuids,
+            &in_change_epochs,
+            &in_flags,
+            &in_metadata,
+            &in_allowlist_phones,
+            &in_username_hashes,
+            &in_username_uuids,
+        ])?;
+        let mut reply_scratch = self.reply_scratch.writer()?;
+
+        // before replying to anyone, check the lookup still finds the entries the directory was committed with
+        if lookup.is_none() && !canaries.is_empty() {
+            let canary_phones: Vec<Phone> = canaries.iter().map(|canary| canary.phone).collect();
+            let mut canary_results = SecretValue::new(vec![0u8; canaries.len().saturating_mul(BYTES_PER_UUID)]);
             unsafe {
                 hash_lookup(
                     in_phones.as_ptr(),
                     in_uuids.as_ptr(),
                     args.in_phone_count,
-                    query_phones_chunk,
+                    &canary_phones,
+                    canary_results.get_mut(),
+                )?;
+            }
+            DirectoryCanary::verify(&canaries, canary_results.get())?;
+        }
+
+        let result_layout = ResultLayout {
+            bytes_per_pni,
+            bytes_per_change_epoch,
+            bytes_per_flags,
+            bytes_per_metadata: in_metadata_size,
+        };
+        let bytes_per_result = result_layout.bytes_per_result();
+        let reply_assembler = ReplyAssembler {
+            result_layout,
+            directory_epoch: args.directory_epoch,
+            directory_generation: args.directory_generation,
+            reply_padding_size: self.reply_padding_size,
+        };
+
+        let mut lookup = match lookup {
+            Some(lookup) => lookup,
+            None => {
+                let in_query_phones_result_len = (self.query_phones)
+                    .len()
+                    .checked_mul(bytes_per_result)
+                    .ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+                // the distinct phones of the batch are looked up once each, and the query phones' results copied out of theirs
+                let distinct = match self.dedup_query_phones {
+                    true => Some(DistinctLookup::new(&self.query_phones, bytes_per_result)?),
+                    false => None,
+                };
+                let distinct_chunk_count = distinct.as_ref().map_or(0, DistinctLookup::chunk_count);
+                BatchLookup {
+                    directory:                          BatchLookup::directory(args),
+                    directory_pin,
+                    chunks:                             LookupChunks::new(distinct_chunk_count, self.query_phones.len()),
+                    distinct,
+                    // erased once the batch is done
+                    reply_batch:                        ReplyBatch::new()?,
+                    sealed_results:                     Default::default(),
+                    // cache line aligned for the vectorized hash lookup writing into it
+                    in_query_phones_result:             SecretValue::new(SecretBuffer::new(in_query_phones_result_len, SecretAllocation::CacheLine)?),
+                    in_query_phones_result_done_len:    0,
+                    in_query_phones_result_replied_len: 0,
+                    miss_count:                         0,
+                    reply_error:                        None,
+                }
+            }
+        };
+
+        // each result is looked up in the directory, unless it's copied out of that of its distinct phone
+        let lookup_directory = |phones_chunk: &[Phone], result_chunk: &mut [u8]| -> Result<(), SgxStatus> {
+            if bytes_per_result == BYTES_PER_UUID {
+                unsafe {
+                    hash_lookup(
+                        in_phones.as_ptr(),
+                        in_uuids.as_ptr(),
+                        args.in_phone_count,
+                        phones_chunk,
+                        result_chunk,
+                    )?;
+                }
+            } else {
+                Self::lookup_uuid_column(
+                    &in_phones,
+                    &in_uuids,
+                    args.in_phone_count,
+                    phones_chunk,
+                    0,
+                    bytes_per_result,
+                    result_chunk,
+                )?;
+                if bytes_per_pni != 0 {
+                    Self::lookup_uuid_column(
+                        &in_phones,
+                        &in_pni_uuids,
+                        args.in_phone_count,
+                        phones_chunk,
+                        result_layout.pni_start(),
+                        bytes_per_result,
+                        result_chunk,
+                    )?;
+                }
+                if bytes_per_change_epoch != 0 {
+                    Self::lookup_change_epoch_column(
+                        &in_phones,
+                        &in_change_epochs,
+                        args.in_phone_count,
+                        phones_chunk,
+                        result_layout.change_epoch_start(),
+                        bytes_per_result,
+                        result_chunk,
+                    )?;
+                }
+                if bytes_per_flags != 0 {
+                    Self::lookup_flags_column(
+                        &in_phones,
+                        &in_flags,
+                        args.in_phone_count,
+                        phones_chunk,
+                        result_layout.flags_start(),
+                        bytes_per_result,
+                        result_chunk,
+                    )?;
+                }
+                if in_metadata_size != 0 {
+                    Self::lookup_metadata_column(
+                        &in_phones,
+                        &in_metadata,
+                        args.in_phone_count,
+                        phones_chunk,
+                        result_layout.metadata_start(),
+                        bytes_per_result,
+                        result_chunk,
+                    )?;
+                }
+            }
+            if !args.in_allowlist_phones.is_null() {
+                Self::apply_allowlist(
+                    &in_allowlist_phones,
+                    args.in_allowlist_phone_count,
+                    phones_chunk,
+                    bytes_per_result,
+                    result_chunk,
+                )?;
+            }
+            Ok(())
+        };
+
+        for chunk in lookup.chunks.schedule(args.max_chunks) {
+            let query_chunk = match chunk {
+                LookupChunk::Distinct(distinct_chunk) => {
+                    let distinct = lookup.distinct.as_mut().ok_or(SGX_ERROR_UNEXPECTED)?;
+                    let (distinct_phones_chunk, distinct_result_chunk) = distinct.chunk_mut(distinct_chunk).ok_or(SGX_ERROR_UNEXPECTED)?;
+                    lookup_directory(distinct_phones_chunk, distinct_result_chunk)?;
+                    lookup.chunks.advance();
+                    continue;
+                }
+                LookupChunk::Query(query_chunk) => query_chunk,
+            };
+
+            let query_phones_chunk_range = LookupChunks::query_phones(query_chunk, self.query_phones.len());
+            let query_phones_chunk = self.query_phones.get(query_phones_chunk_range.clone()).ok_or(SGX_ERROR_UNEXPECTED)?;
+            let in_query_phones_result_chunk_end =
+                (lookup.in_query_phones_result_done_len).saturating_add(query_phones_chunk.len().saturating_mul(bytes_per_result));
+            let in_query_phones_result_chunk = (lookup.in_query_phones_result.get_mut())
+                .get_mut(lookup.in_query_phones_result_done_len..in_query_phones_result_chunk_end)
+                .ok_or(SGX_ERROR_UNEXPECTED)?;
+            match &lookup.distinct {
+                Some(distinct) => distinct.fan_out(query_phones_chunk, in_query_phones_result_chunk)?,
+                None => lookup_directory(query_phones_chunk, in_query_phones_result_chunk)?,
+            }
+            if !self.query_uuids.is_empty() {
+                let query_uuids_chunk = self.query_uuids.get(query_phones_chunk_range.clone()).ok_or(SGX_ERROR_UNEXPECTED)?;
+                Self::apply_uuid_lookup(&in_uuids, args.in_phone_count, query_uuids_chunk, bytes_per_result, in_query_phones_result_chunk)?;
+            }
+            if !self.query_username_hashes.is_empty() {
+                let query_username_hashes_chunk = (self.query_username_hashes.get(query_phones_chunk_range)).ok_or(SGX_ERROR_UNEXPECTED)?;
+                Self::apply_username_lookup(
+                    &in_username_hashes,
+                    &in_username_uuids,
+                    args.in_username_count,
+                    query_username_hashes_chunk,
+                    bytes_per_result,
                     in_query_phones_result_chunk,
                 )?;
             }
+            lookup.miss_count = (lookup.miss_count).saturating_add(Self::count_misses(in_query_phones_result_chunk, bytes_per_result));
+            lookup.in_query_phones_result_done_len = in_query_phones_result_chunk_end;
+            lookup.chunks.advance();
+
+            // reply to each request as soon as the chunks covering its phones have been looked up
+            while let Some(request) = self.requests.front() {
+                let request_in_query_phones_result_end = (lookup.in_query_phones_result_replied_len)
+                    .saturating_add(request.request_phone_count.to_usize().saturating_mul(bytes_per_result));
+                if request_in_query_phones_result_end > lookup.in_query_phones_result_done_len {
+                    break;
+                }
+                lookup.sealed_results.unseal(lookup.in_query_phones_result.get_mut())?;
+                let request_in_query_phones_result = (lookup.in_query_phones_result.get_mut())
+                    .get_mut(lookup.in_query_phones_result_replied_len..request_in_query_phones_result_end)
+                    .ok_or(SGX_ERROR_UNEXPECTED)?;
+                if let Some(replied_request) = self.requests.pop_front() {
+                    let reply = reply_assembler.assemble(
+                        replied_request.reply_layout,
+                        replied_request.reply_flags,
+                        replied_request.since_change_token,
+                        replied_request.commitment_nonce.get(),
+                        request_in_query_phones_result,
+                    );
+                    clear(request_in_query_phones_result);
+                    // a failure to reply to one client doesn't hold up the rest, but fails the stop call finishing the batch
+                    let reply_batch = Some(&lookup.reply_batch).filter(|_| replied_request.reply_flags.batch_key());
+                    let reply_tos = (replied_request.duplicates.into_iter())
+                        .map(|duplicate| (duplicate.from, duplicate.response_key))
+                        .chain(iter::once((replied_request.from, replied_request.response_key)));
+                    for (from, response_key) in reply_tos {
+                        let reply_res = encrypt_reply(reply.get(), response_key.as_ref())
+                            .and_then(|mut encrypted_reply| reply_scratch.reply(from, encrypted_reply.get_mut(), reply_batch));
+                        if let Err(error) = reply_res {
+                            lookup.reply_error.get_or_insert(error);
+                        }
+                    }
+                }
+                lookup.in_query_phones_result_replied_len = request_in_query_phones_result_end;
+            }
+
+            // the request left at the head of the queue waits on later chunks with its results so far sealed
+            if let Some(request) = self.requests.front() {
+                (lookup.sealed_results).seal(
+                    &request.from,
+                    lookup.in_query_phones_result.get_mut(),
+                    lookup.in_query_phones_result_replied_len,
+                    lookup.in_query_phones_result_done_len,
+                )?;
+            }
+        }
+
+        // hand the rest of the batch back to the host to carry on with in a later stop call
+        if lookup.in_query_phones_result_done_len < lookup.in_query_phones_result.get().len() {
+            let continuation_token = lookup.chunks.continuation_token();
+            self.lookup = Some(lookup);
+            return Ok(SgxsdTerminate::Suspended(self, continuation_token));
+        }
+
+        LOOKUP_MISS_RATE.record(lookup.miss_count, self.query_phones.len().to_u64());
+        match lookup.reply_error {
+            Some(reply_error) => Err(reply_error),
+            None => Ok(SgxsdTerminate::Done),
+        }
+    }
+
+    fn metrics(&self, now_ticks: u64) -> Result<ServerMetrics, SgxStatus> {
+        let mut metrics = ServerMetrics {
+            pending_request_count: self.requests.len().to_u64(),
+            request_phone_count_histogram: self.request_phone_count_histogram,
+            held_phone_count: self.held_phone_count().to_u64(),
+            ..Default::default()
+        };
+        for request in &self.requests {
+            // ticks come from the host and aren't trusted to be monotonic
+            let queue_age_ticks = now_ticks.saturating_sub(request.admission_ticks);
+            metrics.max_queue_age_ticks = metrics.max_queue_age_ticks.max(queue_age_ticks);
+            let queue_age_bits = 64u32.wrapping_sub(queue_age_ticks.leading_zeros());
+            let bucket = queue_age_bits.to_usize().min(QUEUE_AGE_HISTOGRAM_BUCKETS - 1);
+            if let Some(bucket_count) = metrics.queue_age_histogram.get_mut(bucket) {
+                *bucket_count = bucket_count.saturating_add(1);
+            }
+        }
+        if let Some(lookup_miss_rate_ewma_ppm) = LOOKUP_MISS_RATE.ewma_ppm() {
+            metrics.lookup_miss_rate_ewma_ppm = lookup_miss_rate_ewma_ppm;
+            metrics.lookup_miss_rate_alert = (self.miss_rate_alert_ppm != 0 && lookup_miss_rate_ewma_ppm > self.miss_rate_alert_ppm).into();
+        }
+        Ok(metrics)
+    }
+
+    fn cancel(&mut self, tag: u64) -> Result<(), SgxStatus> {
+        // once the lookup of the batch has started, the places of its phones in the lookup are fixed
+        if self.lookup.is_some() {
+            return Err(INCIDENT_LATCH.violation(HostViolation::CallOrder, SGX_ERROR_INVALID_STATE));
         }
 
-        let mut in_query_phones_result_remaining = &mut in_query_phones_result.get_mut()[..];
-        for request in self.requests {
-            let (request_in_query_phones_result, in_query_phones_result_rest) =
-                in_query_phones_result_remaining.split_at_mut(request.request_phone_count.to_usize() * BYTES_PER_UUID);
-            request.from.reply(request_in_query_phones_result)?;
-            in_query_phones_result_remaining = in_query_phones_result_rest;
+        // a resubmission is dropped on its own, and a request that was resubmitted is replaced by one of its resubmissions
+        for request in &mut self.requests {
+            if let Some(duplicate_index) = (request.duplicates.iter()).position(|duplicate| duplicate.from.tag() == Some(tag)) {
+                request.duplicates.remove(duplicate_index);
+                return Ok(());
+            }
+        }
+        let request_index = (self.requests.iter())
+            .position(|request| request.from.tag() == Some(tag))
+            .ok_or(SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND)?;
+        let promoted = (self.requests.get_mut(request_index))
+            .filter(|request| !request.duplicates.is_empty())
+            .map(|request| {
+                let duplicate = request.duplicates.remove(0);
+                request.response_key = duplicate.response_key;
+                (mem::replace(&mut request.from, duplicate.from), request.request_phone_count.to_usize())
+            });
+        if let Some((cancelled_from, request_phone_count)) = promoted {
+            self.release_session_phones(&cancelled_from, request_phone_count);
+            let session_id = (self.requests.get(request_index)).and_then(|request| request.from.client_pubkey()).copied();
+            if let Some(session_id) = session_id {
+                self.charge_session_phones(session_id, request_phone_count);
+            }
+            // a request id only names the request within the session it's to be replied to
+            (self.request_id_indices).retain(|(request_session_id, _), request_id_index| {
+                request_id_index.request_index != request_index || Some(*request_session_id) == session_id
+            });
+            return Ok(());
         }
 
+        // otherwise the request's phones are taken out of the batch, from wherever the requests before it leave off
+        let request = self.requests.remove(request_index).ok_or(SGX_ERROR_UNEXPECTED)?;
+        let request_phone_count = request.request_phone_count.to_usize();
+        let request_phones_start = (self.requests.iter())
+            .take(request_index)
+            .fold(0usize, |request_phones_start, earlier_request| {
+                request_phones_start.saturating_add(earlier_request.request_phone_count.to_usize())
+            });
+        remove_keys(&mut self.query_phones, request_phones_start, request_phone_count);
+        if !self.query_uuids.is_empty() {
+            remove_keys(&mut self.query_uuids, request_phones_start, request_phone_count);
+        }
+        if !self.query_username_hashes.is_empty() {
+            remove_keys(&mut self.query_username_hashes, request_phones_start, request_phone_count);
+        }
+        self.request_indices.retain(|_, index| *index != request_index);
+        for index in self.request_indices.values_mut().filter(|index| **index > request_index) {
+            *index = index.saturating_sub(1);
+        }
+        self.request_id_indices.retain(|_, request_id_index| request_id_index.request_index != request_index);
+        for request_id_index in self.request_id_indices.values_mut().filter(|index| index.request_index > request_index) {
+            request_id_index.request_index = request_id_index.request_index.saturating_sub(1);
+        }
+        self.release_session_phones(&request.from, request_phone_count);
         Ok(())
     }
 }
 
 //
-// PhoneList
+// PartialRequest
 //
 
-impl PhoneList {
-    pub fn new(capacity: usize) -> Self {
+impl PartialRequest {
+    // join a fragment onto those before it into a new buffer, along with its chunk commitments if it has them
+    fn add_fragment(
+        previous: Option<Self>,
+        args: &CallArgs,
+        fragment: SecretValue<Box<[u8]>>,
+        fragment_chunk_commitments: Option<Vec<u8>>,
+    ) -> Result<Self, SgxStatus>
+    {
+        let mut previous = match previous {
+            Some(previous) => previous,
+            None => {
+                return Ok(Self {
+                    query_data:         fragment,
+                    query_phone_count:  args.query_phone_count.to_usize(),
+                    query_commitment:   args.query_commitment,
+                    chunk_commitments:  fragment_chunk_commitments,
+                    reply_layout:       args.reply_layout,
+                    since_change_token: args.since_change_token,
+                })
+            }
+        };
+        if previous.query_commitment != args.query_commitment
+            || previous.reply_layout != args.reply_layout
+            || previous.since_change_token != args.since_change_token
+            || previous.chunk_commitments.is_some() != fragment_chunk_commitments.is_some()
+        {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        if let (Some(chunk_commitments), Some(fragment_chunk_commitments)) = (&mut previous.chunk_commitments, fragment_chunk_commitments) {
+            chunk_commitments.extend_from_slice(&fragment_chunk_commitments);
+        }
+        let query_data_len = previous.query_data.get().len().saturating_add(fragment.get().len());
+        let mut query_data = SecretValue::new(vec![0u8; query_data_len].into_boxed_slice());
+        let (previous_data, fragment_data) = query_data.get_mut().split_at_mut(previous.query_data.get().len());
+        previous_data.copy_from_slice(previous.query_data.get());
+        fragment_data.copy_from_slice(fragment.get());
+        Ok(Self {
+            query_data,
+            query_phone_count: previous.query_phone_count.saturating_add(args.query_phone_count.to_usize()),
+            ..previous
+        })
+    }
+}
+
+//
+// BatchLookup
+//
+
+impl BatchLookup {
+    // the stop args naming the directory the batch is looked up in, which mustn't change between stop calls
+    fn directory(args: &StopArgs) -> StopArgs {
+        StopArgs {
+            max_chunks: 0,
+            continuation_token: 0,
+            ..*args
+        }
+    }
+
+    fn resumed_by(&self, args: &StopArgs) -> bool {
+        args.continuation_token == self.chunks.continuation_token() && Self::directory(args) == self.directory
+    }
+
+    fn retried_by(&self, args: &StopArgs) -> bool {
+        args.continuation_token < self.chunks.continuation_token() && Self::directory(args) == self.directory
+    }
+}
+
+//
+// ProtocolVersion
+//
+
+impl ProtocolVersion {
+    // told apart from a malformed request, so the host can tell a client too new for this enclave to fall back
+    fn from_id(protocol_version: ProtocolVersionId) -> Result<Self, SgxStatus> {
+        match protocol_version {
+            CDS_PROTOCOL_VERSION_0 => Ok(Self::V0),
+            CDS_PROTOCOL_VERSION_1 => Ok(Self::V1),
+            _ => Err(CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION),
+        }
+    }
+}
+
+//
+// QueryId
+//
+
+impl QueryId {
+    fn new(args: &CallArgs) -> Self {
+        Self {
+            iv: args.query.iv.data,
+            mac: args.query.mac.data,
+            commitment: args.query_commitment,
+            reply_flags: args.reply_flags,
+            reply_layout: args.reply_layout,
+            since_change_token: args.since_change_token,
+            protocol_version: args.protocol_version,
+            query_key_size: args.query_key_size,
+        }
+    }
+}
+
+//
+// PhoneList
+//
+
+impl PhoneList {
+    pub fn new(capacity: usize) -> Self {
         Self(Vec::with_capacity(capacity))
     }
 }
@@ -220,36 +1541,330 @@ impl DerefMut for PhoneList {
     }
 }
 
+//
+// UuidList
+//
+
+impl UuidList {
+    pub fn new(capacity: usize) -> Self {
+        Self(Vec::with_capacity(capacity))
+    }
+}
+
+impl Drop for UuidList {
+    fn drop(&mut self) {
+        let byte_len = self.0.len().saturating_mul(mem::size_of::<Uuid>());
+        let clear_res = unsafe { memset_s(self.0.as_mut_ptr() as *mut c_void, byte_len, 0, byte_len) };
+        assert_eq!(clear_res, 0);
+    }
+}
+
+impl Deref for UuidList {
+    type Target = Vec<Uuid>;
+
+    fn deref(&self) -> &Vec<Uuid> {
+        &self.0
+    }
+}
+impl DerefMut for UuidList {
+    fn deref_mut(&mut self) -> &mut Vec<Uuid> {
+        &mut self.0
+    }
+}
+
+//
+// UsernameHashList
+//
+
+impl UsernameHashList {
+    pub fn new(capacity: usize) -> Self {
+        Self(Vec::with_capacity(capacity))
+    }
+}
+
+impl Drop for UsernameHashList {
+    fn drop(&mut self) {
+        let byte_len = self.0.len().saturating_mul(mem::size_of::<UsernameHash>());
+        let clear_res = unsafe { memset_s(self.0.as_mut_ptr() as *mut c_void, byte_len, 0, byte_len) };
+        assert_eq!(clear_res, 0);
+    }
+}
+
+impl Deref for UsernameHashList {
+    type Target = Vec<UsernameHash>;
+
+    fn deref(&self) -> &Vec<UsernameHash> {
+        &self.0
+    }
+}
+impl DerefMut for UsernameHashList {
+    fn deref_mut(&mut self) -> &mut Vec<UsernameHash> {
+        &mut self.0
+    }
+}
+
 //
 //
 // RequestPhoneList
 //
 
 impl<'a> IntoIterator for &'a RequestPhoneList {
-    type IntoIter = iter::Map<slice::ChunksExact<'a, u8>, fn(&[u8]) -> Phone>;
-    type Item = Phone;
+    type IntoIter = iter::Map<slice::ChunksExact<'a, u8>, fn(&[u8]) -> E164Phone>;
+    type Item = E164Phone;
 
     fn into_iter(self) -> Self::IntoIter {
-        let phones_data = self.data.get().get(COMMITMENT_NONCE_SIZE..).unwrap_or_default();
-        phones_data
-            .chunks_exact(mem::size_of::<Phone>())
+        self.keys_data()
+            .chunks_exact(self.bytes_per_key)
             .map(RequestPhoneList::decode_phone)
     }
 }
 
 impl RequestPhoneList {
-    fn new(data: Box<[u8]>) -> Self {
-        Self {
-            data: SecretValue::new(data),
-        }
+    fn new(data: SecretValue<Box<[u8]>>, bytes_per_key: usize) -> Self {
+        Self { data, bytes_per_key }
     }
 
-    fn iter(&self) -> impl ExactSizeIterator<Item = Phone> + '_ {
+    // each phone key, or a placeholder phone for each uuid or username hash key
+    fn iter(&self) -> impl ExactSizeIterator<Item = E164Phone> + '_ {
         self.into_iter()
     }
 
-    fn decode_phone(data: &[u8]) -> Phone {
-        u64::from_ne_bytes(data.try_into().expect("chunks are of size 8"))
+    // tagged keys may be uuids, and identifiers always are
+    fn has_uuid_keys(&self) -> bool {
+        self.bytes_per_key == BYTES_PER_TAGGED_KEY || self.bytes_per_key == BYTES_PER_IDENTIFIER
+    }
+
+    fn is_username_hashes(&self) -> bool {
+        self.bytes_per_key == BYTES_PER_USERNAME_HASH
+    }
+
+    fn commitment_label(&self) -> &'static [u8] {
+        match self.bytes_per_key {
+            BYTES_PER_USERNAME_HASH => USERNAME_HASH_QUERY_COMMITMENT_LABEL,
+            BYTES_PER_IDENTIFIER => IDENTIFIER_QUERY_COMMITMENT_LABEL,
+            _ => &[],
+        }
+    }
+
+    fn commitment_nonce(&self) -> SecretValue<[u8; COMMITMENT_NONCE_SIZE]> {
+        let mut commitment_nonce = SecretValue::new([0; COMMITMENT_NONCE_SIZE]);
+        if let Some(commitment_nonce_data) = self.data.get().get(..COMMITMENT_NONCE_SIZE) {
+            commitment_nonce.get_mut().copy_from_slice(commitment_nonce_data);
+        }
+        commitment_nonce
+    }
+
+    // each uuid key, or a zero uuid for each phone key
+    fn uuids(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.keys_data().chunks_exact(self.bytes_per_key).map(Self::decode_uuid)
+    }
+
+    // each username hash key, or a zero hash for each key of a query of phones or tagged keys
+    fn username_hashes(&self) -> impl Iterator<Item = UsernameHash> + '_ {
+        (self.keys_data().chunks_exact(self.bytes_per_key)).map(|data| data.try_into().unwrap_or([0; USERNAME_HASH_SIZE]))
+    }
+
+    // each phone key is rewritten by the first canonicalization rule it matches, without branching on which keys are phones
+    fn canonicalize(&mut self, canonicalization_rules: &CanonicalizationRules) {
+        if self.is_username_hashes() {
+            return;
+        }
+        let bytes_per_key = self.bytes_per_key;
+        let keys_data = self.data.get_mut().get_mut(COMMITMENT_NONCE_SIZE..).unwrap_or_default();
+        for key_data in keys_data.chunks_exact_mut(bytes_per_key) {
+            let phone_mask = !Self::decode_uuid_mask(key_data);
+            if let Some(phone_data) = key_data.get_mut(..BYTES_PER_PHONE) {
+                let phone = Self::decode_word(Some(phone_data));
+                let canonical_phone = (canonicalization_rules.canonicalize(phone) & phone_mask) | (phone & !phone_mask);
+                phone_data.copy_from_slice(&canonical_phone.to_ne_bytes());
+            }
+        }
+    }
+
+    // check every key without stopping at the first invalid one, so how long it takes doesn't tell which it was
+    fn check_keys(&self) -> Result<(), SgxStatus> {
+        let username_hashes = self.is_username_hashes();
+        let mut invalid = false;
+        let mut invalid_phone = false;
+        for key_data in self.keys_data().chunks_exact(self.bytes_per_key) {
+            let tag = Self::decode_tag(key_data);
+            let phone = Self::decode_word(key_data.get(..BYTES_PER_PHONE));
+            let uuid = Self::decode_uuid(key_data);
+            let hash_bits = key_data.iter().fold(0u8, |hash_bits, byte| hash_bits | byte);
+            invalid |= username_hashes & (hash_bits == 0);
+            invalid |= !username_hashes & (tag != QUERY_KEY_TAG_PHONE) & (tag != QUERY_KEY_TAG_UUID);
+            invalid_phone |= !username_hashes & (tag == QUERY_KEY_TAG_PHONE) & E164Phone::new(phone).is_err();
+            invalid |= (tag == QUERY_KEY_TAG_UUID) & AccountUuid::new(uuid).is_err();
+        }
+        // a query only holding a phone that can't be an E.164 number is told apart, so the client can drop that contact
+        if invalid {
+            Err(SGX_ERROR_INVALID_PARAMETER)
+        } else if invalid_phone {
+            Err(CDS_ERROR_INVALID_QUERY_PHONE)
+        } else {
+            Ok(())
+        }
+    }
+
+    // a deployment may require keys in strictly ascending order, by tag then as big-endian numbers, compared obliviously
+    fn check_sorted(&self) -> Result<(), SgxStatus> {
+        let mut unsorted = 0u64;
+        let mut sort_keys = self.keys_data().chunks_exact(self.bytes_per_key).map(Self::decode_sort_key);
+        if let Some(mut previous_sort_key) = sort_keys.next() {
+            for sort_key in sort_keys {
+                unsorted |= sorts_before(&previous_sort_key, &sort_key) ^ 1;
+                previous_sort_key = sort_key;
+            }
+        }
+        if unsorted != 0 {
+            Err(CDS_ERROR_QUERY_NOT_SORTED)
+        } else {
+            Ok(())
+        }
+    }
+
+    // no key may appear twice in a query; they're put in order with a bitonic sort so repeats end up next to each other
+    fn check_distinct(&self) -> Result<(), SgxStatus> {
+        let mut sort_keys: Vec<[u64; SORT_KEY_WORDS]> = (self.keys_data().chunks_exact(self.bytes_per_key))
+            .map(Self::decode_sort_key)
+            .collect();
+        let padded_len = sort_keys.len().checked_next_power_of_two().ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+        sort_keys.resize(padded_len, NO_SORT_KEY);
+        bitonic_sort(&mut sort_keys);
+
+        // the padding sorts after every key, and its repeats don't count
+        let mut repeated = 0u64;
+        for (previous_sort_key, sort_key) in sort_keys.iter().zip(sort_keys.iter().skip(1)) {
+            repeated |= (sorts_before(previous_sort_key, sort_key) ^ 1) & sorts_before(sort_key, &NO_SORT_KEY);
+        }
+
+        let byte_len = sort_keys.len().saturating_mul(mem::size_of::<[u64; SORT_KEY_WORDS]>());
+        let clear_res = unsafe { memset_s(sort_keys.as_mut_ptr() as *mut c_void, byte_len, 0, byte_len) };
+        assert_eq!(clear_res, 0);
+
+        if repeated != 0 {
+            Err(CDS_ERROR_DUPLICATE_QUERY_PHONE)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn decode_sort_key(data: &[u8]) -> [u64; SORT_KEY_WORDS] {
+        let mut sort_key = [0; SORT_KEY_WORDS];
+        let key_words = (data.chunks_exact(BYTES_PER_PHONE)).map(|word_data| u64::from_be(Self::decode_word(Some(word_data))));
+        for (sort_key_word, word) in sort_key.iter_mut().zip(iter::once(u64::from(Self::decode_tag(data))).chain(key_words)) {
+            *sort_key_word = word;
+        }
+        sort_key
+    }
+
+    fn keys_data(&self) -> &[u8] {
+        self.data.get().get(COMMITMENT_NONCE_SIZE..).unwrap_or_default()
+    }
+
+    // only called on keys that have passed check_keys
+    fn decode_phone(data: &[u8]) -> E164Phone {
+        let placeholder_mask = Self::decode_uuid_mask(data) | 0u64.wrapping_sub(u64::from(data.len() == BYTES_PER_USERNAME_HASH));
+        let phone = Self::decode_word(data.get(..BYTES_PER_PHONE));
+        E164Phone((phone & !placeholder_mask) | (UUID_KEY_PLACEHOLDER_PHONE.to_be() & placeholder_mask))
+    }
+
+    // only tagged keys carry a tag; an identifier is read as a uuid, and every other untagged key as a phone
+    fn decode_tag(data: &[u8]) -> u8 {
+        match data.len() {
+            BYTES_PER_TAGGED_KEY => data.get(BYTES_PER_UUID).copied().unwrap_or(QUERY_KEY_TAG_PHONE),
+            BYTES_PER_IDENTIFIER => QUERY_KEY_TAG_UUID,
+            _ => QUERY_KEY_TAG_PHONE,
+        }
+    }
+
+    fn decode_uuid_mask(data: &[u8]) -> u64 {
+        0u64.wrapping_sub(u64::from(Self::decode_tag(data) == QUERY_KEY_TAG_UUID))
+    }
+
+    fn decode_uuid(data: &[u8]) -> Uuid {
+        let uuid_mask = Self::decode_uuid_mask(data);
+        Uuid {
+            data64: [
+                Self::decode_word(data.get(..BYTES_PER_PHONE)) & uuid_mask,
+                Self::decode_word(data.get(BYTES_PER_PHONE..BYTES_PER_UUID)) & uuid_mask,
+            ],
+        }
+    }
+
+    fn decode_word(data: Option<&[u8]>) -> u64 {
+        u64::from_ne_bytes(data.and_then(|data| data.try_into().ok()).unwrap_or_default())
+    }
+}
+
+// remove the keys of a cancelled request from those of the batch, clearing the places they leave free at its end
+fn remove_keys<T>(keys: &mut Vec<T>, start: usize, count: usize) {
+    if let Some(keys_from_start) = keys.get_mut(start..) {
+        keys_from_start.rotate_left(count.min(keys_from_start.len()));
+    }
+    let remaining_len = keys.len().saturating_sub(count);
+    if let Some(removed_keys) = keys.get_mut(remaining_len..) {
+        let byte_len = removed_keys.len().saturating_mul(mem::size_of::<T>());
+        let clear_res = unsafe { memset_s(removed_keys.as_mut_ptr() as *mut c_void, byte_len, 0, byte_len) };
+        assert_eq!(clear_res, 0);
+    }
+    keys.truncate(remaining_len);
+}
+
+// 1 if left sorts strictly before right, comparing word by word, else 0, without branching on either
+fn sorts_before(left: &[u64], right: &[u64]) -> u64 {
+    let mut less = 0u64;
+    let mut equal = 1u64;
+    for (&left_word, &right_word) in left.iter().zip(right) {
+        let difference = left_word ^ right_word;
+        let word_less = ((!left_word & right_word) | (!difference & left_word.wrapping_sub(right_word))) >> 63;
+        less |= equal & word_less;
+        equal &= ((difference | difference.wrapping_neg()) >> 63) ^ 1;
+    }
+    less
+}
+
+impl ObliviousOrd for [u64; SORT_KEY_WORDS] {
+    fn sorts_before(&self, other: &Self) -> u64 {
+        sorts_before(self, other)
+    }
+
+    fn swap_if(&mut self, other: &mut Self, swap: u64) {
+        for (word, other_word) in self.iter_mut().zip(other.iter_mut()) {
+            word.swap_if(other_word, swap);
+        }
+    }
+}
+
+//
+// E164Phone
+//
+
+impl E164Phone {
+    pub fn new(phone: Phone) -> Result<Self, SgxStatus> {
+        if u64::from_be(phone).wrapping_sub(1) < MAX_E164_NUMBER {
+            Ok(Self(phone))
+        } else {
+            Err(CDS_ERROR_INVALID_QUERY_PHONE)
+        }
+    }
+
+    pub fn get(self) -> Phone {
+        self.0
+    }
+}
+
+//
+// AccountUuid
+//
+
+impl AccountUuid {
+    pub fn new(uuid: Uuid) -> Result<Self, SgxStatus> {
+        if (uuid.data64[0] | uuid.data64[1]) != 0 {
+            Ok(Self(uuid))
+        } else {
+            Err(SGX_ERROR_INVALID_PARAMETER)
+        }
     }
 }
 
@@ -259,11 +1874,15 @@ impl RequestPhoneList {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::Cell;
     use std::ffi::c_void;
     use std::mem;
+    use std::ptr;
+    use std::rc::Rc;
 
     use mockers::matchers::*;
     use mockers::*;
+    use sgxsd_ffi::mocks::SgxsdAesGcmDecryptMockHandle;
 
     use super::*;
 
@@ -272,10 +1891,19 @@ mod tests {
         static ref VALID_IN_UUIDS:  Vec<Uuid>  = vec![Uuid { data64: test_ffi::rand() }; 1];
     }
 
+    // a valid E.164 phone key, big-endian as clients send it
+    fn test_phone(number: u64) -> Phone {
+        (15_550_000_000 + number).to_be()
+    }
+    fn test_phones(numbers: impl IntoIterator<Item = u64>) -> Vec<Phone> {
+        numbers.into_iter().map(test_phone).collect()
+    }
+
     fn empty_init_args() -> Box<StartArgs> {
         Box::new(StartArgs {
             max_query_phones: 0,
             max_ratelimit_states: 0,
+            ..Default::default()
         })
     }
     fn empty_call_args() -> Box<CallArgs> {
@@ -289,81 +1917,599 @@ mod tests {
             in_phones: VALID_IN_PHONES.as_ptr() as *mut Phone,
             in_uuids: VALID_IN_UUIDS.as_ptr() as *mut Uuid,
             in_phone_count: 1,
+            ..Default::default()
         })
     }
 
-    #[test]
-    fn test_in_phones_outside_enclave() {
-        let scenario = Scenario::new();
-        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
-        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return(false));
+    #[derive(Clone)]
+    struct MockRequest {
+        phones:          Vec<Phone>,
+        uuids:           Option<Vec<Uuid>>,
+        username_hashes: Option<Vec<UsernameHash>>,
+        query_key_size:  u32,
+        delta_encoded:   bool,
+        query_nonce:     [u8; COMMITMENT_NONCE_SIZE],
+        reply_flags:     u32,
+        query_data:      Vec<u8>,
+        query_key:       [u8; 32],
+        query_iv:        [u8; 12],
+        reply_layout:    ReplyLayoutId,
+        since_change:    u64,
+        request_id:      u64,
+        fragment:        Option<MockFragment>,
+    }
 
-        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
-        server.terminate(Some(&valid_stop_args())).unwrap_err();
+    #[derive(Clone)]
+    struct MockFragment {
+        plaintext:      Vec<u8>,
+        phone_count:    usize,
+        token:          u64,
+        more_fragments: bool,
     }
 
-    #[test]
-    fn test_in_uuids_outside_enclave() {
-        let valid_stop_args = valid_stop_args();
+    impl MockRequest {
+        fn new(phones: Vec<Phone>) -> Self {
+            Self {
+                query_data: test_ffi::rand_bytes(vec![0; COMMITMENT_NONCE_SIZE + phones.len() * BYTES_PER_PHONE]),
+                query_key: test_ffi::rand(),
+                query_iv: test_ffi::rand(),
+                query_nonce: test_ffi::rand(),
+                phones,
+                reply_flags: CDS_REPLY_FLAG_BATCH_KEY | CDS_REPLY_FLAG_QUERY_NONCE,
+                uuids: None,
+                username_hashes: None,
+                query_key_size: 0,
+                delta_encoded: false,
+                reply_layout: CDS_REPLY_LAYOUT_UUID,
+                since_change: 0,
+                request_id: 0,
+                fragment: None,
+            }
+        }
 
-        let scenario = Scenario::new();
-        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
-        scenario.expect(
-            sgx_is_outside_enclave
-                .sgx_is_outside_enclave(valid_stop_args.in_phones as *const c_void, any())
-                .and_return(true),
-        );
-        scenario.expect(
-            sgx_is_outside_enclave
-                .sgx_is_outside_enclave(valid_stop_args.in_uuids as *const c_void, any())
-                .and_return(false),
-        );
+        fn with_reply_flags(self, reply_flags: u32) -> Self {
+            Self { reply_flags, ..self }
+        }
 
-        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
-        server.terminate(Some(&valid_stop_args)).unwrap_err();
-    }
+        fn with_request_id(self, request_id: u64) -> Self {
+            Self { request_id, ..self }
+        }
 
-    #[test]
-    fn test_in_phones_overflow() {
-        let scenario = Scenario::new();
-        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
-        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).never());
+        // a retry of this request, encrypted anew
+        fn reencrypted(&self) -> Self {
+            Self {
+                query_data: test_ffi::rand_bytes(vec![0; self.query_data.len()]),
+                query_iv: test_ffi::rand(),
+                ..self.clone()
+            }
+        }
 
-        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
-        server
-            .terminate(Some(&StopArgs {
-                in_phones: VALID_IN_PHONES.as_ptr() as *mut Phone,
-                in_uuids: VALID_IN_UUIDS.as_ptr() as *mut Uuid,
-                in_phone_count: 1 + usize::max_value() / mem::size_of::<Phone>(),
-            }))
-            .unwrap_err();
-    }
+        fn with_reply_layout(self, reply_layout: ReplyLayoutId) -> Self {
+            Self { reply_layout, ..self }
+        }
 
-    #[test]
-    fn test_in_uuids_overflow() {
-        let scenario = Scenario::new();
-        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
-        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).never());
+        // a request for what changed since the reply that handed back the given change token
+        fn with_since_change(self, since_change: u64) -> Self {
+            Self {
+                reply_layout: CDS_REPLY_LAYOUT_UUID_CHANGES,
+                since_change,
+                ..self
+            }
+        }
 
-        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
-        server
-            .terminate(Some(&StopArgs {
-                in_phones: VALID_IN_PHONES.as_ptr() as *mut Phone,
-                in_uuids: VALID_IN_UUIDS.as_ptr() as *mut Uuid,
-                in_phone_count: 1 + usize::max_value() / mem::size_of::<Uuid>(),
-            }))
-            .unwrap_err();
-    }
+        // a request with tagged keys, where each non-zero uuid stands in place of the phone at its index
+        fn with_uuids(phones: Vec<Phone>, uuids: Vec<Uuid>) -> Self {
+            Self {
+                query_data: test_ffi::rand_bytes(vec![0; COMMITMENT_NONCE_SIZE + phones.len() * BYTES_PER_TAGGED_KEY]),
+                uuids: Some(uuids),
+                ..Self::new(phones)
+            }
+        }
 
-    #[test]
-    fn test_zero_max_batch() {
-        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
-        server.terminate(Some(&empty_stop_args())).unwrap();
-    }
+        // a request in CDS_PROTOCOL_VERSION_1 of the given phones, which must be in ascending order
+        fn with_delta_phones(phones: Vec<Phone>) -> Self {
+            let request = Self {
+                delta_encoded: true,
+                ..Self::new(phones)
+            };
+            Self {
+                query_data: test_ffi::rand_bytes(vec![0; request.plaintext().len()]),
+                ..request
+            }
+        }
 
-    #[test]
-    fn test_empty_batch() {
-        let valid_stop_args = valid_stop_args();
+        // a request of untagged identifiers, each looked up as a uuid, naming the size of its keys
+        fn with_identifiers(identifiers: Vec<Uuid>) -> Self {
+            Self {
+                query_data: test_ffi::rand_bytes(vec![0; COMMITMENT_NONCE_SIZE + identifiers.len() * BYTES_PER_IDENTIFIER]),
+                query_key_size: BYTES_PER_IDENTIFIER as u32,
+                ..Self::with_uuids(vec![0; identifiers.len()], identifiers)
+            }
+        }
+
+        fn with_username_hashes(username_hashes: Vec<UsernameHash>) -> Self {
+            Self {
+                query_data: test_ffi::rand_bytes(vec![0; COMMITMENT_NONCE_SIZE + username_hashes.len() * BYTES_PER_USERNAME_HASH]),
+                username_hashes: Some(username_hashes.clone()),
+                ..Self::new(vec![0; username_hashes.len()])
+            }
+        }
+
+        // the requests handing this one in as fragments of the given numbers of its phones, each encrypted on its own
+        fn fragments(&self, token: u64, fragment_phone_counts: &[usize]) -> Vec<Self> {
+            let plaintext = self.plaintext();
+            let bytes_per_key = (plaintext.len() - COMMITMENT_NONCE_SIZE) / self.phones.len();
+            let mut fragments = Vec::new();
+            let mut fragment_start = 0;
+            for (index, phone_count) in fragment_phone_counts.iter().enumerate() {
+                let fragment_end = fragment_start + phone_count * bytes_per_key + if index == 0 { COMMITMENT_NONCE_SIZE } else { 0 };
+                let fragment = MockFragment {
+                    plaintext: plaintext[fragment_start..fragment_end].to_vec(),
+                    phone_count: *phone_count,
+                    token,
+                    more_fragments: index + 1 < fragment_phone_counts.len(),
+                };
+                fragments.push(Self {
+                    query_data: test_ffi::rand_bytes(vec![0; fragment.plaintext.len()]),
+                    fragment: Some(fragment),
+                    ..self.clone()
+                });
+                fragment_start = fragment_end;
+            }
+            fragments
+        }
+
+        fn call_args(&mut self) -> CallArgs {
+            let mut query = EncryptedMessage {
+                size: self.query_data.len() as u32,
+                data: self.query_data.as_mut_ptr(),
+                ..Default::default()
+            };
+            query.iv.data = self.query_iv;
+            CallArgs {
+                query_phone_count: (self.fragment.as_ref()).map_or(self.phones.len(), |fragment| fragment.phone_count) as u32,
+                query,
+                query_commitment: *MOCK_COMMITMENT,
+                reply_layout: self.reply_layout,
+                query_more_fragments: (self.fragment.as_ref()).map_or(false, |fragment| fragment.more_fragments) as u32,
+                query_fragment_token: (self.fragment.as_ref()).map_or(0, |fragment| fragment.token),
+                since_change_token: self.since_change,
+                query_key_size: self.query_key_size,
+                protocol_version: if self.delta_encoded { CDS_PROTOCOL_VERSION_1 } else { CDS_PROTOCOL_VERSION_0 },
+                request_id: self.request_id,
+                reply_flags: self.reply_flags,
+                ..Default::default()
+            }
+        }
+
+        fn plaintext(&self) -> Vec<u8> {
+            if let Some(fragment) = &self.fragment {
+                return fragment.plaintext.clone();
+            }
+            let mut plaintext = self.query_nonce.to_vec();
+            if let Some(username_hashes) = &self.username_hashes {
+                plaintext.extend(username_hashes.iter().flatten());
+                return plaintext;
+            }
+            if self.delta_encoded {
+                let mut previous_phone = 0;
+                for phone in self.phones.iter().map(|phone| u64::from_be(*phone)) {
+                    let mut delta = phone - previous_phone;
+                    while delta >= 0x80 {
+                        plaintext.push((delta & 0x7f) as u8 | 0x80);
+                        delta >>= 7;
+                    }
+                    plaintext.push(delta as u8);
+                    previous_phone = phone;
+                }
+                return plaintext;
+            }
+            match &self.uuids {
+                Some(uuids) if self.query_key_size == BYTES_PER_IDENTIFIER as u32 => {
+                    for uuid in uuids {
+                        plaintext.extend(uuid.data64.iter().flat_map(|word| word.to_ne_bytes().to_vec()));
+                    }
+                }
+                Some(uuids) => {
+                    for (phone, uuid) in self.phones.iter().zip(uuids) {
+                        if *uuid == Uuid::default() {
+                            plaintext.extend(&phone.to_ne_bytes());
+                            plaintext.extend(&[0; BYTES_PER_UUID - BYTES_PER_PHONE]);
+                            plaintext.push(QUERY_KEY_TAG_PHONE);
+                        } else {
+                            plaintext.extend(uuid.data64.iter().flat_map(|word| word.to_ne_bytes().to_vec()));
+                            plaintext.push(QUERY_KEY_TAG_UUID);
+                        }
+                    }
+                }
+                None => plaintext.extend(self.phones.iter().flat_map(|phone| phone.to_ne_bytes().to_vec())),
+            }
+            plaintext
+        }
+
+        // a reply asking for it starts with the nonce of its query
+        fn expected_reply_nonce(&self) -> Vec<u8> {
+            if self.reply_flags & CDS_REPLY_FLAG_QUERY_NONCE == 0 { Vec::new() } else { self.query_nonce.to_vec() }
+        }
+
+        fn expected_reply(&self, in_phones: &[Phone], in_uuids: &[Uuid], in_metadata: Option<&[u32]>) -> Vec<u8> {
+            self.expected_reply_with_pnis(in_phones, in_uuids, None, in_metadata)
+        }
+
+        fn expected_reply_with_pnis(
+            &self,
+            in_phones: &[Phone],
+            in_uuids: &[Uuid],
+            in_pni_uuids: Option<&[Uuid]>,
+            in_metadata: Option<&[u32]>,
+        ) -> Vec<u8>
+        {
+            let uuid_bytes = |uuid: &Uuid| unsafe { uuid.data64 }.iter().flat_map(|word| word.to_ne_bytes().to_vec()).collect::<Vec<u8>>();
+            let with_pni = self.reply_layout == CDS_REPLY_LAYOUT_ACI_PNI;
+            let mut reply = self.expected_reply_nonce();
+            for (index, phone) in self.phones.iter().enumerate() {
+                let uuid = self.uuids.as_ref().map(|uuids| uuids[index]).unwrap_or_default();
+                if uuid != Uuid::default() {
+                    match in_uuids.contains(&uuid) {
+                        true => reply.extend(uuid_bytes(&uuid)),
+                        false => reply.extend(&[0; BYTES_PER_UUID]),
+                    }
+                    reply.extend(Some([0; BYTES_PER_UUID]).filter(|_| with_pni).iter().flatten());
+                    reply.extend(in_metadata.map(|_| [0; METADATA_SIZE]).iter().flatten());
+                    continue;
+                }
+                match in_phones.iter().position(|in_phone| in_phone == phone) {
+                    Some(index) => {
+                        reply.extend(uuid_bytes(&in_uuids[index]));
+                        if with_pni {
+                            match in_pni_uuids {
+                                Some(in_pni_uuids) => reply.extend(uuid_bytes(&in_pni_uuids[index])),
+                                None => reply.extend(&[0; BYTES_PER_UUID]),
+                            }
+                        }
+                        reply.extend(in_metadata.map(|in_metadata| in_metadata[index].to_ne_bytes()).iter().flatten());
+                    }
+                    None => {
+                        reply.extend(&[0; BYTES_PER_UUID]);
+                        reply.extend(Some([0; BYTES_PER_UUID]).filter(|_| with_pni).iter().flatten());
+                        reply.extend(in_metadata.map(|_| [0; METADATA_SIZE]).iter().flatten());
+                    }
+                }
+            }
+            reply.extend(&[0; DIRECTORY_GENERATION_SIZE]);
+            reply
+        }
+
+        fn expected_changes_reply(
+            &self,
+            in_phones: &[Phone],
+            in_uuids: &[Uuid],
+            in_change_epochs: Option<&[u64]>,
+            in_metadata: Option<&[u32]>,
+            change_token: u64,
+        ) -> Vec<u8>
+        {
+            let mut reply = self.expected_reply_nonce();
+            reply.extend(&change_token.to_le_bytes());
+            for phone in &self.phones {
+                let index = in_phones.iter().position(|in_phone| in_phone == phone);
+                let change_epoch = match in_change_epochs {
+                    Some(in_change_epochs) => index.map_or(0, |index| in_change_epochs[index]),
+                    None => u64::max_value(),
+                };
+                if self.since_change != 0 && change_epoch <= self.since_change {
+                    reply.push(0);
+                    reply.extend(&[0; BYTES_PER_UUID]);
+                    reply.extend(in_metadata.map(|_| [0; METADATA_SIZE]).iter().flatten());
+                    continue;
+                }
+                reply.push(1);
+                match index {
+                    Some(index) => {
+                        reply.extend(unsafe { in_uuids[index].data64 }.iter().flat_map(|word| word.to_ne_bytes().to_vec()));
+                        reply.extend(in_metadata.map(|in_metadata| in_metadata[index].to_ne_bytes()).iter().flatten());
+                    }
+                    None => {
+                        reply.extend(&[0; BYTES_PER_UUID]);
+                        reply.extend(in_metadata.map(|_| [0; METADATA_SIZE]).iter().flatten());
+                    }
+                }
+            }
+            reply.extend(&[0; DIRECTORY_GENERATION_SIZE]);
+            reply
+        }
+
+        fn expected_username_reply(&self, in_username_hashes: &[UsernameHash], in_username_uuids: &[Uuid]) -> Vec<u8> {
+            let mut reply = self.expected_reply_nonce();
+            for username_hash in self.username_hashes.iter().flatten() {
+                match in_username_hashes.iter().position(|in_username_hash| in_username_hash == username_hash) {
+                    Some(index) => reply.extend(unsafe { in_username_uuids[index].data64 }.iter().flat_map(|word| word.to_ne_bytes().to_vec())),
+                    None => reply.extend(&[0; BYTES_PER_UUID]),
+                }
+            }
+            reply.extend(&[0; DIRECTORY_GENERATION_SIZE]);
+            reply
+        }
+    }
+
+    lazy_static::lazy_static! {
+        static ref MOCK_COMMITMENT: [u8; 32] = test_ffi::rand();
+    }
+
+    fn expect_valid_requests(scenario: &Scenario, requests: &[MockRequest]) -> SgxsdAesGcmDecryptMockHandle {
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+
+        let decrypt = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_AES_GCM_DECRYPT, scenario);
+        for (request_index, request) in requests.iter().enumerate() {
+            let resubmissions = requests.get(..request_index).unwrap_or_default();
+            if resubmissions.iter().any(|resubmission| resubmission.query_data == request.query_data) {
+                continue;
+            }
+            let submission_count = requests.iter().filter(|submission| submission.query_data == request.query_data).count();
+            let query_key = request.query_key;
+            let query_data = request.query_data.clone();
+            scenario.expect(
+                decrypt
+                    .sgxsd_aes_gcm_decrypt(check(move |key| *key == &query_key), check(move |src| *src == &query_data[..]), any(), any())
+                    .and_return_clone(Ok(request.plaintext()))
+                    .times(submission_count as u32),
+            );
+        }
+
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(*MOCK_COMMITMENT).times(..));
+
+        decrypt
+    }
+
+    fn expect_sealed_results(scenario: &Scenario, decrypt: &SgxsdAesGcmDecryptMockHandle, sealed_results: Vec<Vec<u8>>) {
+        let seal_key: [u8; 32] = test_ffi::rand();
+        let hmac = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256HMAC, scenario);
+        scenario.expect(hmac.hmac_key_init(any()).and_return_clone(()).times(..));
+        scenario.expect(hmac.hmac_update(any()).and_return_clone(()).times(..));
+        scenario.expect(hmac.hmac_out().and_return_clone(seal_key).times(..));
+
+        let encrypt = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_AES_GCM_ENCRYPT, scenario);
+        for plaintext in sealed_results {
+            let ciphertext: Vec<u8> = plaintext.iter().map(|byte| !byte).collect();
+            let encrypt_plaintext = plaintext.clone();
+            scenario.expect(
+                encrypt
+                    .sgxsd_aes_gcm_encrypt(check(move |key| *key == &seal_key), check(move |src| *src == &encrypt_plaintext[..]), any(), any())
+                    .and_return(Ok(ciphertext.clone())),
+            );
+            scenario.expect(
+                decrypt
+                    .sgxsd_aes_gcm_decrypt(check(move |key| *key == &seal_key), check(move |src| *src == &ciphertext[..]), any(), any())
+                    .and_return(Ok(plaintext)),
+            );
+        }
+    }
+
+    fn expect_replies(scenario: &Scenario, expected_replies: Vec<Vec<u8>>) {
+        expect_replies_in_batch(scenario, expected_replies.into_iter().map(|expected_reply| (expected_reply, true)).collect());
+    }
+
+    // each expected reply along with whether it's encrypted under the batch keypair
+    fn expect_replies_in_batch(scenario: &Scenario, expected_replies: Vec<(Vec<u8>, bool)>) {
+        let reply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_REPLY, scenario);
+        let mut reply_seq = Sequence::new();
+        // every reply of a batch under its keypair shares it
+        let batch_pubkey: Rc<Cell<Option<[u8; 32]>>> = Default::default();
+        for (expected_reply, in_batch) in expected_replies {
+            let batch_pubkey = Rc::clone(&batch_pubkey);
+            reply_seq.expect(
+                reply
+                    .sgxsd_enclave_server_reply(
+                        check(move |reply_buf| *reply_buf == &expected_reply[..]),
+                        any(),
+                        check(move |batch: &Option<sgxsd_reply_batch_t>| match batch {
+                            Some(batch) if in_batch => {
+                                let expected_pubkey = batch_pubkey.get().unwrap_or(batch.pubkey.x);
+                                batch_pubkey.set(Some(expected_pubkey));
+                                batch.pubkey.x == expected_pubkey
+                            }
+                            Some(_) => false,
+                            None => !in_batch,
+                        }),
+                    )
+                    .and_return(SGX_SUCCESS),
+            );
+        }
+        scenario.expect(reply_seq);
+    }
+
+    fn clear_mocks() {
+        test_ffi::clear(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_AES_GCM_DECRYPT);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_REPLY);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_AES_GCM_ENCRYPT);
+        test_ffi::clear(&sgxsd_ffi::mocks::BEARSSL_SHA256HMAC);
+    }
+
+    #[test]
+    fn test_in_phones_outside_enclave() {
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return(false));
+
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        server.terminate(Some(&valid_stop_args())).unwrap_err();
+    }
+
+    #[test]
+    fn test_uncommitted_directory_epoch() {
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).never());
+
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        assert_eq!(
+            server.terminate(Some(&StopArgs {
+                directory_epoch: u64::max_value(),
+                ..*valid_stop_args()
+            }))
+            .map(drop),
+            Err(CDS_ERROR_DIRECTORY_EPOCH_MISMATCH)
+        );
+    }
+
+    #[test]
+    fn test_in_uuids_outside_enclave() {
+        let valid_stop_args = valid_stop_args();
+
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(
+            sgx_is_outside_enclave
+                .sgx_is_outside_enclave(valid_stop_args.in_phones as *const c_void, any())
+                .and_return(true),
+        );
+        scenario.expect(
+            sgx_is_outside_enclave
+                .sgx_is_outside_enclave(valid_stop_args.in_uuids as *const c_void, any())
+                .and_return(false),
+        );
+
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        server.terminate(Some(&valid_stop_args)).unwrap_err();
+    }
+
+    #[test]
+    fn test_in_phones_overflow() {
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).never());
+
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: VALID_IN_PHONES.as_ptr() as *mut Phone,
+                in_uuids: VALID_IN_UUIDS.as_ptr() as *mut Uuid,
+                in_phone_count: 1 + usize::max_value() / mem::size_of::<Phone>(),
+                ..Default::default()
+            }))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_in_uuids_overflow() {
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).never());
+
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: VALID_IN_PHONES.as_ptr() as *mut Phone,
+                in_uuids: VALID_IN_UUIDS.as_ptr() as *mut Uuid,
+                in_phone_count: 1 + usize::max_value() / mem::size_of::<Uuid>(),
+                ..Default::default()
+            }))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_zero_max_batch() {
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        server.terminate(Some(&empty_stop_args())).unwrap();
+    }
+
+    #[test]
+    fn test_config() {
+        let args = StartArgs { max_query_phones: 100, max_ratelimit_states: 10, ..Default::default() };
+        let config = SgxsdServerState::config(Some(&args)).unwrap();
+
+        let mut reply_scratch = [0; 8];
+        let scratch_args = StartArgs { reply_scratch: reply_scratch.as_mut_ptr(), reply_scratch_size: reply_scratch.len(), ..args };
+        assert_eq!(SgxsdServerState::config(Some(&scratch_args)).unwrap(), config);
+
+        let sorted_config = SgxsdServerState::config(Some(&StartArgs { require_sorted_query: 1, ..args })).unwrap();
+        let limited_config = SgxsdServerState::config(Some(&StartArgs { max_phones_per_request: 10, ..args })).unwrap();
+        assert_ne!(sorted_config, config);
+        assert_ne!(limited_config, config);
+        assert_ne!(sorted_config, limited_config);
+
+        assert_eq!(SgxsdServerState::config(None), Err(SGX_ERROR_INVALID_PARAMETER));
+    }
+
+    #[test]
+    fn test_unauthenticated_canonicalization_rules() {
+        let mut canonicalization_rules = vec![CANONICALIZATION_RULES_VERSION, 0, 0];
+
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone([0; 32]).times(..));
+
+        assert_eq!(
+            SgxsdServerState::init(Some(&StartArgs {
+                canonicalization_rules: canonicalization_rules.as_mut_ptr(),
+                canonicalization_rules_size: canonicalization_rules.len(),
+                ..Default::default()
+            }))
+            .err(),
+            Some(CDS_ERROR_INVALID_CANONICALIZATION_RULES)
+        );
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_canonicalized_query_phones() {
+        // +44 0xxxxxxxxxx -> +44 xxxxxxxxxx
+        let mut canonicalization_rules_data = vec![CANONICALIZATION_RULES_VERSION, 1, 0, 13, 3, 2];
+        canonicalization_rules_data.extend_from_slice(&440u64.to_le_bytes());
+        canonicalization_rules_data.extend_from_slice(&44u64.to_le_bytes());
+        let scenario = Scenario::new();
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone([0x5a; 32]).times(..));
+        let canonicalization_rules = CanonicalizationRules::parse(&canonicalization_rules_data, Some(&[0x5a; 32])).unwrap();
+        drop(scenario);
+        clear_mocks();
+
+        // a query is checked for repeats, and looked up, by the canonical spelling of each phone
+        let spellings = vec![4402079460000u64.to_be(), 442079460000u64.to_be()];
+        let mut requests = vec![MockRequest::new(spellings[..1].to_vec()), MockRequest::new(spellings.clone())];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(2));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 3,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        server.canonicalization_rules = canonicalization_rules;
+        let results: Vec<_> = (requests.iter_mut())
+            .map(|request| {
+                let call_args = request.call_args();
+                let query_key = request.query_key;
+                server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock()).map_err(|(error, _)| error)
+            })
+            .collect();
+        assert_eq!(results, vec![Ok(()), Err(CDS_ERROR_DUPLICATE_QUERY_PHONE)]);
+        assert_eq!(&server.query_phones[..], &spellings[1..]);
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_empty_batch() {
+        let valid_stop_args = valid_stop_args();
 
         let scenario = Scenario::new();
         let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
@@ -381,6 +2527,7 @@ mod tests {
         let server = SgxsdServerState::init(Some(&StartArgs {
             max_query_phones: 1,
             max_ratelimit_states: 0,
+            ..Default::default()
         }))
         .unwrap();
         server.terminate(Some(&valid_stop_args)).unwrap();
@@ -398,6 +2545,7 @@ mod tests {
         let mut server = SgxsdServerState::init(Some(&StartArgs {
             max_query_phones: 1,
             max_ratelimit_states: 0,
+            ..Default::default()
         }))
         .unwrap();
         assert_eq!(
@@ -409,4 +2557,2562 @@ mod tests {
         );
         server.terminate(Some(&empty_stop_args())).unwrap();
     }
+
+    #[test]
+    fn test_untrusted_read_limit_exceeded() {
+        let mut request = MockRequest::new(test_phones(vec![2, 3, 4]));
+
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return(SGX_SUCCESS),
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 3,
+            max_ratelimit_states: 0,
+            max_untrusted_read_bytes: request.query_data.len() as u32 - 1,
+            ..Default::default()
+        }))
+        .unwrap();
+        let call_args = request.call_args();
+        let query_key = request.query_key;
+        assert_eq!(
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .unwrap_err()
+                .0,
+            CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED
+        );
+        server.terminate(Some(&empty_stop_args())).unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_unknown_reply_flags() {
+        let scenario = Scenario::new();
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(2));
+
+        let mut request = MockRequest::new(test_phones(vec![2]));
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 1,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        let unknown_flag_args = CallArgs {
+            reply_flags: CDS_REPLY_FLAG_QUERY_NONCE << 1,
+            ..request.call_args()
+        };
+        let reserved_args = CallArgs {
+            reply_reserved: 1,
+            ..request.call_args()
+        };
+        for call_args in &[unknown_flag_args, reserved_args] {
+            assert_eq!(
+                server
+                    .handle_call(Some(call_args), &request.query_key, SgxsdMsgFrom::mock())
+                    .unwrap_err()
+                    .0,
+                SGX_ERROR_INVALID_PARAMETER
+            );
+        }
+        assert!(server.requests.is_empty());
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_replies_across_chunks() {
+        let in_phones: Vec<Phone> = test_phones(2..(MAX_HASH_TABLE_SIZE as u64 + 4));
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let mut requests = vec![
+            MockRequest::new(in_phones[..MAX_HASH_TABLE_SIZE - 1].to_vec()),
+            MockRequest::new(vec![in_phones[MAX_HASH_TABLE_SIZE - 1], test_phone(u32::max_value().into()), in_phones[MAX_HASH_TABLE_SIZE + 1]]),
+            MockRequest::new(vec![in_phones[0]]),
+        ];
+
+        let scenario = Scenario::new();
+        let decrypt = expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, None))
+            .collect();
+        // the first phone of the second request is looked up a chunk before the rest of it
+        expect_sealed_results(&scenario, &decrypt, vec![expected_replies[1][COMMITMENT_NONCE_SIZE..][..BYTES_PER_UUID].to_vec()]);
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: MAX_HASH_TABLE_SIZE as u32 + 3,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_legacy_replies_outside_batch() {
+        let in_phones: Vec<Phone> = test_phones(2..6);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // each flag is honoured on its own: only the clients that asked for it are replied to under the batch keypair, and
+        // only those that asked for it have their query nonce start the reply
+        let mut requests = vec![
+            MockRequest::new(vec![in_phones[1], in_phones[0]]).with_reply_flags(0),
+            MockRequest::new(vec![in_phones[2], test_phone(u32::max_value().into())]).with_reply_flags(CDS_REPLY_FLAG_BATCH_KEY),
+            MockRequest::with_delta_phones(vec![in_phones[0], in_phones[3]]).with_reply_flags(CDS_REPLY_FLAG_QUERY_NONCE),
+            MockRequest::with_delta_phones(vec![in_phones[2], in_phones[3]]),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        expect_replies_in_batch(
+            &scenario,
+            (requests.iter())
+                .map(|request| {
+                    let in_batch = request.reply_flags & CDS_REPLY_FLAG_BATCH_KEY != 0;
+                    (request.expected_reply(&in_phones, &in_uuids, None), in_batch)
+                })
+                .collect(),
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 8,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_after_reply_failure() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![2])),
+            MockRequest::new(test_phones(vec![3, 4])),
+            MockRequest::new(test_phones(vec![5])),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, None))
+            .collect();
+        // the request after the one whose reply fails is still replied to
+        let reply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_REPLY, &scenario);
+        let mut reply_seq = Sequence::new();
+        for (expected_reply, reply_res) in expected_replies.into_iter().zip(vec![SGX_SUCCESS, SGX_ERROR_UNEXPECTED, SGX_SUCCESS]) {
+            reply_seq.expect(
+                reply
+                    .sgxsd_enclave_server_reply(check(move |reply_buf| *reply_buf == &expected_reply[..]), any(), any())
+                    .and_return(reply_res),
+            );
+        }
+        scenario.expect(reply_seq);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 4,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        let error = server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                ..Default::default()
+            }))
+            .unwrap_err();
+        assert_eq!(error, SGX_ERROR_UNEXPECTED);
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_across_stop_calls() {
+        let in_phones: Vec<Phone> = test_phones(2..(MAX_HASH_TABLE_SIZE as u64 + 4));
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let mut requests = vec![
+            MockRequest::new(in_phones[..MAX_HASH_TABLE_SIZE - 1].to_vec()),
+            MockRequest::new(vec![in_phones[MAX_HASH_TABLE_SIZE - 1], test_phone(u32::max_value().into()), in_phones[MAX_HASH_TABLE_SIZE + 1]]),
+        ];
+
+        let scenario = Scenario::new();
+        let decrypt = expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, None))
+            .collect();
+        // the first phone of the second request is looked up in the first stop call, and kept sealed until the second
+        expect_sealed_results(&scenario, &decrypt, vec![expected_replies[1][COMMITMENT_NONCE_SIZE..][..BYTES_PER_UUID].to_vec()]);
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: MAX_HASH_TABLE_SIZE as u32 + 2,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        let stop_args = StopArgs {
+            in_phones: in_phones.as_ptr() as *mut Phone,
+            in_uuids: in_uuids.as_ptr() as *mut Uuid,
+            in_phone_count: in_phones.len(),
+            max_chunks: 1,
+            ..Default::default()
+        };
+        let server = match server.terminate(Some(&stop_args)).unwrap() {
+            SgxsdTerminate::Suspended(server, 1) => server,
+            progress => panic!("unexpected terminate progress {:?}", progress),
+        };
+        assert!(server.lookup.is_some());
+        assert_eq!(server.requests.len(), 1);
+
+        // retrying the first stop call replies to nothing again
+        let server = match server.terminate(Some(&stop_args)).unwrap() {
+            SgxsdTerminate::Suspended(server, 1) => server,
+            progress => panic!("unexpected terminate progress {:?}", progress),
+        };
+        assert_eq!(server.requests.len(), 1);
+
+        match server
+            .terminate(Some(&StopArgs {
+                continuation_token: 1,
+                ..stop_args
+            }))
+            .unwrap()
+        {
+            SgxsdTerminate::Done => (),
+            progress => panic!("unexpected terminate progress {:?}", progress),
+        }
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_calls_during_stop() {
+        let in_phones: Vec<Phone> = test_phones(2..(MAX_HASH_TABLE_SIZE as u64 + 4));
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let mut requests = vec![MockRequest::new(in_phones[..MAX_HASH_TABLE_SIZE + 1].to_vec())];
+
+        let scenario = Scenario::new();
+        let decrypt = expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, None))
+            .collect();
+        expect_sealed_results(&scenario, &decrypt, vec![
+            expected_replies[0][COMMITMENT_NONCE_SIZE..][..MAX_HASH_TABLE_SIZE * BYTES_PER_UUID].to_vec(),
+        ]);
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: MAX_HASH_TABLE_SIZE as u32 + 2,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        let stop_args = StopArgs {
+            in_phones: in_phones.as_ptr() as *mut Phone,
+            in_uuids: in_uuids.as_ptr() as *mut Uuid,
+            in_phone_count: in_phones.len(),
+            max_chunks: 1,
+            ..Default::default()
+        };
+        let mut server = match server.terminate(Some(&stop_args)).unwrap() {
+            SgxsdTerminate::Suspended(server, 1) => server,
+            progress => panic!("unexpected terminate progress {:?}", progress),
+        };
+
+        // neither a new request nor a cancellation can change the batch part way through its lookup
+        let mut late_request = MockRequest::new(vec![in_phones[0]]);
+        let call_args = late_request.call_args();
+        let query_key = late_request.query_key;
+        let error = server
+            .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+            .map_err(|(error, _)| error)
+            .unwrap_err();
+        assert_eq!(error, SGX_ERROR_INVALID_STATE);
+        assert_eq!(server.cancel(1), Err(SGX_ERROR_INVALID_STATE));
+        assert_eq!(server.requests.len(), 1);
+
+        match server
+            .terminate(Some(&StopArgs {
+                continuation_token: 1,
+                ..stop_args
+            }))
+            .unwrap()
+        {
+            SgxsdTerminate::Done => (),
+            progress => panic!("unexpected terminate progress {:?}", progress),
+        }
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_in_query_order() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // neither the directory's order nor the distinct phones of the batch looked up once each change where a result
+        // is replied
+        let mut requests = vec![
+            MockRequest::new(vec![in_phones[5], in_phones[1], test_phone(u32::max_value().into()), in_phones[0]]),
+            MockRequest::new(in_phones.iter().rev().copied().collect()),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        expect_replies(&scenario, requests.iter().map(|request| request.expected_reply(&in_phones, &in_uuids, None)).collect());
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 12,
+            max_ratelimit_states: 0,
+            dedup_query_phones: 1,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_with_distinct_phones() {
+        let in_phones: Vec<Phone> = test_phones(2..(MAX_HASH_TABLE_SIZE as u64 + 4));
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+
+        // the second request repeats a phone of the first, which is looked up in the directory once for both
+        let mut requests = vec![
+            MockRequest::new(in_phones[..MAX_HASH_TABLE_SIZE - 1].to_vec()),
+            MockRequest::new(vec![
+                in_phones[0],
+                test_phone(u32::max_value().into()),
+                in_phones[MAX_HASH_TABLE_SIZE + 1],
+                in_phones[MAX_HASH_TABLE_SIZE - 1],
+            ]),
+        ];
+
+        let scenario = Scenario::new();
+        let decrypt = expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, Some(&in_metadata)))
+            .collect();
+        expect_sealed_results(&scenario, &decrypt, vec![expected_replies[1][COMMITMENT_NONCE_SIZE..][..BYTES_PER_UUID + METADATA_SIZE].to_vec()]);
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: MAX_HASH_TABLE_SIZE as u32 + 3,
+            max_ratelimit_states: 0,
+            dedup_query_phones: 1,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+
+        // the two chunks of distinct phones are looked up before any request can be replied to
+        let stop_args = StopArgs {
+            in_phones: in_phones.as_ptr() as *mut Phone,
+            in_uuids: in_uuids.as_ptr() as *mut Uuid,
+            in_metadata: in_metadata.as_ptr() as *mut u8,
+            in_metadata_size: METADATA_SIZE,
+            in_phone_count: in_phones.len(),
+            max_chunks: 2,
+            ..Default::default()
+        };
+        let server = match server.terminate(Some(&stop_args)).unwrap() {
+            SgxsdTerminate::Suspended(server, 2) => server,
+            progress => panic!("unexpected terminate progress {:?}", progress),
+        };
+        assert_eq!(server.requests.len(), 2);
+
+        match server
+            .terminate(Some(&StopArgs {
+                continuation_token: 2,
+                ..stop_args
+            }))
+            .unwrap()
+        {
+            SgxsdTerminate::Done => (),
+            progress => panic!("unexpected terminate progress {:?}", progress),
+        }
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_to_reply_scratch() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let mut requests = vec![MockRequest::new(vec![in_phones[0]]), MockRequest::new(vec![in_phones[1], in_phones[2]])];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, None))
+            .collect();
+        // only the first reply fits in the region, so the second is sent with an ocall
+        let seal_reply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_SEAL_REPLY, &scenario);
+        let expected_sealed_reply = expected_replies[0].clone();
+        scenario.expect(
+            seal_reply
+                .sgxsd_enclave_server_seal_reply(check(move |reply_buf| *reply_buf == &expected_sealed_reply[..]), any(), any())
+                .and_return(SGX_SUCCESS),
+        );
+        expect_replies(&scenario, vec![expected_replies[1].clone()]);
+
+        let reply_size = COMMITMENT_NONCE_SIZE + BYTES_PER_UUID + DIRECTORY_GENERATION_SIZE;
+        let mut reply_scratch = vec![0xff; 8 + 72 + reply_size + 8];
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 3,
+            reply_scratch: reply_scratch.as_mut_ptr(),
+            reply_scratch_size: reply_scratch.len(),
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        assert_eq!(reply_scratch[..8], (72 + reply_size as u64).to_le_bytes());
+        assert_eq!(reply_scratch[8 + 60..8 + 64], (reply_size as u32).to_le_bytes());
+        assert_eq!(reply_scratch[8 + 64..8 + 72], [0; 8]);
+        assert_eq!(reply_scratch[8 + 72..8 + 72 + reply_size], expected_replies[0][..]);
+
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_SEAL_REPLY);
+    }
+
+    #[test]
+    fn test_continuation_token_mismatch() {
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        assert_eq!(
+            server
+                .terminate(Some(&StopArgs {
+                    continuation_token: 1,
+                    ..Default::default()
+                }))
+                .unwrap_err(),
+            SGX_ERROR_INVALID_PARAMETER
+        );
+    }
+
+    #[test]
+    fn test_count_misses() {
+        let mut results = vec![0u8; 3 * (BYTES_PER_UUID + METADATA_SIZE)];
+        assert_eq!(SgxsdServerState::count_misses(&results, BYTES_PER_UUID + METADATA_SIZE), 3);
+
+        // metadata alone doesn't make a hit
+        results[BYTES_PER_UUID] = 1;
+        results[BYTES_PER_UUID + METADATA_SIZE + BYTES_PER_UUID - 1] = 1;
+        assert_eq!(SgxsdServerState::count_misses(&results, BYTES_PER_UUID + METADATA_SIZE), 2);
+        assert_eq!(SgxsdServerState::count_misses(&results[..2 * BYTES_PER_UUID], BYTES_PER_UUID), 1);
+    }
+
+    #[test]
+    fn test_request_phone_count_metrics() {
+        let mut requests: Vec<MockRequest> =
+            [1, 9, 10, 99, 100, 1000].iter().map(|&phone_count| MockRequest::new(test_phones(0..phone_count))).collect();
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(requests.len() as u32));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 1219,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.metrics(0).unwrap().request_phone_count_histogram, [2, 2, 1, 1, 0, 0]);
+
+        // the largest requests all land in the last bucket
+        server.count_request_phones(100_000);
+        server.count_request_phones(usize::max_value());
+        assert_eq!(server.metrics(0).unwrap().request_phone_count_histogram, [2, 2, 1, 1, 0, 2]);
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_queue_age_metrics() {
+        let mut requests: Vec<MockRequest> = (2..7).map(|phone| MockRequest::new(vec![test_phone(phone)])).collect();
+        let admission_ticks = [1000, 999, 998, 900, 0];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(requests.len() as u32));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: requests.len() as u32,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(server.metrics(1000).unwrap(), Default::default());
+
+        for (request, admission_ticks) in requests.iter_mut().zip(&admission_ticks) {
+            let call_args = CallArgs {
+                admission_ticks: *admission_ticks,
+                ..request.call_args()
+            };
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+
+        let metrics = server.metrics(1000).unwrap();
+        assert_eq!(metrics.pending_request_count, 5);
+        assert_eq!(metrics.held_phone_count, 5);
+        assert_eq!(metrics.max_queue_age_ticks, 1000);
+        let mut expected_histogram = [0; QUEUE_AGE_HISTOGRAM_BUCKETS];
+        expected_histogram[0] = 1;
+        expected_histogram[1] = 1;
+        expected_histogram[2] = 1;
+        expected_histogram[7] = 1;
+        expected_histogram[10] = 1;
+        assert_eq!(metrics.queue_age_histogram, expected_histogram);
+
+        // a clock running backwards counts as no wait, and very old requests land in the last bucket
+        let metrics = server.metrics(u64::max_value()).unwrap();
+        assert_eq!(metrics.queue_age_histogram[QUEUE_AGE_HISTOGRAM_BUCKETS - 1], 5);
+        assert_eq!(server.metrics(0).unwrap().queue_age_histogram[0], 5);
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_invalid_metadata_size() {
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        assert_eq!(
+            server
+                .terminate(Some(&StopArgs {
+                    in_metadata_size: METADATA_SIZE + 1,
+                    ..Default::default()
+                }))
+                .unwrap_err(),
+            SGX_ERROR_INVALID_PARAMETER
+        );
+    }
+
+    #[test]
+    fn test_padded_replies() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // replies are padded to a multiple of 40 bytes, and one that's already a multiple is left as it is
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![2])),
+            MockRequest::new(test_phones(vec![3, 11, 4])),
+            MockRequest::new(test_phones(vec![5, 6])),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .zip(&[80, 120, 80])
+            .map(|(request, padded_reply_len)| {
+                let mut reply = request.expected_reply(&in_phones, &in_uuids, None);
+                reply.resize(*padded_reply_len, 0);
+                reply
+            })
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 6,
+            reply_padding_size: 40,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+
+    #[test]
+    fn test_replies_with_directory_generation() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let directory_generation: u64 = 0x0102_0304_0506_0708;
+
+        // the generation follows the results of every reply, whatever its layout, and comes before any padding
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![2, 11])),
+            MockRequest::new(test_phones(vec![3])).with_reply_layout(CDS_REPLY_LAYOUT_ACI_PNI),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| {
+                let mut reply = request.expected_reply(&in_phones, &in_uuids, None);
+                let generation_start = reply.len() - DIRECTORY_GENERATION_SIZE;
+                reply[generation_start..].copy_from_slice(&directory_generation.to_le_bytes());
+                reply.resize(128, 0);
+                reply
+            })
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 3,
+            reply_padding_size: 128,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                directory_generation,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+    #[test]
+    fn test_replies_with_metadata() {
+        let in_phones: Vec<Phone> = test_phones(2..(MAX_HASH_TABLE_SIZE as u64 + 4));
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+
+        let mut requests = vec![
+            MockRequest::new(in_phones[..MAX_HASH_TABLE_SIZE - 1].to_vec()),
+            MockRequest::new(vec![in_phones[MAX_HASH_TABLE_SIZE - 1], test_phone(u32::max_value().into()), in_phones[MAX_HASH_TABLE_SIZE + 1]]),
+        ];
+
+        let scenario = Scenario::new();
+        let decrypt = expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, Some(&in_metadata)))
+            .collect();
+        expect_sealed_results(&scenario, &decrypt, vec![expected_replies[1][COMMITMENT_NONCE_SIZE..][..BYTES_PER_UUID + METADATA_SIZE].to_vec()]);
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: MAX_HASH_TABLE_SIZE as u32 + 2,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_metadata: in_metadata.as_ptr() as *mut u8,
+                in_metadata_size: METADATA_SIZE,
+                directory_epoch: 0,
+                max_chunks: 0,
+                continuation_token: 0,
+                in_allowlist_phones: ptr::null_mut(),
+                in_allowlist_phone_count: 0,
+                in_pni_uuids: ptr::null_mut(),
+                in_username_hashes: ptr::null_mut(),
+                in_username_uuids: ptr::null_mut(),
+                in_username_count: 0,
+                in_change_epochs: ptr::null_mut(),
+                directory_generation: 0,
+                in_flags: ptr::null_mut(),
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_with_allowlist() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+        // the allowlist may name phones that aren't in the directory, which still aren't found
+        let in_allowlist_phones: Vec<Phone> = test_phones(vec![3, 5, 11]);
+
+        let mut requests = vec![MockRequest::new(test_phones(vec![2, 3, 5, 11])), MockRequest::new(test_phones(vec![4, 12]))];
+
+        let allowed = |index: &usize| in_allowlist_phones.contains(&in_phones[*index]);
+        let allowed_in_phones: Vec<Phone> = (0..in_phones.len()).filter(allowed).map(|index| in_phones[index]).collect();
+        let allowed_in_uuids: Vec<Uuid> = (0..in_phones.len()).filter(allowed).map(|index| in_uuids[index]).collect();
+        let allowed_in_metadata: Vec<u32> = (0..in_phones.len()).filter(allowed).map(|index| in_metadata[index]).collect();
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&allowed_in_phones, &allowed_in_uuids, Some(&allowed_in_metadata)))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 6,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_metadata: in_metadata.as_ptr() as *mut u8,
+                in_metadata_size: METADATA_SIZE,
+                in_allowlist_phones: in_allowlist_phones.as_ptr() as *mut Phone,
+                in_allowlist_phone_count: in_allowlist_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_with_uuid_keys() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+        let missing_uuid = Uuid { data64: test_ffi::rand() };
+
+        // the batch holds phone keys both before and after the request mixing in uuid keys
+        let no_uuid = Uuid::default();
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![6])),
+            MockRequest::with_uuids(test_phones(vec![2, 0, 11, 0, 5]), vec![no_uuid, in_uuids[3], no_uuid, missing_uuid, no_uuid]),
+            MockRequest::new(test_phones(vec![4, 12])),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, Some(&in_metadata)))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 8,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.query_uuids.len(), 8);
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_metadata: in_metadata.as_ptr() as *mut u8,
+                in_metadata_size: METADATA_SIZE,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_with_identifier_keys() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let missing_uuid = Uuid { data64: test_ffi::rand() };
+
+        // the batch holds phone keys both before and after the request of identifiers
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![6])),
+            MockRequest::with_identifiers(vec![in_uuids[3], missing_uuid, in_uuids[7]]),
+            MockRequest::new(test_phones(vec![4, 12])),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, None))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 6,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.query_uuids.len(), 6);
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_with_delta_phones() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // phones sent as deltas are looked up alongside those sent whole, and a resubmission shares their lookup
+        let delta_request = MockRequest::with_delta_phones(test_phones(vec![3, 4, 11, 1_000_000_000]));
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![6])),
+            delta_request.clone(),
+            delta_request.clone(),
+            MockRequest::new(test_phones(vec![4, 12])),
+        ];
+        assert!(delta_request.query_data.len() < MockRequest::new(delta_request.phones.clone()).query_data.len());
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply(&in_phones, &in_uuids, None))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 7,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.query_phones.len(), 7);
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_with_response_keys() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // a resubmission without a response key is replied to in the clear, even though the request it resubmits sent one
+        let keyed_request = MockRequest::new(in_phones[..3].to_vec());
+        let mut requests = vec![keyed_request.clone(), MockRequest::new(in_phones[3..5].to_vec()), keyed_request];
+        let response_key: [u8; 32] = test_ffi::rand();
+        let response_keys = vec![Some(response_key), None, None];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = requests.iter().map(|request| request.expected_reply(&in_phones, &in_uuids, None)).collect();
+
+        let encrypt = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_AES_GCM_ENCRYPT, &scenario);
+        let keyed_reply = expected_replies[0].clone();
+        let encrypted_keyed_reply: Vec<u8> = keyed_reply.iter().map(|byte| !byte).collect();
+        scenario.expect(
+            encrypt
+                .sgxsd_aes_gcm_encrypt(check(move |key| *key == &response_key), check(move |src| *src == &keyed_reply[..]), any(), any())
+                .and_return(Ok(encrypted_keyed_reply.clone())),
+        );
+
+        let reply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_REPLY, &scenario);
+        let mut reply_seq = Sequence::new();
+        let header_len = SGXSD_AES_GCM_IV_SIZE as usize + SGXSD_AES_GCM_MAC_SIZE as usize;
+        let plain_replies = vec![expected_replies[2].clone(), expected_replies[1].clone()];
+        reply_seq.expect(
+            reply
+                .sgxsd_enclave_server_reply(check(move |reply_buf| *reply_buf == &plain_replies[0][..]), any(), any())
+                .and_return(SGX_SUCCESS),
+        );
+        reply_seq.expect(
+            reply
+                .sgxsd_enclave_server_reply(
+                    check(move |reply_buf: &&[u8]| {
+                        reply_buf.len() == header_len + encrypted_keyed_reply.len() && reply_buf[header_len..] == encrypted_keyed_reply[..]
+                    }),
+                    any(),
+                    any(),
+                )
+                .and_return(SGX_SUCCESS),
+        );
+        let plain_reply = expected_replies[1].clone();
+        reply_seq.expect(
+            reply
+                .sgxsd_enclave_server_reply(check(move |reply_buf| *reply_buf == &plain_reply[..]), any(), any())
+                .and_return(SGX_SUCCESS),
+        );
+        scenario.expect(reply_seq);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: in_phones.len() as u32,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for (request, response_key) in requests.iter_mut().zip(&response_keys) {
+            let call_args = request.call_args();
+            let mut request_data = request.query_key.to_vec();
+            request_data.extend(response_key.iter().flatten());
+            server
+                .handle_call(Some(&call_args), &request_data, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+
+        // request data neither a query key alone nor one followed by a response key
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return(SGX_SUCCESS));
+        let mut short_request = MockRequest::new(in_phones[5..6].to_vec());
+        let call_args = short_request.call_args();
+        let request_data = [&short_request.query_key[..], &response_key[..16]].concat();
+        let res = server.handle_call(Some(&call_args), &request_data, SgxsdMsgFrom::mock());
+        assert_eq!(res.map_err(|(error, _)| error), Err(CDS_ERROR_INVALID_REQUEST_SIZE));
+
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_query_key_size() {
+        let query_data_len = |key_count: usize, bytes_per_key: usize| COMMITMENT_NONCE_SIZE + key_count * bytes_per_key;
+
+        // told by the size of the query, where identifiers are read as twice as many phones
+        assert_eq!(SgxsdServerState::query_bytes_per_key(query_data_len(3, BYTES_PER_TAGGED_KEY), 3, 0), Ok(BYTES_PER_TAGGED_KEY));
+        assert_eq!(SgxsdServerState::query_bytes_per_key(query_data_len(4, BYTES_PER_IDENTIFIER), 8, 0), Ok(BYTES_PER_PHONE));
+        assert_eq!(
+            SgxsdServerState::query_bytes_per_key(query_data_len(4, BYTES_PER_IDENTIFIER), 4, 0),
+            Err(CDS_ERROR_INVALID_REQUEST_SIZE)
+        );
+
+        // or named by the client, when it must match the query
+        let identifier_size = BYTES_PER_IDENTIFIER as u32;
+        assert_eq!(
+            SgxsdServerState::query_bytes_per_key(query_data_len(4, BYTES_PER_IDENTIFIER), 4, identifier_size),
+            Ok(BYTES_PER_IDENTIFIER)
+        );
+        assert_eq!(
+            SgxsdServerState::query_bytes_per_key(query_data_len(4, BYTES_PER_IDENTIFIER), 8, identifier_size),
+            Err(CDS_ERROR_INVALID_REQUEST_SIZE)
+        );
+        assert_eq!(
+            SgxsdServerState::query_bytes_per_key(query_data_len(4, BYTES_PER_PHONE), 4, BYTES_PER_PHONE as u32),
+            Ok(BYTES_PER_PHONE)
+        );
+        assert_eq!(SgxsdServerState::query_bytes_per_key(query_data_len(4, 12), 4, 12), Err(CDS_ERROR_INVALID_REQUEST_SIZE));
+    }
+
+    #[test]
+    fn test_replies_with_pni_uuids() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_pni_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+
+        // requests asking for pairs are batched with those asking for uuids only, which don't get the PNIs looked up
+        let no_uuid = Uuid::default();
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![2, 3, 11])),
+            MockRequest::new(test_phones(vec![4, 12, 5])).with_reply_layout(CDS_REPLY_LAYOUT_ACI_PNI),
+            MockRequest::with_uuids(test_phones(vec![6, 0]), vec![no_uuid, in_uuids[7]]).with_reply_layout(CDS_REPLY_LAYOUT_ACI_PNI),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply_with_pnis(&in_phones, &in_uuids, Some(&in_pni_uuids), Some(&in_metadata)))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 8,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_metadata: in_metadata.as_ptr() as *mut u8,
+                in_metadata_size: METADATA_SIZE,
+                in_pni_uuids: in_pni_uuids.as_ptr() as *mut Uuid,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_pni_replies_without_pni_uuids() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let mut requests = vec![MockRequest::new(test_phones(vec![4, 12])).with_reply_layout(CDS_REPLY_LAYOUT_ACI_PNI)];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_reply_with_pnis(&in_phones, &in_uuids, None, None))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 2,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+
+    #[test]
+    fn test_replies_with_flags() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_flags: Vec<u8> = in_phones.iter().map(|_| test_ffi::rand::<u8>() | 1).collect();
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+
+        // requests asking for flags are batched with those asking for uuids only, and get zero flags for missing phones
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![2, 11])),
+            MockRequest::new(test_phones(vec![4, 12, 5])).with_reply_layout(CDS_REPLY_LAYOUT_UUID_FLAGS),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let mut expected_flags_reply = requests[1].query_nonce.to_vec();
+        for phone in &requests[1].phones {
+            match in_phones.iter().position(|in_phone| in_phone == phone) {
+                Some(index) => {
+                    expected_flags_reply.extend(unsafe { in_uuids[index].data64 }.iter().flat_map(|word| word.to_ne_bytes().to_vec()));
+                    expected_flags_reply.push(in_flags[index]);
+                    expected_flags_reply.extend(&in_metadata[index].to_ne_bytes());
+                }
+                None => expected_flags_reply.extend(&[0; BYTES_PER_UUID + FLAGS_SIZE + METADATA_SIZE]),
+            }
+        }
+        expected_flags_reply.extend(&[0; DIRECTORY_GENERATION_SIZE]);
+        expect_replies(
+            &scenario,
+            vec![requests[0].expected_reply(&in_phones, &in_uuids, Some(&in_metadata)), expected_flags_reply],
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 5,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_metadata: in_metadata.as_ptr() as *mut u8,
+                in_metadata_size: METADATA_SIZE,
+                in_flags: in_flags.as_ptr() as *mut u8,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+    #[test]
+    fn test_invalid_reply_layout() {
+        let scenario = Scenario::new();
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return(SGX_SUCCESS),
+        );
+
+        let mut request = MockRequest::new(test_phones(vec![2])).with_reply_layout(CDS_REPLY_LAYOUT_UUID_FLAGS + 1);
+        let call_args = request.call_args();
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 1,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(
+            server
+                .handle_call(Some(&call_args), &request.query_key, SgxsdMsgFrom::mock())
+                .unwrap_err()
+                .0,
+            SGX_ERROR_INVALID_PARAMETER
+        );
+        assert!(server.requests.is_empty());
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_invalid_reply_order() {
+        let scenario = Scenario::new();
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return(SGX_SUCCESS),
+        );
+
+        let mut request = MockRequest::new(test_phones(vec![2]));
+        let call_args = CallArgs {
+            reply_order: CDS_REPLY_ORDER_QUERY + 1,
+            ..request.call_args()
+        };
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 1,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(
+            server
+                .handle_call(Some(&call_args), &request.query_key, SgxsdMsgFrom::mock())
+                .unwrap_err()
+                .0,
+            SGX_ERROR_INVALID_PARAMETER
+        );
+        assert!(server.requests.is_empty());
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_unsupported_protocol_version() {
+        let scenario = Scenario::new();
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return(SGX_SUCCESS),
+        );
+
+        let mut request = MockRequest::new(test_phones(vec![2]));
+        let call_args = CallArgs {
+            protocol_version: CDS_PROTOCOL_VERSION_1 + 1,
+            ..request.call_args()
+        };
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 1,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        assert_eq!(
+            server
+                .handle_call(Some(&call_args), &request.query_key, SgxsdMsgFrom::mock())
+                .unwrap_err()
+                .0,
+            CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION
+        );
+        assert!(server.requests.is_empty());
+
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_invalid_uuid_keys() {
+        let uuid = Uuid { data64: test_ffi::rand() };
+        let mut requests = vec![
+            MockRequest::with_uuids(test_phones(vec![2, 0]), vec![Uuid::default(), uuid]),
+            MockRequest::with_uuids(test_phones(vec![2, 0]), vec![Uuid::default(), uuid]),
+        ];
+
+        // an unknown tag, and a zero uuid
+        let mut unknown_tag = requests[0].plaintext();
+        *unknown_tag.last_mut().unwrap() = QUERY_KEY_TAG_UUID + 1;
+        let mut zero_uuid = requests[1].plaintext();
+        let zero_uuid_len = zero_uuid.len();
+        zero_uuid[zero_uuid_len - BYTES_PER_TAGGED_KEY..zero_uuid_len - 1].copy_from_slice(&[0; BYTES_PER_UUID]);
+
+        let scenario = Scenario::new();
+        let sgx_is_outside_enclave = test_ffi::mock_for(&sgx_ffi::mocks::SGX_IS_OUTSIDE_ENCLAVE, &scenario);
+        scenario.expect(sgx_is_outside_enclave.sgx_is_outside_enclave(any(), any()).and_return_clone(true).times(..));
+        let decrypt = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_AES_GCM_DECRYPT, &scenario);
+        for (request, plaintext) in requests.iter().zip(vec![unknown_tag, zero_uuid]) {
+            let query_data = request.query_data.clone();
+            scenario.expect(
+                decrypt
+                    .sgxsd_aes_gcm_decrypt(any(), check(move |src| *src == &query_data[..]), any(), any())
+                    .and_return(Ok(plaintext)),
+            );
+        }
+        let sha256 = test_ffi::mock_for(&sgxsd_ffi::mocks::BEARSSL_SHA256, &scenario);
+        scenario.expect(sha256.update(any()).and_return_clone(()).times(..));
+        scenario.expect(sha256.out().and_return_clone(*MOCK_COMMITMENT).times(..));
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return_clone(SGX_SUCCESS)
+                .times(2),
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 2,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            assert_eq!(
+                server
+                    .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                    .unwrap_err()
+                    .0,
+                SGX_ERROR_INVALID_PARAMETER
+            );
+        }
+        assert!(server.query_phones.is_empty());
+        server.terminate(Some(&empty_stop_args())).unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_replies_with_username_hashes() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_pni_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+        let in_username_hashes: Vec<UsernameHash> = (0..4).map(|_| test_ffi::rand()).collect();
+        let in_username_uuids: Vec<Uuid> = in_username_hashes.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // a hash differing from one in the table only in its last byte isn't found, and a username query is replied
+        // to with uuids alone, even though the phone queries around it get PNIs and metadata
+        let mut partial_username_hash = in_username_hashes[1];
+        partial_username_hash[BYTES_PER_USERNAME_HASH - 1] ^= 1;
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![6])).with_reply_layout(CDS_REPLY_LAYOUT_ACI_PNI),
+            MockRequest::with_username_hashes(vec![in_username_hashes[2], partial_username_hash, in_username_hashes[0]]),
+            MockRequest::with_uuids(test_phones(vec![0, 3]), vec![in_uuids[5], Uuid::default()]),
+            MockRequest::new(test_phones(vec![4, 12])),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| match request.username_hashes {
+                Some(_) => request.expected_username_reply(&in_username_hashes, &in_username_uuids),
+                None => request.expected_reply_with_pnis(&in_phones, &in_uuids, Some(&in_pni_uuids), Some(&in_metadata)),
+            })
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 8,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.query_username_hashes.len(), 8);
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_pni_uuids: in_pni_uuids.as_ptr() as *mut Uuid,
+                in_metadata: in_metadata.as_ptr() as *mut u8,
+                in_metadata_size: METADATA_SIZE,
+                in_username_hashes: in_username_hashes.as_ptr() as *mut u8,
+                in_username_uuids: in_username_uuids.as_ptr() as *mut Uuid,
+                in_username_count: in_username_hashes.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_invalid_username_hash_queries() {
+        let username_hash: UsernameHash = test_ffi::rand();
+        let mut requests = vec![
+            // a zero hash stands for the other keys of a batch
+            MockRequest::with_username_hashes(vec![username_hash, [0; USERNAME_HASH_SIZE]]),
+            // username queries have no PNIs to be replied with
+            MockRequest::with_username_hashes(vec![username_hash]).with_reply_layout(CDS_REPLY_LAYOUT_ACI_PNI),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return_clone(SGX_SUCCESS)
+                .times(requests.len() as u32),
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 4,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            assert_eq!(
+                server
+                    .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                    .unwrap_err()
+                    .0,
+                SGX_ERROR_INVALID_PARAMETER
+            );
+        }
+        assert!(server.query_phones.is_empty());
+        assert!(server.query_username_hashes.is_empty());
+        server.terminate(Some(&empty_stop_args())).unwrap();
+
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_replies_with_changes() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let mut in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let in_change_epochs: Vec<u64> = vec![3, 7, 5, 9, 2, 8, 4, 6];
+        let in_metadata: Vec<u32> = in_phones.iter().map(|_| test_ffi::rand()).collect();
+        // the entry of a phone removed from the directory is kept with a zero uuid, so its removal is reported as a change
+        in_uuids[3] = Uuid::default();
+
+        // requests for what changed are batched with those for everything, and a request with no change token is replied
+        // to with every entry; a phone missing from the directory hasn't changed unless it's asked about for the first time
+        let mut requests = vec![
+            MockRequest::new(test_phones(vec![2, 3, 4, 5, 11])).with_since_change(5),
+            MockRequest::new(test_phones(vec![6, 9])),
+            MockRequest::new(test_phones(vec![7, 12])).with_since_change(0),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| match request.reply_layout {
+                CDS_REPLY_LAYOUT_UUID_CHANGES => {
+                    request.expected_changes_reply(&in_phones, &in_uuids, Some(&in_change_epochs), Some(&in_metadata), 0)
+                }
+                _ => request.expected_reply(&in_phones, &in_uuids, Some(&in_metadata)),
+            })
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 9,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_metadata: in_metadata.as_ptr() as *mut u8,
+                in_metadata_size: METADATA_SIZE,
+                in_change_epochs: in_change_epochs.as_ptr() as *mut u64,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_changes_replies_without_change_epochs() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // with no change epochs to go by, every entry is reported as changed
+        let mut requests = vec![MockRequest::new(test_phones(vec![4, 12])).with_since_change(5)];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = (requests.iter())
+            .map(|request| request.expected_changes_reply(&in_phones, &in_uuids, None, None, 0))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 2,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_phone_count: in_phones.len(),
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_invalid_changes_queries() {
+        // the change epochs of the directory are only looked up by phone
+        let mut requests = vec![
+            MockRequest::with_uuids(test_phones(vec![2, 0]), vec![Uuid::default(), Uuid { data64: test_ffi::rand() }]).with_since_change(5),
+            MockRequest::with_username_hashes(vec![test_ffi::rand()]).with_since_change(5),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return_clone(SGX_SUCCESS)
+                .times(requests.len() as u32),
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 4,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            assert_eq!(
+                server
+                    .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                    .unwrap_err()
+                    .0,
+                SGX_ERROR_INVALID_PARAMETER
+            );
+        }
+        assert!(server.query_phones.is_empty());
+        server.terminate(Some(&empty_stop_args())).unwrap();
+
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_sorts_before() {
+        assert_eq!(sorts_before(&[0, 1, 0], &[0, 2, 0]), 1);
+        assert_eq!(sorts_before(&[0, 2, 0], &[0, 1, 0]), 0);
+        assert_eq!(sorts_before(&[0, 1, 5], &[0, 1, 5]), 0);
+        assert_eq!(sorts_before(&[0, 1, 5], &[0, 1, 6]), 1);
+        assert_eq!(sorts_before(&[0, u64::max_value(), 0], &[1, 0, 0]), 1);
+        assert_eq!(sorts_before(&[0, 1 << 63, 0], &[0, (1 << 63) - 1, 0]), 0);
+        assert_eq!(sorts_before(&[0, (1 << 63) - 1, 0], &[0, 1 << 63, 0]), 1);
+    }
+
+    #[test]
+    fn test_unsorted_query_keys() {
+        let uuid = Uuid { data64: test_ffi::rand() };
+        let mut sorted_requests = vec![
+            MockRequest::new(test_phones(vec![2, 3, 10])),
+            // phone keys sort before uuid keys
+            MockRequest::with_uuids(test_phones(vec![2, 0]), vec![Uuid::default(), uuid]),
+        ];
+        let mut unsorted_requests = vec![
+            MockRequest::new(test_phones(vec![3, 2])),
+            MockRequest::new(test_phones(vec![4, 4])),
+            MockRequest::with_uuids(test_phones(vec![0, 2]), vec![uuid, Uuid::default()]),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &[&sorted_requests[..], &unsorted_requests[..]].concat());
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return_clone(SGX_SUCCESS)
+                .times(unsorted_requests.len() as u32),
+        );
+        let expected_replies: Vec<Vec<u8>> = (sorted_requests.iter())
+            .map(|request| request.expected_reply(&VALID_IN_PHONES, &VALID_IN_UUIDS, None))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 11,
+            max_ratelimit_states: 0,
+            require_sorted_query: 1,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut sorted_requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        for request in &mut unsorted_requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            assert_eq!(
+                server
+                    .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                    .unwrap_err()
+                    .0,
+                CDS_ERROR_QUERY_NOT_SORTED
+            );
+        }
+        assert_eq!(server.query_phones.len(), 5);
+        server.terminate(Some(&valid_stop_args())).unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_duplicate_query_keys() {
+        let uuid = Uuid { data64: test_ffi::rand() };
+        let mut distinct_requests = vec![
+            MockRequest::new(test_phones(vec![10, 2, 3])),
+            // the placeholder phones uuid keys are looked up with don't count as repeats
+            MockRequest::with_uuids(test_phones(vec![0, 2, 0]), vec![uuid, Uuid::default(), Uuid { data64: test_ffi::rand() }]),
+        ];
+        let mut duplicate_requests = vec![
+            MockRequest::new(test_phones(vec![4, 2, 4])),
+            MockRequest::with_uuids(test_phones(vec![0, 2, 0]), vec![uuid, Uuid::default(), uuid]),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &[&distinct_requests[..], &duplicate_requests[..]].concat());
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return_clone(SGX_SUCCESS)
+                .times(duplicate_requests.len() as u32),
+        );
+        let expected_replies: Vec<Vec<u8>> = (distinct_requests.iter())
+            .map(|request| request.expected_reply(&VALID_IN_PHONES, &VALID_IN_UUIDS, None))
+            .collect();
+        expect_replies(&scenario, expected_replies);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 12,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut distinct_requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        for request in &mut duplicate_requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            assert_eq!(
+                server
+                    .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                    .unwrap_err()
+                    .0,
+                CDS_ERROR_DUPLICATE_QUERY_PHONE
+            );
+        }
+        assert_eq!(server.query_phones.len(), 6);
+        server.terminate(Some(&valid_stop_args())).unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_invalid_phone_keys() {
+        // a zero phone, which the directory uses for empty slots, and one of 16 digits
+        let mut requests = vec![
+            MockRequest::new(vec![test_phone(2), 0]),
+            MockRequest::new(vec![1_000_000_000_000_000u64.to_be(), test_phone(3)]),
+            MockRequest::with_uuids(vec![0, test_phone(4)], vec![Uuid::default(), Uuid::default()]),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        scenario.expect(
+            test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario)
+                .sgxsd_enclave_server_noreply(any())
+                .and_return_clone(SGX_SUCCESS)
+                .times(requests.len() as u32),
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 6,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            assert_eq!(
+                server
+                    .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                    .unwrap_err()
+                    .0,
+                CDS_ERROR_INVALID_QUERY_PHONE
+            );
+        }
+        assert!(server.query_phones.is_empty());
+        server.terminate(Some(&empty_stop_args())).unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_key_newtypes() {
+        assert_eq!(E164Phone::new(test_phone(2)).map(E164Phone::get), Ok(test_phone(2)));
+        assert_eq!(E164Phone::new(999_999_999_999_999u64.to_be()).map(E164Phone::get), Ok(999_999_999_999_999u64.to_be()));
+        assert_eq!(E164Phone::new(1u64.to_be()).map(E164Phone::get), Ok(1u64.to_be()));
+        assert_eq!(E164Phone::new(0), Err(CDS_ERROR_INVALID_QUERY_PHONE));
+        assert_eq!(E164Phone::new(1_000_000_000_000_000u64.to_be()), Err(CDS_ERROR_INVALID_QUERY_PHONE));
+        // a small number in the wrong byte order is out of range
+        assert_eq!(E164Phone::new(2), Err(CDS_ERROR_INVALID_QUERY_PHONE));
+
+        let uuid = Uuid { data64: [0, 1] };
+        assert_eq!(AccountUuid::new(uuid), Ok(AccountUuid(uuid)));
+        assert_eq!(AccountUuid::new(Uuid::default()), Err(SGX_ERROR_INVALID_PARAMETER));
+    }
+
+    #[test]
+    fn test_fair_admission() {
+        let session_a = [0xa; 32];
+        let session_b = [0xb; 32];
+        let mut requests: Vec<(MockRequest, [u8; 32], Result<(), SgxStatus>)> = vec![
+            (MockRequest::new(test_phones(vec![2, 3, 4])), session_a, Ok(())),
+            (MockRequest::new(test_phones(vec![5, 6, 7])), session_a, Ok(())),
+            (MockRequest::new(test_phones(vec![8])), session_a, Ok(())),
+            // from here on less than fair_admission_phones remain, but a lone session may still use all of them
+            (MockRequest::new(test_phones(vec![9])), session_a, Ok(())),
+            (MockRequest::new(test_phones(vec![10])), session_b, Ok(())),
+            (MockRequest::new(test_phones(vec![11])), session_a, Err(CDS_ERROR_FAIR_SHARE_EXCEEDED)),
+            (MockRequest::new(test_phones(vec![12])), session_b, Ok(())),
+        ];
+
+        let scenario = Scenario::new();
+        let mock_requests: Vec<MockRequest> = requests.iter().map(|(request, _, _)| request.clone()).collect();
+        expect_valid_requests(&scenario, &mock_requests);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(requests.len() as u32));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 10,
+            fair_admission_phones: 4,
+            ..Default::default()
+        }))
+        .unwrap();
+        for (request, session, expected_result) in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            let result = server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock_for_session(*session));
+            assert_eq!(result.map_err(|(error, _)| error), *expected_result);
+        }
+        assert_eq!(server.query_phones.len(), 10);
+        assert_eq!(server.session_phone_counts.get(&session_a), Some(&8));
+        assert_eq!(server.session_phone_counts.get(&session_b), Some(&2));
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_batch_full() {
+        let mut requests = vec![MockRequest::new(test_phones(vec![2, 3])), MockRequest::new(test_phones(vec![4]))];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests[..1]);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(requests.len() as u32));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 2,
+            ..Default::default()
+        }))
+        .unwrap();
+        let expected_results = vec![Ok(()), Err(CDS_ERROR_BATCH_FULL)];
+        for (request, expected_result) in requests.iter_mut().zip(expected_results) {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            let result = server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock());
+            assert_eq!(result.map_err(|(error, _)| error), expected_result);
+        }
+        assert_eq!(server.query_phones.len(), 2);
+        // the host learns how full the batch it was refused by is
+        assert_eq!(server.metrics(0).unwrap().held_phone_count, 2);
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_interactive_reserve() {
+        let bulk = CDS_REQUEST_PRIORITY_BULK;
+        let interactive = CDS_REQUEST_PRIORITY_INTERACTIVE;
+        let mut requests = vec![
+            (MockRequest::new(test_phones(vec![2])), bulk, Ok(())),
+            // would leave less than the reserve, though it fits
+            (MockRequest::new(test_phones(vec![3, 4])), bulk, Err(CDS_ERROR_REQUEST_DEFERRED)),
+            (MockRequest::new(test_phones(vec![5, 6])), interactive, Ok(())),
+            (MockRequest::new(test_phones(vec![7])), bulk + 1, Err(SGX_ERROR_INVALID_PARAMETER)),
+            (MockRequest::new(test_phones(vec![8])), interactive, Ok(())),
+        ];
+
+        let scenario = Scenario::new();
+        let admitted: Vec<MockRequest> = (requests.iter())
+            .filter(|(_, _, expected_result)| expected_result.is_ok())
+            .map(|(request, _, _)| request.clone())
+            .collect();
+        expect_valid_requests(&scenario, &admitted);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(requests.len() as u32));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 4,
+            interactive_reserve_phones: 2,
+            ..Default::default()
+        }))
+        .unwrap();
+        for (request, priority, expected_result) in &mut requests {
+            let call_args = CallArgs {
+                priority: *priority,
+                ..request.call_args()
+            };
+            let query_key = request.query_key;
+            let result = server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock());
+            assert_eq!(result.map_err(|(error, _)| error), *expected_result);
+        }
+        assert_eq!(server.query_phones.len(), 4);
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_request_phone_limit() {
+        // a fragmented query is held to the limit across its fragments
+        let fragments = MockRequest::new(test_phones(vec![7, 8, 9])).fragments(1, &[2, 1]);
+        let mut requests = vec![MockRequest::new(test_phones(vec![2, 3])), MockRequest::new(test_phones(vec![4, 5, 6]))];
+        requests.extend(fragments);
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &[requests[0].clone(), requests[2].clone()]);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(requests.len() as u32));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 8,
+            max_phones_per_request: 2,
+            ..Default::default()
+        }))
+        .unwrap();
+        let expected_results = vec![
+            Ok(()),
+            Err(CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED),
+            Ok(()),
+            Err(CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED),
+        ];
+        for (request, expected_result) in requests.iter_mut().zip(expected_results) {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            let result = server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock());
+            assert_eq!(result.map_err(|(error, _)| error), expected_result);
+        }
+        assert_eq!(server.query_phones.len(), 2);
+        assert!(server.partial_requests.is_empty());
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_denied_session() {
+        let denied_session = [0xd; 32];
+        let mut request = MockRequest::new(test_phones(vec![2]));
+
+        let scenario = Scenario::new();
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return(SGX_SUCCESS));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 1,
+            ..Default::default()
+        }))
+        .unwrap();
+        SESSION_DENYLIST.replace(vec![denied_session]).unwrap();
+        let call_args = request.call_args();
+        let query_key = request.query_key;
+        let result = server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock_for_session(denied_session));
+        SESSION_DENYLIST.replace(Vec::new()).unwrap();
+        assert_eq!(result.map_err(|(error, _)| error), Err(CDS_ERROR_SESSION_DENIED));
+        assert!(server.query_phones.is_empty());
+
+        drop(server);
+        drop(scenario);
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_canary_mismatch() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let mut in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+        let mut request = MockRequest::new(in_phones[..4].to_vec());
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &[request.clone()]);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return(SGX_SUCCESS));
+
+        // the only test to commit to the shared active directory
+        let commit_args = DirectoryCommitArgs {
+            epoch: 1,
+            in_phones: in_phones.as_ptr(),
+            in_phone_count: in_phones.len(),
+            in_uuids: in_uuids.as_ptr(),
+            expected_digest: *MOCK_COMMITMENT,
+        };
+        assert_eq!(ACTIVE_DIRECTORY.commit(&commit_args), Ok(()));
+        let stop_args = StopArgs {
+            in_phones: in_phones.as_ptr() as *mut Phone,
+            in_uuids: in_uuids.as_mut_ptr(),
+            in_phone_count: in_phones.len(),
+            directory_epoch: 1,
+            ..Default::default()
+        };
+        let server = SgxsdServerState::init(Some(&empty_init_args())).unwrap();
+        assert_eq!(server.terminate(Some(&stop_args)).map(drop), Ok(()));
+
+        // the untrusted side rewrites the committed uuids, so the request is dropped without a reply
+        for uuid in &mut in_uuids {
+            uuid.data64 = [!uuid.data64[0], !uuid.data64[1]];
+        }
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 4,
+            ..Default::default()
+        }))
+        .unwrap();
+        let call_args = request.call_args();
+        let query_key = request.query_key;
+        server
+            .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+            .map_err(|(error, _)| error)
+            .unwrap();
+        assert_eq!(server.terminate(Some(&stop_args)).map(drop), Err(CDS_ERROR_CANARY_MISMATCH));
+
+        // neither lookup holds the directory active any more, whether it finished or failed
+        assert_eq!(ACTIVE_DIRECTORY.commit(&DirectoryCommitArgs { epoch: 2, ..commit_args }), Ok(()));
+
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_duplicate_requests() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let request = MockRequest::new(in_phones[..4].to_vec());
+        let mut requests = vec![request.clone(), MockRequest::new(in_phones[4..].to_vec()), request];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let expected_replies: Vec<Vec<u8>> = requests.iter().map(|request| request.expected_reply(&in_phones, &in_uuids, None)).collect();
+        expect_replies(
+            &scenario,
+            vec![expected_replies[2].clone(), expected_replies[0].clone(), expected_replies[1].clone()],
+        );
+
+        // the duplicate doesn't count against the batch capacity
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: in_phones.len() as u32,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for request in &mut requests {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.requests.len(), 2);
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_retried_request_ids() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // a retry shares the request it retries only within the session that gave the id
+        let request = MockRequest::new(in_phones[..4].to_vec()).with_request_id(7);
+        let mut requests = vec![request.clone(), request.reencrypted(), MockRequest::new(in_phones[4..].to_vec()).with_request_id(7)];
+        let sessions = [[1; 32], [1; 32], [2; 32]];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(1));
+        let expected_replies: Vec<Vec<u8>> = requests.iter().map(|request| request.expected_reply(&in_phones, &in_uuids, None)).collect();
+        expect_replies(
+            &scenario,
+            vec![expected_replies[1].clone(), expected_replies[0].clone(), expected_replies[2].clone()],
+        );
+
+        // the retry doesn't count against the batch capacity
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: in_phones.len() as u32,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for (request, session) in requests.iter_mut().zip(&sessions) {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock_for_session(*session))
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.requests.len(), 2);
+
+        // a retry under the same id must be of the same query
+        let mut call_args = request.reencrypted().call_args();
+        call_args.query_commitment = [0; 32];
+        let result = server.handle_call(Some(&call_args), &request.query_key, SgxsdMsgFrom::mock_for_session([1; 32]));
+        assert_eq!(result.map_err(|(error, _)| error), Err(CDS_ERROR_QUERY_COMMITMENT_MISMATCH));
+
+        // and ask for the same reply, which it shares
+        let retries = vec![
+            request.reencrypted().with_reply_layout(CDS_REPLY_LAYOUT_ACI_PNI),
+            request.reencrypted().with_since_change(1),
+            request.reencrypted().with_reply_flags(0),
+        ];
+        for mut retry in retries {
+            let call_args = retry.call_args();
+            let result = server.handle_call(Some(&call_args), &retry.query_key, SgxsdMsgFrom::mock_for_session([1; 32]));
+            assert_eq!(result.map_err(|(error, _)| error), Err(SGX_ERROR_INVALID_PARAMETER));
+        }
+        assert_eq!(server.requests.len(), 2);
+
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_cancelled_requests() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        let cancelled_request = MockRequest::new(in_phones[2..5].to_vec());
+        let resubmitted_request = MockRequest::new(in_phones[5..7].to_vec());
+        let mut requests = vec![
+            MockRequest::new(in_phones[..2].to_vec()),
+            cancelled_request.clone(),
+            resubmitted_request.clone(),
+            resubmitted_request.clone(),
+            resubmitted_request.clone(),
+        ];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(2));
+        let resubmitted_reply = resubmitted_request.expected_reply(&in_phones, &in_uuids, None);
+        expect_replies(
+            &scenario,
+            vec![requests[0].expected_reply(&in_phones, &in_uuids, None), resubmitted_reply.clone(), resubmitted_reply],
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: in_phones.len() as u32,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for (tag, request) in (1..).zip(&mut requests[..4]) {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock_for_tag(tag))
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+
+        // the phones of a cancelled request are taken out of the batch, and a resubmitted request is replied to in place
+        // of its resubmission
+        server.cancel(2).unwrap();
+        server.cancel(3).unwrap();
+        assert_eq!(server.cancel(2), Err(SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND));
+        assert_eq!(&server.query_phones[..], &[&in_phones[..2], &in_phones[5..7]].concat()[..]);
+        assert_eq!(server.requests.len(), 2);
+
+        // a later resubmission still finds the request it shares
+        let call_args = requests[4].call_args();
+        let query_key = requests[4].query_key;
+        server
+            .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock_for_tag(5))
+            .map_err(|(error, _)| error)
+            .unwrap();
+        assert_eq!(server.requests.len(), 2);
+
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+    }
+
+    #[test]
+    fn test_cancelled_request_with_duplicate() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+
+        let request = MockRequest::new(in_phones[..3].to_vec()).with_request_id(7);
+        let mut requests = vec![request.clone(), request];
+        let sessions = [[1; 32], [2; 32]];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &requests);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(2));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: in_phones.len() as u32,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for ((tag, request), session) in (1..).zip(&mut requests).zip(&sessions) {
+            let call_args = request.call_args();
+            let query_key = request.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock_for_session_tag(*session, tag))
+                .map_err(|(error, _)| error)
+                .unwrap();
+        }
+        assert_eq!(server.session_phone_counts.get(&[1; 32]), Some(&3));
+        assert_eq!(server.session_phone_counts.get(&[2; 32]), None);
+        assert!(server.request_id_indices.contains_key(&([1; 32], 7)));
+
+        // the phones of a request replied to its duplicate in its place count towards the duplicate's session, and its
+        // request id no longer names it
+        server.cancel(1).unwrap();
+        assert_eq!(server.requests.len(), 1);
+        assert_eq!(server.session_phone_counts.get(&[1; 32]), None);
+        assert_eq!(server.session_phone_counts.get(&[2; 32]), Some(&3));
+        assert!(server.request_id_indices.is_empty());
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_replies_to_fragmented_query() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // another request is admitted whole between the fragments of the first, which is only queued after its last
+        let fragmented_request = MockRequest::new(test_phones(vec![2, 11, 3, 4, 5]));
+        let fragments = fragmented_request.fragments(7, &[2, 1, 2]);
+        let other_request = MockRequest::new(test_phones(vec![6]));
+        let mut calls = vec![fragments[0].clone(), other_request.clone(), fragments[1].clone(), fragments[2].clone()];
+
+        let scenario = Scenario::new();
+        expect_valid_requests(&scenario, &calls);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(2));
+        expect_replies(
+            &scenario,
+            vec![
+                other_request.expected_reply(&in_phones, &in_uuids, None),
+                fragmented_request.expected_reply(&in_phones, &in_uuids, None),
+            ],
+        );
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 6,
+            max_ratelimit_states: 0,
+            ..Default::default()
+        }))
+        .unwrap();
+        for (call_index, call) in calls.iter_mut().enumerate() {
+            let call_args = call.call_args();
+            let query_key = call.query_key;
+            server
+                .handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock())
+                .map_err(|(error, _)| error)
+                .unwrap();
+            if call_index == 2 {
+                assert_eq!(server.partial_requests.len(), 1);
+                assert_eq!(server.query_phones.len(), 1);
+            }
+        }
+        assert!(server.partial_requests.is_empty());
+        assert_eq!(server.query_phones.len(), 6);
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_invalid_fragmented_queries() {
+        let request = MockRequest::new(test_phones(vec![2, 3]));
+        let session_a = [0xa; 32];
+        let session_b = [0xb; 32];
+        let untokened_fragments = request.fragments(0, &[1, 1]);
+        let fragments = request.fragments(1, &[1, 1]);
+        let mut mismatched_fragment = fragments[1].clone();
+        mismatched_fragment.reply_layout = CDS_REPLY_LAYOUT_ACI_PNI;
+        let mismatched_change_fragment = fragments[1].clone().with_since_change(5);
+        let mut calls: Vec<(MockRequest, [u8; 32], Result<(), SgxStatus>)> = vec![
+            // a fragment must name the query it's part of by a token
+            (untokened_fragments[0].clone(), session_a, Err(SGX_ERROR_INVALID_PARAMETER)),
+            // the fragments of a query must all ask for the same reply layout, and the query is dropped if they don't
+            (fragments[0].clone(), session_a, Ok(())),
+            (mismatched_fragment, session_a, Err(SGX_ERROR_INVALID_PARAMETER)),
+            (fragments[1].clone(), session_a, Err(CDS_ERROR_INVALID_REQUEST_SIZE)),
+            // or for what changed since the same reply
+            (fragments[0].clone(), session_a, Ok(())),
+            (mismatched_change_fragment, session_a, Err(SGX_ERROR_INVALID_PARAMETER)),
+            (fragments[1].clone(), session_a, Err(CDS_ERROR_INVALID_REQUEST_SIZE)),
+            // fragments under the same token from another session are part of another query
+            (fragments[0].clone(), session_a, Ok(())),
+            (fragments[1].clone(), session_b, Err(CDS_ERROR_INVALID_REQUEST_SIZE)),
+            // and the fragments held take up the batch
+            (MockRequest::new(test_phones(vec![4, 5, 6])), session_b, Err(CDS_ERROR_BATCH_FULL)),
+        ];
+
+        let scenario = Scenario::new();
+        let decrypted_calls: Vec<MockRequest> = (calls.iter())
+            .filter(|(call, _, _)| call.fragment.as_ref().map_or(false, |fragment| fragment.token != 0))
+            .map(|(call, _, _)| call.clone())
+            .collect();
+        expect_valid_requests(&scenario, &decrypted_calls);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(calls.len() as u32));
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 3,
+            ..Default::default()
+        }))
+        .unwrap();
+        for (call, session, expected_result) in &mut calls {
+            let call_args = call.call_args();
+            let query_key = call.query_key;
+            let result = server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock_for_session(*session));
+            assert_eq!(result.map_err(|(error, _)| error), *expected_result);
+        }
+        assert_eq!(server.partial_requests.len(), 1);
+        assert!(server.query_phones.is_empty());
+
+        drop(server);
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
+
+    #[test]
+    fn test_chunked_query_commitments() {
+        let in_phones: Vec<Phone> = test_phones(2..10);
+        let in_uuids: Vec<Uuid> = in_phones.iter().map(|_| Uuid { data64: test_ffi::rand() }).collect();
+
+        // chunks of 32 bytes: the nonce, the next four phones, then the last
+        let mut chunked_request = MockRequest::new(test_phones(vec![2, 3, 4, 5, 6]));
+        let mut mismatched_request = MockRequest::new(test_phones(vec![7, 8, 9, 10, 11]));
+        let mut miscounted_request = MockRequest::new(test_phones(vec![7, 8, 9, 10, 11]));
+        let fragments = MockRequest::new(test_phones(vec![7, 8])).fragments(1, &[1, 1]);
+        let chunk_commitments = vec![*MOCK_COMMITMENT; 3];
+        let mismatched_chunk_commitments = vec![*MOCK_COMMITMENT, [0; 32], *MOCK_COMMITMENT];
+
+        let scenario = Scenario::new();
+        let decrypted_calls = vec![
+            chunked_request.clone(),
+            mismatched_request.clone(),
+            miscounted_request.clone(),
+            fragments[0].clone(),
+            fragments[1].clone(),
+        ];
+        expect_valid_requests(&scenario, &decrypted_calls);
+        let noreply = test_ffi::mock_for(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY, &scenario);
+        scenario.expect(noreply.sgxsd_enclave_server_noreply(any()).and_return_clone(SGX_SUCCESS).times(4));
+        expect_replies(&scenario, vec![chunked_request.expected_reply(&in_phones, &in_uuids, None)]);
+
+        let mut server = SgxsdServerState::init(Some(&StartArgs {
+            max_query_phones: 10,
+            ..Default::default()
+        }))
+        .unwrap();
+        let mut handle_chunked_call = |call: &mut MockRequest, chunk_commitments: &[[u8; 32]]| {
+            let mut call_args = call.call_args();
+            if !chunk_commitments.is_empty() {
+                call_args.query_chunk_commitments = chunk_commitments.as_ptr() as *const u8;
+                call_args.query_chunk_size = 32;
+                call_args.query_chunk_commitment_count = chunk_commitments.len() as u32;
+            }
+            let query_key = call.query_key;
+            let result = server.handle_call(Some(&call_args), &query_key, SgxsdMsgFrom::mock());
+            result.map_err(|(error, _)| error)
+        };
+        assert_eq!(handle_chunked_call(&mut chunked_request, &chunk_commitments), Ok(()));
+        // a query is refused at the first chunk that doesn't match its commitment
+        assert_eq!(
+            handle_chunked_call(&mut mismatched_request, &mismatched_chunk_commitments),
+            Err(CDS_ERROR_QUERY_COMMITMENT_MISMATCH)
+        );
+        // or if it doesn't carry a commitment for each of its chunks
+        assert_eq!(
+            handle_chunked_call(&mut miscounted_request, &chunk_commitments[..2]),
+            Err(CDS_ERROR_INVALID_REQUEST_SIZE)
+        );
+        // every fragment of a query must be committed to a chunk at a time if any is
+        let mut fragments = fragments;
+        assert_eq!(handle_chunked_call(&mut fragments[0], &chunk_commitments[..2]), Ok(()));
+        assert_eq!(handle_chunked_call(&mut fragments[1], &[]), Err(SGX_ERROR_INVALID_PARAMETER));
+        assert!(server.partial_requests.is_empty());
+        assert_eq!(server.query_phones.len(), 5);
+
+        server
+            .terminate(Some(&StopArgs {
+                in_phones: in_phones.as_ptr() as *mut Phone,
+                in_uuids: in_uuids.as_ptr() as *mut Uuid,
+                in_phone_count: in_phones.len(),
+                ..Default::default()
+            }))
+            .unwrap();
+
+        drop(scenario);
+        clear_mocks();
+        test_ffi::clear(&sgxsd_ffi::mocks::SGXSD_ENCLAVE_SERVER_NOREPLY);
+    }
 }
@@ -0,0 +1,382 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Assembly of the replies to the requests of a batch.
+//!
+//! A reply holds the results of its query in the layout the client asked for, preceded by its query nonce and change
+//! token if it asked for those, and followed by the generation of the directory it was looked up in, then padded with
+//! zeroes. It's then encrypted under the client's response key if it sent one, before sgxsd encrypts it for the session.
+
+use alloc::vec::Vec;
+use core::mem;
+
+use sgx_ffi::sgx::*;
+use sgx_ffi::util::SecretValue;
+use sgxsd_ffi::{AesGcmIv, AesGcmKey, AesGcmMac, RdRand};
+
+use crate::ffi::hash_lookup::*;
+use crate::ffi::sgxsd::*;
+use crate::service::sort::*;
+
+//
+// public API
+//
+
+pub const DIRECTORY_GENERATION_SIZE: usize = CDS_DIRECTORY_GENERATION_SIZE as usize;
+
+/// What the reply to a request holds for each of its phones, as asked for by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyLayout {
+    Uuid,
+    AciPni,
+    /// The only layout of replies to queries of username hashes.
+    UsernameUuid,
+    /// Whether each phone's entry changed since the client's last reply, and its uuid and metadata if it did.
+    UuidChanges,
+    UuidFlags,
+}
+
+/// What a client asked of its reply beyond its results, each flag checked on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplyFlags(u32);
+
+/// Where each part of a result sits in the lookup of a batch, each part after the uuid only if the directory has it.
+#[derive(Clone, Copy)]
+pub struct ResultLayout {
+    pub bytes_per_pni: usize,
+    pub bytes_per_change_epoch: usize,
+    pub bytes_per_flags: usize,
+    pub bytes_per_metadata: usize,
+}
+
+/// Assembles the replies of a stop call, which share its directory and padding.
+pub struct ReplyAssembler {
+    pub result_layout: ResultLayout,
+    pub directory_epoch: u64,
+    pub directory_generation: u64,
+    pub reply_padding_size: usize,
+}
+
+//
+// internal
+//
+
+const BYTES_PER_UUID: usize = mem::size_of::<Uuid>();
+
+const CHANGE_TOKEN_SIZE: usize = CDS_CHANGE_TOKEN_SIZE as usize;
+
+// the parts of one result in a lookup, each empty if the directory has none
+struct ResultParts<'a> {
+    uuid: &'a [u8],
+    pni: &'a [u8],
+    change_epoch: &'a [u8],
+    flags: &'a [u8],
+    metadata: &'a [u8],
+}
+
+//
+// ReplyLayout impls
+//
+
+impl ReplyLayout {
+    pub fn from_id(reply_layout: ReplyLayoutId) -> Result<Self, SgxStatus> {
+        match reply_layout {
+            CDS_REPLY_LAYOUT_UUID => Ok(Self::Uuid),
+            CDS_REPLY_LAYOUT_ACI_PNI => Ok(Self::AciPni),
+            CDS_REPLY_LAYOUT_UUID_CHANGES => Ok(Self::UuidChanges),
+            CDS_REPLY_LAYOUT_UUID_FLAGS => Ok(Self::UuidFlags),
+            _ => Err(SGX_ERROR_INVALID_PARAMETER),
+        }
+    }
+
+    /// The layout of a reply to a query of the given keys; change epochs are only looked up by phone.
+    pub fn for_keys(self, has_uuid_keys: bool, is_username_hashes: bool) -> Result<Self, SgxStatus> {
+        match (self, has_uuid_keys, is_username_hashes) {
+            (Self::UuidChanges, false, false) => Ok(self),
+            (Self::UuidChanges, _, _) => Err(SGX_ERROR_INVALID_PARAMETER),
+            (_, _, false) => Ok(self),
+            (Self::Uuid, _, true) => Ok(Self::UsernameUuid),
+            (_, _, true) => Err(SGX_ERROR_INVALID_PARAMETER),
+        }
+    }
+
+    // the size in a reply of a result laid out in the lookup as given
+    fn bytes_per_result(self, result_layout: ResultLayout) -> usize {
+        match self {
+            Self::Uuid => BYTES_PER_UUID.saturating_add(result_layout.bytes_per_metadata),
+            Self::AciPni => (BYTES_PER_UUID * 2).saturating_add(result_layout.bytes_per_metadata),
+            Self::UsernameUuid => BYTES_PER_UUID,
+            Self::UuidChanges => (1 + BYTES_PER_UUID).saturating_add(result_layout.bytes_per_metadata),
+            Self::UuidFlags => (BYTES_PER_UUID + FLAGS_SIZE).saturating_add(result_layout.bytes_per_metadata),
+        }
+    }
+
+    fn change_token_size(self) -> usize {
+        match self {
+            Self::UuidChanges => CHANGE_TOKEN_SIZE,
+            _ => 0,
+        }
+    }
+
+    // append each result in this layout, dropping the parts it doesn't hold and zero-filling those the lookup lacked
+    fn extend_reply(self, reply: &mut Vec<u8>, query_phones_result: &[u8], result_layout: ResultLayout, since_change_token: u64) {
+        for query_phone_result in query_phones_result.chunks_exact(result_layout.bytes_per_result()) {
+            let ResultParts {
+                uuid,
+                pni,
+                change_epoch,
+                flags,
+                metadata,
+            } = result_layout.split(query_phone_result);
+            match self {
+                Self::Uuid => {
+                    reply.extend_from_slice(uuid);
+                    reply.extend_from_slice(metadata);
+                }
+                Self::AciPni => {
+                    reply.extend_from_slice(uuid);
+                    match pni.is_empty() {
+                        true => reply.extend_from_slice(&[0; BYTES_PER_UUID]),
+                        false => reply.extend_from_slice(pni),
+                    }
+                    reply.extend_from_slice(metadata);
+                }
+                Self::UsernameUuid => reply.extend_from_slice(uuid),
+                // the uuid and metadata of an unchanged entry are cleared without branching on which it is
+                Self::UuidChanges => {
+                    let changed = Self::changed_since(since_change_token, change_epoch);
+                    let changed_mask = 0u8.wrapping_sub(changed);
+                    reply.push(changed);
+                    reply.extend(uuid.iter().chain(metadata).map(|byte| byte & changed_mask));
+                }
+                Self::UuidFlags => {
+                    reply.extend_from_slice(uuid);
+                    match flags.is_empty() {
+                        true => reply.extend_from_slice(&[0; FLAGS_SIZE]),
+                        false => reply.extend_from_slice(flags),
+                    }
+                    reply.extend_from_slice(metadata);
+                }
+            }
+        }
+    }
+
+    // 1 if the entry changed after the epoch of the client's change token, or if either is missing
+    fn changed_since(since_change_token: u64, change_epoch: &[u8]) -> u8 {
+        let mut change_epoch_bytes = [0; CHANGE_EPOCH_SIZE];
+        if since_change_token == 0 || change_epoch.len() != change_epoch_bytes.len() {
+            return 1;
+        }
+        change_epoch_bytes.copy_from_slice(change_epoch);
+        let changed = since_change_token.sorts_before(&u64::from_ne_bytes(change_epoch_bytes));
+        u8::from(changed != 0)
+    }
+}
+
+//
+// ReplyFlags impls
+//
+
+impl ReplyFlags {
+    const KNOWN: u32 = CDS_REPLY_FLAG_BATCH_KEY | CDS_REPLY_FLAG_QUERY_NONCE;
+
+    /// The flags a call names, refusing any this enclave doesn't know rather than have the client misread its reply.
+    pub fn from_args(args: &CallArgs) -> Result<Self, SgxStatus> {
+        if args.reply_flags & !Self::KNOWN != 0 || args.reply_reserved != 0 {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        Ok(Self(args.reply_flags))
+    }
+
+    /// Whether the reply is encrypted under a key mixed with the batch keypair.
+    pub fn batch_key(self) -> bool {
+        self.0 & CDS_REPLY_FLAG_BATCH_KEY != 0
+    }
+
+    fn query_nonce(self) -> bool {
+        self.0 & CDS_REPLY_FLAG_QUERY_NONCE != 0
+    }
+}
+
+//
+// ResultLayout impls
+//
+
+impl ResultLayout {
+    pub fn pni_start(self) -> usize {
+        BYTES_PER_UUID
+    }
+
+    pub fn change_epoch_start(self) -> usize {
+        self.pni_start().saturating_add(self.bytes_per_pni)
+    }
+
+    pub fn flags_start(self) -> usize {
+        self.change_epoch_start().saturating_add(self.bytes_per_change_epoch)
+    }
+
+    pub fn metadata_start(self) -> usize {
+        self.flags_start().saturating_add(self.bytes_per_flags)
+    }
+
+    pub fn bytes_per_result(self) -> usize {
+        self.metadata_start().saturating_add(self.bytes_per_metadata)
+    }
+
+    fn split(self, query_phone_result: &[u8]) -> ResultParts<'_> {
+        let (uuid, pni_and_rest) = query_phone_result.split_at(BYTES_PER_UUID.min(query_phone_result.len()));
+        let (pni, change_epoch_and_rest) = pni_and_rest.split_at(self.bytes_per_pni.min(pni_and_rest.len()));
+        let change_epoch_len = self.bytes_per_change_epoch.min(change_epoch_and_rest.len());
+        let (change_epoch, flags_and_metadata) = change_epoch_and_rest.split_at(change_epoch_len);
+        let (flags, metadata) = flags_and_metadata.split_at(self.bytes_per_flags.min(flags_and_metadata.len()));
+        ResultParts {
+            uuid,
+            pni,
+            change_epoch,
+            flags,
+            metadata,
+        }
+    }
+}
+
+//
+// ReplyAssembler impls
+//
+
+impl ReplyAssembler {
+    /// The reply to a request of the given results, laid out in the lookup as this stop call's are.
+    pub fn assemble(
+        &self,
+        reply_layout: ReplyLayout,
+        reply_flags: ReplyFlags,
+        since_change_token: u64,
+        query_nonce: &[u8],
+        query_phones_result: &[u8],
+    ) -> SecretValue<Vec<u8>>
+    {
+        let query_nonce = if reply_flags.query_nonce() { query_nonce } else { &[] };
+        let result_count = query_phones_result.len().checked_div(self.result_layout.bytes_per_result()).unwrap_or(0);
+        let reply_len = (reply_layout.bytes_per_result(self.result_layout))
+            .saturating_mul(result_count)
+            .saturating_add(query_nonce.len())
+            .saturating_add(reply_layout.change_token_size())
+            .saturating_add(DIRECTORY_GENERATION_SIZE);
+        let padded_reply_len = self.padded_reply_len(reply_len);
+
+        let mut reply = SecretValue::new(Vec::with_capacity(padded_reply_len));
+        reply.get_mut().extend_from_slice(query_nonce);
+        if reply_layout == ReplyLayout::UuidChanges {
+            reply.get_mut().extend_from_slice(&self.directory_epoch.to_le_bytes());
+        }
+        reply_layout.extend_reply(reply.get_mut(), query_phones_result, self.result_layout, since_change_token);
+        reply.get_mut().extend_from_slice(&self.directory_generation.to_le_bytes());
+        reply.get_mut().resize(padded_reply_len, 0);
+        reply
+    }
+
+    // padded to a multiple of the reply padding size, so its length only gives away the bucket of its query's size
+    fn padded_reply_len(&self, reply_len: usize) -> usize {
+        match reply_len.checked_rem(self.reply_padding_size) {
+            None | Some(0) => reply_len,
+            Some(remainder) => reply_len.saturating_add(self.reply_padding_size.saturating_sub(remainder)),
+        }
+    }
+}
+
+/// Encrypts a reply under the response key the client sent, if any, as a random IV, the MAC, then the encrypted reply.
+pub fn encrypt_reply(reply: &[u8], response_key: Option<&AesGcmKey>) -> Result<SecretValue<Vec<u8>>, SgxStatus> {
+    let response_key = match response_key {
+        Some(response_key) => response_key,
+        None => return Ok(SecretValue::new(reply.to_vec())),
+    };
+    let iv = AesGcmIv {
+        data: RdRand.rand_bytes([0; SGXSD_AES_GCM_IV_SIZE as usize]),
+    };
+    let mut mac = AesGcmMac::default();
+    let header_len = iv.data.len().saturating_add(mac.data.len());
+    let mut encrypted_reply = SecretValue::new(Vec::with_capacity(header_len.saturating_add(reply.len())));
+    encrypted_reply.get_mut().extend_from_slice(&iv.data);
+    encrypted_reply.get_mut().resize(header_len, 0);
+    encrypted_reply.get_mut().extend_from_slice(reply);
+    let (header, encrypted_data) = encrypted_reply.get_mut().split_at_mut(header_len);
+    response_key.encrypt(encrypted_data, &[], &iv, &mut mac)?;
+    if let Some(mac_data) = header.get_mut(iv.data.len()..) {
+        mac_data.copy_from_slice(&mac.data);
+    }
+    Ok(encrypted_reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padded_reply_len() {
+        let assembler = ReplyAssembler {
+            result_layout: ResultLayout {
+                bytes_per_pni: 0,
+                bytes_per_change_epoch: 0,
+                bytes_per_flags: 0,
+                bytes_per_metadata: 0,
+            },
+            directory_epoch: 0,
+            directory_generation: 0,
+            reply_padding_size: 40,
+        };
+        assert_eq!(assembler.padded_reply_len(0), 0);
+        assert_eq!(assembler.padded_reply_len(1), 40);
+        assert_eq!(assembler.padded_reply_len(40), 40);
+        assert_eq!(assembler.padded_reply_len(41), 80);
+        let assembler = ReplyAssembler {
+            reply_padding_size: 0,
+            ..assembler
+        };
+        assert_eq!(assembler.padded_reply_len(41), 41);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let change_epoch = 5u64.to_ne_bytes();
+        assert_eq!(ReplyLayout::changed_since(0, &change_epoch), 1);
+        assert_eq!(ReplyLayout::changed_since(4, &change_epoch), 1);
+        assert_eq!(ReplyLayout::changed_since(5, &change_epoch), 0);
+        assert_eq!(ReplyLayout::changed_since(6, &change_epoch), 0);
+        assert_eq!(ReplyLayout::changed_since(4, &[]), 1);
+    }
+
+    #[test]
+    fn test_assemble_reply() {
+        let result_layout = ResultLayout {
+            bytes_per_pni: BYTES_PER_UUID,
+            bytes_per_change_epoch: 0,
+            bytes_per_flags: 0,
+            bytes_per_metadata: 0,
+        };
+        let assembler = ReplyAssembler {
+            result_layout,
+            directory_epoch: 3,
+            directory_generation: 7,
+            reply_padding_size: 0,
+        };
+        let query_phones_result: Vec<u8> = (0..2 * result_layout.bytes_per_result() as u8).collect();
+        let query_nonce = [9; 32];
+
+        // only the uuid of each result is replied in the uuid layout, after the nonce only if the client asked for it
+        let reply = assembler.assemble(ReplyLayout::Uuid, ReplyFlags(0), 0, &query_nonce, &query_phones_result);
+        let mut expected_reply = query_phones_result[..BYTES_PER_UUID].to_vec();
+        expected_reply.extend(&query_phones_result[2 * BYTES_PER_UUID..][..BYTES_PER_UUID]);
+        expected_reply.extend(&7u64.to_le_bytes());
+        assert_eq!(reply.get(), &expected_reply);
+
+        let reply_flags = ReplyFlags(CDS_REPLY_FLAG_QUERY_NONCE);
+        let reply = assembler.assemble(ReplyLayout::AciPni, reply_flags, 0, &query_nonce, &query_phones_result);
+        let mut expected_reply = query_nonce.to_vec();
+        expected_reply.extend(&query_phones_result);
+        expected_reply.extend(&7u64.to_le_bytes());
+        assert_eq!(reply.get(), &expected_reply);
+    }
+}
@@ -0,0 +1,136 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Replies written into untrusted memory donated by the host.
+//!
+//! A server may be started with a reply scratch region, which each stop call writes the replies it encrypts into
+//! instead of making an ocall per reply, leaving the host to send them once the call returns. The region starts with
+//! the size of the replies written so far, updated after each one, so the host sends every reply written even if the
+//! stop call fails part way through. Replies that don't fit in what's left of the region are sent with an ocall as
+//! usual.
+
+use core::convert::TryFrom;
+use core::mem;
+
+use sgx_ffi::sgx::*;
+use sgx_ffi::untrusted_slice::UntrustedSlice;
+use sgx_ffi::util::ToU64;
+use sgxsd_ffi::ecalls::*;
+
+//
+// public API
+//
+
+pub struct ReplyScratch {
+    data: *mut u8,
+    size: usize,
+}
+
+pub struct ReplyScratchWriter {
+    region:       UntrustedSlice<'static>,
+    replies_size: usize,
+}
+
+//
+// internal
+//
+
+// a cds_reply_scratch_t
+const REPLY_SCRATCH_HEADER_SIZE: usize = mem::size_of::<u64>();
+
+// a cds_scratch_reply_t: the reply header, the size of its data, then its tag
+const SCRATCH_REPLY_HEADER_SIZE: usize = mem::size_of::<sgxsd_reply_header_t>() + mem::size_of::<u32>() + mem::size_of::<u64>();
+
+const SCRATCH_REPLY_ALIGN: usize = mem::size_of::<u64>();
+
+//
+// ReplyScratch impls
+//
+
+// the region is in untrusted memory, which is checked again on each stop call before being written
+unsafe impl Send for ReplyScratch {}
+
+impl ReplyScratch {
+    pub fn new(data: *mut u8, size: usize) -> Result<Self, SgxStatus> {
+        let reply_scratch = Self { data, size };
+        let region = reply_scratch.region()?;
+        if region.len() != 0 && region.len() < REPLY_SCRATCH_HEADER_SIZE {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        Ok(reply_scratch)
+    }
+
+    /// Starts writing the replies of a stop call over those of the last one, which the host has sent by now.
+    pub fn writer(&self) -> Result<ReplyScratchWriter, SgxStatus> {
+        let writer = ReplyScratchWriter {
+            region:       self.region()?,
+            replies_size: 0,
+        };
+        writer.write_replies_size()?;
+        Ok(writer)
+    }
+
+    fn region(&self) -> Result<UntrustedSlice<'static>, SgxStatus> {
+        UntrustedSlice::new(self.data, self.size).map_err(|()| SGX_ERROR_INVALID_PARAMETER)
+    }
+}
+
+impl Default for ReplyScratch {
+    fn default() -> Self {
+        Self {
+            data: core::ptr::null_mut(),
+            size: 0,
+        }
+    }
+}
+
+//
+// ReplyScratchWriter impls
+//
+
+impl ReplyScratchWriter {
+    /// Encrypts `msg` in place and replies with it to `from`, in the region if it fits or with an ocall otherwise, under
+    /// a key mixed with `batch` if there is one.
+    pub fn reply(&mut self, from: SgxsdMsgFrom, msg: &mut [u8], batch: Option<&ReplyBatch>) -> Result<(), SgxStatus> {
+        let reply_start = REPLY_SCRATCH_HEADER_SIZE.saturating_add(self.replies_size);
+        let data_start = reply_start.saturating_add(SCRATCH_REPLY_HEADER_SIZE);
+        let data_end = data_start.saturating_add(msg.len());
+        let data_size = match u32::try_from(msg.len()) {
+            Ok(data_size) if data_end <= self.region.len() => data_size,
+            _ => return from.reply(msg, batch),
+        };
+
+        let sealed_reply = from.seal_reply(msg, batch)?;
+        let data_size_bytes = data_size.to_le_bytes();
+        let tag_bytes = sealed_reply.tag.to_le_bytes();
+        let mut reply_header = [0; SCRATCH_REPLY_HEADER_SIZE];
+        let reply_header_fields = (sealed_reply.header.iv.data.iter())
+            .chain(&sealed_reply.header.mac.data)
+            .chain(&sealed_reply.header.batch_pubkey.x)
+            .chain(&data_size_bytes)
+            .chain(&tag_bytes);
+        for (reply_header_byte, field_byte) in reply_header.iter_mut().zip(reply_header_fields) {
+            *reply_header_byte = *field_byte;
+        }
+        self.region.offset(reply_start).write_bytes(&reply_header).map_err(|()| SGX_ERROR_UNEXPECTED)?;
+        self.region.offset(data_start).write_bytes(msg).map_err(|()| SGX_ERROR_UNEXPECTED)?;
+
+        // the padding after the last reply may run past the end of the region, since nothing follows it
+        let padded_data_end = data_end.saturating_add(SCRATCH_REPLY_ALIGN - 1) & !(SCRATCH_REPLY_ALIGN - 1);
+        self.replies_size = padded_data_end.min(self.region.len()).saturating_sub(REPLY_SCRATCH_HEADER_SIZE);
+        self.write_replies_size()
+    }
+
+    fn write_replies_size(&self) -> Result<(), SgxStatus> {
+        if self.region.len() == 0 {
+            return Ok(());
+        }
+        (self.region)
+            .write_bytes(&self.replies_size.to_u64().to_le_bytes())
+            .map_err(|()| SGX_ERROR_UNEXPECTED)
+    }
+}
@@ -0,0 +1,92 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Results sealed while their request waits on later lookup chunks.
+//!
+//! A request whose phones span several lookup chunks, possibly over several stop calls, has the results looked up for
+//! it so far encrypted under a key derived from its session, so they aren't left in the clear in enclave memory while
+//! the rest are looked up. They're decrypted again just before it's replied to.
+
+use alloc::vec::Vec;
+use core::mem;
+
+use sgx_ffi::sgx::*;
+use sgxsd_ffi::ecalls::*;
+use sgxsd_ffi::{AesGcmIv, AesGcmKey, AesGcmMac};
+
+//
+// public API
+//
+
+#[derive(Default)]
+pub struct SealedResults {
+    key: Option<AesGcmKey>,
+    pieces: Vec<SealedResultsPiece>,
+    next_iv: u64,
+    sealed_len: usize,
+}
+
+//
+// internal
+//
+
+const SEALED_RESULTS_KEY_LABEL: &[u8] = b"cds sealed results";
+
+struct SealedResultsPiece {
+    start: usize,
+    end: usize,
+    iv: AesGcmIv,
+    mac: AesGcmMac,
+}
+
+//
+// SealedResults impls
+//
+
+impl SealedResults {
+    /// Seals the results in `results[..done_len]` that are neither replied nor sealed yet, for the request of `from`.
+    pub fn seal(&mut self, from: &SgxsdMsgFrom, results: &mut [u8], replied_len: usize, done_len: usize) -> Result<(), SgxStatus> {
+        let start = replied_len.max(self.sealed_len);
+        if start >= done_len {
+            return Ok(());
+        }
+        let unsealed_results = results.get_mut(start..done_len).ok_or(SGX_ERROR_UNEXPECTED)?;
+        if self.key.is_none() {
+            self.key = Some(from.derive_key(SEALED_RESULTS_KEY_LABEL)?);
+        }
+        let key = self.key.as_ref().ok_or(SGX_ERROR_UNEXPECTED)?;
+
+        // IVs are never reused within a batch, even if two requests in it derived the same key
+        let mut iv = AesGcmIv::default();
+        (iv.data.get_mut(..mem::size_of::<u64>()))
+            .ok_or(SGX_ERROR_UNEXPECTED)?
+            .copy_from_slice(&self.next_iv.to_le_bytes());
+        self.next_iv = self.next_iv.wrapping_add(1);
+
+        let mut mac = AesGcmMac::default();
+        key.encrypt(unsealed_results, &[], &iv, &mut mac)?;
+        self.pieces.push(SealedResultsPiece {
+            start,
+            end: done_len,
+            iv,
+            mac,
+        });
+        self.sealed_len = done_len;
+        Ok(())
+    }
+
+    /// Unseals every piece of `results` sealed so far, in place.
+    pub fn unseal(&mut self, results: &mut [u8]) -> Result<(), SgxStatus> {
+        if let Some(key) = self.key.take() {
+            for piece in self.pieces.drain(..) {
+                let piece_results = results.get_mut(piece.start..piece.end).ok_or(SGX_ERROR_UNEXPECTED)?;
+                key.decrypt(piece_results, &[], &piece.iv, &piece.mac)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//
+// Copyright (C) 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Oblivious sorting of secret values.
+//!
+//! A bitonic sorting network makes the same compare-exchanges in the same order whatever the values being sorted, and
+//! each compare-exchange is made without branching on them, so how long a sort takes and which memory it touches only
+//! depend on how many values there are.
+
+//
+// public API
+//
+
+/// Values compared and swapped without branching on them.
+pub trait ObliviousOrd {
+    /// 1 if `self` sorts strictly before `other`, else 0.
+    fn sorts_before(&self, other: &Self) -> u64;
+
+    /// Swaps `self` and `other` if `swap` is 1, and leaves them if it's 0.
+    fn swap_if(&mut self, other: &mut Self, swap: u64);
+}
+
+/// Sorts a power-of-two number of values into ascending order.
+pub fn bitonic_sort<T: ObliviousOrd>(values: &mut [T]) {
+    let mut block_len = 2;
+    while block_len <= values.len() {
+        let mut distance = block_len >> 1;
+        while distance != 0 {
+            for index in 0..values.len() {
+                let partner = index ^ distance;
+                if partner > index {
+                    compare_exchange(values, index, partner, index & block_len != 0);
+                }
+            }
+            distance >>= 1;
+        }
+        block_len = block_len.saturating_mul(2);
+    }
+}
+
+//
+// internal
+//
+
+// put the values at two indices in order, or the reverse
+fn compare_exchange<T: ObliviousOrd>(values: &mut [T], low_index: usize, high_index: usize, descending: bool) {
+    let (low, high) = values.split_at_mut(high_index);
+    if let (Some(low_value), Some(high_value)) = (low.get_mut(low_index), high.first_mut()) {
+        let swap = high_value.sorts_before(low_value) ^ u64::from(descending);
+        low_value.swap_if(high_value, swap);
+    }
+}
+
+//
+// ObliviousOrd impls
+//
+
+impl ObliviousOrd for u64 {
+    fn sorts_before(&self, other: &Self) -> u64 {
+        let difference = self ^ other;
+        ((!self & other) | (!difference & self.wrapping_sub(*other))) >> 63
+    }
+
+    fn swap_if(&mut self, other: &mut Self, swap: u64) {
+        let difference = (*self ^ *other) & swap.wrapping_neg();
+        *self ^= difference;
+        *other ^= difference;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitonic_sort() {
+        let mut values: Vec<u64> = vec![7, u64::max_value(), 3, 0, 1 << 63, 3, 12, 9];
+        bitonic_sort(&mut values);
+        assert_eq!(values, vec![0, 3, 3, 7, 9, 12, 1 << 63, u64::max_value()]);
+    }
+}
@@ -32,6 +32,30 @@
 #define CDS_MAX_HASH_TABLE_ORDER 13
 #endif
 
+// size of the optional metadata word carried by each directory entry and returned alongside its uuid
+#define CDS_DIRECTORY_METADATA_SIZE 4
+
+// size of each username hash in the username table, and of each key of a query made up of username hashes
+#define CDS_USERNAME_HASH_SIZE 32
+
+// number of power-of-two buckets in the queue age histogram reported by sgxsd_enclave_server_get_metrics
+#define CDS_QUEUE_AGE_HISTOGRAM_BUCKETS 16
+
+// number of power-of-ten buckets in the request phone count histogram reported by sgxsd_enclave_server_get_metrics
+#define CDS_REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS 6
+
+// upper bound on the size of the sealed incident record returned by sgxsd_enclave_get_incident_record
+#define CDS_MAX_INCIDENT_RECORD_SIZE 256
+
+// upper bound on the number of entries returned by one sgxsd_enclave_sample_directory call
+#define CDS_MAX_DIRECTORY_SAMPLE_COUNT 4096
+
+// upper bound on the number of client public keys in the list given to sgxsd_enclave_set_session_denylist
+#define CDS_MAX_SESSION_DENYLIST_COUNT 65536
+
+// upper bound on the iterations run by one sgxsd_enclave_run_benchmark call
+#define CDS_MAX_BENCHMARK_ITERATIONS 1024
+
 typedef struct cds_encrypted_msg {
     sgxsd_aes_gcm_iv_t iv;
     sgxsd_aes_gcm_mac_t mac;
@@ -43,8 +67,35 @@ _Static_assert(sizeof(cds_encrypted_msg_t) == sizeof(sgxsd_aes_gcm_iv_t) + sizeo
 typedef struct sgxsd_server_init_args {
     uint32_t max_query_phones;
     uint32_t max_ratelimit_states;
+    uint32_t max_untrusted_read_bytes; // per call, or 0 for no limit
+    uint32_t fair_admission_phones; // remaining capacity below which each session is held to its fair share, or 0
+    uint32_t miss_rate_alert_ppm; // lookup miss rate average above which sgxsd_enclave_server_get_metrics alerts, or 0
+    uint32_t require_sorted_query; // nonzero to refuse queries whose keys aren't in strictly ascending order
+    const uint8_t* canonicalization_rules; // see cds_enclave/src/service/canonicalize.rs
+    size_t canonicalization_rules_size;
+    uint8_t* reply_scratch; // NULL, or untrusted memory each stop call writes its replies into, see cds_reply_scratch_t
+    size_t reply_scratch_size;
+    uint32_t reply_padding_size; // 0, or the size each reply is padded with zeroes to a multiple of, to hide its query's size
+    uint32_t dedup_query_phones; // nonzero to look up each distinct phone of a batch only once, see cds_enclave/src/service/distinct.rs
+    uint32_t max_phones_per_request; // 0, or the most phones one query may hold, see CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED
+    uint32_t interactive_reserve_phones; // capacity kept for interactive requests, see CDS_ERROR_REQUEST_DEFERRED
 } sgxsd_server_init_args_t, cds_start_args_t;
-_Static_assert(sizeof(cds_start_args_t) == sizeof(uint32_t) + sizeof(uint32_t), "Enclave ABI compatibility");
+_Static_assert(sizeof(cds_start_args_t) == sizeof(uint64_t) * 9, "Enclave ABI compatibility");
+
+// the reply scratch region of a server starts with a cds_reply_scratch_t, followed by the replies of the last stop call
+// one after another, each a cds_scratch_reply_t then its encrypted data padded to a multiple of 8 bytes; replies that
+// don't fit are sent with sgxsd_ocall_reply instead, so the host must send those in the region before the next stop call
+typedef struct cds_reply_scratch {
+    uint64_t size; // of the replies following
+} cds_reply_scratch_t;
+_Static_assert(sizeof(cds_reply_scratch_t) == sizeof(uint64_t), "Enclave ABI compatibility");
+
+typedef struct cds_scratch_reply {
+    sgxsd_reply_header_t header;
+    uint32_t data_size;
+    sgxsd_msg_tag_t tag;
+} cds_scratch_reply_t;
+_Static_assert(sizeof(cds_scratch_reply_t) == sizeof(sgxsd_reply_header_t) + sizeof(uint32_t) + sizeof(sgxsd_msg_tag_t), "Enclave ABI compatibility");
 
 typedef struct sgxsd_server_handle_call_args {
     uint32_t query_phone_count;
@@ -53,15 +104,155 @@ typedef struct sgxsd_server_handle_call_args {
     uint8_t *ratelimit_state_data;
     cds_encrypted_msg_t query;
     uint8_t  query_commitment[SGXSD_SHA256_HASH_SIZE];
+    uint64_t admission_ticks; // host clock, only used for queue age metrics
+    uint32_t reply_flags; // cds_reply_flag_t bits, as named by the client
+    uint32_t reply_reserved; // 0
+    uint32_t reply_layout; // a cds_reply_layout_t, as asked for by the client
+    uint32_t query_more_fragments; // nonzero if further fragments of the query follow in calls with the same token
+    uint64_t query_fragment_token; // 0, or the token the host names a query handed in over several calls by
+    uint64_t since_change_token; // for CDS_REPLY_LAYOUT_UUID_CHANGES, the change token of the client's previous reply, or 0
+    uint32_t protocol_version; // a cds_protocol_version_t, as named by the client
+    uint32_t query_key_size; // 0 to tell the size of each key by that of the query, or the size the client named, as it must for 128-bit identifiers
+    const uint8_t *query_chunk_commitments; // NULL, or a commitment to each query_chunk_size bytes in turn of the query this call carries
+    uint32_t query_chunk_size; // the last chunk may be shorter; query_commitment then commits to the chunk commitments of every call
+    uint32_t query_chunk_commitment_count;
+    uint64_t request_id; // 0, or an id the client gave the query, so that a retry of it by the same session shares its reply
+    uint32_t reply_order; // a cds_reply_order_t, as asked for by the client
+    uint32_t priority; // a cds_request_priority_t, as the host ranks the request
 } sgxsd_server_handle_call_args_t, cds_call_args_t;
-_Static_assert(sizeof(cds_call_args_t) == sizeof(uint32_t) + sizeof(uint32_t) + sizeof(cds_encrypted_msg_t) + SGXSD_SHA256_HASH_SIZE + sizeof(uuid_t) + sizeof(uint8_t *), "Enclave ABI compatibility");
+_Static_assert(sizeof(cds_call_args_t) == sizeof(uint32_t) + sizeof(uint32_t) + sizeof(cds_encrypted_msg_t) + SGXSD_SHA256_HASH_SIZE + sizeof(uuid_t) + sizeof(uint8_t *) + sizeof(uint64_t) + sizeof(uint32_t) + sizeof(uint32_t) + sizeof(uint64_t) + sizeof(uint64_t) + sizeof(uint64_t) + sizeof(uint32_t) + sizeof(uint32_t) + sizeof(uint8_t *) + sizeof(uint32_t) + sizeof(uint32_t) + sizeof(uint64_t) + sizeof(uint32_t) + sizeof(uint32_t), "Enclave ABI compatibility");
 
 typedef struct sgxsd_server_terminate_args {
     const phone_t* in_phones;
     size_t in_phone_count;
     const uuid_t* in_uuids;
+    const uint8_t* in_metadata;
+    size_t in_metadata_size; // either 0 or CDS_DIRECTORY_METADATA_SIZE bytes per entry
+    uint64_t directory_epoch; // 0, or the epoch of the committed directory the above must refer to
+    uint64_t max_chunks; // 0 to look up the whole batch in this call, or the most lookup chunks to run before returning
+    uint64_t continuation_token; // 0 on the first stop call, or the token returned by the last one (an older one retries)
+    const phone_t* in_allowlist_phones; // NULL, or the only phones the lookup may find in the directory
+    size_t in_allowlist_phone_count;
+    const uuid_t* in_pni_uuids; // NULL, or the PNI of each entry, returned after its uuid to requests asking for pairs
+    const uint8_t* in_username_hashes; // NULL, or the username table queries of username hashes are looked up in
+    const uuid_t* in_username_uuids; // the uuid of each username in the table
+    size_t in_username_count;
+    const uint64_t* in_change_epochs; // NULL, or the directory epoch at which each entry was last changed
+    uint64_t directory_generation; // 0, or the generation of the directory the host loaded the above from, returned in every reply
+    const uint8_t* in_flags; // NULL, or a byte of flags for each entry, returned after its uuid to requests asking for them
 } sgxsd_server_terminate_args_t, cds_stop_args_t;
-_Static_assert(sizeof(cds_stop_args_t) == sizeof(uint64_t) + sizeof(uint64_t) + sizeof(uint64_t), "Enclave ABI compatibility");
+_Static_assert(sizeof(cds_stop_args_t) == sizeof(uint64_t) * 17, "Enclave ABI compatibility");
+
+// a commit is refused with SGX_ERROR_INVALID_STATE until every lookup naming the active epoch, including those suspended
+// between stop calls, is done
+typedef struct sgxsd_directory_commit_args {
+    uint64_t epoch;
+    const phone_t* in_phones;
+    size_t in_phone_count;
+    const uuid_t* in_uuids;
+    uint8_t expected_digest[SGXSD_SHA256_HASH_SIZE]; // SHA-256 of the little-endian epoch, in_phones, then in_uuids
+} sgxsd_directory_commit_args_t, cds_directory_commit_args_t;
+_Static_assert(sizeof(cds_directory_commit_args_t) == sizeof(uint64_t) + sizeof(uint64_t) + sizeof(uint64_t) + sizeof(uint64_t) + SGXSD_SHA256_HASH_SIZE, "Enclave ABI compatibility");
+
+typedef struct sgxsd_directory_sample_args {
+    uint64_t epoch; // the epoch of the committed directory to sample
+    uint8_t sample_key[SGXSD_SHA256_HASH_SIZE]; // operator-provided HMAC-SHA256 key for the phone hashes
+} sgxsd_directory_sample_args_t, cds_directory_sample_args_t;
+_Static_assert(sizeof(cds_directory_sample_args_t) == sizeof(uint64_t) + SGXSD_SHA256_HASH_SIZE, "Enclave ABI compatibility");
+
+typedef struct sgxsd_directory_sample {
+    uint8_t phone_hash[SGXSD_SHA256_HASH_SIZE]; // HMAC-SHA256 under sample_key of the little-endian phone number
+    uuid_t uuid;
+} sgxsd_directory_sample_t, cds_directory_sample_t;
+_Static_assert(sizeof(cds_directory_sample_t) == SGXSD_SHA256_HASH_SIZE + sizeof(uuid_t), "Enclave ABI compatibility");
+
+typedef struct sgxsd_server_metrics {
+    uint64_t pending_request_count;
+    uint64_t max_queue_age_ticks;
+    // bucket 0 counts requests admitted at the current tick, bucket i > 0 those aged [2^(i-1), 2^i) ticks,
+    // and the last bucket also everything older
+    uint64_t queue_age_histogram[CDS_QUEUE_AGE_HISTOGRAM_BUCKETS];
+    // moving average over the enclave's finished batches of the fraction of queries not found, in parts per million,
+    // and whether it's above the miss_rate_alert_ppm of this server; both 0 until a large enough batch has finished
+    uint64_t lookup_miss_rate_ewma_ppm;
+    uint64_t lookup_miss_rate_alert;
+    // requests admitted to the batch so far by their number of phones: bucket i counts those of [10^i, 10^(i+1)),
+    // and the last bucket also everything larger
+    uint64_t request_phone_count_histogram[CDS_REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS];
+    // phones of the requests admitted to the batch and of the query fragments held for it, out of its max_query_phones;
+    // a call refused with CDS_ERROR_BATCH_FULL asked for more than the difference
+    uint64_t held_phone_count;
+} sgxsd_server_metrics_t, cds_server_metrics_t;
+_Static_assert(sizeof(cds_server_metrics_t) == sizeof(uint64_t) * (5 + CDS_QUEUE_AGE_HISTOGRAM_BUCKETS + CDS_REQUEST_PHONE_COUNT_HISTOGRAM_BUCKETS), "Enclave ABI compatibility");
+
+//
+// benchmark profiles
+//
+
+// the synthetic workloads run by sgxsd_enclave_run_benchmark, which the host times from outside the enclave
+typedef enum cds_benchmark_profile {
+    CDS_BENCHMARK_LOOKUP_CHUNK = 1, // one chunk of query phones looked up in a synthetic directory
+    CDS_BENCHMARK_AES_GCM      = 2, // AES-GCM encryption of a fixed-size buffer
+    CDS_BENCHMARK_DIVREM       = 3, // a batch of 64-bit divisions and remainders
+} cds_benchmark_profile_t;
+
+//
+// reply layouts
+//
+
+// what a reply holds for each phone of its query, after any CDS_REPLY_FLAG_QUERY_NONCE; each is followed by the phone's
+// metadata word if the directory has them, and a phone missing from the directory gets all zeroes
+typedef enum cds_reply_layout {
+    CDS_REPLY_LAYOUT_UUID         = 0, // its uuid
+    CDS_REPLY_LAYOUT_ACI_PNI      = 1, // its uuid (ACI), then its PNI, which is zero if the stop call gave no in_pni_uuids
+    CDS_REPLY_LAYOUT_UUID_CHANGES = 2, // a byte set if its entry changed after since_change_token, then its uuid if it did
+    CDS_REPLY_LAYOUT_UUID_FLAGS   = 3, // its uuid, then the flags of its entry, which are zero if the stop call gave no in_flags
+} cds_reply_layout_t;
+
+// the order a reply holds the results of its query in, named by the client so that other orders can be added without
+// a reply being misread; an order the enclave doesn't know is refused with SGX_ERROR_INVALID_PARAMETER
+typedef enum cds_reply_order {
+    CDS_REPLY_ORDER_QUERY = 0, // one for each key of the query, in the order the client sent them
+} cds_reply_order_t;
+
+// how urgently the host wants a request looked up; once less than interactive_reserve_phones of a batch's capacity would
+// remain, bulk requests are refused with CDS_ERROR_REQUEST_DEFERRED so the host can hand them in to a later batch
+typedef enum cds_request_priority {
+    CDS_REQUEST_PRIORITY_INTERACTIVE = 0, // a query a user is waiting on
+    CDS_REQUEST_PRIORITY_BULK        = 1, // a re-sync that can wait for a later batch
+} cds_request_priority_t;
+
+// a reply in CDS_REPLY_LAYOUT_UUID_CHANGES has the directory epoch it was looked up in after any CDS_REPLY_FLAG_QUERY_NONCE,
+// as the change token the client hands in with its next query; every entry is reported as changed to a query with no
+// token, or when the stop call gave no in_change_epochs
+#define CDS_CHANGE_TOKEN_SIZE 8
+
+// every reply has the little-endian directory_generation of the stop call after its results and before any padding, so
+// that it can be told which build of the directory it was looked up in; clients reading only their results ignore it
+#define CDS_DIRECTORY_GENERATION_SIZE 8
+
+//
+// protocol versions
+//
+
+// the wire format of a query, so that clients of an old and a new one can be served side by side while the new one rolls
+// out; a version the enclave doesn't know is refused with CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION
+typedef enum cds_protocol_version {
+    CDS_PROTOCOL_VERSION_0 = 0, // a commitment nonce, then the keys of the query in any of the forms it may take
+    CDS_PROTOCOL_VERSION_1 = 1, // a commitment nonce, then ascending phones, each as a LEB128 varint of its difference from the last
+} cds_protocol_version_t;
+
+//
+// reply flags
+//
+
+// what a client asks of its reply beyond its results, each flag on its own; a reply with none is encrypted under the
+// request's server key alone and starts with its results, as clients have always read it. A flag the enclave doesn't
+// know is refused with SGX_ERROR_INVALID_PARAMETER
+typedef enum cds_reply_flag {
+    CDS_REPLY_FLAG_BATCH_KEY   = 1, // encrypted under a key mixed with the batch keypair, whose public key is its AAD
+    CDS_REPLY_FLAG_QUERY_NONCE = 2, // starts with the commitment nonce of its query
+} cds_reply_flag_t;
 
 //
 // error codes
@@ -70,6 +261,21 @@ _Static_assert(sizeof(cds_stop_args_t) == sizeof(uint64_t) + sizeof(uint64_t) +
 typedef enum cds_status_code {
     CDS_ERROR_INVALID_REQUEST_SIZE      = SGX_MK_ERROR(0x20001),
     CDS_ERROR_QUERY_COMMITMENT_MISMATCH = SGX_MK_ERROR(0x20002),
+    CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED = SGX_MK_ERROR(0x20003),
+    CDS_ERROR_INVALID_CANONICALIZATION_RULES = SGX_MK_ERROR(0x20004),
+    CDS_ERROR_DIRECTORY_DIGEST_MISMATCH = SGX_MK_ERROR(0x20005),
+    CDS_ERROR_DIRECTORY_EPOCH_MISMATCH = SGX_MK_ERROR(0x20006),
+    CDS_ERROR_ENCLAVE_HALTED = SGX_MK_ERROR(0x20007),
+    CDS_ERROR_FAIR_SHARE_EXCEEDED = SGX_MK_ERROR(0x20008),
+    CDS_ERROR_CANARY_MISMATCH = SGX_MK_ERROR(0x20009),
+    CDS_ERROR_BATCH_FULL = SGX_MK_ERROR(0x2000A),
+    CDS_ERROR_SESSION_DENIED = SGX_MK_ERROR(0x2000B),
+    CDS_ERROR_QUERY_NOT_SORTED = SGX_MK_ERROR(0x2000C),
+    CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION = SGX_MK_ERROR(0x2000D),
+    CDS_ERROR_DUPLICATE_QUERY_PHONE = SGX_MK_ERROR(0x2000E),
+    CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED = SGX_MK_ERROR(0x2000F),
+    CDS_ERROR_INVALID_QUERY_PHONE = SGX_MK_ERROR(0x20010),
+    CDS_ERROR_REQUEST_DEFERRED = SGX_MK_ERROR(0x20011),
 } cds_status_code_t;
 
 #endif
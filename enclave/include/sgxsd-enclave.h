@@ -26,6 +26,11 @@
 
 #include "sgxsd.h"
 
+// consecutive messages of a session that fail to decrypt under its key before the key is erased
+#ifndef SGXSD_SESSION_MAX_DECRYPT_FAILURES
+#define SGXSD_SESSION_MAX_DECRYPT_FAILURES 8
+#endif
+
 typedef struct sgxsd_msg_buf {
     uint8_t *data;
     uint32_t size;
@@ -35,8 +40,16 @@ typedef struct sgxsd_msg_from {
     bool valid;
     sgxsd_msg_tag_t tag;
     sgxsd_aes_gcm_key_t server_key;
+    sgxsd_curve25519_public_key_t client_pubkey;
 } sgxsd_msg_from_t;
 
+// replies sent together share a batch keypair: its public key is sent in each reply header, and its private key is
+// mixed into each reply key, so the request keys alone can't decrypt the replies once the batch is erased
+typedef struct sgxsd_reply_batch {
+    uint8_t privkey[SGXSD_CURVE25519_KEY_SIZE];
+    sgxsd_curve25519_public_key_t pubkey;
+} sgxsd_reply_batch_t;
+
 //
 // callbacks
 //
@@ -44,16 +57,44 @@ typedef struct sgxsd_msg_from {
 // the incomplete type sgxsd_server_state doesn't necessarily need to be defined
 typedef struct sgxsd_server_state sgxsd_server_state_t;
 
-/* the incomplete types sgxsd_server_{init,handle_call,terminate}_args must be defined and included before sgxsd APIs
-   in the .edl file */
+/* the incomplete types sgxsd_server_{init,handle_call,terminate}_args, sgxsd_server_metrics,
+   sgxsd_directory_{commit,sample}_args and sgxsd_directory_sample must be defined and included before sgxsd APIs in
+   the .edl file */
 typedef struct sgxsd_server_init_args sgxsd_server_init_args_t;
 typedef struct sgxsd_server_handle_call_args sgxsd_server_handle_call_args_t;
 typedef struct sgxsd_server_terminate_args sgxsd_server_terminate_args_t;
+typedef struct sgxsd_server_metrics sgxsd_server_metrics_t;
+typedef struct sgxsd_directory_commit_args sgxsd_directory_commit_args_t;
+typedef struct sgxsd_directory_sample_args sgxsd_directory_sample_args_t;
+typedef struct sgxsd_directory_sample sgxsd_directory_sample_t;
 
 // the callbacks sgxsd_enclave_server_{init,handle_call,terminate} handle sgxsd_enclave_server_{start,call,stop} calls
 sgx_status_t sgxsd_enclave_server_init(const sgxsd_server_init_args_t *p_args, sgxsd_server_state_t **pp_state);
+// the callback sgxsd_enclave_server_config encodes the settings of *p_args which change what a server answers into
+// p_config, for the quotes of the enclave to attest to while a server started with them is running
+sgx_status_t sgxsd_enclave_server_config(const sgxsd_server_init_args_t *p_args, uint8_t *p_config, size_t config_size,
+                                         size_t *p_config_len);
 sgx_status_t sgxsd_enclave_server_handle_call(const sgxsd_server_handle_call_args_t *p_args, sgxsd_msg_buf_t msg, sgxsd_msg_from_t from, sgxsd_server_state_t **pp_state);
-sgx_status_t sgxsd_enclave_server_terminate(const sgxsd_server_terminate_args_t *p_args, sgxsd_server_state_t *p_state);
+// sgxsd_enclave_server_terminate sets *pp_state to NULL once it has consumed the state, or else leaves the state in
+// place and sets *p_continuation_token for the stop call which is to carry on terminating it
+sgx_status_t sgxsd_enclave_server_terminate(const sgxsd_server_terminate_args_t *p_args, uint64_t *p_continuation_token,
+                                            sgxsd_server_state_t **pp_state);
+// the callback sgxsd_enclave_server_metrics handles sgxsd_enclave_server_get_metrics calls
+sgx_status_t sgxsd_enclave_server_metrics(uint64_t now_ticks, sgxsd_server_metrics_t *p_metrics, const sgxsd_server_state_t *p_state);
+// the callback sgxsd_enclave_server_cancel handles sgxsd_enclave_server_cancel_call calls, replying to the call made
+// with msg_tag with sgxsd_enclave_server_noreply, or returning SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND if it has none
+sgx_status_t sgxsd_enclave_server_cancel(sgxsd_msg_tag_t msg_tag, sgxsd_server_state_t *p_state);
+// the callback sgxsd_enclave_directory_commit handles sgxsd_enclave_commit_directory calls
+sgx_status_t sgxsd_enclave_directory_commit(const sgxsd_directory_commit_args_t *p_args);
+// the callback sgxsd_enclave_directory_sample handles sgxsd_enclave_sample_directory calls
+sgx_status_t sgxsd_enclave_directory_sample(const sgxsd_directory_sample_args_t *p_args,
+                                            sgxsd_directory_sample_t *p_samples, size_t sample_count);
+// the callback sgxsd_enclave_session_denylist handles sgxsd_enclave_set_session_denylist calls
+sgx_status_t sgxsd_enclave_session_denylist(const sgxsd_curve25519_public_key_t *p_session_ids, size_t session_id_count);
+// the callback sgxsd_enclave_incident_record handles sgxsd_enclave_get_incident_record calls
+sgx_status_t sgxsd_enclave_incident_record(uint8_t *p_record, size_t record_size, size_t *p_record_len);
+// the callback sgxsd_enclave_benchmark handles sgxsd_enclave_run_benchmark calls
+sgx_status_t sgxsd_enclave_benchmark(uint32_t profile, uint32_t iterations);
 
 // the api for getting a SHA256-HMAC fingerprint of the phone numbers
 typedef uint64_t phone_t;
@@ -67,8 +108,18 @@ sgx_status_t sgxsd_enclave_create_ratelimit_fingerprint(uint8_t fingerprint_key[
 // public api
 //
 
-// sgxsd_enclave_server_reply sends a reply to the message corresponding to the given sgxsd_msg_from from handle_call
-sgx_status_t sgxsd_enclave_server_reply(sgxsd_msg_buf_t reply_buf, sgxsd_msg_from_t *p_from);
+// sgxsd_enclave_new_reply_batch generates a new batch keypair, which the caller must erase after its last reply
+sgx_status_t sgxsd_enclave_new_reply_batch(sgxsd_reply_batch_t *p_batch);
+
+// sgxsd_enclave_server_reply sends a reply to the message corresponding to the given sgxsd_msg_from from handle_call,
+// under a key mixed with the given batch keypair, or under the request's server key alone if p_batch is NULL, for
+// clients that haven't negotiated batch reply keys
+sgx_status_t sgxsd_enclave_server_reply(sgxsd_msg_buf_t reply_buf, sgxsd_msg_from_t *p_from, const sgxsd_reply_batch_t *p_batch);
+
+// sgxsd_enclave_server_seal_reply encrypts a reply in place as sgxsd_enclave_server_reply would, but leaves handing the
+// encrypted reply and its header to the untrusted code to the caller instead of sending it
+sgx_status_t sgxsd_enclave_server_seal_reply(sgxsd_msg_buf_t reply_buf, sgxsd_msg_from_t *p_from, const sgxsd_reply_batch_t *p_batch,
+                                             sgxsd_reply_header_t *p_reply_header);
 
 sgx_status_t sgxsd_enclave_server_noreply(sgxsd_msg_from_t *p_from);
 
@@ -94,6 +145,10 @@ typedef struct sgxsd_rand_buf {
 
 sgx_status_t sgxsd_enclave_read_rand(sgxsd_rand_buf_t *p_privkey);
 
+// sgxsd_enclave_get_sealing_key derives a key bound to the enclave measurement and platform, for data that must
+// outlive the enclave instance
+sgx_status_t sgxsd_enclave_get_sealing_key(sgxsd_aes_gcm_key_t *p_key);
+
 //
 // internal definitions
 //
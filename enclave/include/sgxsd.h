@@ -96,8 +96,32 @@ typedef struct sgxsd_msg_header {
 } sgxsd_msg_header_t;
 _Static_assert(sizeof(sgxsd_msg_header_t) == sizeof(sgxsd_aes_gcm_iv_t) + sizeof(sgxsd_aes_gcm_mac_t) + sizeof(sgxsd_pending_request_id_t), "Enclave ABI compatibility");
 
+typedef struct sgxsd_reply_header {
+  sgxsd_aes_gcm_iv_t iv;
+  sgxsd_aes_gcm_mac_t mac;
+  sgxsd_curve25519_public_key_t batch_pubkey;
+} sgxsd_reply_header_t;
+_Static_assert(sizeof(sgxsd_reply_header_t) == sizeof(sgxsd_aes_gcm_iv_t) + sizeof(sgxsd_aes_gcm_mac_t) + sizeof(sgxsd_curve25519_public_key_t), "Enclave ABI compatibility");
+
+// what a session was used for, counted by the enclave as its calls succeed (little-endian)
+typedef struct sgxsd_session_usage {
+  uint64_t request_count;
+  uint64_t query_phone_count;
+  uint64_t ratelimit_phone_count;
+} sgxsd_session_usage_t;
+_Static_assert(sizeof(sgxsd_session_usage_t) == sizeof(uint64_t) * 3, "Enclave ABI compatibility");
+
+// the usage of a closed session, encrypted to its client with the server key of the session and the session id as AAD
+typedef struct sgxsd_session_summary {
+  uint8_t data[sizeof(sgxsd_session_usage_t)];
+  sgxsd_aes_gcm_iv_t iv;
+  sgxsd_aes_gcm_mac_t mac;
+} sgxsd_session_summary_t;
+_Static_assert(sizeof(sgxsd_session_summary_t) == sizeof(sgxsd_session_usage_t) + sizeof(sgxsd_aes_gcm_iv_t) + sizeof(sgxsd_aes_gcm_mac_t), "Enclave ABI compatibility");
+
 typedef struct sgxsd_node_init_args {
   uint8_t pending_requests_table_order;
+  uint32_t max_retry_replies; // replies kept for sgxsd_enclave_flush_replies when their ocall fails, or 0 to drop them
 } sgxsd_node_init_args_t;
 
 typedef uint64_t sgxsd_server_state_handle_t;
@@ -134,6 +158,9 @@ sgxsd_status_t sgxsd_get_next_quote(sgx_enclave_id_t enclave_id, sgx_spid_t spid
 
 typedef enum sgxsd_status_code {
   SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND = SGX_MK_ERROR(0x10001),
+  SGXSD_ERROR_SESSION_NOT_FOUND = SGX_MK_ERROR(0x10002),
+  SGXSD_ERROR_SESSION_REPLAYED = SGX_MK_ERROR(0x10003),
+  SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED = SGX_MK_ERROR(0x10004),
 } sgxsd_status_code_t;
 
 #endif
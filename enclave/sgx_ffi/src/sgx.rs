@@ -12,7 +12,7 @@ use core::slice;
 
 use super::bindgen_wrapper::{sgx_attributes_t, sgx_create_report, sgx_measurement_t, sgx_report_data_t, sgx_target_info_t};
 pub use super::bindgen_wrapper::{
-    sgx_report_t as SgxReport, sgx_status_t as SgxStatus, SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_INVALID_STATE, SGX_ERROR_UNEXPECTED,
+    sgx_report_t as SgxReport, sgx_status_t as SgxStatus, SGX_ERROR_INVALID_PARAMETER, SGX_ERROR_INVALID_STATE, SGX_ERROR_OUT_OF_MEMORY, SGX_ERROR_UNEXPECTED,
     SGX_SUCCESS,
 };
 
@@ -6,6 +6,7 @@
 //
 
 use alloc::vec::Vec;
+use core::cell::Cell;
 use core::marker::*;
 use core::num::*;
 use core::ptr::*;
@@ -17,11 +18,19 @@ pub enum UntrustedSlice<'a> {
         data: NonNull<u8>,
         size: NonZeroUsize,
 
-        _phantom: &'a PhantomData<()>,
+        read_limit: Option<&'a UntrustedReadLimit>,
+        _phantom:   &'a PhantomData<()>,
     },
     Empty,
 }
 
+/// Bounds the number of bytes one ecall copies in from untrusted memory, across every `UntrustedSlice` read
+/// under it, so that a bogus size from the untrusted side is refused before the copy rather than after it.
+pub struct UntrustedReadLimit {
+    remaining: Cell<usize>,
+    exceeded:  Cell<bool>,
+}
+
 //
 // UntrustedSlice impls
 //
@@ -47,6 +56,7 @@ impl<'a> UntrustedSlice<'a> {
             Ok(UntrustedSlice::NonEmpty {
                 data,
                 size,
+                read_limit: None,
                 _phantom: &PhantomData,
             })
         } else {
@@ -54,6 +64,19 @@ impl<'a> UntrustedSlice<'a> {
         }
     }
 
+    pub fn with_read_limit<'b>(self, read_limit: &'b UntrustedReadLimit) -> UntrustedSlice<'b>
+    where 'a: 'b {
+        match self {
+            UntrustedSlice::NonEmpty { data, size, _phantom, .. } => UntrustedSlice::NonEmpty {
+                data,
+                size,
+                read_limit: Some(read_limit),
+                _phantom,
+            },
+            UntrustedSlice::Empty => UntrustedSlice::Empty,
+        }
+    }
+
     pub fn len(&self) -> usize {
         match self {
             UntrustedSlice::NonEmpty { size, .. } => size.get(),
@@ -70,13 +93,14 @@ impl<'a> UntrustedSlice<'a> {
 
     pub fn offset(&self, offset: usize) -> UntrustedSlice<'_> {
         match self {
-            UntrustedSlice::NonEmpty { data, size, _phantom } => {
+            UntrustedSlice::NonEmpty { data, size, read_limit, _phantom } => {
                 if let Some(size) = size.get().checked_sub(offset) {
                     if let Some(size) = NonZeroUsize::new(size) {
                         let data = unsafe { NonNull::new_unchecked(data.as_ptr().add(offset)) };
                         UntrustedSlice::NonEmpty {
                             data,
                             size,
+                            read_limit: *read_limit,
                             _phantom: &PhantomData,
                         }
                     } else {
@@ -92,8 +116,11 @@ impl<'a> UntrustedSlice<'a> {
 
     pub fn read_bytes(&self, read_count: usize) -> Result<Vec<u8>, ()> {
         match self {
-            UntrustedSlice::NonEmpty { data, size, _phantom } => {
+            UntrustedSlice::NonEmpty { data, size, read_limit, _phantom } => {
                 if read_count <= size.get() {
+                    if let Some(read_limit) = read_limit {
+                        read_limit.take(read_count)?;
+                    }
                     let mut dest = Vec::with_capacity(read_count);
                     unsafe {
                         data.as_ptr().copy_to_nonoverlapping(dest.as_mut_ptr(), read_count);
@@ -116,7 +143,7 @@ impl<'a> UntrustedSlice<'a> {
 
     pub fn write_bytes(&self, write_bytes: &[u8]) -> Result<(), ()> {
         match self {
-            UntrustedSlice::NonEmpty { data, size, _phantom } => {
+            UntrustedSlice::NonEmpty { data, size, .. } => {
                 if write_bytes.len() <= size.get() {
                     unsafe {
                         write_bytes.as_ptr().copy_to(data.as_ptr(), write_bytes.len());
@@ -143,6 +170,37 @@ impl<'a> Default for UntrustedSlice<'a> {
     }
 }
 
+//
+// UntrustedReadLimit impls
+//
+
+impl UntrustedReadLimit {
+    pub const fn new(max_read_bytes: usize) -> Self {
+        Self {
+            remaining: Cell::new(max_read_bytes),
+            exceeded:  Cell::new(false),
+        }
+    }
+
+    pub const fn unlimited() -> Self {
+        Self::new(usize::max_value())
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.get()
+    }
+
+    fn take(&self, read_count: usize) -> Result<(), ()> {
+        if let Some(remaining) = self.remaining.get().checked_sub(read_count) {
+            self.remaining.set(remaining);
+            Ok(())
+        } else {
+            self.exceeded.set(true);
+            Err(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use mockers::*;
@@ -245,4 +303,22 @@ mod test {
         assert!(untrusted.offset(usize::max_value()).write_bytes(&[0]).is_err());
         assert!(untrusted.offset(usize::max_value()).read_bytes(usize::max_value()).is_err());
     }
+
+    #[test]
+    fn test_read_limit() {
+        let scenario = Scenario::new();
+        let test_vec = TestVec::new(10);
+
+        mocks::expect_sgx_is_outside_enclave(&scenario, test_vec.ptr as *const libc::c_void, test_vec.size, true);
+        let read_limit = UntrustedReadLimit::new(test_vec.size + 2);
+        let untrusted = UntrustedSlice::new(test_vec.ptr, test_vec.size).unwrap().with_read_limit(&read_limit);
+
+        assert_eq!(untrusted.read_bytes(test_vec.size).unwrap().len(), test_vec.size);
+        assert!(!read_limit.exceeded());
+        assert!(untrusted.offset(test_vec.size - 3).read_bytes(3).is_err());
+        assert!(read_limit.exceeded());
+        assert_eq!(untrusted.offset(test_vec.size - 2).read_bytes(2).unwrap().len(), 2);
+        assert!(untrusted.read_bytes(1).is_err());
+        assert!(untrusted.read_bytes(0).unwrap().is_empty());
+    }
 }
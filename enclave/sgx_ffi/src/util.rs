@@ -5,10 +5,15 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 //
 
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
 use core::ffi::c_void;
 use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::slice;
 
 use super::bindgen_wrapper::dlmallinfo;
+use super::sgx::*;
 
 pub use super::bindgen_wrapper::{consttime_memequal, memset_s};
 
@@ -31,6 +36,27 @@ pub fn consttime_eq(left: impl AsRef<[u8]>, right: impl AsRef<[u8]>) -> bool {
 #[derive(Default)]
 pub struct SecretValue<T: AsMut<[u8]> + ?Sized>(T);
 
+/// How a [`SecretBuffer`] is placed in enclave memory.
+///
+/// There is no way to keep secrets from being swapped out: the untrusted kernel can evict any enclave page at any time,
+/// with or without EDMM, though it only ever sees the page encrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecretAllocation {
+    /// Whatever alignment the allocator gives.
+    Unaligned,
+    /// Aligned to a cache line, so that no SIMD load or store splits one.
+    CacheLine,
+    /// Page aligned and padded to a whole number of pages, so that nothing else shares its pages.
+    Page,
+}
+
+/// A zeroed heap buffer placed according to a [`SecretAllocation`], for holding secrets in a [`SecretValue`].
+pub struct SecretBuffer {
+    data:   NonNull<u8>,
+    len:    usize,
+    layout: Layout,
+}
+
 pub struct MemoryStatus {
     pub footprint_bytes: u32,
     pub used_bytes:      u32,
@@ -95,6 +121,76 @@ impl<T: AsMut<[u8]> + ?Sized> Drop for SecretValue<T> {
     }
 }
 
+//
+// SecretAllocation impls
+//
+
+impl SecretAllocation {
+    pub const CACHE_LINE_SIZE: usize = 64;
+    pub const PAGE_SIZE: usize = 4096;
+
+    fn layout(self, len: usize) -> Result<Layout, SgxStatus> {
+        let (align, size) = match self {
+            Self::Unaligned => (1, len),
+            Self::CacheLine => (Self::CACHE_LINE_SIZE, len),
+            Self::Page => {
+                let size = len.checked_add(Self::PAGE_SIZE - 1).ok_or(SGX_ERROR_INVALID_PARAMETER)? & !(Self::PAGE_SIZE - 1);
+                (Self::PAGE_SIZE, size)
+            }
+        };
+        // zero-sized allocations aren't allowed, so empty buffers still take up the minimum
+        Layout::from_size_align(size.max(align), align).map_err(|_| SGX_ERROR_INVALID_PARAMETER)
+    }
+}
+
+//
+// SecretBuffer impls
+//
+
+// the buffer is uniquely owned, like a Box
+unsafe impl Send for SecretBuffer {}
+unsafe impl Sync for SecretBuffer {}
+
+impl SecretBuffer {
+    pub fn new(len: usize, allocation: SecretAllocation) -> Result<Self, SgxStatus> {
+        let layout = allocation.layout(len)?;
+        let data = NonNull::new(unsafe { alloc_zeroed(layout) }).ok_or(SGX_ERROR_OUT_OF_MEMORY)?;
+        Ok(Self { data, len, layout })
+    }
+}
+
+impl Deref for SecretBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for SecretBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for SecretBuffer {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for SecretBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Drop for SecretBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.data.as_ptr(), self.layout) };
+    }
+}
+
 //
 // MemoryStatus impls
 //
@@ -140,3 +236,26 @@ impl ToU64 for usize {
         self as u64
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_buffer_allocation() {
+        for &(allocation, align) in &[
+            (SecretAllocation::Unaligned, 1),
+            (SecretAllocation::CacheLine, SecretAllocation::CACHE_LINE_SIZE),
+            (SecretAllocation::Page, SecretAllocation::PAGE_SIZE),
+        ] {
+            for &len in &[0, 1, 4096, 4097] {
+                let mut buffer = SecretValue::new(SecretBuffer::new(len, allocation).unwrap());
+                assert_eq!(buffer.get().len(), len);
+                assert_eq!(buffer.get().as_ptr() as usize % align, 0);
+                assert!(buffer.get().iter().all(|byte| *byte == 0));
+                buffer.get_mut().iter_mut().for_each(|byte| *byte = 0xff);
+            }
+        }
+        assert_eq!(SecretBuffer::new(usize::max_value(), SecretAllocation::Page).err(), Some(SGX_ERROR_INVALID_PARAMETER));
+    }
+}
@@ -143,6 +143,7 @@ pub const SGXSD_AES_GCM_MAC_SIZE: u32 = 16;
 pub const SGXSD_AES_GCM_KEY_SIZE: u32 = 32;
 pub const SGXSD_CURVE25519_KEY_SIZE: u32 = 32;
 pub const SGXSD_SHA256_HASH_SIZE: u32 = 32;
+pub const SGXSD_SESSION_MAX_DECRYPT_FAILURES: u32 = 8;
 pub const BR_HASHDESC_ID_OFF: u32 = 0;
 pub const BR_HASHDESC_ID_MASK: u32 = 255;
 pub const BR_HASHDESC_OUT_OFF: u32 = 8;
@@ -2434,19 +2435,71 @@ fn bindgen_test_layout_sgxsd_msg_header() {
 pub type sgxsd_msg_header_t = sgxsd_msg_header;
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_reply_header {
+    pub iv: sgxsd_aes_gcm_iv_t,
+    pub mac: sgxsd_aes_gcm_mac_t,
+    pub batch_pubkey: sgxsd_curve25519_public_key_t,
+}
+#[test]
+fn bindgen_test_layout_sgxsd_reply_header() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_reply_header>(),
+        60usize,
+        concat!("Size of: ", stringify!(sgxsd_reply_header))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_reply_header>(),
+        1usize,
+        concat!("Alignment of ", stringify!(sgxsd_reply_header))
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_header>())).iv as *const _ as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_header),
+            "::",
+            stringify!(iv)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_header>())).mac as *const _ as usize },
+        12usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_header),
+            "::",
+            stringify!(mac)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_header>())).batch_pubkey as *const _ as usize },
+        28usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_header),
+            "::",
+            stringify!(batch_pubkey)
+        )
+    );
+}
+pub type sgxsd_reply_header_t = sgxsd_reply_header;
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct sgxsd_node_init_args {
     pub pending_requests_table_order: u8,
+    pub max_retry_replies: u32,
 }
 #[test]
 fn bindgen_test_layout_sgxsd_node_init_args() {
     assert_eq!(
         ::core::mem::size_of::<sgxsd_node_init_args>(),
-        1usize,
+        8usize,
         concat!("Size of: ", stringify!(sgxsd_node_init_args))
     );
     assert_eq!(
         ::core::mem::align_of::<sgxsd_node_init_args>(),
-        1usize,
+        4usize,
         concat!("Alignment of ", stringify!(sgxsd_node_init_args))
     );
     assert_eq!(
@@ -2462,6 +2515,18 @@ fn bindgen_test_layout_sgxsd_node_init_args() {
             stringify!(pending_requests_table_order)
         )
     );
+    assert_eq!(
+        unsafe {
+            &(*(::core::ptr::null::<sgxsd_node_init_args>())).max_retry_replies as *const _ as usize
+        },
+        4usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_node_init_args),
+            "::",
+            stringify!(max_retry_replies)
+        )
+    );
 }
 pub type sgxsd_node_init_args_t = sgxsd_node_init_args;
 pub type sgxsd_server_state_handle_t = u64;
@@ -2639,6 +2704,9 @@ extern "C" {
     ) -> sgxsd_status_t;
 }
 pub const SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND: sgxsd_status_code = 65537;
+pub const SGXSD_ERROR_SESSION_NOT_FOUND: sgxsd_status_code = 65538;
+pub const SGXSD_ERROR_SESSION_REPLAYED: sgxsd_status_code = 65539;
+pub const SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED: sgxsd_status_code = 65540;
 pub type sgxsd_status_code = u32;
 pub use self::sgxsd_status_code as sgxsd_status_code_t;
 #[repr(C)]
@@ -2692,12 +2760,13 @@ pub struct sgxsd_msg_from {
     pub valid: bool,
     pub tag: sgxsd_msg_tag_t,
     pub server_key: sgxsd_aes_gcm_key_t,
+    pub client_pubkey: sgxsd_curve25519_public_key_t,
 }
 #[test]
 fn bindgen_test_layout_sgxsd_msg_from() {
     assert_eq!(
         ::core::mem::size_of::<sgxsd_msg_from>(),
-        48usize,
+        80usize,
         concat!("Size of: ", stringify!(sgxsd_msg_from))
     );
     assert_eq!(
@@ -2735,6 +2804,16 @@ fn bindgen_test_layout_sgxsd_msg_from() {
             stringify!(server_key)
         )
     );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_msg_from>())).client_pubkey as *const _ as usize },
+        48usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_msg_from),
+            "::",
+            stringify!(client_pubkey)
+        )
+    );
 }
 impl Default for sgxsd_msg_from {
     fn default() -> Self {
@@ -2743,6 +2822,46 @@ impl Default for sgxsd_msg_from {
 }
 pub type sgxsd_msg_from_t = sgxsd_msg_from;
 #[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct sgxsd_reply_batch {
+    pub privkey: [u8; 32usize],
+    pub pubkey: sgxsd_curve25519_public_key_t,
+}
+#[test]
+fn bindgen_test_layout_sgxsd_reply_batch() {
+    assert_eq!(
+        ::core::mem::size_of::<sgxsd_reply_batch>(),
+        64usize,
+        concat!("Size of: ", stringify!(sgxsd_reply_batch))
+    );
+    assert_eq!(
+        ::core::mem::align_of::<sgxsd_reply_batch>(),
+        1usize,
+        concat!("Alignment of ", stringify!(sgxsd_reply_batch))
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_batch>())).privkey as *const _ as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_batch),
+            "::",
+            stringify!(privkey)
+        )
+    );
+    assert_eq!(
+        unsafe { &(*(::core::ptr::null::<sgxsd_reply_batch>())).pubkey as *const _ as usize },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(sgxsd_reply_batch),
+            "::",
+            stringify!(pubkey)
+        )
+    );
+}
+pub type sgxsd_reply_batch_t = sgxsd_reply_batch;
+#[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct sgxsd_server_state {
     _unused: [u8; 0],
@@ -2766,12 +2885,44 @@ pub struct sgxsd_server_terminate_args {
     _unused: [u8; 0],
 }
 pub type sgxsd_server_terminate_args_t = sgxsd_server_terminate_args;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct sgxsd_server_metrics {
+    _unused: [u8; 0],
+}
+pub type sgxsd_server_metrics_t = sgxsd_server_metrics;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct sgxsd_directory_commit_args {
+    _unused: [u8; 0],
+}
+pub type sgxsd_directory_commit_args_t = sgxsd_directory_commit_args;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct sgxsd_directory_sample_args {
+    _unused: [u8; 0],
+}
+pub type sgxsd_directory_sample_args_t = sgxsd_directory_sample_args;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct sgxsd_directory_sample {
+    _unused: [u8; 0],
+}
+pub type sgxsd_directory_sample_t = sgxsd_directory_sample;
 extern "C" {
     pub fn sgxsd_enclave_server_init(
         p_args: *const sgxsd_server_init_args_t,
         pp_state: *mut *mut sgxsd_server_state_t,
     ) -> sgx_status_t;
 }
+extern "C" {
+    pub fn sgxsd_enclave_server_config(
+        p_args: *const sgxsd_server_init_args_t,
+        p_config: *mut u8,
+        config_size: usize,
+        p_config_len: *mut usize,
+    ) -> sgx_status_t;
+}
 extern "C" {
     pub fn sgxsd_enclave_server_handle_call(
         p_args: *const sgxsd_server_handle_call_args_t,
@@ -2783,9 +2934,46 @@ extern "C" {
 extern "C" {
     pub fn sgxsd_enclave_server_terminate(
         p_args: *const sgxsd_server_terminate_args_t,
-        p_state: *mut sgxsd_server_state_t,
+        p_continuation_token: *mut u64,
+        pp_state: *mut *mut sgxsd_server_state_t,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn sgxsd_enclave_server_metrics(
+        now_ticks: u64,
+        p_metrics: *mut sgxsd_server_metrics_t,
+        p_state: *const sgxsd_server_state_t,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn sgxsd_enclave_server_cancel(msg_tag: sgxsd_msg_tag_t, p_state: *mut sgxsd_server_state_t) -> sgx_status_t;
+}
+extern "C" {
+    pub fn sgxsd_enclave_directory_commit(p_args: *const sgxsd_directory_commit_args_t) -> sgx_status_t;
+}
+extern "C" {
+    pub fn sgxsd_enclave_directory_sample(
+        p_args: *const sgxsd_directory_sample_args_t,
+        p_samples: *mut sgxsd_directory_sample_t,
+        sample_count: usize,
     ) -> sgx_status_t;
 }
+extern "C" {
+    pub fn sgxsd_enclave_session_denylist(
+        p_session_ids: *const sgxsd_curve25519_public_key_t,
+        session_id_count: usize,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn sgxsd_enclave_incident_record(
+        p_record: *mut u8,
+        record_size: usize,
+        p_record_len: *mut usize,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn sgxsd_enclave_benchmark(profile: u32, iterations: u32) -> sgx_status_t;
+}
 pub type phone_t = u64;
 extern "C" {
     pub fn sgxsd_enclave_create_ratelimit_fingerprint(
@@ -2797,10 +2985,22 @@ extern "C" {
         fingerprint_size: usize,
     ) -> sgx_status_t;
 }
+extern "C" {
+    pub fn sgxsd_enclave_new_reply_batch(p_batch: *mut sgxsd_reply_batch_t) -> sgx_status_t;
+}
 extern "C" {
     pub fn sgxsd_enclave_server_reply(
         reply_buf: sgxsd_msg_buf_t,
         p_from: *mut sgxsd_msg_from_t,
+        p_batch: *const sgxsd_reply_batch_t,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn sgxsd_enclave_server_seal_reply(
+        reply_buf: sgxsd_msg_buf_t,
+        p_from: *mut sgxsd_msg_from_t,
+        p_batch: *const sgxsd_reply_batch_t,
+        p_reply_header: *mut sgxsd_reply_header_t,
     ) -> sgx_status_t;
 }
 extern "C" {
@@ -2863,6 +3063,9 @@ pub type sgxsd_curve25519_private_key_t = sgxsd_rand_buf;
 extern "C" {
     pub fn sgxsd_enclave_read_rand(p_privkey: *mut sgxsd_rand_buf_t) -> sgx_status_t;
 }
+extern "C" {
+    pub fn sgxsd_enclave_get_sealing_key(p_key: *mut sgxsd_aes_gcm_key_t) -> sgx_status_t;
+}
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct sgxsd_curve25519_key_pair {
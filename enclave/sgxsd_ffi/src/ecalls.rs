@@ -8,29 +8,57 @@
 #![allow(clippy::all, clippy::option_unwrap_used, clippy::cast_sign_loss)]
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
 use core::ptr;
 use core::slice;
 
 use num_traits::ToPrimitive;
 
-use super::bindgen_wrapper::{sgxsd_enclave_server_noreply, sgxsd_enclave_server_reply};
-pub use super::bindgen_wrapper::{sgxsd_msg_buf_t, sgxsd_msg_from_t};
+use super::bindgen_wrapper::{
+    sgxsd_enclave_new_reply_batch, sgxsd_enclave_server_noreply, sgxsd_enclave_server_reply, sgxsd_enclave_server_seal_reply,
+};
+pub use super::bindgen_wrapper::{sgxsd_msg_buf_t, sgxsd_msg_from_t, sgxsd_msg_tag_t, sgxsd_reply_batch_t, sgxsd_reply_header_t};
 use sgx_ffi::sgx::*;
-use sgx_ffi::util::clear;
+use sgx_ffi::util::{clear, SecretValue};
+
+use crate::{AesGcmKey, SHA256HMACContext};
 
 pub trait SgxsdServer: Send + Sized {
     type InitArgs;
     type HandleCallArgs;
     type TerminateArgs;
+    type Metrics;
 
     fn init(_args: Option<&Self::InitArgs>) -> Result<Self, SgxStatus>;
+    /// Encodes the settings of `args` which change what the server answers, which quotes attest to while it runs.
+    fn config(args: Option<&Self::InitArgs>) -> Result<Vec<u8>, SgxStatus>;
     fn handle_call(
         &mut self,
         args: Option<&Self::HandleCallArgs>,
         request_data: &[u8],
         from: SgxsdMsgFrom,
     ) -> Result<(), (SgxStatus, SgxsdMsgFrom)>;
-    fn terminate(self, _args: Option<&Self::TerminateArgs>) -> Result<(), SgxStatus>;
+    fn terminate(self, _args: Option<&Self::TerminateArgs>) -> Result<SgxsdTerminate<Self>, SgxStatus>;
+    fn metrics(&self, now_ticks: u64) -> Result<Self::Metrics, SgxStatus>;
+    /// Drops the call made with the message tag `tag`, replying to it with no reply, before the server is terminated.
+    fn cancel(&mut self, tag: u64) -> Result<(), SgxStatus>;
+}
+
+/// How far a call to [`SgxsdServer::terminate`] got.
+pub enum SgxsdTerminate<S> {
+    /// The server has finished terminating and been consumed.
+    Done,
+    /// The server has more left to do, which a later stop call carries on with by passing the host this token back.
+    Suspended(S, u64),
+}
+impl<S> fmt::Debug for SgxsdTerminate<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Done => f.write_str("Done"),
+            Self::Suspended(_, continuation_token) => f.debug_tuple("Suspended").field(continuation_token).finish(),
+        }
+    }
 }
 
 // wrap sgxsd_msg_from_t to make sure sgxsd_ocall_reply is called exactly once on it
@@ -39,9 +67,10 @@ pub struct SgxsdMsgFrom(Option<Box<sgxsd_msg_from_t>>);
 impl SgxsdMsgFrom {
     fn new(from: &mut sgxsd_msg_from_t) -> Self {
         let mut boxed_from = Box::new(sgxsd_msg_from_t {
-            valid:      from.valid,
-            tag:        from.tag,
-            server_key: Default::default(),
+            valid:         from.valid,
+            tag:           from.tag,
+            server_key:    Default::default(),
+            client_pubkey: from.client_pubkey,
         });
         boxed_from.server_key.data.copy_from_slice(&from.server_key.data);
         let res = Self(Some(boxed_from));
@@ -52,14 +81,43 @@ impl SgxsdMsgFrom {
 
     #[cfg(any(test, feature = "test"))]
     pub fn mock() -> Self {
+        Self::mock_for_session(Default::default())
+    }
+
+    #[cfg(any(test, feature = "test"))]
+    pub fn mock_for_tag(tag: u64) -> Self {
+        let mut msg_from = sgxsd_msg_from_t {
+            valid: true,
+            ..Default::default()
+        };
+        msg_from.tag.__bindgen_anon_1.tag = tag;
+        Self::new(&mut msg_from)
+    }
+
+    #[cfg(any(test, feature = "test"))]
+    pub fn mock_for_session_tag(client_pubkey: [u8; 32], tag: u64) -> Self {
+        let mut msg_from = sgxsd_msg_from_t {
+            valid: true,
+            client_pubkey: super::bindgen_wrapper::sgxsd_curve25519_public_key_t { x: client_pubkey },
+            ..Default::default()
+        };
+        msg_from.tag.__bindgen_anon_1.tag = tag;
+        Self::new(&mut msg_from)
+    }
+
+    #[cfg(any(test, feature = "test"))]
+    pub fn mock_for_session(client_pubkey: [u8; 32]) -> Self {
         Self::new(&mut sgxsd_msg_from_t {
-            tag:        Default::default(),
-            valid:      true,
-            server_key: Default::default(),
+            tag:           Default::default(),
+            valid:         true,
+            server_key:    Default::default(),
+            client_pubkey: super::bindgen_wrapper::sgxsd_curve25519_public_key_t { x: client_pubkey },
         })
     }
 
-    pub fn reply(mut self, msg: &mut [u8]) -> Result<(), SgxStatus> {
+    /// Encrypts `msg` in place and sends it to the client, under a key mixed with `batch` if there is one, or under the
+    /// request keys alone for a client that didn't negotiate batch reply keys.
+    pub fn reply(mut self, msg: &mut [u8], batch: Option<&ReplyBatch>) -> Result<(), SgxStatus> {
         if let Some(size) = msg.len().to_u32() {
             let msg_buf = sgxsd_msg_buf_t {
                 data: msg.as_mut_ptr(),
@@ -67,7 +125,7 @@ impl SgxsdMsgFrom {
             };
             if let Some(mut msg_from) = self.0.take() {
                 let msg_from_ref = &mut *msg_from;
-                match unsafe { sgxsd_enclave_server_reply(msg_buf, msg_from_ref) } {
+                match unsafe { sgxsd_enclave_server_reply(msg_buf, msg_from_ref, ReplyBatch::as_ptr(batch)) } {
                     0 => Ok(()),
                     err => Err(err),
                 }
@@ -79,6 +137,46 @@ impl SgxsdMsgFrom {
         }
     }
 
+    /// Encrypts `msg` in place as [`reply`](Self::reply) would, but returns its header for the caller to hand to the
+    /// untrusted code along with it, instead of sending it.
+    pub fn seal_reply(mut self, msg: &mut [u8], batch: Option<&ReplyBatch>) -> Result<SealedReply, SgxStatus> {
+        let size = msg.len().to_u32().ok_or(SGX_ERROR_UNEXPECTED)?;
+        let msg_buf = sgxsd_msg_buf_t {
+            data: msg.as_mut_ptr(),
+            size,
+        };
+        let mut msg_from = self.0.take().ok_or(SGX_ERROR_INVALID_STATE)?;
+        let tag = unsafe { msg_from.tag.__bindgen_anon_1.tag };
+        let mut header = Default::default();
+        match unsafe { sgxsd_enclave_server_seal_reply(msg_buf, &mut *msg_from, ReplyBatch::as_ptr(batch), &mut header) } {
+            0 => Ok(SealedReply { header, tag }),
+            err => Err(err),
+        }
+    }
+
+    /// The tag the untrusted code handed in with this message, which it replies to the client under.
+    pub fn tag(&self) -> Option<u64> {
+        self.0.as_ref().map(|from| unsafe { from.tag.__bindgen_anon_1.tag })
+    }
+
+    /// The public key the client negotiated the session of this message with.
+    pub fn client_pubkey(&self) -> Option<&[u8; 32]> {
+        self.0.as_ref().map(|from| &from.client_pubkey.x)
+    }
+
+    /// Derives a key private to the enclave from the session key of this message (HKDF-SHA256 expand with
+    /// `label` as info), e.g. to keep state belonging to its sender encrypted until it is replied to.
+    pub fn derive_key(&self, label: &[u8]) -> Result<AesGcmKey, SgxStatus> {
+        let from = self.0.as_ref().ok_or(SGX_ERROR_INVALID_STATE)?;
+        let mut hmac = SHA256HMACContext::new(from.server_key.data);
+        hmac.update(label);
+        hmac.update(&[1]);
+        let mut key_data = SecretValue::new([0; SHA256HMACContext::hash_len()]);
+        hmac.result(key_data.get_mut());
+        hmac.clear();
+        AesGcmKey::new(key_data.get())
+    }
+
     fn forget(mut self) {
         if let Some(mut from) = self.0.take() {
             from.valid = false;
@@ -95,6 +193,39 @@ impl Drop for SgxsdMsgFrom {
     }
 }
 
+/// A reply encrypted by [`SgxsdMsgFrom::seal_reply`], which the untrusted code sends as it would one from
+/// `sgxsd_ocall_reply`.
+pub struct SealedReply {
+    pub header: sgxsd_reply_header_t,
+    pub tag:    u64,
+}
+
+// ephemeral keypair mixed into the keys of the replies sent together, so they can't be decrypted with the request keys
+// alone once it is erased on drop
+pub struct ReplyBatch(sgxsd_reply_batch_t);
+impl ReplyBatch {
+    pub fn new() -> Result<Self, SgxStatus> {
+        let mut batch = Self(Default::default());
+        match unsafe { sgxsd_enclave_new_reply_batch(&mut batch.0) } {
+            0 => Ok(batch),
+            err => Err(err),
+        }
+    }
+
+    pub const fn pubkey(&self) -> &[u8; 32] {
+        &self.0.pubkey.x
+    }
+
+    fn as_ptr(batch: Option<&Self>) -> *const sgxsd_reply_batch_t {
+        batch.map_or(ptr::null(), |batch| &batch.0)
+    }
+}
+impl Drop for ReplyBatch {
+    fn drop(&mut self) {
+        clear(&mut self.0.privkey[..]);
+    }
+}
+
 pub fn sgxsd_enclave_server_init<S>(p_args: *const S::InitArgs, pp_state: *mut *mut S) -> SgxStatus
 where S: SgxsdServer {
     let args = unsafe { p_args.as_ref() };
@@ -107,6 +238,29 @@ where S: SgxsdServer {
     }
 }
 
+pub fn sgxsd_enclave_server_config<S>(
+    p_args: *const S::InitArgs,
+    p_config: *mut u8,
+    config_size: usize,
+    p_config_len: *mut usize,
+) -> SgxStatus
+where
+    S: SgxsdServer,
+{
+    let args = unsafe { p_args.as_ref() };
+    let config = match S::config(args) {
+        Ok(config) => config,
+        Err(err) => return err,
+    };
+    let (p_config, config_len_out) = match (ptr::NonNull::new(p_config), unsafe { p_config_len.as_mut() }) {
+        (Some(p_config), Some(config_len_out)) if config.len() <= config_size => (p_config, config_len_out),
+        _ => return SGX_ERROR_INVALID_PARAMETER,
+    };
+    unsafe { slice::from_raw_parts_mut(p_config.as_ptr(), config.len()) }.copy_from_slice(&config);
+    *config_len_out = config.len();
+    0
+}
+
 pub fn sgxsd_enclave_server_handle_call<S>(
     p_args: *const S::HandleCallArgs,
     msg_buf: sgxsd_msg_buf_t,
@@ -132,16 +286,57 @@ where
     }
 }
 
-pub fn sgxsd_enclave_server_terminate<S>(p_args: *const S::TerminateArgs, p_state: *mut S) -> SgxStatus
-where S: SgxsdServer {
+pub fn sgxsd_enclave_server_terminate<S>(
+    p_args: *const S::TerminateArgs,
+    p_continuation_token: *mut u64,
+    pp_state: *mut *mut S,
+) -> SgxStatus
+where
+    S: SgxsdServer,
+{
     let args = unsafe { p_args.as_ref() };
-    let state = unsafe { Box::from_raw(p_state) };
+    let state = unsafe { Box::from_raw(*pp_state) };
+    unsafe { *pp_state = ptr::null_mut() };
     match state.terminate(args) {
+        Ok(SgxsdTerminate::Done) => 0,
+        Ok(SgxsdTerminate::Suspended(state, continuation_token)) => {
+            unsafe {
+                *p_continuation_token = continuation_token;
+                *pp_state = Box::into_raw(Box::new(state));
+            }
+            0
+        }
+        Err(err) => err,
+    }
+}
+
+pub fn sgxsd_enclave_server_cancel<S>(msg_tag: sgxsd_msg_tag_t, p_state: *mut S) -> SgxStatus
+where S: SgxsdServer {
+    let state = match unsafe { p_state.as_mut() } {
+        Some(state) => state,
+        None => return SGX_ERROR_INVALID_PARAMETER,
+    };
+    match state.cancel(unsafe { msg_tag.__bindgen_anon_1.tag }) {
         Ok(()) => 0,
         Err(err) => err,
     }
 }
 
+pub fn sgxsd_enclave_server_metrics<S>(now_ticks: u64, p_metrics: *mut S::Metrics, p_state: *const S) -> SgxStatus
+where S: SgxsdServer {
+    let (state, metrics_out) = match unsafe { (p_state.as_ref(), p_metrics.as_mut()) } {
+        (Some(state), Some(metrics_out)) => (state, metrics_out),
+        _ => return SGX_ERROR_INVALID_PARAMETER,
+    };
+    match state.metrics(now_ticks) {
+        Ok(metrics) => {
+            *metrics_out = metrics;
+            0
+        }
+        Err(err) => err,
+    }
+}
+
 pub struct ECallSlice(pub Option<ptr::NonNull<u8>>, pub usize);
 
 impl AsRef<[u8]> for ECallSlice {
@@ -164,7 +359,9 @@ mod tests {
     use super::*;
     use mockers::{matchers::*, *};
 
-    use super::super::bindgen_wrapper::{sgxsd_server_handle_call_args_t, sgxsd_server_init_args_t, sgxsd_server_terminate_args_t};
+    use super::super::bindgen_wrapper::{
+        sgxsd_server_handle_call_args_t, sgxsd_server_init_args_t, sgxsd_server_terminate_args_t, SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND,
+    };
 
     fn expect_msg_from_drop(scenario: &Scenario, msg_from: &sgxsd_msg_from_t) {
         let msg_from = *msg_from;
@@ -198,29 +395,117 @@ mod tests {
         let reply_from: sgxsd_msg_from_t = test_ffi::rand();
         let mut reply_from_2 = reply_from.clone();
 
+        let batch = ReplyBatch::new().unwrap();
+        let batch_pubkey = *batch.pubkey();
+
         let sgxsd_enclave_server_reply = test_ffi::mock_for(&mocks::SGXSD_ENCLAVE_SERVER_REPLY, &scenario);
         scenario.expect(sgxsd_enclave_server_reply
                         .sgxsd_enclave_server_reply(
                             check(move |msg_buf| *msg_buf == &reply_data[..]),
                             check(move |msg_from: &sgxsd_msg_from_t|
                                   unsafe { msg_from.tag.__bindgen_anon_1.tag == reply_from.tag.__bindgen_anon_1.tag } &&
-                                  msg_from.server_key.data == reply_from.server_key.data)
+                                  msg_from.server_key.data == reply_from.server_key.data &&
+                                  msg_from.client_pubkey == reply_from.client_pubkey),
+                            check(move |batch: &Option<sgxsd_reply_batch_t>| batch.map(|batch| batch.pubkey.x) == Some(batch_pubkey))
                         ).and_return(0));
 
-        SgxsdMsgFrom::new(&mut reply_from_2).reply(&mut reply_data_2[..]).unwrap();
+        SgxsdMsgFrom::new(&mut reply_from_2).reply(&mut reply_data_2[..], Some(&batch)).unwrap();
+        drop(scenario);
+
+        test_ffi::clear(&mocks::SGXSD_ENCLAVE_SERVER_REPLY);
+    }
+
+    #[test]
+    fn msg_from_reply_without_batch() {
+        let scenario = Scenario::new();
+
+        let mut reply_data: Box<[u8; 32]> = Box::new(test_ffi::rand());
+        let mut reply_from: sgxsd_msg_from_t = test_ffi::rand();
+
+        let sgxsd_enclave_server_reply = test_ffi::mock_for(&mocks::SGXSD_ENCLAVE_SERVER_REPLY, &scenario);
+        scenario.expect(sgxsd_enclave_server_reply
+                        .sgxsd_enclave_server_reply(any(), any(), check(|batch: &Option<sgxsd_reply_batch_t>| batch.is_none()))
+                        .and_return(0));
+
+        SgxsdMsgFrom::new(&mut reply_from).reply(&mut reply_data[..], None).unwrap();
         drop(scenario);
 
         test_ffi::clear(&mocks::SGXSD_ENCLAVE_SERVER_REPLY);
     }
 
-    struct MockSgxsdServer {}
+    #[test]
+    fn msg_from_seal_reply() {
+        let scenario = Scenario::new();
+
+        let reply_data: Box<[u8; 32]> = Box::new(test_ffi::rand());
+        let mut reply_data_2 = reply_data.clone();
+
+        let reply_from: sgxsd_msg_from_t = test_ffi::rand();
+        let mut reply_from_2 = reply_from.clone();
+
+        let batch = ReplyBatch::new().unwrap();
+        let batch_pubkey = *batch.pubkey();
+
+        let sgxsd_enclave_server_seal_reply = test_ffi::mock_for(&mocks::SGXSD_ENCLAVE_SERVER_SEAL_REPLY, &scenario);
+        scenario.expect(sgxsd_enclave_server_seal_reply
+                        .sgxsd_enclave_server_seal_reply(
+                            check(move |msg_buf| *msg_buf == &reply_data[..]),
+                            check(move |msg_from: &sgxsd_msg_from_t|
+                                  unsafe { msg_from.tag.__bindgen_anon_1.tag == reply_from.tag.__bindgen_anon_1.tag } &&
+                                  msg_from.server_key.data == reply_from.server_key.data),
+                            check(move |batch: &Option<sgxsd_reply_batch_t>| batch.map(|batch| batch.pubkey.x) == Some(batch_pubkey))
+                        ).and_return(0));
+
+        let sealed_reply = SgxsdMsgFrom::new(&mut reply_from_2).seal_reply(&mut reply_data_2[..], Some(&batch)).unwrap();
+        assert_eq!(sealed_reply.tag, unsafe { reply_from.tag.__bindgen_anon_1.tag });
+        assert_eq!(sealed_reply.header.batch_pubkey.x, batch_pubkey);
+        drop(scenario);
+
+        test_ffi::clear(&mocks::SGXSD_ENCLAVE_SERVER_SEAL_REPLY);
+    }
+
+    #[test]
+    fn msg_from_derive_key() {
+        let scenario = Scenario::new();
+
+        let mut from: sgxsd_msg_from_t = test_ffi::rand();
+        let server_key = from.server_key.data;
+        let label: [u8; 8] = test_ffi::rand();
+
+        let hmac = test_ffi::mock_for(&mocks::BEARSSL_SHA256HMAC, &scenario);
+        let mut hmac_seq = Sequence::new();
+        hmac_seq.expect(hmac.hmac_key_init(check(move |key_data| *key_data == &server_key[..])).and_return(()));
+        hmac_seq.expect(hmac.hmac_update(check(move |data| *data == &label[..])).and_return(()));
+        hmac_seq.expect(hmac.hmac_update(check(|data| *data == &[1][..])).and_return(()));
+        hmac_seq.expect(hmac.hmac_out().and_return(test_ffi::rand()));
+        scenario.expect(hmac_seq);
+
+        let msg_from = SgxsdMsgFrom::new(&mut from);
+        assert!(msg_from.derive_key(&label).is_ok());
+        msg_from.forget();
+        drop(scenario);
+
+        test_ffi::clear(&mocks::BEARSSL_SHA256HMAC);
+    }
+
+    struct MockSgxsdServer {
+        continuation_token: u64,
+    }
     impl SgxsdServer for MockSgxsdServer {
         type HandleCallArgs = sgxsd_server_handle_call_args_t;
         type InitArgs = sgxsd_server_init_args_t;
         type TerminateArgs = sgxsd_server_terminate_args_t;
+        type Metrics = u64;
 
         fn init(_args: Option<&Self::InitArgs>) -> Result<Self, SgxStatus> {
-            Ok(Self {})
+            Ok(Self { continuation_token: 0 })
+        }
+
+        fn config(args: Option<&Self::InitArgs>) -> Result<Vec<u8>, SgxStatus> {
+            match args {
+                Some(_) => Ok(vec![1, 2, 3]),
+                None => Err(SGX_ERROR_INVALID_PARAMETER),
+            }
         }
 
         fn handle_call(
@@ -233,13 +518,27 @@ mod tests {
             Ok(())
         }
 
-        fn terminate(self, _args: Option<&Self::TerminateArgs>) -> Result<(), SgxStatus> {
-            Ok(())
+        fn terminate(self, _args: Option<&Self::TerminateArgs>) -> Result<SgxsdTerminate<Self>, SgxStatus> {
+            match self.continuation_token {
+                0 => Ok(SgxsdTerminate::Done),
+                continuation_token => Ok(SgxsdTerminate::Suspended(self, continuation_token)),
+            }
+        }
+
+        fn metrics(&self, now_ticks: u64) -> Result<Self::Metrics, SgxStatus> {
+            Ok(now_ticks)
+        }
+
+        fn cancel(&mut self, tag: u64) -> Result<(), SgxStatus> {
+            match tag {
+                42 => Ok(()),
+                _ => Err(SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND),
+            }
         }
     }
 
     fn mock_sgxsd_server() -> Box<*mut MockSgxsdServer> {
-        let state = Box::new(MockSgxsdServer {});
+        let state = Box::new(MockSgxsdServer { continuation_token: 0 });
         Box::new(Box::into_raw(state))
     }
 
@@ -249,6 +548,33 @@ mod tests {
         sgxsd_enclave_server_init(std::ptr::null(), &mut *state);
     }
 
+    #[test]
+    fn sgxsd_enclave_server_config_null_args() {
+        let mut config = [0; 3];
+        let mut config_len = 0;
+        let res = sgxsd_enclave_server_config::<MockSgxsdServer>(std::ptr::null(), config.as_mut_ptr(), config.len(), &mut config_len);
+        assert_eq!(res, SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn sgxsd_enclave_server_config_too_small() {
+        let p_args = ptr::NonNull::<sgxsd_server_init_args_t>::dangling().as_ptr();
+        let mut config = [0; 2];
+        let mut config_len = 0;
+        let res = sgxsd_enclave_server_config::<MockSgxsdServer>(p_args, config.as_mut_ptr(), config.len(), &mut config_len);
+        assert_eq!(res, SGX_ERROR_INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn sgxsd_enclave_server_config_valid() {
+        let p_args = ptr::NonNull::<sgxsd_server_init_args_t>::dangling().as_ptr();
+        let mut config = [0; 4];
+        let mut config_len = 0;
+        let res = sgxsd_enclave_server_config::<MockSgxsdServer>(p_args, config.as_mut_ptr(), config.len(), &mut config_len);
+        assert_eq!(res, 0);
+        assert_eq!(&config[..config_len], &[1, 2, 3]);
+    }
+
     #[test]
     fn sgxsd_enclave_server_handle_call_null_args() {
         let scenario = Scenario::new();
@@ -313,7 +639,46 @@ mod tests {
 
     #[test]
     fn sgxsd_enclave_server_terminate_null_args() {
+        let mut pp_state = mock_sgxsd_server();
+        let mut continuation_token = 0;
+        assert_eq!(sgxsd_enclave_server_terminate(std::ptr::null(), &mut continuation_token, &mut *pp_state), 0);
+        assert!(pp_state.is_null());
+    }
+
+    #[test]
+    fn sgxsd_enclave_server_terminate_suspended() {
+        let mut pp_state = Box::new(Box::into_raw(Box::new(MockSgxsdServer { continuation_token: 42 })));
+        let mut continuation_token = 0;
+        assert_eq!(sgxsd_enclave_server_terminate(std::ptr::null(), &mut continuation_token, &mut *pp_state), 0);
+        assert_eq!(continuation_token, 42);
+        assert!(!pp_state.is_null());
+        unsafe { Box::from_raw(*pp_state) };
+    }
+
+    #[test]
+    fn sgxsd_enclave_server_metrics_null_metrics() {
+        let pp_state = mock_sgxsd_server();
+        assert_eq!(sgxsd_enclave_server_metrics(0, std::ptr::null_mut(), *pp_state), SGX_ERROR_INVALID_PARAMETER);
+        unsafe { Box::from_raw(*pp_state) };
+    }
+
+    #[test]
+    fn sgxsd_enclave_server_metrics_valid() {
         let pp_state = mock_sgxsd_server();
-        sgxsd_enclave_server_terminate(std::ptr::null(), *pp_state);
+        let mut metrics = 0;
+        assert_eq!(sgxsd_enclave_server_metrics(42, &mut metrics, *pp_state), 0);
+        assert_eq!(metrics, 42);
+        unsafe { Box::from_raw(*pp_state) };
+    }
+
+    #[test]
+    fn sgxsd_enclave_server_cancel_valid() {
+        let pp_state = mock_sgxsd_server();
+        let mut msg_tag: sgxsd_msg_tag_t = Default::default();
+        msg_tag.__bindgen_anon_1.tag = 42;
+        assert_eq!(sgxsd_enclave_server_cancel(msg_tag, *pp_state), 0);
+        msg_tag.__bindgen_anon_1.tag = 43;
+        assert_eq!(sgxsd_enclave_server_cancel(msg_tag, *pp_state), SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND);
+        unsafe { Box::from_raw(*pp_state) };
     }
 }
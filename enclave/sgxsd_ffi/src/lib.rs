@@ -62,6 +62,7 @@ extern crate alloc;
 )]
 mod bindgen_wrapper;
 pub mod ecalls;
+pub mod sealed;
 
 #[cfg(any(test, feature = "test"))]
 pub mod mocks;
@@ -14,12 +14,13 @@ use rand::distributions::*;
 use rand::*;
 use test_ffi::*;
 
-pub use super::bindgen_wrapper::{sgxsd_aes_gcm_key_t, sgxsd_msg_buf_t, sgxsd_msg_from_t};
+pub use super::bindgen_wrapper::{sgxsd_aes_gcm_key_t, sgxsd_msg_buf_t, sgxsd_msg_from_t, sgxsd_reply_batch_t};
 
 use super::bindgen_wrapper::{
     br_hash_class, br_hmac_key_context, br_hmac_context,
     br_sha1_SIZE, br_sha1_context, br_sha224_context, br_sha256_SIZE, br_sha256_context, sgx_status_t, sgxsd_aes_gcm_iv_t,
-    sgxsd_aes_gcm_mac_t, sgxsd_msg_tag__bindgen_ty_1, sgxsd_msg_tag_t, sgxsd_rand_buf_t,
+    sgxsd_aes_gcm_mac_t, sgxsd_curve25519_public_key_t, sgxsd_msg_tag__bindgen_ty_1, sgxsd_msg_tag_t, sgxsd_rand_buf_t,
+    sgxsd_reply_header_t,
 };
 use crate::SHA256HMACContext;
 
@@ -30,6 +31,7 @@ use crate::SHA256HMACContext;
 thread_local! {
     pub static SGXSD_ENCLAVE_SERVER_NOREPLY: RefCell<Option<SgxsdEnclaveServerNoreplyMock>> = RefCell::new(None);
     pub static SGXSD_ENCLAVE_SERVER_REPLY:   RefCell<Option<SgxsdEnclaveServerReplyMock>>   = RefCell::new(None);
+    pub static SGXSD_ENCLAVE_SERVER_SEAL_REPLY: RefCell<Option<SgxsdEnclaveServerSealReplyMock>> = RefCell::new(None);
     pub static SGXSD_AES_GCM_ENCRYPT:        RefCell<Option<SgxsdAesGcmEncryptMock>>        = RefCell::new(None);
     pub static SGXSD_AES_GCM_DECRYPT:        RefCell<Option<SgxsdAesGcmDecryptMock>>        = RefCell::new(None);
     pub static SGXSD_ENCLAVE_READ_RAND:      RefCell<Option<SgxsdEnclaveReadRandMock>>      = RefCell::new(None);
@@ -51,7 +53,12 @@ pub trait SgxsdEnclaveServerNoreply {
 
 #[mocked]
 pub trait SgxsdEnclaveServerReply {
-    fn sgxsd_enclave_server_reply(&self, reply_buf: &[u8], from: sgxsd_msg_from_t) -> sgx_status_t;
+    fn sgxsd_enclave_server_reply(&self, reply_buf: &[u8], from: sgxsd_msg_from_t, batch: Option<sgxsd_reply_batch_t>) -> sgx_status_t;
+}
+
+#[mocked]
+pub trait SgxsdEnclaveServerSealReply {
+    fn sgxsd_enclave_server_seal_reply(&self, reply_buf: &[u8], from: sgxsd_msg_from_t, batch: Option<sgxsd_reply_batch_t>) -> sgx_status_t;
 }
 
 #[mocked]
@@ -104,12 +111,18 @@ impl Distribution<sgxsd_aes_gcm_key_t> for Standard {
         sgxsd_aes_gcm_key_t { data: rng.sample(self) }
     }
 }
+impl Distribution<sgxsd_curve25519_public_key_t> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> sgxsd_curve25519_public_key_t {
+        sgxsd_curve25519_public_key_t { x: rng.sample(self) }
+    }
+}
 impl Distribution<sgxsd_msg_from_t> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> sgxsd_msg_from_t {
         sgxsd_msg_from_t {
-            tag:        rng.sample(self),
-            valid:      true,
-            server_key: rng.sample(self),
+            tag:           rng.sample(self),
+            valid:         true,
+            server_key:    rng.sample(self),
+            client_pubkey: rng.sample(self),
         }
     }
 }
@@ -148,7 +161,12 @@ pub mod impls {
     }
 
     #[no_mangle]
-    pub extern "C" fn sgxsd_enclave_server_reply(reply_buf: sgxsd_msg_buf_t, from: *mut sgxsd_msg_from_t) -> sgx_status_t {
+    pub extern "C" fn sgxsd_enclave_server_reply(
+        reply_buf: sgxsd_msg_buf_t,
+        from: *mut sgxsd_msg_from_t,
+        batch: *const sgxsd_reply_batch_t,
+    ) -> sgx_status_t
+    {
         assert!(!reply_buf.data.is_null());
         assert_ne!(reply_buf.size, 0);
         let reply_buf = unsafe { std::slice::from_raw_parts_mut(reply_buf.data, reply_buf.size as usize) };
@@ -156,10 +174,41 @@ pub mod impls {
             mock.borrow()
                 .as_ref()
                 .expect("no mock for sgxsd_enclave_server_reply")
-                .sgxsd_enclave_server_reply(reply_buf, unsafe { *from })
+                .sgxsd_enclave_server_reply(reply_buf, unsafe { *from }, unsafe { batch.as_ref() }.copied())
+        })
+    }
+
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_server_seal_reply(
+        reply_buf: sgxsd_msg_buf_t,
+        from: *mut sgxsd_msg_from_t,
+        batch: *const sgxsd_reply_batch_t,
+        p_reply_header: *mut sgxsd_reply_header_t,
+    ) -> sgx_status_t
+    {
+        assert!(!reply_buf.data.is_null());
+        assert_ne!(reply_buf.size, 0);
+        let reply_buf = unsafe { std::slice::from_raw_parts_mut(reply_buf.data, reply_buf.size as usize) };
+        let reply_header = unsafe { p_reply_header.as_mut().expect("p_reply_header is null") };
+        read_rand(&mut reply_header.iv.data);
+        read_rand(&mut reply_header.mac.data);
+        reply_header.batch_pubkey = unsafe { batch.as_ref() }.map(|batch| batch.pubkey).unwrap_or_default();
+        SGXSD_ENCLAVE_SERVER_SEAL_REPLY.with(|mock| {
+            mock.borrow()
+                .as_ref()
+                .expect("no mock for sgxsd_enclave_server_seal_reply")
+                .sgxsd_enclave_server_seal_reply(reply_buf, unsafe { *from }, unsafe { batch.as_ref() }.copied())
         })
     }
 
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_new_reply_batch(p_batch: *mut sgxsd_reply_batch_t) -> sgx_status_t {
+        let batch = unsafe { p_batch.as_mut().expect("p_batch is null") };
+        read_rand(&mut batch.privkey);
+        read_rand(&mut batch.pubkey.x);
+        0
+    }
+
     #[no_mangle]
     pub extern "C" fn sgxsd_aes_gcm_encrypt(
         p_key: *const sgxsd_aes_gcm_key_t,
@@ -298,6 +347,13 @@ pub mod impls {
         })
     }
 
+    #[no_mangle]
+    pub extern "C" fn sgxsd_enclave_get_sealing_key(p_key: *mut sgxsd_aes_gcm_key_t) -> sgx_status_t {
+        let key = unsafe { p_key.as_mut().expect("p_key is null") };
+        read_rand(&mut key.data);
+        0
+    }
+
     #[no_mangle]
     pub extern "C" fn br_sha256_init(ctx: *mut br_sha256_context) {
         unsafe { std::ptr::write_volatile(ctx, std::mem::zeroed()) };
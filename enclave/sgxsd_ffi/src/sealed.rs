@@ -0,0 +1,295 @@
+//
+// Copyright (C) 2019, 2020 Signal Messenger, LLC.
+// All rights reserved.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+//
+
+//! Self-describing envelope for data sealed or exported by the enclave.
+//!
+//! Layout (integers little-endian):
+//!
+//! ```text
+//! magic[4] version:u8 kind:u8 cipher_suite:u8 kdf:u8 kdf_label_len:u8 kdf_label[kdf_label_len]
+//! iv[12] payload_len:u32 payload[payload_len] mac[16]
+//! ```
+//!
+//! Everything before the payload is authenticated as AAD. Parsing rejects unknown versions and algorithm
+//! identifiers as well as truncated or trailing data, so that a change of algorithm is always an explicit
+//! new identifier rather than a reinterpretation of existing blobs. The kind of a blob is named by the code
+//! that seals it, and a blob is only opened as the kind it was sealed as.
+
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use num_traits::ToPrimitive;
+use sgx_ffi::util::SecretValue;
+
+use crate::bindgen_wrapper::{
+    sgx_status_t as SgxStatus, sgxsd_aes_gcm_key, sgxsd_enclave_get_sealing_key, SGX_ERROR_INVALID_PARAMETER, SGX_SUCCESS,
+};
+use crate::{AesGcmIv, AesGcmKey, AesGcmMac, RdRand, SHA256HMACContext};
+
+//
+// public API
+//
+
+pub const SEALED_BLOB_MAGIC: [u8; 4] = *b"CDSB";
+pub const SEALED_BLOB_VERSION: u8 = 1;
+pub const MAX_KDF_LABEL_SIZE: usize = 64;
+
+/// AES-256-GCM, with a random 96-bit IV.
+pub const CIPHER_SUITE_AES_256_GCM: u8 = 1;
+
+/// The first block of HKDF-Expand (RFC 5869) with SHA-256, taking the sealing key as the pseudorandom key and the
+/// envelope's label as info. There is no HKDF-Extract step, as the sealing key is already uniformly random.
+pub const KDF_HKDF_EXPAND_SHA256: u8 = 1;
+
+pub struct SealedBlob<'a> {
+    pub kind:      u8,
+    pub kdf_label: &'a [u8],
+    iv:            AesGcmIv,
+    aad:           &'a [u8],
+    payload:       &'a [u8],
+    mac:           AesGcmMac,
+}
+
+//
+// internal
+//
+
+const IV_SIZE: usize = 12;
+const MAC_SIZE: usize = 16;
+
+struct Reader<'a>(&'a [u8]);
+
+//
+// SealedBlob impls
+//
+
+impl<'a> SealedBlob<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, SgxStatus> {
+        let mut reader = Reader(data);
+        if reader.read(SEALED_BLOB_MAGIC.len())? != SEALED_BLOB_MAGIC || reader.read_u8()? != SEALED_BLOB_VERSION {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        let kind = reader.read_u8()?;
+        if reader.read_u8()? != CIPHER_SUITE_AES_256_GCM || reader.read_u8()? != KDF_HKDF_EXPAND_SHA256 {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        let kdf_label_len = reader.read_u8()?.into();
+        if kdf_label_len == 0 || kdf_label_len > MAX_KDF_LABEL_SIZE {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        let kdf_label = reader.read(kdf_label_len)?;
+        let iv = AesGcmIv {
+            data: reader.read(IV_SIZE)?.try_into().map_err(|_| SGX_ERROR_INVALID_PARAMETER)?,
+        };
+        let payload_len = reader.read_u32()?.to_usize().ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+        let aad = data.get(..data.len() - reader.0.len()).ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+        let payload = reader.read(payload_len)?;
+        let mac = AesGcmMac {
+            data: reader.read(MAC_SIZE)?.try_into().map_err(|_| SGX_ERROR_INVALID_PARAMETER)?,
+        };
+        if !reader.0.is_empty() {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+
+        Ok(Self {
+            kind,
+            kdf_label,
+            iv,
+            aad,
+            payload,
+            mac,
+        })
+    }
+
+    pub fn seal(sealing_key: &[u8; 32], kind: u8, kdf_label: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, SgxStatus> {
+        let kdf_label_len = match kdf_label.len() {
+            kdf_label_len @ 1..=MAX_KDF_LABEL_SIZE => kdf_label_len.to_u8().ok_or(SGX_ERROR_INVALID_PARAMETER)?,
+            _ => return Err(SGX_ERROR_INVALID_PARAMETER),
+        };
+        let payload_len = plaintext.len().to_u32().ok_or(SGX_ERROR_INVALID_PARAMETER)?;
+        let iv = AesGcmIv {
+            data: RdRand.rand_bytes([0; IV_SIZE]),
+        };
+
+        let mut blob = Vec::with_capacity(Self::header_len(kdf_label.len()) + plaintext.len() + MAC_SIZE);
+        blob.extend_from_slice(&SEALED_BLOB_MAGIC);
+        blob.extend_from_slice(&[
+            SEALED_BLOB_VERSION,
+            kind,
+            CIPHER_SUITE_AES_256_GCM,
+            KDF_HKDF_EXPAND_SHA256,
+            kdf_label_len,
+        ]);
+        blob.extend_from_slice(kdf_label);
+        blob.extend_from_slice(&iv.data);
+        blob.extend_from_slice(&payload_len.to_le_bytes());
+        let aad_len = blob.len();
+        blob.extend_from_slice(plaintext);
+
+        let key = Self::expand_key(sealing_key, kdf_label)?;
+        let mut mac = AesGcmMac::default();
+        let (aad, payload) = blob.split_at_mut(aad_len);
+        key.encrypt(payload, aad, &iv, &mut mac)?;
+        blob.extend_from_slice(&mac.data);
+        Ok(blob)
+    }
+
+    pub fn open(&self, sealing_key: &[u8; 32], expected_kind: u8) -> Result<SecretValue<Vec<u8>>, SgxStatus> {
+        if self.kind != expected_kind {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        let key = Self::expand_key(sealing_key, self.kdf_label)?;
+        let mut plaintext = SecretValue::new(self.payload.to_vec());
+        key.decrypt(plaintext.get_mut(), self.aad, &self.iv, &self.mac)?;
+        Ok(plaintext)
+    }
+
+    // T(1) = HMAC-SHA256(sealing_key, kdf_label || 0x01), which is all of the output of HKDF-Expand a 32-byte key needs
+    fn expand_key(sealing_key: &[u8; 32], kdf_label: &[u8]) -> Result<AesGcmKey, SgxStatus> {
+        let mut okm = SecretValue::new([0; SHA256HMACContext::hash_len()]);
+        let mut hmac = SHA256HMACContext::new(*sealing_key);
+        hmac.update(kdf_label);
+        hmac.update(&[1]);
+        hmac.result(okm.get_mut());
+        hmac.clear();
+        AesGcmKey::new(okm.get())
+    }
+
+    const fn header_len(kdf_label_len: usize) -> usize {
+        SEALED_BLOB_MAGIC.len() + 5 + kdf_label_len + IV_SIZE + 4
+    }
+}
+
+//
+// sealing key
+//
+
+/// Returns the key sealed blobs meant to outlive this enclave instance should be sealed with, which is bound to
+/// the enclave measurement and to the platform it runs on.
+pub fn sealing_key() -> Result<SecretValue<[u8; 32]>, SgxStatus> {
+    let mut key = SecretValue::new(sgxsd_aes_gcm_key { data: [0; 32] });
+    match unsafe { sgxsd_enclave_get_sealing_key(key.get_mut()) } {
+        SGX_SUCCESS => Ok(SecretValue::new(key.get().data)),
+        error => Err(error),
+    }
+}
+
+//
+// Reader impls
+//
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, len: usize) -> Result<&'a [u8], SgxStatus> {
+        if len > self.0.len() {
+            return Err(SGX_ERROR_INVALID_PARAMETER);
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, SgxStatus> {
+        Ok(u8::from_le_bytes(self.read(1)?.try_into().map_err(|_| SGX_ERROR_INVALID_PARAMETER)?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, SgxStatus> {
+        Ok(u32::from_le_bytes(self.read(4)?.try_into().map_err(|_| SGX_ERROR_INVALID_PARAMETER)?))
+    }
+}
+
+//
+// tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::super::mocks;
+    use super::*;
+
+    use mockers::matchers::*;
+    use mockers::Scenario;
+
+    const KIND: u8 = 1;
+    const LABEL: &[u8] = b"incident-record";
+
+    fn valid_blob() -> Vec<u8> {
+        SealedBlob::seal(&test_ffi::rand(), KIND, LABEL, &[1, 2, 3]).unwrap()
+    }
+
+    #[test]
+    fn parse_valid() {
+        let blob = valid_blob();
+        let sealed = SealedBlob::parse(&blob).unwrap();
+        assert_eq!(sealed.kind, KIND);
+        assert_eq!(sealed.kdf_label, LABEL);
+        assert_eq!(sealed.payload.len(), 3);
+        assert_eq!(sealed.aad.len(), SealedBlob::header_len(LABEL.len()));
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let blob = valid_blob();
+        for len in 0..blob.len() {
+            assert!(SealedBlob::parse(&blob[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_trailing_data() {
+        let mut blob = valid_blob();
+        blob.push(0);
+        assert!(SealedBlob::parse(&blob).is_err());
+    }
+
+    #[test]
+    fn parse_unknown_identifiers() {
+        let blob = valid_blob();
+        // the version, cipher suite and KDF, either side of the kind
+        for &offset in &[SEALED_BLOB_MAGIC.len(), SEALED_BLOB_MAGIC.len() + 2, SEALED_BLOB_MAGIC.len() + 3] {
+            let mut bad_blob = blob.clone();
+            bad_blob[offset] = 0xFF;
+            assert!(SealedBlob::parse(&bad_blob).is_err());
+        }
+        let mut bad_blob = blob.clone();
+        bad_blob[0] ^= 1;
+        assert!(SealedBlob::parse(&bad_blob).is_err());
+    }
+
+    #[test]
+    fn seal_invalid_label() {
+        assert!(SealedBlob::seal(&test_ffi::rand(), KIND, &[], &[]).is_err());
+        assert!(SealedBlob::seal(&test_ffi::rand(), KIND, &[0; MAX_KDF_LABEL_SIZE + 1], &[]).is_err());
+    }
+
+    #[test]
+    fn open_other_kind() {
+        let sealing_key = test_ffi::rand();
+        let blob = SealedBlob::seal(&sealing_key, KIND, LABEL, &[1, 2, 3]).unwrap();
+        assert!(SealedBlob::parse(&blob).unwrap().open(&sealing_key, KIND + 1).is_err());
+    }
+
+    #[test]
+    fn open_authenticates_header() {
+        let sealing_key = test_ffi::rand();
+        let blob = SealedBlob::seal(&sealing_key, KIND, LABEL, &[1, 2, 3]).unwrap();
+        let sealed = SealedBlob::parse(&blob).unwrap();
+        let expected_aad = sealed.aad.to_vec();
+
+        let scenario = Scenario::new();
+        let decrypt_mock = test_ffi::mock_for(&mocks::SGXSD_AES_GCM_DECRYPT, &scenario);
+        scenario.expect(
+            decrypt_mock
+                .sgxsd_aes_gcm_decrypt(any(), any(), any(), check(move |aad: &&[u8]| *aad == &expected_aad[..]))
+                .and_return(Ok(vec![4, 5, 6])),
+        );
+
+        let plaintext = sealed.open(&sealing_key, KIND).unwrap();
+        assert_eq!(plaintext.get(), &[4, 5, 6]);
+
+        test_ffi::clear(&mocks::SGXSD_AES_GCM_DECRYPT);
+    }
+}
@@ -18,6 +18,8 @@ package org.whispersystems.contactdiscovery.configuration;
 
 import com.fasterxml.jackson.annotation.JsonProperty;
 
+import java.util.Optional;
+
 /**
  * Configuration for service rate limits
  *
@@ -46,6 +48,10 @@ public class RateLimitsConfiguration {
     @JsonProperty
     private double leakRatePerMinute;
 
+    // candidate limit evaluated alongside this one on the same traffic, whose verdicts are only counted, never enforced
+    @JsonProperty
+    private RateLimitConfiguration shadow;
+
     public RateLimitConfiguration(int bucketSize, double leakRatePerMinute) {
       this.bucketSize        = bucketSize;
       this.leakRatePerMinute = leakRatePerMinute;
@@ -60,5 +66,9 @@ public class RateLimitsConfiguration {
     public double getLeakRatePerMinute() {
       return leakRatePerMinute;
     }
+
+    public Optional<RateLimitConfiguration> getShadow() {
+      return Optional.ofNullable(shadow);
+    }
   }
 }
@@ -0,0 +1,15 @@
+/*
+ * Copyright 2021 Signal Messenger, LLC
+ * SPDX-License-Identifier: AGPL-3.0-only
+ */
+
+package org.whispersystems.contactdiscovery.enclave;
+
+/**
+ * A session's key was erased after too many of its messages failed to decrypt, so the
+ * client has to negotiate a new session. Answered like a missing pending request.
+ */
+public class SessionRenegotiationRequiredException extends NoSuchPendingRequestException {
+  public SessionRenegotiationRequiredException() {
+  }
+}
@@ -181,11 +181,13 @@ public class SgxEnclave implements Runnable {
   private Exception convertSgxException(SgxException ex) {
     if (ex.getCode() <= Integer.MAX_VALUE) {
       switch ((int) ex.getCode()) {
-        case SgxException.SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND: return new NoSuchPendingRequestException();
-        case SgxException.SABD_ERROR_INVALID_REQUEST_SIZE:       return new InvalidRequestSizeException();
-        case SgxException.SGX_ERROR_MAC_MISMATCH:                return new AEADBadTagException();
-        case SgxException.SGX_ERROR_INVALID_PARAMETER:           return new IllegalArgumentException(ex.getName(), ex);
-        case SgxException.SGX_ERROR_INVALID_STATE:               return new IllegalStateException(ex.getName(), ex);
+        case SgxException.SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND:      return new NoSuchPendingRequestException();
+        case SgxException.SGXSD_ERROR_SESSION_NOT_FOUND:              return new NoSuchPendingRequestException();
+        case SgxException.SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED: return new SessionRenegotiationRequiredException();
+        case SgxException.SABD_ERROR_INVALID_REQUEST_SIZE:            return new InvalidRequestSizeException();
+        case SgxException.SGX_ERROR_MAC_MISMATCH:                     return new AEADBadTagException();
+        case SgxException.SGX_ERROR_INVALID_PARAMETER:                return new IllegalArgumentException(ex.getName(), ex);
+        case SgxException.SGX_ERROR_INVALID_STATE:                    return new IllegalStateException(ex.getName(), ex);
       }
     }
     return ex;
@@ -271,7 +273,9 @@ public class SgxEnclave implements Runnable {
                                                             byte[] requestId,
                                                             SgxsdMessage query,
                                                             int queryPhoneCount,
-                                                            byte[] queryCommitment)
+                                                            byte[] queryCommitment,
+                                                            int protocolVersion,
+                                                            int replyFlags)
     {
       if (processed) {
         throw new IllegalStateException("batch_already_processed");
@@ -293,6 +297,8 @@ public class SgxEnclave implements Runnable {
       callArgs.query_mac          = query.getMac();
       callArgs.query_phone_count  = queryPhoneCount;
       callArgs.query_commitment   = queryCommitment;
+      callArgs.protocol_version   = protocolVersion;
+      callArgs.reply_flags        = replyFlags;
 
       try {
         nativeServerCall(getEnclaveState().id, stateHandle, callArgs, future);
@@ -369,6 +375,8 @@ public class SgxEnclave implements Runnable {
     byte[] msg_iv;
     byte[] msg_mac;
     byte[] pending_request_id;
+    int    protocol_version;
+    int    reply_flags;
   }
 
   private static native void nativeEnclaveStart(String enclavePath, boolean debug, byte pendingRequestsTableOrder, EnclaveStartCallback callback) throws SgxException;
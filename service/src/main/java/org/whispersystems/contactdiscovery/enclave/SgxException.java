@@ -45,12 +45,35 @@ public class SgxException extends Exception {
 
   // from sgxsd.h:
   public static final int
-    SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND = (0x10001);
+    SGXSD_ERROR_PENDING_REQUEST_NOT_FOUND      = (0x10001),
+    SGXSD_ERROR_SESSION_NOT_FOUND              = (0x10002),
+    SGXSD_ERROR_SESSION_REPLAYED               = (0x10003),
+    SGXSD_ERROR_SESSION_RENEGOTIATION_REQUIRED = (0x10004);
 
   // from sabd.h:
   public static final int
       SABD_ERROR_INVALID_REQUEST_SIZE = (0x20001);
 
+  // from cds.h:
+  public static final int
+    CDS_ERROR_INVALID_REQUEST_SIZE           = (0x20001),
+    CDS_ERROR_QUERY_COMMITMENT_MISMATCH      = (0x20002),
+    CDS_ERROR_UNTRUSTED_READ_LIMIT_EXCEEDED  = (0x20003),
+    CDS_ERROR_INVALID_CANONICALIZATION_RULES = (0x20004),
+    CDS_ERROR_DIRECTORY_DIGEST_MISMATCH      = (0x20005),
+    CDS_ERROR_DIRECTORY_EPOCH_MISMATCH       = (0x20006),
+    CDS_ERROR_ENCLAVE_HALTED                 = (0x20007),
+    CDS_ERROR_FAIR_SHARE_EXCEEDED            = (0x20008),
+    CDS_ERROR_CANARY_MISMATCH                = (0x20009),
+    CDS_ERROR_BATCH_FULL                     = (0x2000A),
+    CDS_ERROR_SESSION_DENIED                 = (0x2000B),
+    CDS_ERROR_QUERY_NOT_SORTED               = (0x2000C),
+    CDS_ERROR_UNSUPPORTED_PROTOCOL_VERSION   = (0x2000D),
+    CDS_ERROR_DUPLICATE_QUERY_PHONE          = (0x2000E),
+    CDS_ERROR_REQUEST_PHONE_LIMIT_EXCEEDED   = (0x2000F),
+    CDS_ERROR_INVALID_QUERY_PHONE            = (0x20010),
+    CDS_ERROR_REQUEST_DEFERRED               = (0x20011);
+
   // from sgx_error.h:
   public static final int
     SGX_ERROR_UNEXPECTED         = (0x0001),      /* Unexpected error */
@@ -25,14 +25,24 @@ public class SgxsdMessage {
   private final byte[] data;
   private final byte[] iv;
   private final byte[] mac;
+  private final byte[] batchPublicKey;
 
   public SgxsdMessage(byte[] data, byte[] iv, byte[] mac) {
-    if (data == null || iv == null || mac == null) {
+    this(data, iv, mac, new byte[0]);
+  }
+
+  /**
+   * @param batchPublicKey the ephemeral public key of the reply batch, mixed into the key of
+   *                       replies from the enclave, or empty for messages to the enclave
+   */
+  public SgxsdMessage(byte[] data, byte[] iv, byte[] mac, byte[] batchPublicKey) {
+    if (data == null || iv == null || mac == null || batchPublicKey == null) {
       throw new IllegalArgumentException();
     }
-    this.data = data;
-    this.iv   = iv;
-    this.mac  = mac;
+    this.data           = data;
+    this.iv             = iv;
+    this.mac            = mac;
+    this.batchPublicKey = batchPublicKey;
   }
 
   public byte[] getData() {
@@ -46,4 +56,8 @@ public class SgxsdMessage {
   public byte[] getMac() {
     return mac;
   }
+
+  public byte[] getBatchPublicKey() {
+    return batchPublicKey;
+  }
 }
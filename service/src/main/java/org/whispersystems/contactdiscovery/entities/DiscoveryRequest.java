@@ -78,6 +78,14 @@ public class DiscoveryRequest {
   @JsonProperty
   private String context = "Default";
 
+  @JsonProperty
+  @Min(0)
+  private int protocolVersion;
+
+  @JsonProperty
+  @Min(0)
+  private int replyFlags;
+
   public DiscoveryRequest() {
 
   }
@@ -117,8 +125,16 @@ public class DiscoveryRequest {
 
   public String getContext() { return context; }
 
+  public int getProtocolVersion() {
+    return protocolVersion;
+  }
+
+  public int getReplyFlags() {
+    return replyFlags;
+  }
+
   public String toString() {
-    return "{ addressCount: " + addressCount + ", iv: " + Hex.encodeHexString(iv) + ", data: " + Hex.encodeHexString(data) + ", mac: " + Hex.encodeHexString(mac) + ", commitment: " + Hex.encodeHexString(commitment) + ", envelopes: " + envelopes + ", context: " + context + "   }";
+    return "{ addressCount: " + addressCount + ", iv: " + Hex.encodeHexString(iv) + ", data: " + Hex.encodeHexString(data) + ", mac: " + Hex.encodeHexString(mac) + ", commitment: " + Hex.encodeHexString(commitment) + ", envelopes: " + envelopes + ", context: " + context + ", protocolVersion: " + protocolVersion + ", replyFlags: " + replyFlags + "   }";
   }
 
   @Override
@@ -132,12 +148,14 @@ public class DiscoveryRequest {
            Arrays.equals(mac, that.mac) &&
            Arrays.equals(commitment, that.commitment) &&
            Objects.equals(envelopes, that.envelopes) &&
-           Objects.equals(context, that.context);
+           Objects.equals(context, that.context) &&
+           protocolVersion == that.protocolVersion &&
+           replyFlags == that.replyFlags;
   }
 
   @Override
   public int hashCode() {
-    int result = Objects.hash(addressCount, envelopes, context);
+    int result = Objects.hash(addressCount, envelopes, context, protocolVersion, replyFlags);
     result = 31 * result + Arrays.hashCode(iv);
     result = 31 * result + Arrays.hashCode(data);
     result = 31 * result + Arrays.hashCode(mac);
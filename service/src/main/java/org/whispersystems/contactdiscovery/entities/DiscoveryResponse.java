@@ -55,13 +55,20 @@ public class DiscoveryResponse {
   @JsonDeserialize(using = ByteArrayAdapter.Deserializing.class)
   private byte[] mac;
 
+  @JsonProperty
+  @NotNull
+  @JsonSerialize(using = ByteArrayAdapter.Serializing.class)
+  @JsonDeserialize(using = ByteArrayAdapter.Deserializing.class)
+  private byte[] batchPublic;
+
   public DiscoveryResponse() {}
 
-  public DiscoveryResponse(byte[] requestId, byte[] iv, byte[] data, byte[] mac) {
-    this.requestId = requestId;
-    this.iv        = iv;
-    this.data      = data;
-    this.mac       = mac;
+  public DiscoveryResponse(byte[] requestId, byte[] iv, byte[] data, byte[] mac, byte[] batchPublic) {
+    this.requestId   = requestId;
+    this.iv          = iv;
+    this.data        = data;
+    this.mac         = mac;
+    this.batchPublic = batchPublic;
   }
 
   public byte[] getRequestId() {
@@ -80,7 +87,11 @@ public class DiscoveryResponse {
     return mac;
   }
 
+  public byte[] getBatchPublic() {
+    return batchPublic;
+  }
+
   public String toString() {
-    return "{iv: " + (iv == null ? null : Hex.encodeHexString(iv)) + ", data: " + (data == null ? null: Hex.encodeHexString(data)) + ", mac: " + (mac == null ? null :Hex.encodeHexString(mac)) + "}";
+    return "{iv: " + (iv == null ? null : Hex.encodeHexString(iv)) + ", data: " + (data == null ? null: Hex.encodeHexString(data)) + ", mac: " + (mac == null ? null :Hex.encodeHexString(mac)) + ", batchPublic: " + (batchPublic == null ? null : Hex.encodeHexString(batchPublic)) + "}";
   }
 }
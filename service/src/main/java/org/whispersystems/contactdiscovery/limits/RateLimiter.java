@@ -23,11 +23,13 @@ import com.fasterxml.jackson.core.JsonProcessingException;
 import com.fasterxml.jackson.databind.ObjectMapper;
 import org.slf4j.Logger;
 import org.slf4j.LoggerFactory;
+import org.whispersystems.contactdiscovery.configuration.RateLimitsConfiguration.RateLimitConfiguration;
 import org.whispersystems.contactdiscovery.util.Constants;
 import org.whispersystems.contactdiscovery.util.SystemMapper;
 
 import java.io.IOException;
 import java.time.Clock;
+import java.util.Optional;
 
 import static com.codahale.metrics.MetricRegistry.name;
 import redis.clients.jedis.Jedis;
@@ -39,29 +41,48 @@ public class RateLimiter {
   private final ObjectMapper mapper = SystemMapper.getMapper();
 
   private final Meter       meter;
+  private final Meter       shadowStricterMeter;
+  private final Meter       shadowLaxerMeter;
   private final Pool<Jedis> cacheClient;
   private final String      name;
   private final int         bucketSize;
   private final double      leakRatePerMillis;
 
+  private final Optional<RateLimitConfiguration> shadow;
+
   public RateLimiter(Pool<Jedis> cacheClient, String name,
                      int bucketSize, double leakRatePerMinute)
+  {
+    this(cacheClient, name, bucketSize, leakRatePerMinute, Optional.empty());
+  }
+
+  public RateLimiter(Pool<Jedis> cacheClient, String name,
+                     int bucketSize, double leakRatePerMinute,
+                     Optional<RateLimitConfiguration> shadow)
   {
     MetricRegistry metricRegistry = SharedMetricRegistries.getOrCreate(Constants.METRICS_NAME);
 
-    this.meter             = metricRegistry.meter(name(getClass(), name, "exceeded"));
-    this.cacheClient       = cacheClient;
-    this.name              = name;
-    this.bucketSize        = bucketSize;
-    this.leakRatePerMillis = leakRatePerMinute / (60.0 * 1000.0);
+    this.meter               = metricRegistry.meter(name(getClass(), name, "exceeded"));
+    this.shadowStricterMeter = metricRegistry.meter(name(getClass(), name, "shadowExceeded"));
+    this.shadowLaxerMeter    = metricRegistry.meter(name(getClass(), name, "shadowAllowed"));
+    this.cacheClient         = cacheClient;
+    this.name                = name;
+    this.bucketSize          = bucketSize;
+    this.leakRatePerMillis   = toLeakRatePerMillis(leakRatePerMinute);
+    this.shadow              = shadow;
   }
 
   public void validate(String key, int amount) throws RateLimitExceededException {
-    LeakyBucket bucket = getBucket(key);
+    LeakyBucket bucket  = getBucket(getBucketName(key), bucketSize, leakRatePerMillis);
+    boolean     allowed = bucket.add(amount);
 
-    if (bucket.add(amount)) {
-      setBucket(key, bucket);
-    } else {
+    if (allowed) {
+      setBucket(getBucketName(key), bucket, bucketSize, leakRatePerMillis);
+    }
+
+    shadow.ifPresent(candidate -> validateShadow(candidate, key, amount, allowed));
+
+    if (!allowed) {
       meter.mark();
       throw new RateLimitExceededException(key + " , " + amount, (long) (amount / leakRatePerMillis));
     }
@@ -71,18 +92,39 @@ public class RateLimiter {
     validate(key, 1);
   }
 
-  private void setBucket(String key, LeakyBucket bucket) {
+  // the candidate keeps buckets of its own, and only counts where its verdict differs from the enforced one
+  private void validateShadow(RateLimitConfiguration candidate, String key, int amount, boolean allowed) {
+    try {
+      double      shadowLeakRatePerMillis = toLeakRatePerMillis(candidate.getLeakRatePerMinute());
+      LeakyBucket shadowBucket            = getBucket(getShadowBucketName(key), candidate.getBucketSize(), shadowLeakRatePerMillis);
+      boolean     shadowAllowed           = shadowBucket.add(amount);
+
+      if (shadowAllowed) {
+        setBucket(getShadowBucketName(key), shadowBucket, candidate.getBucketSize(), shadowLeakRatePerMillis);
+      }
+
+      if (allowed && !shadowAllowed) {
+        shadowStricterMeter.mark();
+      } else if (!allowed && shadowAllowed) {
+        shadowLaxerMeter.mark();
+      }
+    } catch (RuntimeException e) {
+      logger.warn("Shadow rate limit evaluation failed", e);
+    }
+  }
+
+  private void setBucket(String bucketName, LeakyBucket bucket, int bucketSize, double leakRatePerMillis) {
     try (Jedis jedis = cacheClient.getResource()) {
       String serialized = bucket.serialize(mapper);
-      jedis.setex(getBucketName(key), (int) Math.ceil((bucketSize / leakRatePerMillis) / 1000), serialized);
+      jedis.setex(bucketName, (int) Math.ceil((bucketSize / leakRatePerMillis) / 1000), serialized);
     } catch (JsonProcessingException e) {
       throw new IllegalArgumentException(e);
     }
   }
 
-  private LeakyBucket getBucket(String key) {
+  private LeakyBucket getBucket(String bucketName, int bucketSize, double leakRatePerMillis) {
     try (Jedis jedis = cacheClient.getResource()) {
-      String serialized = jedis.get(getBucketName(key));
+      String serialized = jedis.get(bucketName);
 
       if (serialized != null) {
         return LeakyBucket.fromSerialized(mapper, serialized, Clock.systemUTC());
@@ -97,4 +139,12 @@ public class RateLimiter {
   private String getBucketName(String key) {
     return "leaky_bucket::" + name + "::" + key;
   }
+
+  private String getShadowBucketName(String key) {
+    return "leaky_bucket_shadow::" + name + "::" + key;
+  }
+
+  private static double toLeakRatePerMillis(double leakRatePerMinute) {
+    return leakRatePerMinute / (60.0 * 1000.0);
+  }
 }
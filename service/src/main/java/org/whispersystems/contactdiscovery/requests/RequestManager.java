@@ -220,6 +220,8 @@ public class RequestManager implements Managed {
           for (PendingRequest request : requests) {
             int                      addressCount = request.getRequest().getAddressCount();
             byte[]                   commitment   = request.getRequest().getCommitment();
+            int                      version      = request.getRequest().getProtocolVersion();
+            int                      replyFlags   = request.getRequest().getReplyFlags();
             DiscoveryRequestEnvelope envelope     = request.getRequest().getEnvelopes().get(LOCAL_ENCLAVE_HOST_ID);
             byte[]                   requestId    = envelope.getRequestId();
 
@@ -230,11 +232,12 @@ public class RequestManager implements Managed {
                                                             envelope.getIv(),
                                                             envelope.getMac());
 
-            batch.add(envelopeMessage, requestId, queryMessage, addressCount, commitment)
+            batch.add(envelopeMessage, requestId, queryMessage, addressCount, commitment, version, replyFlags)
                  .thenApply(response -> request.getResponse().complete(new DiscoveryResponse(requestId,
                                                                                              response.getIv(),
                                                                                              response.getData(),
-                                                                                             response.getMac())))
+                                                                                             response.getMac(),
+                                                                                             response.getBatchPublicKey())))
                  .exceptionally(exception -> request.getResponse().completeExceptionally(exception));
           }
 
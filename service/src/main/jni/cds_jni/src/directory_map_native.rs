@@ -1,6 +1,7 @@
 // Copyright 2020 Signal Messenger, LLC.
 // SPDX-License-Identifier: AGPL-3.0-only
 
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Mutex, RwLock};
 
 use jni::objects::{JClass, JObject};
@@ -27,6 +28,8 @@ pub(crate) fn convert_native_handle_to_directory_map_reference(native_handle: jl
 pub struct DirectoryMap {
     building: Mutex<(bool, InternalBuffers)>,
     serving: RwLock<InternalBuffers>,
+    // counts the commits that changed the serving buffers, only ever bumped under their write lock
+    serving_generation: AtomicU64,
 }
 
 impl DirectoryMap {
@@ -34,6 +37,7 @@ impl DirectoryMap {
         Ok(Self {
             building: Mutex::new((false, InternalBuffers::new(starting_capacity, min_load_factor, max_load_factor)?)),
             serving: RwLock::new(InternalBuffers::new(starting_capacity, min_load_factor, max_load_factor)?),
+            serving_generation: AtomicU64::new(0),
         })
     }
 
@@ -72,6 +76,11 @@ impl DirectoryMap {
         borrow(read_lock.e164s_slice(), read_lock.uuids_slice())
     }
 
+    // only names the serving buffers borrowed if read while borrowing them, since a commit may bump it right after
+    pub(crate) fn serving_generation(&self) -> u64 {
+        self.serving_generation.load(Ordering::SeqCst)
+    }
+
     fn commit(&self) -> Result<bool, PossibleError> {
         let mut lock = self
             .building
@@ -86,6 +95,7 @@ impl DirectoryMap {
                 .write()
                 .expect("DirectoryMap serving write lock poisoned while locking during commit");
             std::mem::swap(&mut lock.1, &mut *write_lock);
+            self.serving_generation.fetch_add(1, Ordering::SeqCst);
         }
         {
             let read_lock = self
@@ -291,6 +301,7 @@ mod test {
         assert!(result.is_ok());
         assert!(!result.unwrap());
         assert_eq!(map.size(), 0);
+        assert_eq!(map.serving_generation(), 0);
 
         let result = map.insert(e164, uuid);
         assert!(result.is_ok());
@@ -301,6 +312,7 @@ mod test {
         assert!(result.is_ok());
         assert!(result.unwrap());
         assert_eq!(map.size(), 1);
+        assert_eq!(map.serving_generation(), 1);
 
         let result = map.remove(e164);
         assert!(result.is_ok());
@@ -316,6 +328,7 @@ mod test {
         assert!(result.is_ok());
         assert!(result.unwrap());
         assert_eq!(map.size(), 0);
+        assert_eq!(map.serving_generation(), 2);
     }
 
     #[test]
@@ -9,7 +9,7 @@ use jni::JNIEnv;
 use sgx_sdk_ffi::SgxStatus;
 use thiserror::Error as ThisError;
 
-use cds_enclave_ffi::sgxsd;
+use cds_enclave_ffi::{args, sgxsd};
 use directory_map_native::{convert_native_handle_to_directory_map_reference, DirectoryMap};
 
 mod directory_map_native;
@@ -20,6 +20,7 @@ const SGX_EXCEPTION_CSTOR: &'static str = "(Ljava/lang/String;J)V";
 
 const NULL_POINTER_EXCEPTION_CLASS: &'static str = "java/lang/NullPointerException";
 const RUNTIME_EXCEPTION_CLASS: &'static str = "java/lang/RuntimeException";
+const ILLEGAL_ARGUMENT_EXCEPTION_CLASS: &'static str = "java/lang/IllegalArgumentException";
 
 #[derive(ThisError, Debug)]
 enum PossibleError {
@@ -50,6 +51,12 @@ impl From<sgxsd::SgxsdError> for PossibleError {
     }
 }
 
+impl From<args::ArgsError> for PossibleError {
+    fn from(e: args::ArgsError) -> Self {
+        generic_exception(ILLEGAL_ARGUMENT_EXCEPTION_CLASS, &e.to_string())
+    }
+}
+
 fn throw_sgx_name_code_to_exception(env: JNIEnv, name: &'static str, code: i64) -> Result<(), jni::errors::Error> {
     sgx_name_code_to_exception(&env, name, code)
         .map(|exc| env.throw(JThrowable::from(exc)))
@@ -3,12 +3,14 @@
 
 use std::mem::size_of;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use jni::objects::*;
 use jni::sys::*;
 use jni::{sys, Executor, JNIEnv};
 use sgx_sdk_ffi::{SgxEnclaveId, SgxStatus};
 
+use cds_enclave_ffi::args::{ServerCallArgsBuilder, ServerStartArgsBuilder};
 use cds_enclave_ffi::sgxsd::{self, MessageReply, SgxsdError};
 
 use crate::{
@@ -22,7 +24,7 @@ const SGX_NEGOTIATION_RESPONSE_CSTOR: &'static str = "([B[B[B[B[B)V";
 const NATIVE_CALL_ARGS_CLASS: &'static str = "org/whispersystems/contactdiscovery/enclave/SgxEnclave$NativeServerCallArgs";
 
 const SGXSD_MESSAGE_CLASS: &'static str = "org/whispersystems/contactdiscovery/enclave/SgxsdMessage";
-const SGXSD_MESSAGE_CLASS_CSTOR: &'static str = "([B[B[B)V";
+const SGXSD_MESSAGE_CLASS_CSTOR: &'static str = "([B[B[B[B)V";
 
 const COMPLETABLE_FUTURE_CLASS: &'static str = "java/util/concurrent/CompletableFuture";
 const COMPLETABLE_FUTURE_COMPLETE_METHOD: &'static str = "complete";
@@ -78,7 +80,7 @@ fn enclave_start<'a>(
 
     let enclave_id = sgxsd::sgxsd_create_enclave(enclave_path_c, debug).map_err(PossibleError::from)?;
     let enclave_id_j = enclave_id as i64;
-    sgxsd::sgxsd_node_init(enclave_id, pending_requests_table_order as u8)?;
+    sgxsd::sgxsd_node_init(enclave_id, pending_requests_table_order as u8, 0)?;
     env.call_method(
         callback,
         "runEnclave",
@@ -224,12 +226,17 @@ pub extern "C" fn Java_org_whispersystems_contactdiscovery_enclave_SgxEnclave_na
     return jni_catch(env.clone(), (), || server_start(env, enclave_id, state_handle, max_query_phones));
 }
 
+// the enclave only uses these to report how long requests have been queued, so wall clock milliseconds suffice
+fn host_ticks() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 fn server_start(_env: JNIEnv, enclave_id: i64, state_handle: i64, max_query_phones: i32) -> Result<(), PossibleError> {
-    let args = sgxsd::SgxsdServerInitArgs {
-        max_query_phones: max_query_phones as u32,
-        max_ratelimit_states: 0,
-    };
-    return sgxsd::sgxsd_server_start(enclave_id as u64, &args, state_handle as u64).map_err(PossibleError::from);
+    let args = ServerStartArgsBuilder::new(max_query_phones as u32).build()?;
+    return sgxsd::sgxsd_server_start(enclave_id as u64, args.raw(), state_handle as u64).map_err(PossibleError::from);
 }
 
 #[allow(non_snake_case)]
@@ -279,6 +286,8 @@ fn server_call(env: JNIEnv, enclave_id: i64, state_handle: i64, args: JObject, f
     get_nonnull_fixed_size_array_field(&env, args, "msg_mac", &mut msg_mac[..])?;
 
     let query_phone_count = env.get_field(args, "query_phone_count", "I")?.i()?;
+    let protocol_version = env.get_field(args, "protocol_version", "I")?.i()?;
+    let reply_flags = env.get_field(args, "reply_flags", "I")?.i()?;
 
     let query_iv = &mut [0 as u8; size_of::<sgxsd::SgxsdAesGcmIv>()];
     get_nonnull_fixed_size_array_field(&env, args, "query_iv", &mut query_iv[..])?;
@@ -304,19 +313,17 @@ fn server_call(env: JNIEnv, enclave_id: i64, state_handle: i64, args: JObject, f
     let pending_request_id_mac = &mut [0 as u8; size_of::<sgxsd::SgxsdAesGcmMac>()];
     pending_request_id_mac.clone_from_slice(&pending_request_id_bytes[u64_and_iv..u64_iv_and_mac]);
 
-    let sgxcallargs = sgxsd::SgxsdServerCallArgs {
-        query_phone_count: query_phone_count as u32,
-        ratelimit_state_size: Default::default(),
-        ratelimit_state_uuid: Default::default(),
-        ratelimit_state_data: std::ptr::null_mut(),
-        query: sgxsd::CDSEncryptedMsg {
-            iv: sgxsd::SgxsdAesGcmIv { data: *query_iv },
-            mac: sgxsd::SgxsdAesGcmMac { data: *query_mac },
-            size: query_data.len() as u32,
-            data: query_data.as_mut_ptr(),
-        },
-        query_commitment: *query_commitment,
-    };
+    let sgxcallargs = ServerCallArgsBuilder::new(query_phone_count as u32)
+        .query(
+            sgxsd::SgxsdAesGcmIv { data: *query_iv },
+            sgxsd::SgxsdAesGcmMac { data: *query_mac },
+            &mut query_data,
+        )
+        .query_commitment(*query_commitment)
+        .protocol_version(protocol_version as sgxsd::ProtocolVersion)
+        .reply_flags(reply_flags as sgxsd::ReplyFlags)
+        .admission_ticks(host_ticks())
+        .build()?;
     let msg_header = sgxsd::SgxsdMessageHeader {
         iv: sgxsd::SgxsdAesGcmIv { data: *msg_iv },
         mac: sgxsd::SgxsdAesGcmMac { data: *msg_mac },
@@ -367,7 +374,7 @@ fn server_call(env: JNIEnv, enclave_id: i64, state_handle: i64, args: JObject, f
 
     return sgxsd::sgxsd_server_call(
         enclave_id as u64,
-        sgxcallargs,
+        *sgxcallargs.raw(),
         &msg_header,
         msg_data.as_slice(),
         reply_fun,
@@ -380,7 +387,8 @@ fn complete_future_successfully(env: &JNIEnv, reply: MessageReply, future_ref: G
     let data = slice_to_jni_array(&env, &reply.data)?;
     let iv = slice_to_jni_array(&env, &reply.iv.data)?;
     let mac = slice_to_jni_array(&env, &reply.mac.data)?;
-    let args: Vec<JValue> = vec![data, iv, mac].into_iter().map(JValue::from).collect();
+    let batch_pubkey = slice_to_jni_array(&env, &reply.batch_pubkey)?;
+    let args: Vec<JValue> = vec![data, iv, mac, batch_pubkey].into_iter().map(JValue::from).collect();
     let msg = env.new_object(SGXSD_MESSAGE_CLASS, SGXSD_MESSAGE_CLASS_CSTOR, &args)?;
     let complete_args: &[JValue] = &[msg.into()];
     return env
@@ -470,8 +478,23 @@ fn server_stop(enclave_id: i64, state_handle: i64, directory_map: &DirectoryMap)
             in_phones: &e164s[0],
             in_uuids: &uuids[0],
             in_phone_count: e164s.len() as u64,
+            in_metadata: std::ptr::null(),
+            in_metadata_size: 0,
+            directory_epoch: 0,
+            max_chunks: 0,
+            continuation_token: 0,
+            in_allowlist_phones: std::ptr::null(),
+            in_allowlist_phone_count: 0,
+            in_pni_uuids: std::ptr::null(),
+            in_username_hashes: std::ptr::null(),
+            in_username_uuids: std::ptr::null(),
+            in_username_count: 0,
+            in_change_epochs: std::ptr::null(),
+            directory_generation: directory_map.serving_generation(),
+            in_flags: std::ptr::null(),
         };
-        Ok(sgxsd::sgxsd_server_stop(enclave_id as u64, &args, state_handle as u64)?)
+        sgxsd::sgxsd_server_stop(enclave_id as u64, &args, state_handle as u64)?;
+        Ok(())
     })
 }
 
@@ -480,8 +503,23 @@ fn server_stop_no_directory_map(enclave_id: i64, state_handle: i64) -> Result<()
         in_phones: std::ptr::null(),
         in_uuids: std::ptr::null(),
         in_phone_count: 0,
+        in_metadata: std::ptr::null(),
+        in_metadata_size: 0,
+        directory_epoch: 0,
+        max_chunks: 0,
+        continuation_token: 0,
+        in_allowlist_phones: std::ptr::null(),
+        in_allowlist_phone_count: 0,
+        in_pni_uuids: std::ptr::null(),
+        in_username_hashes: std::ptr::null(),
+        in_username_uuids: std::ptr::null(),
+        in_username_count: 0,
+        in_change_epochs: std::ptr::null(),
+        directory_generation: 0,
+        in_flags: std::ptr::null(),
     };
-    Ok(sgxsd::sgxsd_server_stop(enclave_id as u64, &args, state_handle as u64)?)
+    sgxsd::sgxsd_server_stop(enclave_id as u64, &args, state_handle as u64)?;
+    Ok(())
 }
 
 #[allow(non_snake_case)]
@@ -0,0 +1,81 @@
+package org.whispersystems.contactdiscovery.limits;
+
+import com.codahale.metrics.SharedMetricRegistries;
+import org.junit.Before;
+import org.junit.Test;
+import org.whispersystems.contactdiscovery.configuration.RateLimitsConfiguration.RateLimitConfiguration;
+import org.whispersystems.contactdiscovery.util.Constants;
+import redis.clients.jedis.Jedis;
+import redis.clients.jedis.JedisPool;
+
+import java.util.Optional;
+
+import static com.codahale.metrics.MetricRegistry.name;
+import static org.junit.Assert.assertEquals;
+import static org.junit.Assert.fail;
+import static org.mockito.ArgumentMatchers.anyInt;
+import static org.mockito.ArgumentMatchers.anyString;
+import static org.mockito.ArgumentMatchers.eq;
+import static org.mockito.Mockito.mock;
+import static org.mockito.Mockito.never;
+import static org.mockito.Mockito.verify;
+import static org.mockito.Mockito.when;
+
+public class RateLimiterTest {
+
+  private final JedisPool jedisPool = mock(JedisPool.class);
+  private final Jedis     jedis     = mock(Jedis.class);
+
+  @Before
+  public void setup() {
+    when(jedisPool.getResource()).thenReturn(jedis);
+  }
+
+  @Test
+  public void testShadowStricter() throws RateLimitExceededException {
+    RateLimiter rateLimiter = new RateLimiter(jedisPool, "shadowStricter", 10, 10,
+                                              Optional.of(new RateLimitConfiguration(1, 1)));
+
+    rateLimiter.validate("key", 5);
+
+    assertEquals(1, meterCount("shadowStricter", "shadowExceeded"));
+    assertEquals(0, meterCount("shadowStricter", "shadowAllowed"));
+    verify(jedis).setex(eq("leaky_bucket::shadowStricter::key"), anyInt(), anyString());
+    verify(jedis, never()).setex(eq("leaky_bucket_shadow::shadowStricter::key"), anyInt(), anyString());
+  }
+
+  @Test
+  public void testShadowLaxer() {
+    RateLimiter rateLimiter = new RateLimiter(jedisPool, "shadowLaxer", 1, 1,
+                                              Optional.of(new RateLimitConfiguration(10, 10)));
+
+    try {
+      rateLimiter.validate("key", 5);
+      fail("rate limit not enforced");
+    } catch (RateLimitExceededException e) {
+      // the shadow limit is never enforced, in either direction
+    }
+
+    assertEquals(0, meterCount("shadowLaxer", "shadowExceeded"));
+    assertEquals(1, meterCount("shadowLaxer", "shadowAllowed"));
+    verify(jedis).setex(eq("leaky_bucket_shadow::shadowLaxer::key"), anyInt(), anyString());
+  }
+
+  @Test
+  public void testShadowFailure() throws RateLimitExceededException {
+    when(jedis.get("leaky_bucket_shadow::shadowFailure::key")).thenThrow(new RuntimeException());
+
+    RateLimiter rateLimiter = new RateLimiter(jedisPool, "shadowFailure", 10, 10,
+                                              Optional.of(new RateLimitConfiguration(1, 1)));
+
+    rateLimiter.validate("key", 5);
+
+    assertEquals(0, meterCount("shadowFailure", "shadowExceeded"));
+  }
+
+  private static long meterCount(String rateLimiterName, String meterName) {
+    return SharedMetricRegistries.getOrCreate(Constants.METRICS_NAME)
+                                 .meter(name(RateLimiter.class, rateLimiterName, meterName))
+                                 .getCount();
+  }
+}
@@ -56,6 +56,7 @@ public class ContactDiscoveryResourceTest {
   private final byte[] iv = new byte[12];
   private final byte[] data = new byte[512];
   private final byte[] mac = new byte[32];
+  private final byte[] batchPublic = new byte[32];
 
   private DiscoveryRequest validDiscoveryRequest;
   private DiscoveryRequest invalidDiscoveryRequest;
@@ -80,6 +81,7 @@ public class ContactDiscoveryResourceTest {
     new SecureRandom().nextBytes(iv);
     new SecureRandom().nextBytes(data);
     new SecureRandom().nextBytes(mac);
+    new SecureRandom().nextBytes(batchPublic);
 
     DiscoveryRequestEnvelope validEnvelope = new DiscoveryRequestEnvelope(requestId, new byte[12], new byte[32], new byte[16]);
     DiscoveryRequestEnvelope invalidEnvelope = new DiscoveryRequestEnvelope(requestId, null, null, null);
@@ -90,7 +92,7 @@ public class ContactDiscoveryResourceTest {
     var envelopes = Map.of(RequestManager.LOCAL_ENCLAVE_HOST_ID, validEnvelope, "fakehostid", validEnvelope);
     validMultipleAttestDiscRequest = new DiscoveryRequest(64, new byte[12], new byte[512], new byte[16], new byte[32], envelopes);
 
-    DiscoveryResponse discoveryResponse = new DiscoveryResponse(requestId, iv, data, mac);
+    DiscoveryResponse discoveryResponse = new DiscoveryResponse(requestId, iv, data, mac, batchPublic);
     CompletableFuture<DiscoveryResponse> responseFuture = CompletableFuture.completedFuture(discoveryResponse);
     CompletableFuture<DiscoveryResponse> exceptionFuture = new CompletableFuture<>();
     exceptionFuture.completeExceptionally(new NoSuchEnclaveException("bad enclave id"));
@@ -119,6 +121,7 @@ public class ContactDiscoveryResourceTest {
     assertArrayEquals(iv, response.getIv());
     assertArrayEquals(data, response.getData());
     assertArrayEquals(mac, response.getMac());
+    assertArrayEquals(batchPublic, response.getBatchPublic());
   }
 
   @Test
@@ -142,6 +145,7 @@ public class ContactDiscoveryResourceTest {
     assertArrayEquals(iv, response.getIv());
     assertArrayEquals(data, response.getData());
     assertArrayEquals(mac, response.getMac());
+    assertArrayEquals(batchPublic, response.getBatchPublic());
   }
 
   @Test(expected = BadRequestException.class)